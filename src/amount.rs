@@ -0,0 +1,297 @@
+//! Amounts of funds, with the precision the engine works with.
+//!
+//! By default an amount is a [`Decimal`]. With the `minor-units` feature it's a count of
+//! ten-thousandths in an `i64` instead, which makes the balance arithmetic a lot cheaper at the cost of range.
+//! Both behave the same, including how they're formatted, as long as the values fit.
+
+use core::fmt;
+use core::ops::Neg;
+
+use rust_decimal::Decimal;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// An amount with at most [`Amount::PRECISION`] decimal places.
+///
+/// Balances use it as well, so it can be negative, but records only carry non-negative amounts,
+/// see [`Amount::non_negative`]. Adding or subtracting amounts never loses precision, so there's no rounding involved.
+#[cfg(not(feature = "minor-units"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "Decimal", into = "Decimal"))]
+pub struct Amount(Decimal);
+
+/// An amount with at most [`Amount::PRECISION`] decimal places.
+///
+/// Balances use it as well, so it can be negative, but records only carry non-negative amounts,
+/// see [`Amount::non_negative`]. Adding or subtracting amounts never loses precision, so there's no rounding involved.
+#[cfg(feature = "minor-units")]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "Decimal", into = "Decimal"))]
+pub struct Amount {
+    /// In units of 10^-[`Amount::PRECISION`].
+    minor: i64,
+    /// The number of decimal places to show, like a [`Decimal`] would, trailing zeros beyond
+    /// [`Amount::PRECISION`] included. It doesn't take part in comparisons.
+    scale: u32,
+}
+
+/// What to do with amounts that have more decimal places than [`Amount::PRECISION`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PrecisionPolicy {
+    #[default]
+    Reject,
+    /// Round to [`Amount::PRECISION`] decimal places, with banker's rounding.
+    Round,
+}
+
+impl PrecisionPolicy {
+    /// Rounds `value` if that's the policy, leaving it to [`Amount::new`] to reject it otherwise.
+    pub fn apply(self, value: Decimal) -> Decimal {
+        match self {
+            PrecisionPolicy::Reject => value,
+            PrecisionPolicy::Round => value.round_dp(Amount::PRECISION),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum AmountError {
+    #[error("{0} has more than {precision} decimal places", precision = Amount::PRECISION)]
+    ExcessPrecision(Decimal),
+    #[error("{0} is negative")]
+    Negative(Decimal),
+    /// Only with the `minor-units` feature, when the value doesn't fit.
+    #[error("{0} is out of range")]
+    OutOfRange(Decimal),
+}
+
+impl Amount {
+    /// The number of decimal places amounts are kept to.
+    pub const PRECISION: u32 = 4;
+
+    #[cfg(not(feature = "minor-units"))]
+    pub const ZERO: Self = Self(Decimal::ZERO);
+    #[cfg(feature = "minor-units")]
+    pub const ZERO: Self = Self { minor: 0, scale: 0 };
+
+    #[cfg(not(feature = "minor-units"))]
+    pub const MAX: Self = Self(Decimal::MAX);
+    #[cfg(feature = "minor-units")]
+    pub const MAX: Self = Self {
+        minor: i64::MAX,
+        scale: Self::PRECISION,
+    };
+
+    /// Fails when `value` has more than [`Amount::PRECISION`] decimal places, trailing zeros don't count.
+    pub fn new(value: Decimal) -> Result<Self, AmountError> {
+        if value.normalize().scale() > Self::PRECISION {
+            return Err(AmountError::ExcessPrecision(value));
+        }
+        Self::from_decimal(value)
+    }
+
+    /// Like [`Amount::new`], additionally failing when `value` is negative, as is required for the amount of a record.
+    pub fn non_negative(value: Decimal) -> Result<Self, AmountError> {
+        if value.is_sign_negative() && !value.is_zero() {
+            return Err(AmountError::Negative(value));
+        }
+        Self::new(value)
+    }
+
+    /// Like [`Amount::non_negative`], with excess precision handled according to `policy`.
+    pub fn with_policy(value: Decimal, policy: PrecisionPolicy) -> Result<Self, AmountError> {
+        Self::non_negative(policy.apply(value))
+    }
+
+    pub fn is_negative(&self) -> bool {
+        *self < Self::ZERO
+    }
+}
+
+#[cfg(not(feature = "minor-units"))]
+impl Amount {
+    fn from_decimal(value: Decimal) -> Result<Self, AmountError> {
+        Ok(Self(value))
+    }
+
+    pub fn value(&self) -> Decimal {
+        self.0
+    }
+
+    /// `None` when the result doesn't fit.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    /// `None` when the result doesn't fit.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+}
+
+#[cfg(feature = "minor-units")]
+impl Amount {
+    const ONE: i64 = 10_i64.pow(Self::PRECISION);
+
+    fn from_decimal(value: Decimal) -> Result<Self, AmountError> {
+        use rust_decimal::prelude::ToPrimitive;
+
+        let minor = value
+            .checked_mul(Decimal::from(Self::ONE))
+            .and_then(|minor| minor.to_i64())
+            .ok_or(AmountError::OutOfRange(value))?;
+        Ok(Self {
+            minor,
+            scale: value.scale(),
+        })
+    }
+
+    pub fn value(&self) -> Decimal {
+        let mut value = Decimal::new(self.minor, Self::PRECISION);
+        value.rescale(self.scale);
+        value
+    }
+
+    /// `None` when the result doesn't fit.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        Some(Self {
+            minor: self.minor.checked_add(other.minor)?,
+            scale: self.scale.max(other.scale),
+        })
+    }
+
+    /// `None` when the result doesn't fit.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        Some(Self {
+            minor: self.minor.checked_sub(other.minor)?,
+            scale: self.scale.max(other.scale),
+        })
+    }
+}
+
+#[cfg(feature = "minor-units")]
+impl PartialEq for Amount {
+    fn eq(&self, other: &Self) -> bool {
+        self.minor == other.minor
+    }
+}
+
+#[cfg(feature = "minor-units")]
+impl Eq for Amount {}
+
+#[cfg(feature = "minor-units")]
+impl PartialOrd for Amount {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "minor-units")]
+impl Ord for Amount {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.minor.cmp(&other.minor)
+    }
+}
+
+#[cfg(feature = "minor-units")]
+impl core::hash::Hash for Amount {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.minor.hash(state);
+    }
+}
+
+impl Neg for Amount {
+    type Output = Self;
+
+    #[cfg(not(feature = "minor-units"))]
+    fn neg(self) -> Self {
+        Self(-self.0)
+    }
+
+    #[cfg(feature = "minor-units")]
+    fn neg(self) -> Self {
+        Self {
+            minor: self.minor.saturating_neg(),
+            scale: self.scale,
+        }
+    }
+}
+
+impl PartialEq<Decimal> for Amount {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.value() == *other
+    }
+}
+
+impl TryFrom<Decimal> for Amount {
+    type Error = AmountError;
+
+    fn try_from(value: Decimal) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(amount: Amount) -> Self {
+        amount.value()
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn precision_is_bounded() {
+        assert_eq!(Amount::new(dec!(1.2345)).unwrap().value(), dec!(1.2345));
+        assert_eq!(Amount::new(dec!(1.50000)).unwrap().value(), dec!(1.5));
+        assert_eq!(
+            Amount::new(dec!(1.23456)),
+            Err(AmountError::ExcessPrecision(dec!(1.23456)))
+        );
+        assert_eq!(
+            Amount::non_negative(dec!(-1)),
+            Err(AmountError::Negative(dec!(-1)))
+        );
+        assert_eq!(
+            Amount::with_policy(dec!(1.23456), PrecisionPolicy::Round)
+                .unwrap()
+                .value(),
+            dec!(1.2346)
+        );
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_scale() {
+        let a = Amount::new(dec!(1.5)).unwrap();
+        let b = Amount::new(dec!(0.25)).unwrap();
+
+        assert_eq!(a.checked_add(b).unwrap().to_string(), "1.75");
+        assert_eq!(a.checked_sub(a).unwrap().to_string(), "0.0");
+        assert_eq!((-a).to_string(), "-1.5");
+        assert_eq!(Amount::MAX.checked_add(a), None);
+    }
+
+    #[test]
+    fn trailing_zeros_are_formatted_as_given() {
+        let a = Amount::new(dec!(1.50000)).unwrap();
+        let b = Amount::new(dec!(0.25)).unwrap();
+
+        assert_eq!(a.to_string(), "1.50000");
+        assert_eq!(a.checked_add(b).unwrap().to_string(), "1.75000");
+        assert_eq!((-a).to_string(), "-1.50000");
+        assert_eq!(Decimal::from(a).scale(), 5);
+    }
+}
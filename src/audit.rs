@@ -0,0 +1,515 @@
+//! Tamper-evident audit trails. Every record applied to an account is chained onto the hash of the records before
+//! it, `hash = SHA-256(previous hash || entry)`, so the [`crate::ClientAccount::audit_head`] of an account commits
+//! to everything that was applied to it: changing, dropping or reordering any of those records changes the head.
+//!
+//! Every record is an [`Entry`], encoded field by field after the [`ENCODING_VERSION`]:
+//!
+//! - its [`EntryKind`], one byte;
+//! - the client and the transaction id, each as a big-endian `u32` length followed by its `Debug` output, which is
+//!   the decimal number for the default integer ids;
+//! - a `0` byte without an amount, or a `1` byte followed by the amount without trailing zeros: its mantissa as a
+//!   big-endian `i128` and its scale as one byte.
+//!
+//! Across accounts, [`crate::PaymentEngine::merkle_root`] commits to the balances of all of them at once. An
+//! [`InclusionProof`] lets a client check its own balance against a published root, without the other accounts.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::amount::Amount;
+use crate::pipeline::AccountRow;
+use crate::{AccountAction, Adjustment, DisputeAction, Transaction, WithdrawalAction};
+
+pub type AuditHash = [u8; 32];
+
+/// The head of an account nothing was applied to yet.
+pub const GENESIS: AuditHash = [0; 32];
+
+/// The version of the encoding of an [`Entry`]. Changing the encoding bumps it, so heads of different versions
+/// never match by accident.
+pub const ENCODING_VERSION: u8 = 1;
+
+/// What kind of record an [`Entry`] is, hashed as its discriminant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EntryKind {
+    Deposit = 1,
+    Withdrawal = 2,
+    WithdrawalRequest = 3,
+    Dispute = 4,
+    Resolve = 5,
+    Chargeback = 6,
+    Escalate = 7,
+    ArbitrationWon = 8,
+    ArbitrationLost = 9,
+    Amend = 10,
+    WriteOff = 11,
+    WithdrawalConfirm = 12,
+    WithdrawalCancel = 13,
+    Open = 14,
+    Close = 15,
+}
+
+/// The fields of a record that are chained, see the [module](self) for how they're encoded.
+pub struct Entry<'a> {
+    pub kind: EntryKind,
+    pub client: &'a dyn Debug,
+    /// The transaction the record is, or refers to.
+    pub transaction_id: &'a dyn Debug,
+    pub amount: Option<Amount>,
+}
+
+/// A record that can be chained onto an audit trail.
+pub trait Audited {
+    fn entry(&self) -> Entry<'_>;
+}
+
+impl<A: Audited + ?Sized> Audited for &A {
+    fn entry(&self) -> Entry<'_> {
+        (**self).entry()
+    }
+}
+
+/// The head after applying `record` to an account with head `previous`.
+pub fn chain(previous: &AuditHash, record: &impl Audited) -> AuditHash {
+    let entry = record.entry();
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update([ENCODING_VERSION, entry.kind as u8]);
+    for id in [entry.client, entry.transaction_id] {
+        let mut encoded = String::new();
+        write!(encoded, "{:?}", id).expect("Formatting into a string can't fail.");
+        let length = u32::try_from(encoded.len()).expect("Ids are shorter than 4 GiB.");
+        hasher.update(length.to_be_bytes());
+        hasher.update(encoded.as_bytes());
+    }
+    match entry.amount {
+        Some(amount) => {
+            let value = amount.value().normalize();
+            hasher.update([1]);
+            hasher.update(value.mantissa().to_be_bytes());
+            hasher.update([value.scale() as u8]);
+        }
+        None => hasher.update([0]),
+    }
+    hasher.finalize().into()
+}
+
+/// Whether applying `records`, in order, to a new account leads to `head`.
+pub fn verify<R: Audited>(records: impl IntoIterator<Item = R>, head: &AuditHash) -> bool {
+    records
+        .into_iter()
+        .fold(GENESIS, |previous, record| chain(&previous, &record))
+        == *head
+}
+
+impl<C: Debug, T: Debug> Audited for Transaction<C, T> {
+    fn entry(&self) -> Entry<'_> {
+        let (kind, client, transaction_id, amount) = match self {
+            Transaction::Deposit {
+                client,
+                transaction_id,
+                amount,
+            } => (EntryKind::Deposit, client, transaction_id, amount),
+            Transaction::Withdrawal {
+                client,
+                transaction_id,
+                amount,
+            } => (EntryKind::Withdrawal, client, transaction_id, amount),
+            Transaction::WithdrawalRequest {
+                client,
+                transaction_id,
+                amount,
+            } => (EntryKind::WithdrawalRequest, client, transaction_id, amount),
+        };
+        Entry {
+            kind,
+            client,
+            transaction_id,
+            amount: Some(*amount),
+        }
+    }
+}
+
+impl<C: Debug, T: Debug> Audited for DisputeAction<C, T> {
+    fn entry(&self) -> Entry<'_> {
+        let (kind, client, transaction_id) = match self {
+            DisputeAction::Dispute {
+                client,
+                referenced_transaction_id,
+            } => (EntryKind::Dispute, client, referenced_transaction_id),
+            DisputeAction::Resolve {
+                client,
+                referenced_transaction_id,
+            } => (EntryKind::Resolve, client, referenced_transaction_id),
+            DisputeAction::Chargeback {
+                client,
+                referenced_transaction_id,
+            } => (EntryKind::Chargeback, client, referenced_transaction_id),
+            DisputeAction::Escalate {
+                client,
+                referenced_transaction_id,
+            } => (EntryKind::Escalate, client, referenced_transaction_id),
+            DisputeAction::ArbitrationWon {
+                client,
+                referenced_transaction_id,
+            } => (EntryKind::ArbitrationWon, client, referenced_transaction_id),
+            DisputeAction::ArbitrationLost {
+                client,
+                referenced_transaction_id,
+            } => (
+                EntryKind::ArbitrationLost,
+                client,
+                referenced_transaction_id,
+            ),
+        };
+        Entry {
+            kind,
+            client,
+            transaction_id,
+            amount: None,
+        }
+    }
+}
+
+impl<C: Debug, T: Debug> Audited for Adjustment<C, T> {
+    fn entry(&self) -> Entry<'_> {
+        match self {
+            Adjustment::Amend {
+                client,
+                referenced_transaction_id,
+                amount,
+            } => Entry {
+                kind: EntryKind::Amend,
+                client,
+                transaction_id: referenced_transaction_id,
+                amount: Some(*amount),
+            },
+            Adjustment::WriteOff {
+                client,
+                transaction_id,
+            } => Entry {
+                kind: EntryKind::WriteOff,
+                client,
+                transaction_id,
+                amount: None,
+            },
+        }
+    }
+}
+
+impl<C: Debug, T: Debug> Audited for WithdrawalAction<C, T> {
+    fn entry(&self) -> Entry<'_> {
+        let (kind, client, transaction_id) = match self {
+            WithdrawalAction::WithdrawalConfirm {
+                client,
+                referenced_transaction_id,
+            } => (
+                EntryKind::WithdrawalConfirm,
+                client,
+                referenced_transaction_id,
+            ),
+            WithdrawalAction::WithdrawalCancel {
+                client,
+                referenced_transaction_id,
+            } => (
+                EntryKind::WithdrawalCancel,
+                client,
+                referenced_transaction_id,
+            ),
+        };
+        Entry {
+            kind,
+            client,
+            transaction_id,
+            amount: None,
+        }
+    }
+}
+
+impl<C: Debug, T: Debug> Audited for AccountAction<C, T> {
+    fn entry(&self) -> Entry<'_> {
+        match self {
+            // The initial deposit is chained as a deposit of its own.
+            AccountAction::Open {
+                client,
+                transaction_id,
+                ..
+            } => Entry {
+                kind: EntryKind::Open,
+                client,
+                transaction_id,
+                amount: None,
+            },
+            AccountAction::Close {
+                client,
+                transaction_id,
+            } => Entry {
+                kind: EntryKind::Close,
+                client,
+                transaction_id,
+                amount: None,
+            },
+        }
+    }
+}
+
+/// Distinguishes leaves from inner nodes, so an inner node can't be passed off as an account.
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+/// The leaf of an account in the Merkle tree: `SHA-256(0x00 || "client,available,held,total,locked")`, with the
+/// columns as in the CSV output, e.g. `1,1.5,0,1.5,false`.
+pub fn leaf_hash<C: Debug>(row: &AccountRow<C>) -> AuditHash {
+    let mut hasher = HashWriter(Sha256::new());
+    hasher.0.update([LEAF]);
+    write!(
+        hasher,
+        "{:?},{},{},{},{}",
+        row.client, row.available, row.held, row.total, row.locked
+    )
+    .expect("Hashing can't fail.");
+    hasher.0.finalize().into()
+}
+
+fn node_hash(left: &AuditHash, right: &AuditHash) -> AuditHash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Pairs up the nodes of a level, a node without a pair moves up as it is.
+fn parent_level(level: &[AuditHash]) -> Vec<AuditHash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!("Chunks hold one or two nodes."),
+        })
+        .collect()
+}
+
+/// The root over `leaves`, [`GENESIS`] when there are none.
+pub fn merkle_root(mut leaves: Vec<AuditHash>) -> AuditHash {
+    while leaves.len() > 1 {
+        leaves = parent_level(&leaves);
+    }
+    leaves.first().copied().unwrap_or(GENESIS)
+}
+
+/// The siblings on the path from the leaf at `index` up to the root.
+pub fn inclusion_proof(mut leaves: Vec<AuditHash>, mut index: usize) -> InclusionProof {
+    let mut siblings = Vec::new();
+    while leaves.len() > 1 {
+        match leaves.get(index ^ 1) {
+            Some(sibling) if index.is_multiple_of(2) => siblings.push(Sibling::Right(*sibling)),
+            Some(sibling) => siblings.push(Sibling::Left(*sibling)),
+            // Moves up without a pair.
+            None => {}
+        }
+        leaves = parent_level(&leaves);
+        index /= 2;
+    }
+    InclusionProof { siblings }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sibling {
+    Left(AuditHash),
+    Right(AuditHash),
+}
+
+/// Proves that an account is part of a Merkle root, see [`crate::PaymentEngine::inclusion_proof`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    /// From the leaf up.
+    pub siblings: Vec<Sibling>,
+}
+
+impl InclusionProof {
+    /// The root the proof leads to from `leaf`.
+    pub fn root(&self, leaf: AuditHash) -> AuditHash {
+        self.siblings
+            .iter()
+            .fold(leaf, |node, sibling| match sibling {
+                Sibling::Left(left) => node_hash(left, &node),
+                Sibling::Right(right) => node_hash(&node, right),
+            })
+    }
+
+    /// Whether the account as described by `row` is part of `root`.
+    pub fn verify<C: Debug>(&self, row: &AccountRow<C>, root: &AuditHash) -> bool {
+        self.root(leaf_hash(row)) == *root
+    }
+}
+
+/// Feeds formatted output straight into the hash, without building the string first.
+struct HashWriter(Sha256);
+
+impl Write for HashWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.update(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::{DisputeAction, PaymentEngine, Transaction};
+
+    #[test]
+    fn heads_commit_to_the_applied_records_in_order() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: Amount::non_negative(dec!(3.0)).unwrap(),
+        };
+        // Rejected for insufficient funds, so not part of the trail.
+        let withdrawal = Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 2,
+            amount: Amount::non_negative(dec!(5.0)).unwrap(),
+        };
+        let dispute = DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        };
+        let resolve = DisputeAction::Resolve {
+            client: 1,
+            referenced_transaction_id: 1,
+        };
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.apply(deposit.clone());
+        payment_engine.apply(withdrawal.clone());
+        payment_engine.apply(dispute.clone());
+        payment_engine.apply(resolve.clone());
+        let head = payment_engine.get_client_state(1).unwrap().audit_head();
+
+        let trail: [&dyn Audited; 3] = [&deposit, &dispute, &resolve];
+        assert!(verify(trail, &head));
+        assert!(!verify(
+            [&deposit as &dyn Audited, &resolve, &dispute],
+            &head
+        ));
+        assert!(!verify(
+            [&deposit as &dyn Audited, &withdrawal, &dispute, &resolve],
+            &head
+        ));
+        assert_ne!(head, GENESIS);
+    }
+
+    #[test]
+    fn entries_are_encoded_independently_of_debug_output() {
+        let deposit = |amount| Transaction::Deposit {
+            client: 1u16,
+            transaction_id: 1u32,
+            amount: Amount::non_negative(amount).unwrap(),
+        };
+        // Trailing zeros don't change the amount, so they don't change the head either.
+        assert_eq!(
+            chain(&GENESIS, &deposit(dec!(1.5))),
+            chain(&GENESIS, &deposit(dec!(1.5000)))
+        );
+        assert_ne!(
+            chain(&GENESIS, &deposit(dec!(1.5))),
+            chain(&GENESIS, &deposit(dec!(15)))
+        );
+        // The ids are length prefixed, so client 1 with transaction 12 isn't client 11 with transaction 2.
+        let withdrawal = |client, transaction_id| Transaction::Withdrawal {
+            client,
+            transaction_id,
+            amount: Amount::non_negative(dec!(1)).unwrap(),
+        };
+        assert_ne!(
+            chain(&GENESIS, &withdrawal(1u16, 12u32)),
+            chain(&GENESIS, &withdrawal(11, 2))
+        );
+        let mut expected = Sha256::new();
+        expected.update(GENESIS);
+        expected.update([ENCODING_VERSION, EntryKind::Deposit as u8]);
+        expected.update([0, 0, 0, 1, b'1', 0, 0, 0, 1, b'1', 1]);
+        expected.update(15i128.to_be_bytes());
+        expected.update([1]);
+        assert_eq!(
+            chain(&GENESIS, &deposit(dec!(1.5))),
+            <AuditHash>::from(expected.finalize())
+        );
+    }
+
+    #[test]
+    fn closing_an_account_is_part_of_its_trail() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: Amount::non_negative(dec!(3.0)).unwrap(),
+        };
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.apply(deposit.clone());
+        payment_engine.apply(Transaction::Deposit {
+            client: 2,
+            transaction_id: 2,
+            amount: Amount::non_negative(dec!(1.0)).unwrap(),
+        });
+        assert_eq!(
+            payment_engine.close_account(1, Some(2), 3),
+            Some(crate::Outcome::Applied)
+        );
+
+        let sweep = Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 3,
+            amount: Amount::non_negative(dec!(3.0)).unwrap(),
+        };
+        let close = AccountAction::Close {
+            client: 1,
+            transaction_id: 3,
+        };
+        let trail: [&dyn Audited; 3] = [&deposit, &sweep, &close];
+        let head = payment_engine.get_client_state(1).unwrap().audit_head();
+        assert!(verify(trail, &head));
+    }
+
+    #[test]
+    fn accounts_are_proven_against_the_merkle_root() {
+        let mut payment_engine = PaymentEngine::default();
+        assert_eq!(payment_engine.merkle_root(), GENESIS);
+        for client in 1..=5u16 {
+            payment_engine.apply(Transaction::Deposit {
+                client,
+                transaction_id: u32::from(client),
+                amount: Amount::non_negative(Decimal::from(client)).unwrap(),
+            });
+        }
+        let root = payment_engine.merkle_root();
+
+        for client in 1..=5 {
+            let row = AccountRow::from(payment_engine.get_client_state(client).unwrap());
+            let proof = payment_engine.inclusion_proof(client).unwrap();
+            assert!(proof.verify(&row, &root));
+            let forged = AccountRow {
+                total: Amount::non_negative(dec!(100)).unwrap(),
+                ..row
+            };
+            assert!(!proof.verify(&forged, &root));
+        }
+        assert_eq!(payment_engine.inclusion_proof(6), None);
+
+        payment_engine.apply(Transaction::Deposit {
+            client: 3,
+            transaction_id: 6,
+            amount: Amount::non_negative(dec!(1.0)).unwrap(),
+        });
+        assert_ne!(payment_engine.merkle_root(), root);
+    }
+}
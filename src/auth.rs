@@ -0,0 +1,173 @@
+//! API keys for the listeners the engine is exposed through, e.g. the TCP listener of the `tcp` feature.
+//!
+//! Every key has a [`Role`]. A role allows what the roles below it allow: a read-only key can look at the engine, an
+//! ingest key can also send it records and an admin key can also run operational commands, e.g. draining.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    ReadOnly,
+    Ingest,
+    Admin,
+}
+
+impl Role {
+    pub fn allows(self, required: Role) -> bool {
+        self >= required
+    }
+}
+
+impl FromStr for Role {
+    type Err = AuthError;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "read-only" => Ok(Self::ReadOnly),
+            "ingest" => Ok(Self::Ingest),
+            "admin" => Ok(Self::Admin),
+            other => Err(AuthError::UnknownRole(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ReadOnly => "read-only",
+            Self::Ingest => "ingest",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AuthError {
+    #[error("unknown role '{0}', expected read-only, ingest or admin")]
+    UnknownRole(String),
+    #[error("line {0} is not a role followed by a key")]
+    InvalidLine(usize),
+    #[error("the key on line {0} is listed before")]
+    DuplicateKey(usize),
+}
+
+/// The keys that are accepted, each with its role.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ApiKeys {
+    keys: Vec<(String, Role)>,
+}
+
+impl ApiKeys {
+    /// One key per line, preceded by its role, e.g. `ingest 6f1c9a...`. Empty lines and lines starting with `#` are
+    /// skipped.
+    pub fn parse(text: &str) -> Result<Self, AuthError> {
+        let mut keys = Self::default();
+        for (number, line) in (1..).zip(text.lines()) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (role, key) = line
+                .split_once(char::is_whitespace)
+                .map(|(role, key)| (role, key.trim()))
+                .filter(|(_, key)| !key.is_empty() && !key.contains(char::is_whitespace))
+                .ok_or(AuthError::InvalidLine(number))?;
+            let role = role.parse()?;
+            if keys.role(key.as_bytes()).is_some() {
+                return Err(AuthError::DuplicateKey(number));
+            }
+            keys.insert(key, role);
+        }
+        Ok(keys)
+    }
+
+    /// Accepts `key` with `role`, replacing the role it had if it was accepted already.
+    pub fn insert(&mut self, key: impl Into<String>, role: Role) {
+        let key = key.into();
+        self.keys.retain(|(existing, _)| *existing != key);
+        self.keys.push((key, role));
+    }
+
+    /// The role of `key`, `None` when it isn't accepted. Every key is compared in full, so how long this takes doesn't
+    /// tell how much of a key was right.
+    pub fn role(&self, key: &[u8]) -> Option<Role> {
+        let mut found = None;
+        for (accepted, role) in &self.keys {
+            if constant_time_eq(accepted.as_bytes(), key) {
+                found = Some(*role);
+            }
+        }
+        found
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Leaves the keys themselves out.
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.keys.iter().map(|(_, role)| role))
+            .finish()
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_parsed_with_their_roles() {
+        let keys = ApiKeys::parse(
+            "# Dashboards\nread-only 0123abcd\n\ningest feed-key\n  admin   operator-key  \n",
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys.role(b"0123abcd"), Some(Role::ReadOnly));
+        assert_eq!(keys.role(b"feed-key"), Some(Role::Ingest));
+        assert_eq!(keys.role(b"operator-key"), Some(Role::Admin));
+        assert_eq!(keys.role(b"operator-ke"), None);
+        assert_eq!(keys.role(b""), None);
+        assert_eq!(alloc::format!("{:?}", keys), "[ReadOnly, Ingest, Admin]");
+
+        assert_eq!(
+            ApiKeys::parse("ingest a\nsuperuser b"),
+            Err(AuthError::UnknownRole("superuser".to_string()))
+        );
+        assert_eq!(ApiKeys::parse("admin"), Err(AuthError::InvalidLine(1)));
+        assert_eq!(
+            ApiKeys::parse("\nadmin two keys"),
+            Err(AuthError::InvalidLine(2))
+        );
+        assert_eq!(
+            ApiKeys::parse("admin a\ningest a"),
+            Err(AuthError::DuplicateKey(2))
+        );
+    }
+
+    #[test]
+    fn roles_allow_the_roles_below_them() {
+        assert!(Role::Admin.allows(Role::Ingest));
+        assert!(Role::Ingest.allows(Role::Ingest));
+        assert!(Role::Ingest.allows(Role::ReadOnly));
+        assert!(!Role::ReadOnly.allows(Role::Ingest));
+        assert!(!Role::Ingest.allows(Role::Admin));
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::sync::Arc;
+
+use crate::amount::PrecisionPolicy;
+use crate::config::{AccountCreation, DisputePolicy, EngineConfig, LockedPolicy};
+use crate::event::{EngineObserver, EventQueue};
+use crate::id::{ClientId, TransactionId};
+use crate::screening::{Screening, ScreeningHandle};
+use crate::store::{AccountStore, StoreHandle};
+use crate::PaymentEngine;
+
+/// Configures a [`PaymentEngine`], see [`PaymentEngine::builder`].
+/// Anything that isn't set keeps its default, so a builder without any settings builds the same engine as
+/// [`PaymentEngine::default`].
+pub struct PaymentEngineBuilder<C: ClientId = u16, T: TransactionId = u32> {
+    config: EngineConfig,
+    events: EventQueue<C, T>,
+    store: StoreHandle<C, T>,
+    screening: ScreeningHandle<C>,
+}
+
+impl<C: ClientId, T: TransactionId> Default for PaymentEngineBuilder<C, T> {
+    fn default() -> Self {
+        Self {
+            config: EngineConfig::default(),
+            events: EventQueue::default(),
+            store: StoreHandle::default(),
+            screening: ScreeningHandle::default(),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> PaymentEngineBuilder<C, T> {
+    /// Replaces every setting at once, e.g. with a configuration that was read from a file.
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn dispute_policy(mut self, disputes: DisputePolicy) -> Self {
+        self.config.disputes = disputes;
+        self
+    }
+
+    pub fn precision(mut self, precision: PrecisionPolicy) -> Self {
+        self.config.precision = precision;
+        self
+    }
+
+    pub fn locked_policy(mut self, locked: LockedPolicy) -> Self {
+        self.config.locked = locked;
+        self
+    }
+
+    pub fn account_creation(mut self, account_creation: AccountCreation) -> Self {
+        self.config.account_creation = account_creation;
+        self
+    }
+
+    /// Notifies `observer` of every event, in addition to any observers that were already added.
+    pub fn with_observer(mut self, observer: impl EngineObserver<C, T> + 'static) -> Self {
+        self.events.observe(Arc::new(observer));
+        self
+    }
+
+    /// Writes every account change through to `store`, replacing any store that was set before.
+    pub fn with_store(mut self, store: impl AccountStore<C, T> + 'static) -> Self {
+        self.store = StoreHandle(Some(Arc::new(store)));
+        self
+    }
+
+    /// Rejects the transactions of every client that `screening` blocks, replacing any screening that was set
+    /// before.
+    pub fn with_screening(mut self, screening: impl Screening<C> + 'static) -> Self {
+        self.screening = ScreeningHandle(Some(Arc::new(screening)));
+        self
+    }
+
+    pub fn build(self) -> PaymentEngine<C, T> {
+        PaymentEngine {
+            config: self.config,
+            events: self.events,
+            store: self.store,
+            screening: self.screening,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Settings that change how the engine treats records.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::amount::{Amount, PrecisionPolicy};
+use crate::policy::FundsMovement;
+use crate::wire::WireRecordType;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+// Settings missing from a configuration file keep their default.
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct EngineConfig {
+    pub disputes: DisputePolicy,
+    /// What to do with input amounts that have more decimal places than [`crate::amount::Amount::PRECISION`],
+    /// used by the input adapters when they build the [`crate::amount::Amount`] of a record.
+    pub precision: PrecisionPolicy,
+    /// Which records a locked account still accepts.
+    pub locked: LockedPolicy,
+    /// Rules for the accounts of clients from a country, keyed by the country code of their
+    /// [`crate::metadata::ClientMetadata`].
+    pub jurisdictions: BTreeMap<String, JurisdictionRules>,
+    /// Accounts that were neither created nor had a record applied to them for this many records are dormant,
+    /// see [`crate::PaymentEngine::dormant_accounts`]. Accounts never become dormant when `None`.
+    pub dormant_after: Option<u64>,
+    /// Withdrawal requests that are neither confirmed nor cancelled this many records after they were placed
+    /// expire, their held funds return to the available funds.
+    pub withdrawal_request_ttl: Option<u64>,
+    /// Records of these types are ignored, e.g. to see how the accounts would look without any chargebacks.
+    /// They're rejected as [`crate::RejectionReason::Disabled`] and counted in
+    /// [`crate::stats::EngineStats::disabled`] rather than per type.
+    pub disabled: BTreeSet<WireRecordType>,
+    /// Which records create the account of a client that doesn't have one yet.
+    pub account_creation: AccountCreation,
+}
+
+impl EngineConfig {
+    /// The rules for clients from `country`, if it has any.
+    pub fn jurisdiction(&self, country: &str) -> Option<&JurisdictionRules> {
+        self.jurisdictions.get(country)
+    }
+}
+
+/// Records that don't create an account are rejected for a client without one, leaving the engine untouched:
+/// dispute actions as [`crate::RejectionReason::UnknownTransaction`], transactions as
+/// [`crate::RejectionReason::UnregisteredClient`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AccountCreation {
+    /// Transactions and dispute actions, a dispute action of an unknown client leaves an empty account behind.
+    #[default]
+    AnyRecord,
+    /// Only transactions, so dispute actions never leave empty accounts behind.
+    Transactions,
+    /// Only deposits, a withdrawal of an unknown client is rejected rather than leaving an empty account behind.
+    Deposits,
+    /// No record creates an account, they have to be opened with [`crate::PaymentEngine::open_account`] first.
+    Registered,
+}
+
+impl AccountCreation {
+    /// Whether `transaction` creates the account of a client that doesn't have one yet.
+    pub fn creates_account<C, T>(self, transaction: &crate::Transaction<C, T>) -> bool {
+        match self {
+            AccountCreation::AnyRecord | AccountCreation::Transactions => true,
+            AccountCreation::Deposits => matches!(transaction, crate::Transaction::Deposit { .. }),
+            AccountCreation::Registered => false,
+        }
+    }
+}
+
+/// The scheme rules of a country. Rules that aren't set fall back to the rest of the configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct JurisdictionRules {
+    /// A transaction can only be disputed until this many later transactions have been added to the account.
+    pub dispute_window: Option<u64>,
+    /// Replaces [`DisputePolicy::provisional_credit`].
+    pub provisional_credit: Option<bool>,
+    /// Withdrawals of a larger amount are rejected.
+    pub max_withdrawal: Option<Amount>,
+}
+
+/// How disputes and everything that follows from them are handled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DisputePolicy {
+    /// The number of disputes a client can have open at the same time, further disputes are rejected.
+    /// Unlimited when `None`.
+    pub max_open_per_client: Option<usize>,
+    /// Disputes that are still open this many records after they were opened get resolved automatically,
+    /// like card network rules where unanswered disputes resolve in the customer's favour.
+    pub auto_resolve_after: Option<u64>,
+    /// Disputing a withdrawal immediately credits its amount to the available funds (as required by e.g. Reg E),
+    /// the credit is taken back again when the dispute ends in a chargeback.
+    pub provisional_credit: bool,
+    /// What to do when settling a dispute would leave an account with negative held funds.
+    pub negative_held: NegativeHeldPolicy,
+    /// A chargeback of a transaction that isn't disputed opens the dispute itself and charges it back right away,
+    /// for schemes that don't send a dispute first. Otherwise such a chargeback is rejected.
+    pub direct_chargeback: bool,
+    /// How many times a resolved transaction can be disputed again, each time starting a new dispute round. Only
+    /// transactions whose rounds leave the funds as they were can be, see
+    /// [`crate::policy::FundsMovementPolicy::round_trips`].
+    pub max_redisputes: u32,
+    /// How disputes of deposits and of withdrawals move funds between the available and held funds.
+    pub funds_movement: FundsMovement,
+    /// What to do with dispute actions of a client for a transaction of another client.
+    pub cross_client: CrossClientDisputes,
+}
+
+/// A dispute action of a client for a transaction of another client is always rejected, it's a fraud signal though.
+/// Telling these apart from disputes of transactions that don't exist at all visits every account, but only for
+/// dispute actions of transactions the client doesn't have.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CrossClientDisputes {
+    /// Treat it like any dispute of an unknown transaction, which creates an empty account for a new client.
+    #[default]
+    Ignore,
+    /// Leave the engine untouched, without creating an account for a new client.
+    Reject,
+    /// Like `Reject`, and report it as [`crate::event::EngineEvent::CrossClientDispute`].
+    Report,
+}
+
+/// Held funds can only go negative through inconsistent input, e.g. deposits of negative amounts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum NegativeHeldPolicy {
+    /// Reject the dispute action, leaving the account untouched.
+    #[default]
+    Reject,
+    /// Apply the dispute action, but hold no less than zero.
+    Clamp,
+}
+
+/// An account gets locked by a chargeback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LockedPolicy {
+    /// Reject every record for a locked account.
+    #[default]
+    RejectAll,
+    /// Keep crediting deposits, e.g. so a client can still settle what they owe.
+    /// Withdrawals and dispute actions are rejected.
+    AllowDeposits,
+}
@@ -0,0 +1,171 @@
+//! Encrypts state that is written to disk, e.g. snapshots and checkpoints, which hold every balance in plain text
+//! otherwise.
+//!
+//! Files are sealed with AES-256-GCM: [`MAGIC`], a random 96-bit nonce, then the ciphertext followed by the tag. The
+//! tag authenticates the whole file, a file that was tampered with or encrypted with another key doesn't decrypt.
+//!
+//! Keys come from a [`KeyProvider`], e.g. [`EnvKey`] or [`FileKey`]; one that fetches the key from a KMS implements
+//! the same trait.
+
+use std::path::PathBuf;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+
+use crate::hex::parse_hex;
+
+pub type EncryptionKey = [u8; 32];
+
+/// Starts every encrypted file, so a plain file is told apart from one that doesn't decrypt.
+pub const MAGIC: &[u8; 8] = b"BANKENC1";
+
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("could not get the encryption key: {0}")]
+    Key(String),
+    #[error("not an encrypted file")]
+    Format,
+    #[error("the file could not be decrypted, it was modified or encrypted with another key")]
+    Decrypt,
+}
+
+/// Where the key comes from.
+pub trait KeyProvider {
+    fn key(&self) -> Result<EncryptionKey, EncryptionError>;
+}
+
+/// A hex-encoded key in the environment variable of that name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvKey(pub String);
+
+impl KeyProvider for EnvKey {
+    fn key(&self) -> Result<EncryptionKey, EncryptionError> {
+        let value = std::env::var(&self.0)
+            .map_err(|_| EncryptionError::Key(format!("{} is not set", self.0)))?;
+        parse_hex(value.trim())
+            .ok_or_else(|| EncryptionError::Key(format!("{} is not 64 hex digits", self.0)))
+    }
+}
+
+/// A file holding the key as 32 raw bytes, or as 64 hex digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileKey(pub PathBuf);
+
+impl KeyProvider for FileKey {
+    fn key(&self) -> Result<EncryptionKey, EncryptionError> {
+        let contents = std::fs::read(&self.0).map_err(|e| {
+            EncryptionError::Key(format!("could not read {}: {}", self.0.display(), e))
+        })?;
+        if let Ok(key) = EncryptionKey::try_from(&contents[..]) {
+            return Ok(key);
+        }
+        core::str::from_utf8(&contents)
+            .ok()
+            .and_then(|hex| parse_hex(hex.trim()))
+            .ok_or_else(|| {
+                EncryptionError::Key(format!(
+                    "{} holds neither 32 bytes nor 64 hex digits",
+                    self.0.display()
+                ))
+            })
+    }
+}
+
+#[derive(Clone)]
+pub struct Encryptor {
+    cipher: Aes256Gcm,
+}
+
+impl Encryptor {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    pub fn from_provider(provider: &dyn KeyProvider) -> Result<Self, EncryptionError> {
+        Ok(Self::new(&provider.key()?))
+    }
+
+    /// Every call uses a fresh nonce, encrypting the same contents twice gives different files.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("Encrypting into a Vec can't fail.");
+
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LENGTH + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let rest = sealed.strip_prefix(MAGIC).ok_or(EncryptionError::Format)?;
+        if rest.len() < NONCE_LENGTH {
+            return Err(EncryptionError::Format);
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::Decrypt)
+    }
+}
+
+impl core::fmt::Debug for Encryptor {
+    /// Leaves out the key.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Encryptor").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_files_only_open_unmodified_and_with_the_same_key() {
+        let encryptor = Encryptor::new(&[7; 32]);
+        let sealed = encryptor.encrypt(b"client,available\n1,1.5\n");
+        assert!(sealed.starts_with(MAGIC));
+        assert_ne!(sealed, encryptor.encrypt(b"client,available\n1,1.5\n"));
+        assert_eq!(
+            encryptor.decrypt(&sealed).unwrap(),
+            b"client,available\n1,1.5\n"
+        );
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            encryptor.decrypt(&tampered),
+            Err(EncryptionError::Decrypt)
+        ));
+        assert!(matches!(
+            Encryptor::new(&[8; 32]).decrypt(&sealed),
+            Err(EncryptionError::Decrypt)
+        ));
+        assert!(matches!(
+            encryptor.decrypt(b"client,available\n"),
+            Err(EncryptionError::Format)
+        ));
+    }
+
+    #[test]
+    fn keys_are_read_as_raw_bytes_or_hex() {
+        let path = std::env::temp_dir().join(format!("banking-key-{}", std::process::id()));
+        std::fs::write(&path, [0xab; 32]).unwrap();
+        assert_eq!(FileKey(path.clone()).key().unwrap(), [0xab; 32]);
+        std::fs::write(&path, format!("{}\n", "0f".repeat(32))).unwrap();
+        assert_eq!(FileKey(path.clone()).key().unwrap(), [0x0f; 32]);
+        std::fs::write(&path, "0f0f").unwrap();
+        assert!(matches!(
+            FileKey(path.clone()).key(),
+            Err(EncryptionError::Key(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+//! Errors of the engine itself, as opposed to records it rejects, which are reported as [`crate::Outcome::Rejected`].
+
+use crate::id::{ClientId, TransactionId};
+use crate::{DisputeAction, Transaction};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EngineError<C: ClientId = u16, T: TransactionId = u32> {
+    /// A transaction was added to the account of another client, it's returned as is.
+    #[error(
+        "transaction {:?} of client {:?} can't be added to the account of client {account:?}",
+        transaction.get_transaction_id(),
+        transaction.get_client_id()
+    )]
+    ForeignTransaction {
+        account: C,
+        transaction: Transaction<C, T>,
+    },
+    /// A dispute action was added to the account of another client, it's returned as is.
+    #[error(
+        "dispute action on transaction {:?} of client {:?} can't be added to the account of client {account:?}",
+        dispute_action.get_referenced_transaction_id(),
+        dispute_action.get_client_id()
+    )]
+    ForeignDisputeAction {
+        account: C,
+        dispute_action: DisputeAction<C, T>,
+    },
+}
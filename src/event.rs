@@ -0,0 +1,186 @@
+//! Notable things that happened in the engine, including those it did on its own accord rather than as the direct
+//! result of a record.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::metadata::ClientMetadata;
+use crate::schedule::StandingOrderId;
+use crate::RejectionReason;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum EngineEvent<C = u16, T = u32> {
+    /// A dispute was open for longer than [`crate::config::DisputePolicy::auto_resolve_after`] and got
+    /// resolved in the client's favour.
+    AutoResolved {
+        client: C,
+        transaction_id: T,
+        amount: Amount,
+    },
+    /// A dispute action was rejected because it would have made the held funds negative,
+    /// see [`crate::config::NegativeHeldPolicy::Reject`].
+    NegativeHeldRejected { client: C, transaction_id: T },
+    /// A dispute action would have made the held funds negative by `shortfall`, they were set to zero instead,
+    /// see [`crate::config::NegativeHeldPolicy::Clamp`].
+    NegativeHeldClamped {
+        client: C,
+        transaction_id: T,
+        shortfall: Amount,
+    },
+    /// A chargeback arrived for a transaction that wasn't disputed and opened the dispute itself, it's followed by
+    /// [`EngineEvent::ChargedBack`], see [`crate::config::DisputePolicy::direct_chargeback`].
+    ImplicitDispute {
+        client: C,
+        transaction_id: T,
+        amount: Amount,
+    },
+    /// `client` sent a dispute action for a transaction of `owner`, see
+    /// [`crate::config::CrossClientDisputes::Report`].
+    CrossClientDispute {
+        client: C,
+        transaction_id: T,
+        owner: C,
+    },
+    /// A disputed transaction was charged back, or the arbitration over it was won by the client.
+    ChargedBack {
+        client: C,
+        transaction_id: T,
+        amount: Amount,
+        /// See [`crate::PaymentEngine::set_client_metadata`].
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        metadata: Option<Arc<ClientMetadata>>,
+    },
+    /// The account of `client` got locked, which follows a chargeback.
+    AccountLocked {
+        client: C,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        metadata: Option<Arc<ClientMetadata>>,
+    },
+    /// A withdrawal request wasn't settled within [`crate::config::EngineConfig::withdrawal_request_ttl`] and got
+    /// cancelled, `amount` is available again.
+    HoldExpired {
+        client: C,
+        transaction_id: T,
+        amount: Amount,
+    },
+    /// The [`crate::store::AccountStore`] failed to save or remove the account of `client`,
+    /// the engine's own state is unaffected.
+    StoreFailed { client: C, error: String },
+    /// A run of a standing order was rejected for `reason`, neither account was changed.
+    /// The order itself stays scheduled, see [`crate::PaymentEngine::add_standing_order`].
+    StandingOrderFailed {
+        order: StandingOrderId,
+        from: C,
+        to: C,
+        transaction_id: T,
+        reason: RejectionReason,
+    },
+}
+
+/// Gets notified of every [`EngineEvent`] as it happens, see [`crate::PaymentEngineBuilder::with_observer`].
+/// Events are still buffered for [`crate::PaymentEngine::take_events`] as well.
+pub trait EngineObserver<C = u16, T = u32>: Send + Sync {
+    fn on_event(&self, event: &EngineEvent<C, T>);
+}
+
+impl<C, T, F> EngineObserver<C, T> for F
+where
+    F: Fn(&EngineEvent<C, T>) + Send + Sync,
+{
+    fn on_event(&self, event: &EngineEvent<C, T>) {
+        self(event)
+    }
+}
+
+/// The events that haven't been taken yet, together with the observers to notify of new ones.
+/// Only the buffered events are part of the engine state, observers aren't serialized or compared.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(serialize = "C: Serialize, T: Serialize"))
+)]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(deserialize = "C: Deserialize<'de>, T: Deserialize<'de>"))
+)]
+pub(crate) struct EventQueue<C, T> {
+    buffered: Vec<EngineEvent<C, T>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    observers: Vec<Arc<dyn EngineObserver<C, T>>>,
+}
+
+impl<C, T> EventQueue<C, T> {
+    pub(crate) fn push(&mut self, event: EngineEvent<C, T>) {
+        for observer in &self.observers {
+            observer.on_event(&event);
+        }
+        self.buffered.push(event);
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<EngineEvent<C, T>> {
+        core::mem::take(&mut self.buffered)
+    }
+
+    pub(crate) fn observe(&mut self, observer: Arc<dyn EngineObserver<C, T>>) {
+        self.observers.push(observer);
+    }
+
+    pub(crate) fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    pub(crate) fn approx_memory_bytes(&self) -> usize {
+        self.buffered.capacity() * core::mem::size_of::<EngineEvent<C, T>>()
+    }
+}
+
+impl<C, T> Default for EventQueue<C, T> {
+    fn default() -> Self {
+        Self {
+            buffered: vec![],
+            observers: vec![],
+        }
+    }
+}
+
+impl<C: Clone, T: Clone> Clone for EventQueue<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            buffered: self.buffered.clone(),
+            observers: self.observers.clone(),
+        }
+    }
+}
+
+impl<C: fmt::Debug, T: fmt::Debug> fmt::Debug for EventQueue<C, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventQueue")
+            .field("buffered", &self.buffered)
+            .field("observers", &self.observers.len())
+            .finish()
+    }
+}
+
+impl<C: PartialEq, T: PartialEq> PartialEq for EventQueue<C, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.buffered == other.buffered
+    }
+}
+
+impl<C: Eq, T: Eq> Eq for EventQueue<C, T> {}
@@ -0,0 +1,29 @@
+//! Hex encoded keys, digests and signatures, as read from key files and signature files.
+
+/// Parses exactly `2 * N` hex digits, in either case.
+pub(crate) fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    // `from_str_radix` would also take a sign, e.g. `+f`.
+    if hex.len() != 2 * N || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_exact_number_of_digits_is_parsed() {
+        assert_eq!(parse_hex::<2>("0aFf"), Some([0x0a, 0xff]));
+        assert_eq!(parse_hex::<2>("0aF"), None);
+        assert_eq!(parse_hex::<2>("0aFf00"), None);
+        assert_eq!(parse_hex::<2>("0aFg"), None);
+        assert_eq!(parse_hex::<2>("+aFf"), None);
+        assert_eq!(parse_hex::<1>("é"), None);
+    }
+}
@@ -0,0 +1,37 @@
+//! The types that can identify clients and transactions.
+//!
+//! The engine defaults to `u16` client ids and `u32` transaction ids, as used by the CSV input,
+//! but anything that can be copied, hashed and ordered works, e.g. `u64` or a UUID. With the `serde` feature, ids
+//! also have to be serializable.
+
+use core::fmt::Debug;
+use core::hash::Hash;
+
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+#[cfg(feature = "serde")]
+pub trait ClientId: Copy + Eq + Ord + Hash + Debug + Serialize + DeserializeOwned {}
+
+#[cfg(feature = "serde")]
+impl<C> ClientId for C where C: Copy + Eq + Ord + Hash + Debug + Serialize + DeserializeOwned {}
+
+#[cfg(not(feature = "serde"))]
+pub trait ClientId: Copy + Eq + Ord + Hash + Debug {}
+
+#[cfg(not(feature = "serde"))]
+impl<C> ClientId for C where C: Copy + Eq + Ord + Hash + Debug {}
+
+#[cfg(feature = "serde")]
+pub trait TransactionId: Copy + Eq + Ord + Hash + Debug + Serialize + DeserializeOwned {}
+
+#[cfg(feature = "serde")]
+impl<T> TransactionId for T where T: Copy + Eq + Ord + Hash + Debug + Serialize + DeserializeOwned {}
+
+#[cfg(not(feature = "serde"))]
+pub trait TransactionId: Copy + Eq + Ord + Hash + Debug {}
+
+#[cfg(not(feature = "serde"))]
+impl<T> TransactionId for T where T: Copy + Eq + Ord + Hash + Debug {}
@@ -0,0 +1,267 @@
+//! Secondary indexes over the engine state, so common dashboard queries don't need a full scan.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::ops::{Bound, RangeBounds};
+
+use rust_decimal::Decimal;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::id::{ClientId, TransactionId};
+use crate::stats::AccountTotals;
+use crate::{ClientAccount, HashMap};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+// The bounds on the ids already require them to be deserializable.
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "")))]
+pub(crate) struct Indexes<C: ClientId, T: TransactionId> {
+    /// `(client, transaction)` of every transaction that is currently disputed, with the engine sequence number
+    /// at which the dispute was opened.
+    disputed: BTreeMap<(C, T), u64>,
+    /// The same disputes as `disputed`, ordered from oldest to newest.
+    disputed_by_age: BTreeSet<(u64, C, T)>,
+    locked: BTreeSet<C>,
+    /// Accounts whose available funds are below zero.
+    in_deficit: BTreeSet<C>,
+    /// The clients of every account, by their total.
+    by_total: BTreeMap<Decimal, BTreeSet<C>>,
+    /// `(client, transaction)` of every pending withdrawal request, with the engine sequence number at which it
+    /// was placed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    requested: BTreeMap<(C, T), u64>,
+    /// The same requests as `requested`, ordered from oldest to newest.
+    #[cfg_attr(feature = "serde", serde(default))]
+    requested_by_age: BTreeSet<(u64, C, T)>,
+    /// `(transaction, client)` of every transaction in the history of an account, to find who a transaction belongs
+    /// to. Ids are only unique per client, so a transaction id can appear for several clients.
+    #[cfg_attr(feature = "serde", serde(default))]
+    owners: BTreeSet<(T, C)>,
+}
+
+impl<C: ClientId, T: TransactionId> Default for Indexes<C, T> {
+    fn default() -> Self {
+        Self {
+            disputed: BTreeMap::new(),
+            disputed_by_age: BTreeSet::new(),
+            locked: BTreeSet::new(),
+            in_deficit: BTreeSet::new(),
+            by_total: BTreeMap::new(),
+            requested: BTreeMap::new(),
+            requested_by_age: BTreeSet::new(),
+            owners: BTreeSet::new(),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Indexes<C, T> {
+    pub(crate) fn account_changed(
+        &mut self,
+        client: C,
+        before: AccountTotals,
+        after: AccountTotals,
+    ) {
+        self.remove_total(before.available + before.held, client);
+        self.by_total
+            .entry(after.available + after.held)
+            .or_default()
+            .insert(client);
+        if after.locked {
+            self.locked.insert(client);
+        } else {
+            self.locked.remove(&client);
+        }
+        if after.available < Decimal::ZERO {
+            self.in_deficit.insert(client);
+        } else {
+            self.in_deficit.remove(&client);
+        }
+    }
+
+    /// `disputed` are the transactions of the account that were still disputed, `requested` its pending withdrawal
+    /// requests and `history` every transaction in its history.
+    pub(crate) fn account_removed(
+        &mut self,
+        client: C,
+        totals: AccountTotals,
+        disputed: impl Iterator<Item = T>,
+        requested: impl Iterator<Item = T>,
+        history: impl Iterator<Item = T>,
+    ) {
+        self.remove_total(totals.available + totals.held, client);
+        self.locked.remove(&client);
+        self.in_deficit.remove(&client);
+        for transaction_id in disputed {
+            self.dispute_closed(client, transaction_id);
+        }
+        for transaction_id in requested {
+            self.request_settled(client, transaction_id);
+        }
+        for transaction_id in history {
+            self.owners.remove(&(transaction_id, client));
+        }
+    }
+
+    /// `recorded` is whether the history of the account of `client` holds `transaction_id` now.
+    pub(crate) fn history_changed(&mut self, client: C, transaction_id: T, recorded: bool) {
+        if recorded {
+            self.owners.insert((transaction_id, client));
+        } else {
+            self.owners.remove(&(transaction_id, client));
+        }
+    }
+
+    /// A client other than `client` that has `transaction_id` in its history.
+    pub(crate) fn other_owner(&self, transaction_id: T, client: C) -> Option<C> {
+        let owner = |(id, owner): &(T, C)| (*id == transaction_id).then_some(*owner);
+        self.owners
+            .range(..(transaction_id, client))
+            .next_back()
+            .and_then(owner)
+            .or_else(|| {
+                self.owners
+                    .range((Bound::Excluded((transaction_id, client)), Bound::Unbounded))
+                    .next()
+                    .and_then(owner)
+            })
+    }
+
+    fn remove_total(&mut self, total: Decimal, client: C) {
+        if let Some(clients) = self.by_total.get_mut(&total) {
+            clients.remove(&client);
+            if clients.is_empty() {
+                self.by_total.remove(&total);
+            }
+        }
+    }
+
+    pub(crate) fn dispute_opened(&mut self, client: C, transaction_id: T, sequence: u64) {
+        self.disputed.insert((client, transaction_id), sequence);
+        self.disputed_by_age
+            .insert((sequence, client, transaction_id));
+    }
+
+    pub(crate) fn dispute_closed(&mut self, client: C, transaction_id: T) {
+        if let Some(sequence) = self.disputed.remove(&(client, transaction_id)) {
+            self.disputed_by_age
+                .remove(&(sequence, client, transaction_id));
+        }
+    }
+
+    /// Excludes a dispute from auto-resolution, while it stays open.
+    pub(crate) fn stop_aging(&mut self, client: C, transaction_id: T) {
+        if let Some(sequence) = self.disputed.get(&(client, transaction_id)) {
+            self.disputed_by_age
+                .remove(&(*sequence, client, transaction_id));
+        }
+    }
+
+    pub(crate) fn request_placed(&mut self, client: C, transaction_id: T, sequence: u64) {
+        self.requested.insert((client, transaction_id), sequence);
+        self.requested_by_age
+            .insert((sequence, client, transaction_id));
+    }
+
+    pub(crate) fn request_settled(&mut self, client: C, transaction_id: T) {
+        if let Some(sequence) = self.requested.remove(&(client, transaction_id)) {
+            self.requested_by_age
+                .remove(&(sequence, client, transaction_id));
+        }
+    }
+
+    /// Excludes a request from expiring, while it stays pending.
+    pub(crate) fn stop_expiry(&mut self, client: C, transaction_id: T) {
+        if let Some(sequence) = self.requested.get(&(client, transaction_id)) {
+            self.requested_by_age
+                .remove(&(*sequence, client, transaction_id));
+        }
+    }
+
+    /// The oldest pending withdrawal request, if it was placed at or before `sequence`.
+    pub(crate) fn oldest_request_placed_at_or_before(&self, sequence: u64) -> Option<(C, T)> {
+        self.requested_by_age
+            .first()
+            .filter(|(placed_at, _, _)| *placed_at <= sequence)
+            .map(|(_, client, transaction_id)| (*client, *transaction_id))
+    }
+
+    /// Counts the entries only, not the nodes of the trees holding them.
+    pub(crate) fn approx_memory_bytes(&self) -> usize {
+        (self.disputed.len() + self.requested.len()) * size_of::<((C, T), u64)>()
+            + (self.disputed_by_age.len() + self.requested_by_age.len()) * size_of::<(u64, C, T)>()
+            + (self.locked.len() + self.in_deficit.len()) * size_of::<C>()
+            + self.owners.len() * size_of::<(T, C)>()
+            + self
+                .by_total
+                .values()
+                .map(|clients| size_of::<(Decimal, BTreeSet<C>)>() + clients.len() * size_of::<C>())
+                .sum::<usize>()
+    }
+
+    /// The oldest open dispute, if it was opened at or before `sequence`.
+    pub(crate) fn oldest_dispute_opened_at_or_before(&self, sequence: u64) -> Option<(C, T)> {
+        self.disputed_by_age
+            .first()
+            .filter(|(opened_at, _, _)| *opened_at <= sequence)
+            .map(|(_, client, transaction_id)| (*client, *transaction_id))
+    }
+}
+
+/// Answers queries from the indexes maintained by the engine, see [`crate::PaymentEngine::query`].
+pub struct Query<'a, C: ClientId = u16, T: TransactionId = u32> {
+    pub(crate) state: &'a HashMap<C, Arc<ClientAccount<C, T>>>,
+    pub(crate) indexes: &'a Indexes<C, T>,
+}
+
+impl<'a, C: ClientId, T: TransactionId> Query<'a, C, T> {
+    /// `(client, transaction)` of every transaction that is currently disputed, ordered by client.
+    pub fn disputed_transactions(&self) -> impl Iterator<Item = (C, T)> + 'a {
+        self.indexes.disputed.keys().copied()
+    }
+
+    /// Like [`Query::disputed_transactions`], including the engine sequence number at which each dispute was opened.
+    pub(crate) fn disputed_transactions_since(&self) -> impl Iterator<Item = ((C, T), u64)> + 'a {
+        self.indexes.disputed.iter().map(|(k, v)| (*k, *v))
+    }
+
+    /// Ordered by client id.
+    pub fn locked_accounts(&self) -> impl Iterator<Item = &'a ClientAccount<C, T>> + 'a {
+        let state = self.state;
+        self.indexes.locked.iter().map(move |id| state[id].as_ref())
+    }
+
+    /// Ordered by client id.
+    pub fn accounts_in_deficit(&self) -> impl Iterator<Item = &'a ClientAccount<C, T>> + 'a {
+        let state = self.state;
+        self.indexes
+            .in_deficit
+            .iter()
+            .map(move |id| state[id].as_ref())
+    }
+
+    /// Every account, from the largest total balance to the smallest.
+    pub fn accounts_by_descending_total(
+        &self,
+    ) -> impl Iterator<Item = &'a ClientAccount<C, T>> + 'a {
+        let state = self.state;
+        self.indexes
+            .by_total
+            .iter()
+            .rev()
+            .flat_map(move |(_, ids)| ids.iter().map(move |id| state[id].as_ref()))
+    }
+
+    /// Accounts whose total balance falls within `range`, ordered by ascending total.
+    pub fn accounts_with_total(
+        &self,
+        range: impl RangeBounds<Decimal>,
+    ) -> impl Iterator<Item = &'a ClientAccount<C, T>> + 'a {
+        let state = self.state;
+        self.indexes
+            .by_total
+            .range(range)
+            .flat_map(move |(_, ids)| ids.iter().map(move |id| state[id].as_ref()))
+    }
+}
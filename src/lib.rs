@@ -1,65 +1,254 @@
 #![forbid(unsafe_code)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-use std::collections::HashMap;
+#[cfg(not(any(feature = "std", feature = "alloc")))]
+compile_error!("The engine requires either the `std` or the `alloc` feature.");
 
-use rust_decimal::Decimal;
+extern crate alloc;
 
-#[derive(Debug, Clone)]
-pub enum Transaction {
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+#[cfg(not(feature = "std"))]
+use hashbrown::{hash_map, HashMap, HashSet};
+#[cfg(feature = "std")]
+use std::collections::{hash_map, HashMap, HashSet};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+pub mod amount;
+#[cfg(feature = "audit")]
+pub mod audit;
+pub mod auth;
+mod builder;
+pub mod config;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod error;
+pub mod event;
+#[cfg(any(feature = "encryption", feature = "signing"))]
+mod hex;
+pub mod id;
+pub mod index;
+pub mod metadata;
+#[cfg(feature = "mt940")]
+pub mod mt940;
+pub mod pipeline;
+pub mod policy;
+#[cfg(feature = "std")]
+pub mod rate_limit;
+pub mod schedule;
+pub mod screening;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod simulation;
+pub mod stats;
+pub mod store;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+pub mod tenant;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transform;
+#[cfg(feature = "std")]
+mod view;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod wire;
+
+use amount::Amount;
+pub use builder::PaymentEngineBuilder;
+use config::{
+    AccountCreation, CrossClientDisputes, EngineConfig, LockedPolicy, NegativeHeldPolicy,
+};
+use error::EngineError;
+use event::{EngineEvent, EngineObserver, EventQueue};
+use id::{ClientId, TransactionId};
+use index::{Indexes, Query};
+use metadata::ClientMetadata;
+use policy::{Direction, DisputeStage, FundsChange};
+use schedule::{Schedule, StandingOrder, StandingOrderId, Transfer};
+use screening::{Screening, ScreeningHandle};
+use stats::{AccountTotals, EngineStats, InvariantReport, RecordCounts};
+use store::StoreHandle;
+#[cfg(feature = "std")]
+pub use view::PaymentEngineView;
+use wire::WireRecordType;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum Transaction<C = u16, T = u32> {
     Deposit {
-        client: u16,
-        transaction_id: u32,
-        amount: Decimal,
+        client: C,
+        transaction_id: T,
+        amount: Amount,
     },
     Withdrawal {
-        client: u16,
-        transaction_id: u32,
-        amount: Decimal,
+        client: C,
+        transaction_id: T,
+        amount: Amount,
+    },
+    /// Reserves the funds of a withdrawal by holding them, until the payout is settled with a
+    /// [`WithdrawalAction`]. Once confirmed, it counts as a withdrawal.
+    WithdrawalRequest {
+        client: C,
+        transaction_id: T,
+        amount: Amount,
     },
 }
 
-impl Transaction {
-    fn get_client_id(&self) -> &u16 {
+impl<C, T> Transaction<C, T> {
+    fn get_client_id(&self) -> &C {
         match self {
             Transaction::Deposit { client, .. } => client,
             Transaction::Withdrawal { client, .. } => client,
+            Transaction::WithdrawalRequest { client, .. } => client,
         }
     }
 
-    fn get_transaction_id(&self) -> &u32 {
+    fn get_transaction_id(&self) -> &T {
         match self {
             Transaction::Deposit { transaction_id, .. } => transaction_id,
             Transaction::Withdrawal { transaction_id, .. } => transaction_id,
+            Transaction::WithdrawalRequest { transaction_id, .. } => transaction_id,
+        }
+    }
+
+    fn get_amount(&self) -> &Amount {
+        match self {
+            Transaction::Deposit { amount, .. } => amount,
+            Transaction::Withdrawal { amount, .. } => amount,
+            Transaction::WithdrawalRequest { amount, .. } => amount,
+        }
+    }
+
+    fn amount_mut(&mut self) -> &mut Amount {
+        match self {
+            Transaction::Deposit { amount, .. } => amount,
+            Transaction::Withdrawal { amount, .. } => amount,
+            Transaction::WithdrawalRequest { amount, .. } => amount,
+        }
+    }
+
+    fn direction(&self) -> Direction {
+        match self {
+            Transaction::Deposit { .. } => Direction::Credit,
+            Transaction::Withdrawal { .. } | Transaction::WithdrawalRequest { .. } => {
+                Direction::Debit
+            }
+        }
+    }
+}
+
+/// Transactions that are applied together or not at all, e.g. a payment split across two funding accounts plus a
+/// fee, see [`PaymentEngine::add_composite_transaction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CompositeTransaction<C = u16, T = u32> {
+    pub legs: Vec<Transaction<C, T>>,
+}
+
+impl<C, T> FromIterator<Transaction<C, T>> for CompositeTransaction<C, T> {
+    fn from_iter<I: IntoIterator<Item = Transaction<C, T>>>(legs: I) -> Self {
+        Self {
+            legs: legs.into_iter().collect(),
+        }
+    }
+}
+
+/// Settles a [`Transaction::WithdrawalRequest`], when the payout went through or was called off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum WithdrawalAction<C = u16, T = u32> {
+    /// The held funds leave the account.
+    WithdrawalConfirm {
+        client: C,
+        referenced_transaction_id: T,
+    },
+    /// The held funds return to the available funds.
+    WithdrawalCancel {
+        client: C,
+        referenced_transaction_id: T,
+    },
+}
+
+impl<C, T> WithdrawalAction<C, T> {
+    fn get_client_id(&self) -> &C {
+        match self {
+            WithdrawalAction::WithdrawalConfirm { client, .. } => client,
+            WithdrawalAction::WithdrawalCancel { client, .. } => client,
+        }
+    }
+
+    fn get_referenced_transaction_id(&self) -> &T {
+        match self {
+            WithdrawalAction::WithdrawalConfirm {
+                referenced_transaction_id: id,
+                ..
+            } => id,
+            WithdrawalAction::WithdrawalCancel {
+                referenced_transaction_id: id,
+                ..
+            } => id,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum DisputeAction {
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum DisputeAction<C = u16, T = u32> {
     Dispute {
-        client: u16,
-        referenced_transaction_id: u32,
+        client: C,
+        referenced_transaction_id: T,
     },
     Resolve {
-        client: u16,
-        referenced_transaction_id: u32,
+        client: C,
+        referenced_transaction_id: T,
     },
     Chargeback {
-        client: u16,
-        referenced_transaction_id: u32,
+        client: C,
+        referenced_transaction_id: T,
+    },
+    /// Takes a disputed transaction to arbitration, e.g. after a second presentment by the merchant.
+    /// The funds stay where they are until the arbitration is decided.
+    Escalate {
+        client: C,
+        referenced_transaction_id: T,
+    },
+    /// The arbitration was decided in favour of the client, the funds move as for a chargeback.
+    ArbitrationWon {
+        client: C,
+        referenced_transaction_id: T,
+    },
+    /// The arbitration was decided against the client, the funds move as for a resolve.
+    ArbitrationLost {
+        client: C,
+        referenced_transaction_id: T,
     },
 }
 
-impl DisputeAction {
-    fn get_client_id(&self) -> &u16 {
+impl<C, T> DisputeAction<C, T> {
+    fn get_client_id(&self) -> &C {
         match self {
             DisputeAction::Dispute { client, .. } => client,
             DisputeAction::Resolve { client, .. } => client,
             DisputeAction::Chargeback { client, .. } => client,
+            DisputeAction::Escalate { client, .. } => client,
+            DisputeAction::ArbitrationWon { client, .. } => client,
+            DisputeAction::ArbitrationLost { client, .. } => client,
         }
     }
 
-    fn get_referenced_transaction_id(&self) -> &u32 {
+    fn get_referenced_transaction_id(&self) -> &T {
         match self {
             DisputeAction::Dispute {
                 referenced_transaction_id: id,
@@ -73,19 +262,229 @@ impl DisputeAction {
                 referenced_transaction_id: id,
                 ..
             } => id,
+            DisputeAction::Escalate {
+                referenced_transaction_id: id,
+                ..
+            } => id,
+            DisputeAction::ArbitrationWon {
+                referenced_transaction_id: id,
+                ..
+            } => id,
+            DisputeAction::ArbitrationLost {
+                referenced_transaction_id: id,
+                ..
+            } => id,
+        }
+    }
+}
+
+/// An administrative correction of an account, e.g. for mistakes upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum Adjustment<C = u16, T = u32> {
+    /// Corrects the amount of an accepted transaction that isn't disputed, the funds change by the difference.
+    /// The history keeps the original amount, see [`ClientAccount::original_amount`].
+    Amend {
+        client: C,
+        referenced_transaction_id: T,
+        amount: Amount,
+    },
+    /// Zeroes the available funds of an account in deficit, e.g. after a chargeback of funds that were already
+    /// withdrawn. The debt is posted to the loss ledger, see [`PaymentEngine::loss_ledger`].
+    WriteOff { client: C, transaction_id: T },
+}
+
+impl<C, T> Adjustment<C, T> {
+    fn get_client_id(&self) -> &C {
+        match self {
+            Adjustment::Amend { client, .. } | Adjustment::WriteOff { client, .. } => client,
+        }
+    }
+
+    /// The transaction that was adjusted, or the id of the write-off.
+    fn get_transaction_id(&self) -> &T {
+        match self {
+            Adjustment::Amend {
+                referenced_transaction_id,
+                ..
+            } => referenced_transaction_id,
+            Adjustment::WriteOff { transaction_id, .. } => transaction_id,
+        }
+    }
+}
+
+/// Opens or closes the account of a client, so an input can describe the whole life of an account. With
+/// [`config::AccountCreation::Registered`], the engine rejects any activity outside of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum AccountAction<C = u16, T = u32> {
+    /// See [`PaymentEngine::open_account`]. An `initial_deposit` is deposited as `transaction_id` right away.
+    Open {
+        client: C,
+        transaction_id: T,
+        #[cfg_attr(feature = "serde", serde(default))]
+        initial_deposit: Option<Amount>,
+    },
+    /// See [`PaymentEngine::close_account`], the available funds stay in the closed account.
+    /// `transaction_id` only identifies the record.
+    Close { client: C, transaction_id: T },
+}
+
+impl<C, T> AccountAction<C, T> {
+    fn get_client_id(&self) -> &C {
+        match self {
+            AccountAction::Open { client, .. } | AccountAction::Close { client, .. } => client,
+        }
+    }
+}
+
+/// A debt that was written off, see [`Adjustment::WriteOff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LossEntry<C = u16, T = u32> {
+    pub client: C,
+    /// The id of the write-off.
+    pub transaction_id: T,
+    pub amount: Amount,
+}
+
+/// Anything that can be added to the engine, so upstream code can handle a single stream of records.
+/// It serializes as the transaction or action it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum Record<C = u16, T = u32> {
+    Transaction(Transaction<C, T>),
+    Dispute(DisputeAction<C, T>),
+    Withdrawal(WithdrawalAction<C, T>),
+    Adjustment(Adjustment<C, T>),
+    Account(AccountAction<C, T>),
+}
+
+impl<C: Copy, T> Record<C, T> {
+    /// The client the record belongs to.
+    pub fn client(&self) -> C {
+        *match self {
+            Record::Transaction(transaction) => transaction.get_client_id(),
+            Record::Dispute(action) => action.get_client_id(),
+            Record::Withdrawal(action) => action.get_client_id(),
+            Record::Adjustment(adjustment) => adjustment.get_client_id(),
+            Record::Account(action) => action.get_client_id(),
         }
     }
 }
 
-struct TransactionHistoryRecord {
-    transaction: Transaction,
+impl<C, T> From<Transaction<C, T>> for Record<C, T> {
+    fn from(transaction: Transaction<C, T>) -> Self {
+        Record::Transaction(transaction)
+    }
+}
+
+impl<C, T> From<DisputeAction<C, T>> for Record<C, T> {
+    fn from(dispute_action: DisputeAction<C, T>) -> Self {
+        Record::Dispute(dispute_action)
+    }
+}
+
+impl<C, T> From<WithdrawalAction<C, T>> for Record<C, T> {
+    fn from(action: WithdrawalAction<C, T>) -> Self {
+        Record::Withdrawal(action)
+    }
+}
+
+impl<C, T> From<Adjustment<C, T>> for Record<C, T> {
+    fn from(adjustment: Adjustment<C, T>) -> Self {
+        Record::Adjustment(adjustment)
+    }
+}
+
+impl<C, T> From<AccountAction<C, T>> for Record<C, T> {
+    fn from(action: AccountAction<C, T>) -> Self {
+        Record::Account(action)
+    }
+}
+
+/// The effect a record had on the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    Rejected(RejectionReason),
+}
+
+/// Why a record did not have any effect on the account it was meant for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum RejectionReason {
+    /// The account has been locked by a chargeback.
+    AccountLocked,
+    InsufficientFunds,
+    /// The referenced transaction doesn't exist, or doesn't belong to this client.
+    UnknownTransaction,
+    /// The referenced transaction isn't in a state the action applies to, e.g. resolving an undisputed transaction.
+    InvalidState,
+    /// The client already has the maximum number of open disputes, see [`config::DisputePolicy::max_open_per_client`].
+    TooManyOpenDisputes,
+    /// A recovery deposit or write-off was posted for an account that isn't in deficit.
+    NoOutstandingDebt,
+    /// The action would have made the held funds negative, see [`config::NegativeHeldPolicy`].
+    NegativeHeld,
+    /// The resulting balance, or the amount itself, wouldn't fit in an [`Amount`].
+    BalanceOverflow,
+    /// The amount has more decimal places than [`Amount::PRECISION`], see [`amount::PrecisionPolicy`].
+    ExcessPrecision,
+    /// The amount of a deposit or withdrawal was negative.
+    NegativeAmount,
+    /// The referenced transaction is too old to be disputed, see [`config::JurisdictionRules::dispute_window`].
+    DisputeWindowExpired,
+    /// The withdrawal is larger than allowed, see [`config::JurisdictionRules::max_withdrawal`].
+    WithdrawalLimitExceeded,
+    /// The client is blocked by the screening of the engine, see [`screening::Screening`].
+    Screened,
+    /// The account has been closed, or would have received the funds of an account that is being closed,
+    /// see [`PaymentEngine::close_account`].
+    AccountClosed,
+    /// An account can't be closed while it has open disputes.
+    OpenDisputes,
+    /// An account can't be closed while it holds funds for a withdrawal request.
+    FundsHeld,
+    /// Records of this type are ignored, see [`config::EngineConfig::disabled`].
+    Disabled,
+    /// The account already has a transaction with this id that is disputed or awaiting its settlement.
+    DuplicateTransaction,
+    /// The client has no account and the record doesn't create one, see [`config::AccountCreation`].
+    UnregisteredClient,
+    /// The client already has an account, see [`PaymentEngine::open_account`].
+    AccountExists,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct TransactionHistoryRecord<C, T> {
+    transaction: Transaction<C, T>,
     state: TransactionState,
+    /// The position of this transaction within all transactions of the account.
+    sequence: u64,
+    /// Whether disputing this withdrawal credited its amount provisionally, see [`config::DisputePolicy::provisional_credit`].
+    provisional_credit: bool,
+    /// The amount the transaction had before it was first amended, see [`Adjustment::Amend`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    original_amount: Option<Amount>,
+    /// How many times the transaction was disputed, see [`config::DisputePolicy::max_redisputes`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    dispute_rounds: u32,
 }
 
-impl TransactionHistoryRecord {
-    fn new(transaction: Transaction, accepted: bool) -> Self {
+impl<C, T> TransactionHistoryRecord<C, T> {
+    fn new(transaction: Transaction<C, T>, accepted: bool, sequence: u64) -> Self {
         Self {
             transaction,
+            sequence,
+            provisional_credit: false,
+            original_amount: None,
+            dispute_rounds: 0,
             state: if accepted {
                 TransactionState::Accepted
             } else {
@@ -95,6 +494,21 @@ impl TransactionHistoryRecord {
     }
 }
 
+impl<C: ClientId, T: TransactionId> TransactionHistoryRecord<C, T> {
+    /// Whether this resolved transaction can be disputed again, see [`config::DisputePolicy::max_redisputes`]. A
+    /// round that credited a withdrawal back, provisionally or by the funds movement policy, would credit it again on
+    /// every round, so such a transaction stays resolved.
+    fn redisputable(&self, disputes: &config::DisputePolicy) -> bool {
+        self.state == TransactionState::Resolved
+            && self.dispute_rounds <= disputes.max_redisputes
+            && !self.provisional_credit
+            && disputes
+                .funds_movement
+                .policy()
+                .round_trips(self.transaction.direction(), *self.transaction.get_amount())
+    }
+}
+
 ///
 /// # State diagram
 /// ```none
@@ -109,100 +523,394 @@ impl TransactionHistoryRecord {
 /// ┌────────┐    ┌────────────┐
 /// │Disputed├──► │Chargebacked│
 /// └───┬────┘    └────────────┘
-///     ▼
-/// ┌────────┐
-/// │Resolved│
-/// └────────┘
+///     ├─────────────────┐
+///     ▼                 ▼
+/// ┌────────┐    ┌───────────┐    ┌──────────────┐
+/// │Resolved│    │Arbitration├──► │ArbitrationWon│
+/// └────────┘    └─────┬─────┘    └──────────────┘
+///                     ▼
+///             ┌───────────────┐
+///             │ArbitrationLost│
+///             └───────────────┘
 /// ```
-enum TransactionState {
+///
+/// A [`Transaction::WithdrawalRequest`] is `Requested` until it's confirmed, which makes it `Accepted`,
+/// or `Cancelled`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TransactionState {
     Accepted,
+    Requested,
+    Cancelled,
     Rejected,
     Disputed,
     Resolved,
     Chargebacked,
+    Arbitration,
+    ArbitrationWon,
+    ArbitrationLost,
 }
 
-pub struct ClientAccount {
-    id: u16,
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+// The bounds on the ids already require them to be deserializable.
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "")))]
+pub struct ClientAccount<C: ClientId = u16, T: TransactionId = u32> {
+    id: C,
     /// A history of transactions and whether or not they were accepted.
     /// e.g. a withdrawal might fail due to insufficient funds.
-    transaction_history: HashMap<u32, TransactionHistoryRecord>,
-    dispute_history: Vec<DisputeAction>,
-    available: Decimal,
-    held: Decimal,
+    transaction_history: HashMap<T, TransactionHistoryRecord<C, T>>,
+    dispute_history: Vec<DisputeAction<C, T>>,
+    /// The number of transactions ever added to this account, pruning the history doesn't lower it.
+    transaction_count: u64,
+    /// The number of transactions that are currently disputed.
+    open_disputes: usize,
+    available: Amount,
+    held: Amount,
+    /// The part of `available` that was credited provisionally for disputed withdrawals.
+    provisional: Amount,
+    /// The total by which held funds would have gone negative, but were clamped to zero instead.
+    clamped_held: Amount,
+    /// How far withdrawals may take the available funds below zero.
+    credit_limit: Amount,
     locked: bool,
+    /// See [`PaymentEngine::close_account`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    closed: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    metadata: Option<Arc<ClientMetadata>>,
+    /// The engine sequence number at which the account was created, or a record was last applied to it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    last_activity: u64,
+    /// See [`ClientAccount::audit_head`].
+    #[cfg(feature = "audit")]
+    #[cfg_attr(feature = "serde", serde(default))]
+    audit_head: audit::AuditHash,
+}
+
+// `last_activity` and `audit_head` are bookkeeping of the engine the account is in rather than state of the account,
+// so an account restored into another engine is still equal to the original.
+impl<C: ClientId, T: TransactionId> PartialEq for ClientAccount<C, T> {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            id,
+            transaction_history,
+            dispute_history,
+            transaction_count,
+            open_disputes,
+            available,
+            held,
+            provisional,
+            clamped_held,
+            credit_limit,
+            locked,
+            closed,
+            metadata,
+            last_activity: _,
+            #[cfg(feature = "audit")]
+                audit_head: _,
+        } = self;
+        *id == other.id
+            && *transaction_history == other.transaction_history
+            && *dispute_history == other.dispute_history
+            && *transaction_count == other.transaction_count
+            && *open_disputes == other.open_disputes
+            && *available == other.available
+            && *held == other.held
+            && *provisional == other.provisional
+            && *clamped_held == other.clamped_held
+            && *credit_limit == other.credit_limit
+            && *locked == other.locked
+            && *closed == other.closed
+            && *metadata == other.metadata
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Eq for ClientAccount<C, T> {}
+
+/// A new account for `id` created at engine sequence number `sequence`, with the metadata of the client if there
+/// is any.
+fn new_account<C: ClientId, T: TransactionId>(
+    id: C,
+    metadata: &HashMap<C, Arc<ClientMetadata>>,
+    sequence: u64,
+) -> ClientAccount<C, T> {
+    ClientAccount {
+        metadata: metadata.get(&id).cloned(),
+        last_activity: sequence,
+        ..ClientAccount::new(id)
+    }
+}
+
+/// Where the outcomes of `dispute_action` are counted.
+fn dispute_counts<'a, C, T>(
+    stats: &'a mut EngineStats,
+    dispute_action: &DisputeAction<C, T>,
+) -> &'a mut RecordCounts {
+    match dispute_action {
+        DisputeAction::Dispute { .. } => &mut stats.disputes,
+        DisputeAction::Resolve { .. } => &mut stats.resolves,
+        DisputeAction::Chargeback { .. } => &mut stats.chargebacks,
+        DisputeAction::Escalate { .. } => &mut stats.escalations,
+        DisputeAction::ArbitrationWon { .. } | DisputeAction::ArbitrationLost { .. } => {
+            &mut stats.arbitrations
+        }
+    }
+}
+
+/// An estimate of the memory held by the table of `map`, at one control byte per entry.
+fn hash_map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+/// Checks new values for the available and held funds of an account, as computed with checked arithmetic.
+/// Their sum has to fit as well, since that's the total of the account.
+fn checked_funds(
+    available: Option<Amount>,
+    held: Option<Amount>,
+) -> Result<(Amount, Amount), RejectionReason> {
+    match (available, held) {
+        (Some(available), Some(held)) if available.checked_add(held).is_some() => {
+            Ok((available, held))
+        }
+        _ => Err(RejectionReason::BalanceOverflow),
+    }
+}
+
+/// Like [`checked_funds`], additionally applying the policy for negative held funds.
+fn settle_funds(
+    available: Option<Amount>,
+    held: Option<Amount>,
+    config: &EngineConfig,
+    clamped: &mut Amount,
+) -> Result<(Amount, Amount), RejectionReason> {
+    let (available, held) = checked_funds(available, held)?;
+    if !held.is_negative() {
+        return Ok((available, held));
+    }
+    match config.disputes.negative_held {
+        NegativeHeldPolicy::Reject => Err(RejectionReason::NegativeHeld),
+        NegativeHeldPolicy::Clamp => {
+            *clamped = clamped
+                .checked_sub(held)
+                .ok_or(RejectionReason::BalanceOverflow)?;
+            Ok((available, Amount::ZERO))
+        }
+    }
+}
+
+/// Applies the [`FundsChange`] of a dispute through [`settle_funds`], leaving the funds untouched when it's rejected.
+fn move_funds(
+    available: &mut Amount,
+    held: &mut Amount,
+    change: FundsChange,
+    config: &EngineConfig,
+    clamped: &mut Amount,
+) -> Result<(), RejectionReason> {
+    // Adding zero to zero funds would lose their scale, so funds that don't change are left as they are.
+    let add = |funds: Amount, change: Amount| {
+        if change == Amount::ZERO {
+            Some(funds)
+        } else {
+            funds.checked_add(change)
+        }
+    };
+    (*available, *held) = settle_funds(
+        add(*available, change.available),
+        add(*held, change.held),
+        config,
+        clamped,
+    )?;
+    Ok(())
+}
+
+/// Which settled transactions to drop when pruning the history of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep only the given number of most recent settled transactions.
+    KeepLast(usize),
+    /// Drop settled transactions that have been followed by at least the given number of transactions.
+    OlderThan(u64),
 }
 
-impl ClientAccount {
-    pub fn new(id: u16) -> Self {
+impl<C: ClientId, T: TransactionId> ClientAccount<C, T> {
+    pub fn new(id: C) -> Self {
         Self {
             id,
             transaction_history: HashMap::new(),
             dispute_history: vec![],
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
+            transaction_count: 0,
+            open_disputes: 0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            provisional: Amount::ZERO,
+            clamped_held: Amount::ZERO,
+            credit_limit: Amount::ZERO,
             locked: false,
+            closed: false,
+            metadata: None,
+            last_activity: 0,
+            #[cfg(feature = "audit")]
+            audit_head: audit::GENESIS,
         }
     }
 
-    /// Fails when trying to add a tranasaction that is not for this client, returning the passed in transaction.
-    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), Transaction> {
+    /// Fails when trying to add a tranasaction that is not for this client, see [`EngineError::ForeignTransaction`].
+    pub fn add_transaction(
+        &mut self,
+        transaction: Transaction<C, T>,
+    ) -> Result<Outcome, EngineError<C, T>> {
+        self.apply_transaction(transaction, &EngineConfig::default())
+    }
+
+    pub(crate) fn apply_transaction(
+        &mut self,
+        transaction: Transaction<C, T>,
+        config: &EngineConfig,
+    ) -> Result<Outcome, EngineError<C, T>> {
         if *transaction.get_client_id() != self.id {
-            return Err(transaction);
+            return Err(EngineError::ForeignTransaction {
+                account: self.id,
+                transaction,
+            });
+        }
+        // Recording it would replace the original, forgetting about funds that are held for it. Reusing the id of
+        // a settled transaction stays possible, deduplicating records is up to the caller.
+        if self
+            .transaction_history
+            .get(transaction.get_transaction_id())
+            .is_some_and(|r| {
+                matches!(
+                    r.state,
+                    TransactionState::Disputed
+                        | TransactionState::Arbitration
+                        | TransactionState::Requested
+                )
+            })
+        {
+            return Ok(Outcome::Rejected(RejectionReason::DuplicateTransaction));
         }
 
-        if self.locked {
+        if self.closed {
+            self.record_transaction(transaction, false);
+            return Ok(Outcome::Rejected(RejectionReason::AccountClosed));
+        }
+
+        let deposit_allowed = matches!(transaction, Transaction::Deposit { .. })
+            && config.locked == LockedPolicy::AllowDeposits;
+        if self.locked && !deposit_allowed {
             // Prevent any transaction from having an effect when the client is locked.
-            self.transaction_history.insert(
-                *transaction.get_transaction_id(),
-                TransactionHistoryRecord::new(transaction, false),
-            );
-            return Ok(());
+            self.record_transaction(transaction, false);
+            return Ok(Outcome::Rejected(RejectionReason::AccountLocked));
         }
 
-        match transaction {
+        let max_withdrawal = self.jurisdiction(config).and_then(|r| r.max_withdrawal);
+        let outcome = match transaction {
             Transaction::Deposit { amount, .. } => {
-                self.available += amount;
-                self.transaction_history.insert(
-                    *transaction.get_transaction_id(),
-                    TransactionHistoryRecord::new(transaction, true),
-                );
+                match checked_funds(self.available.checked_add(amount), Some(self.held)) {
+                    Ok((available, _)) => {
+                        self.available = available;
+                        self.record_transaction(transaction, true);
+                        Outcome::Applied
+                    }
+                    Err(reason) => {
+                        self.record_transaction(transaction, false);
+                        Outcome::Rejected(reason)
+                    }
+                }
+            }
+            Transaction::Withdrawal { amount, .. }
+            | Transaction::WithdrawalRequest { amount, .. }
+                if max_withdrawal.is_some_and(|max| amount > max) =>
+            {
+                self.record_transaction(transaction, false);
+                Outcome::Rejected(RejectionReason::WithdrawalLimitExceeded)
             }
-            Transaction::Withdrawal { amount, .. } => {
+            Transaction::Withdrawal { amount, .. }
+            | Transaction::WithdrawalRequest { amount, .. } => {
+                // A request only reserves the funds, they leave the account once it's confirmed.
+                let requested = matches!(transaction, Transaction::WithdrawalRequest { .. });
+                let held = if requested {
+                    self.held.checked_add(amount)
+                } else {
+                    Some(self.held)
+                };
                 if self.withdrawal_amount_allowed(amount) {
-                    self.available -= amount;
-                    self.transaction_history.insert(
-                        *transaction.get_transaction_id(),
-                        TransactionHistoryRecord::new(transaction, true),
-                    );
+                    match checked_funds(self.available.checked_sub(amount), held) {
+                        Ok((available, held)) => {
+                            let transaction_id = *transaction.get_transaction_id();
+                            self.available = available;
+                            self.held = held;
+                            self.record_transaction(transaction, true);
+                            if requested {
+                                self.transaction_history
+                                    .get_mut(&transaction_id)
+                                    .expect("The request was just recorded.")
+                                    .state = TransactionState::Requested;
+                            }
+                            Outcome::Applied
+                        }
+                        Err(reason) => {
+                            self.record_transaction(transaction, false);
+                            Outcome::Rejected(reason)
+                        }
+                    }
                 } else {
-                    self.transaction_history.insert(
-                        *transaction.get_transaction_id(),
-                        TransactionHistoryRecord::new(transaction, false),
-                    );
+                    self.record_transaction(transaction, false);
+                    Outcome::Rejected(RejectionReason::InsufficientFunds)
                 }
             }
-        }
+        };
 
-        Ok(())
+        Ok(outcome)
     }
 
-    /// Fails when trying to add an action for a client that is not this client. Returning the passed in dispute action.
+    /// Fails when trying to add an action for a client that is not this client, see [`EngineError::ForeignDisputeAction`].
     pub fn add_dispute_action(
         &mut self,
-        dispute_action: DisputeAction,
-    ) -> Result<(), DisputeAction> {
+        dispute_action: DisputeAction<C, T>,
+    ) -> Result<Outcome, EngineError<C, T>> {
+        self.apply_dispute_action(dispute_action, &EngineConfig::default())
+    }
+
+    /// Like [`ClientAccount::apply_dispute_action`], also when the account is locked, which it stays.
+    /// Only meant for actions that can't lock the account themselves, see [`PaymentEngine::force_resolve`].
+    pub(crate) fn force_dispute_action(
+        &mut self,
+        dispute_action: DisputeAction<C, T>,
+        config: &EngineConfig,
+    ) -> Result<Outcome, EngineError<C, T>> {
+        let locked = core::mem::replace(&mut self.locked, false);
+        let outcome = self.apply_dispute_action(dispute_action, config);
+        self.locked |= locked;
+        outcome
+    }
+
+    pub(crate) fn apply_dispute_action(
+        &mut self,
+        dispute_action: DisputeAction<C, T>,
+        config: &EngineConfig,
+    ) -> Result<Outcome, EngineError<C, T>> {
         if *dispute_action.get_client_id() != self.id {
-            return Err(dispute_action);
+            return Err(EngineError::ForeignDisputeAction {
+                account: self.id,
+                dispute_action,
+            });
+        }
+
+        if self.closed {
+            self.dispute_history.push(dispute_action);
+            return Ok(Outcome::Rejected(RejectionReason::AccountClosed));
         }
 
         if self.locked {
             // Prevent any transaction from having an effect when the client is locked.
             self.dispute_history.push(dispute_action);
-            return Ok(());
+            return Ok(Outcome::Rejected(RejectionReason::AccountLocked));
         }
 
         let referenced_transaction_id = *dispute_action.get_referenced_transaction_id();
+        let rules = self.jurisdiction(config);
 
         let referenced_transaction =
             match self.transaction_history.get_mut(&referenced_transaction_id) {
@@ -210,549 +918,4720 @@ impl ClientAccount {
                 None => {
                     // Nothing to do, since the transaction doesn't exist (or it doesn't exist for this user!).
                     // Also don't store anything about it, since it's probably just a mistake.
-                    return Ok(());
+                    return Ok(Outcome::Rejected(RejectionReason::UnknownTransaction));
                 }
             };
 
-        match (&mut referenced_transaction.state, &dispute_action) {
-            (state @ TransactionState::Accepted, DisputeAction::Dispute { .. }) => {
-                match referenced_transaction.transaction {
-                    Transaction::Deposit { amount, .. } => {
-                        self.available -= amount;
-                        self.held += amount;
-                    }
-                    Transaction::Withdrawal { .. } => {
-                        // Don't do anything until the dispute is resolved.
-                    }
+        let dispute_allowed = config
+            .disputes
+            .max_open_per_client
+            .is_none_or(|max| self.open_disputes < max);
+        let later_transactions = self.transaction_count - referenced_transaction.sequence;
+        let within_window = rules
+            .and_then(|r| r.dispute_window)
+            .is_none_or(|window| later_transactions < window);
+        let provisional_credit = rules
+            .and_then(|r| r.provisional_credit)
+            .unwrap_or(config.disputes.provisional_credit);
+        // Only checked for resolved transactions, the first round doesn't count as a re-dispute.
+        let redispute_allowed = referenced_transaction.redisputable(&config.disputes);
+        let direction = referenced_transaction.transaction.direction();
+        let amount = *referenced_transaction.transaction.get_amount();
+        let funds_change = |stage| {
+            config
+                .disputes
+                .funds_movement
+                .policy()
+                .funds_change(direction, stage, amount)
+        };
+
+        let outcome = match (&mut referenced_transaction.state, &dispute_action) {
+            (TransactionState::Resolved, DisputeAction::Dispute { .. }) if !redispute_allowed => {
+                // The transaction has been disputed as often as it can be.
+                Outcome::Rejected(RejectionReason::InvalidState)
+            }
+            (
+                TransactionState::Accepted | TransactionState::Resolved,
+                DisputeAction::Dispute { .. },
+            ) if !within_window => Outcome::Rejected(RejectionReason::DisputeWindowExpired),
+            (
+                state @ (TransactionState::Accepted | TransactionState::Resolved),
+                DisputeAction::Dispute { .. },
+            ) if dispute_allowed => {
+                if provisional_credit && direction == Direction::Debit {
+                    // A provisional credit replaces whatever the funds movement policy does with withdrawals.
+                    let (Ok((available, _)), Some(provisional)) = (
+                        checked_funds(self.available.checked_add(amount), Some(self.held)),
+                        self.provisional.checked_add(amount),
+                    ) else {
+                        return Ok(Outcome::Rejected(RejectionReason::BalanceOverflow));
+                    };
+                    self.available = available;
+                    self.provisional = provisional;
+                    referenced_transaction.provisional_credit = true;
+                } else if let Err(reason) = move_funds(
+                    &mut self.available,
+                    &mut self.held,
+                    funds_change(DisputeStage::Opened),
+                    config,
+                    &mut self.clamped_held,
+                ) {
+                    return Ok(Outcome::Rejected(reason));
                 }
                 self.dispute_history.push(dispute_action);
-                *state = TransactionState::Disputed
+                self.open_disputes += 1;
+                referenced_transaction.dispute_rounds += 1;
+                *state = TransactionState::Disputed;
+                Outcome::Applied
+            }
+            (
+                TransactionState::Accepted | TransactionState::Resolved,
+                DisputeAction::Dispute { .. },
+            ) => {
+                // Limiting the number of open disputes keeps a flood of disputes from freezing large amounts of funds.
+                Outcome::Rejected(RejectionReason::TooManyOpenDisputes)
             }
             (TransactionState::Rejected, DisputeAction::Dispute { .. }) => {
                 // Disputing a rejected transaction is a NOOP.
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Disputed, DisputeAction::Dispute { .. }) => {
                 // Don't do anything, disputing a disputed transaction is a NOOP.
-            }
-            (TransactionState::Resolved, DisputeAction::Dispute { .. }) => {
-                // Disputing a resolved transaction is a NOOP, potentially we might want to user to be able to redispute this some amount of times?
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Chargebacked, DisputeAction::Dispute { .. }) => {
                 // Disputing a chargebacked transaction is a NOOP, potentially we might want to user to be able to redispute this some amount of times?
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
 
-            (state @ TransactionState::Disputed, DisputeAction::Resolve { .. }) => {
-                match referenced_transaction.transaction {
-                    Transaction::Deposit { amount, .. } => {
-                        self.available += amount;
-                        self.held -= amount;
-                    }
-                    Transaction::Withdrawal { amount, .. } => {
-                        self.available += amount;
-                    }
+            (state @ TransactionState::Disputed, DisputeAction::Resolve { .. })
+            | (state @ TransactionState::Arbitration, DisputeAction::ArbitrationLost { .. }) => {
+                if referenced_transaction.provisional_credit {
+                    // The client already has the funds, the credit just isn't provisional anymore.
+                    let Some(provisional) = self.provisional.checked_sub(amount) else {
+                        return Ok(Outcome::Rejected(RejectionReason::BalanceOverflow));
+                    };
+                    self.provisional = provisional;
+                } else if let Err(reason) = move_funds(
+                    &mut self.available,
+                    &mut self.held,
+                    funds_change(DisputeStage::Resolved),
+                    config,
+                    &mut self.clamped_held,
+                ) {
+                    return Ok(Outcome::Rejected(reason));
                 }
+                *state = match dispute_action {
+                    DisputeAction::Resolve { .. } => TransactionState::Resolved,
+                    _ => TransactionState::ArbitrationLost,
+                };
                 self.dispute_history.push(dispute_action);
-                *state = TransactionState::Resolved
+                self.open_disputes -= 1;
+                Outcome::Applied
             }
             (TransactionState::Accepted, DisputeAction::Resolve { .. }) => {
                 // We cannot resolve something that is not disputed. Just ignore it.
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Rejected, DisputeAction::Resolve { .. }) => {
                 // If it's rejected, we cannot resolve it.
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Resolved, DisputeAction::Resolve { .. }) => {
                 // NOOP.
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Chargebacked, DisputeAction::Resolve { .. }) => {
                 // It's already been chargebacked, resolving it is not possible..
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
 
-            (state @ TransactionState::Disputed, DisputeAction::Chargeback { .. }) => {
-                match referenced_transaction.transaction {
-                    Transaction::Deposit { amount, .. } => {
-                        self.held -= amount;
-                    }
-                    Transaction::Withdrawal { .. } => {
-                        // We didn't change anything about the funds for a witdrawal,
-                        // so when we chargeback we don't have to do anything.
-                    }
+            (state @ TransactionState::Disputed, DisputeAction::Chargeback { .. })
+            | (state @ TransactionState::Arbitration, DisputeAction::ArbitrationWon { .. }) => {
+                if referenced_transaction.provisional_credit {
+                    let (Ok((available, _)), Some(provisional)) = (
+                        checked_funds(self.available.checked_sub(amount), Some(self.held)),
+                        self.provisional.checked_sub(amount),
+                    ) else {
+                        return Ok(Outcome::Rejected(RejectionReason::BalanceOverflow));
+                    };
+                    self.available = available;
+                    self.provisional = provisional;
+                } else if let Err(reason) = move_funds(
+                    &mut self.available,
+                    &mut self.held,
+                    funds_change(DisputeStage::ChargedBack),
+                    config,
+                    &mut self.clamped_held,
+                ) {
+                    return Ok(Outcome::Rejected(reason));
                 }
                 self.locked = true;
+                *state = match dispute_action {
+                    DisputeAction::Chargeback { .. } => TransactionState::Chargebacked,
+                    _ => TransactionState::ArbitrationWon,
+                };
                 self.dispute_history.push(dispute_action);
-                *state = TransactionState::Chargebacked
+                self.open_disputes -= 1;
+                Outcome::Applied
             }
             (TransactionState::Accepted, DisputeAction::Chargeback { .. }) => {
                 // Cannot chargeback something that is not disputed.
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Rejected, DisputeAction::Chargeback { .. }) => {
                 // Can't change a rejected transaction
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Resolved, DisputeAction::Chargeback { .. }) => {
                 // It's already resolved, we can't chargeback it after that
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
             (TransactionState::Chargebacked, DisputeAction::Chargeback { .. }) => {
                 // NOOP
+                Outcome::Rejected(RejectionReason::InvalidState)
             }
-        }
 
-        Ok(())
+            (state @ TransactionState::Disputed, DisputeAction::Escalate { .. }) => {
+                // The funds stay held (or not) as they were for the dispute, it remains open until it's decided.
+                self.dispute_history.push(dispute_action);
+                *state = TransactionState::Arbitration;
+                Outcome::Applied
+            }
+            (_, DisputeAction::Escalate { .. }) => {
+                // Only an open dispute can be taken to arbitration.
+                Outcome::Rejected(RejectionReason::InvalidState)
+            }
+            (_, DisputeAction::ArbitrationWon { .. } | DisputeAction::ArbitrationLost { .. }) => {
+                // There's no arbitration to decide.
+                Outcome::Rejected(RejectionReason::InvalidState)
+            }
+            (TransactionState::Requested | TransactionState::Cancelled, _) => {
+                // Only a confirmed withdrawal request took funds out of the account.
+                Outcome::Rejected(RejectionReason::InvalidState)
+            }
+            (
+                TransactionState::Arbitration
+                | TransactionState::ArbitrationWon
+                | TransactionState::ArbitrationLost,
+                DisputeAction::Dispute { .. }
+                | DisputeAction::Resolve { .. }
+                | DisputeAction::Chargeback { .. },
+            ) => {
+                // Once in arbitration, only its decision can settle the transaction.
+                Outcome::Rejected(RejectionReason::InvalidState)
+            }
+        };
+
+        Ok(outcome)
     }
 
-    fn withdrawal_amount_allowed(&self, withdrawal_amount: Decimal) -> bool {
-        self.available >= withdrawal_amount
+    fn apply_withdrawal_action(&mut self, action: &WithdrawalAction<C, T>) -> Outcome {
+        if self.closed {
+            return Outcome::Rejected(RejectionReason::AccountClosed);
+        }
+        if self.locked {
+            return Outcome::Rejected(RejectionReason::AccountLocked);
+        }
+        let Some(request) = self
+            .transaction_history
+            .get_mut(action.get_referenced_transaction_id())
+        else {
+            return Outcome::Rejected(RejectionReason::UnknownTransaction);
+        };
+        if request.state != TransactionState::Requested {
+            return Outcome::Rejected(RejectionReason::InvalidState);
+        }
+        let amount = *request.transaction.get_amount();
+        let available = match action {
+            WithdrawalAction::WithdrawalConfirm { .. } => Some(self.available),
+            WithdrawalAction::WithdrawalCancel { .. } => self.available.checked_add(amount),
+        };
+        let (available, held) = match checked_funds(available, self.held.checked_sub(amount)) {
+            Ok(funds) => funds,
+            Err(reason) => return Outcome::Rejected(reason),
+        };
+        self.available = available;
+        self.held = held;
+        request.state = match action {
+            WithdrawalAction::WithdrawalConfirm { .. } => TransactionState::Accepted,
+            WithdrawalAction::WithdrawalCancel { .. } => TransactionState::Cancelled,
+        };
+        Outcome::Applied
     }
 
-    pub fn id(&self) -> u16 {
-        self.id
+    fn apply_adjustment(
+        &mut self,
+        adjustment: &Adjustment<C, T>,
+        config: &EngineConfig,
+    ) -> Outcome {
+        if self.closed {
+            return Outcome::Rejected(RejectionReason::AccountClosed);
+        }
+        match *adjustment {
+            // Debts are mostly left behind by chargebacks, which lock the account, so those can be written off.
+            Adjustment::WriteOff { .. } => self.write_off(),
+            _ if self.locked => Outcome::Rejected(RejectionReason::AccountLocked),
+            Adjustment::Amend {
+                referenced_transaction_id,
+                amount,
+                ..
+            } => self.amend(referenced_transaction_id, amount, config),
+        }
     }
 
-    pub fn available(&self) -> Decimal {
-        self.available
+    fn write_off(&mut self) -> Outcome {
+        if !self.in_deficit() {
+            return Outcome::Rejected(RejectionReason::NoOutstandingDebt);
+        }
+        self.available = Amount::ZERO;
+        Outcome::Applied
     }
 
-    pub fn held(&self) -> Decimal {
-        self.held
+    fn amend(&mut self, transaction_id: T, amount: Amount, config: &EngineConfig) -> Outcome {
+        let max_withdrawal = self.jurisdiction(config).and_then(|r| r.max_withdrawal);
+        let Some(record) = self.transaction_history.get(&transaction_id) else {
+            return Outcome::Rejected(RejectionReason::UnknownTransaction);
+        };
+        if record.state != TransactionState::Accepted {
+            // Disputed or settled transactions keep the amount their dispute was about.
+            return Outcome::Rejected(RejectionReason::InvalidState);
+        }
+        let previous = *record.transaction.get_amount();
+        let is_deposit = matches!(record.transaction, Transaction::Deposit { .. });
+        if !is_deposit && max_withdrawal.is_some_and(|max| amount > max) {
+            return Outcome::Rejected(RejectionReason::WithdrawalLimitExceeded);
+        }
+        // A smaller deposit or a larger withdrawal takes funds from the account, as a withdrawal of the difference.
+        let (taken, given) = if is_deposit {
+            (previous.checked_sub(amount), amount.checked_sub(previous))
+        } else {
+            (amount.checked_sub(previous), previous.checked_sub(amount))
+        };
+        let (Some(taken), Some(given)) = (taken, given) else {
+            return Outcome::Rejected(RejectionReason::BalanceOverflow);
+        };
+        if taken > Amount::ZERO && !self.withdrawal_amount_allowed(taken) {
+            return Outcome::Rejected(RejectionReason::InsufficientFunds);
+        }
+        let available = match checked_funds(self.available.checked_add(given), Some(self.held)) {
+            Ok((available, _)) => available,
+            Err(reason) => return Outcome::Rejected(reason),
+        };
+
+        self.available = available;
+        let record = self
+            .transaction_history
+            .get_mut(&transaction_id)
+            .expect("The transaction was just looked up.");
+        record.original_amount.get_or_insert(previous);
+        *record.transaction.amount_mut() = amount;
+        Outcome::Applied
     }
 
-    pub fn total(&self) -> Decimal {
-        self.available + self.held
+    fn record_transaction(&mut self, transaction: Transaction<C, T>, accepted: bool) {
+        self.transaction_count += 1;
+        self.transaction_history.insert(
+            *transaction.get_transaction_id(),
+            TransactionHistoryRecord::new(transaction, accepted, self.transaction_count),
+        );
     }
 
-    pub fn locked(&self) -> bool {
-        self.locked
+    /// Drops settled transactions (rejected, resolved or charged back) from the history, together with their dispute actions.
+    /// Transactions that can still be disputed or are under dispute are always kept, regardless of `retention`.
+    /// Returns the number of transactions that were dropped.
+    pub fn prune_history(&mut self, retention: Retention) -> usize {
+        self.prune(retention, &EngineConfig::default().disputes)
+            .len()
     }
-}
 
-pub struct PaymentEngine {
-    state: HashMap<u16, ClientAccount>,
-}
+    /// An estimate of the memory held by this account, including its transaction and dispute history.
+    pub fn approx_memory_bytes(&self) -> usize {
+        size_of::<Self>()
+            + hash_map_bytes(&self.transaction_history)
+            + self.dispute_history.capacity() * size_of::<DisputeAction<C, T>>()
+    }
 
-impl Default for PaymentEngine {
-    fn default() -> Self {
-        Self {
-            state: HashMap::new(),
+    /// Like [`ClientAccount::prune_history`], returning the dropped transactions. Resolved transactions that can be
+    /// disputed again under `disputes` are kept.
+    fn prune(&mut self, retention: Retention, disputes: &config::DisputePolicy) -> Vec<T> {
+        let mut settled: Vec<(u64, T)> = self
+            .transaction_history
+            .iter()
+            .filter(|(_, r)| {
+                matches!(
+                    r.state,
+                    TransactionState::Rejected
+                        | TransactionState::Cancelled
+                        | TransactionState::Resolved
+                        | TransactionState::Chargebacked
+                        | TransactionState::ArbitrationWon
+                        | TransactionState::ArbitrationLost
+                ) && !r.redisputable(disputes)
+            })
+            .map(|(id, r)| (r.sequence, *id))
+            .collect();
+        // Most recent first.
+        settled.sort_unstable_by(|a, b| b.cmp(a));
+
+        let to_drop: Vec<T> = match retention {
+            Retention::KeepLast(n) => settled.iter().skip(n).map(|(_, id)| *id).collect(),
+            Retention::OlderThan(age) => settled
+                .iter()
+                .filter(|(sequence, _)| self.transaction_count - sequence >= age)
+                .map(|(_, id)| *id)
+                .collect(),
+        };
+
+        for id in &to_drop {
+            self.transaction_history.remove(id);
         }
+        let history = &self.transaction_history;
+        self.dispute_history
+            .retain(|d| history.contains_key(d.get_referenced_transaction_id()));
+
+        to_drop
     }
-}
 
-impl PaymentEngine {
-    pub fn add_transaction(&mut self, transaction: Transaction) {
-        let client = self
-            .state
-            .entry(*transaction.get_client_id())
-            .or_insert_with(|| ClientAccount::new(*transaction.get_client_id()));
-        // SAFETY:
-        // `add_transaction` only returns an Err if we give it a transaction that does not belong to the client,
-        // while we just ensured that we got the correct client.
-        client
-            .add_transaction(transaction)
-            .expect("Retrieved the correct client.");
+    /// The withdrawal requests that are neither confirmed nor cancelled.
+    fn requested_transaction_ids(&self) -> impl Iterator<Item = T> + '_ {
+        self.transaction_history
+            .iter()
+            .filter(|(_, r)| r.state == TransactionState::Requested)
+            .map(|(id, _)| *id)
     }
 
-    pub fn add_dispute_action(&mut self, dispute_action: DisputeAction) {
-        let client = self
-            .state
-            .entry(*dispute_action.get_client_id())
-            .or_insert_with(|| ClientAccount::new(*dispute_action.get_client_id()));
-        // SAFETY:
-        // `add_dispute_action` only returns an Err if we give it an action that does not belong to the client,
-        // while we just ensured that we got the correct client.
-        client
-            .add_dispute_action(dispute_action)
-            .expect("Retrieved the correct client.");
+    /// The transactions that are disputed or in arbitration.
+    fn disputed_transaction_ids(&self) -> impl Iterator<Item = T> + '_ {
+        self.transaction_history
+            .iter()
+            .filter(|(_, r)| {
+                matches!(
+                    r.state,
+                    TransactionState::Disputed | TransactionState::Arbitration
+                )
+            })
+            .map(|(id, _)| *id)
     }
 
-    pub fn get_all_client_states(&self) -> impl Iterator<Item = &ClientAccount> {
-        self.state.values()
+    fn open_dispute_count(&self) -> u64 {
+        self.open_disputes as u64
     }
 
-    pub fn get_client_state(&self, client_id: u16) -> Option<&ClientAccount> {
-        self.state.get(&client_id)
+    /// The rules for the country of the client, see [`config::EngineConfig::jurisdictions`].
+    fn jurisdiction<'c>(&self, config: &'c EngineConfig) -> Option<&'c config::JurisdictionRules> {
+        let country = self.metadata.as_ref()?.country.as_deref()?;
+        config.jurisdiction(country)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rust_decimal_macros::dec;
+    fn withdrawal_amount_allowed(&self, withdrawal_amount: Amount) -> bool {
+        self.available
+            .checked_add(self.credit_limit)
+            .is_some_and(|spendable| spendable >= withdrawal_amount)
+    }
 
-    use super::*;
+    pub fn id(&self) -> C {
+        self.id
+    }
 
-    #[test]
-    fn no_transactions_no_problem() {
-        let payment_engine = PaymentEngine::default();
-        assert_eq!(payment_engine.get_all_client_states().count(), 0);
+    pub fn available(&self) -> Amount {
+        self.available
     }
 
-    #[test]
-    fn simple_deposit() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
-        payment_engine.add_transaction(Transaction::Deposit {
-            client,
-            transaction_id: 1,
-            amount,
-        });
+    pub fn held(&self) -> Amount {
+        self.held
+    }
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
-        assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available,
-            amount
-        );
+    /// The part of the available funds that is a provisional credit for a disputed withdrawal,
+    /// which is taken back if the dispute ends in a chargeback.
+    pub fn provisional(&self) -> Amount {
+        self.provisional
     }
 
-    #[test]
-    fn withdrawal_with_no_funds_available() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
+    pub fn total(&self) -> Amount {
+        self.available
+            .checked_add(self.held)
+            .expect("The total is checked to fit whenever the funds change.")
+    }
+
+    /// The transactions in the history of the account, in the order they were added. Pruned transactions
+    /// aren't included.
+    pub fn history(&self) -> Vec<HistoryEntry<'_, C, T>> {
+        let mut history: Vec<_> = self
+            .transaction_history
+            .values()
+            .map(|record| HistoryEntry {
+                transaction: &record.transaction,
+                state: record.state,
+                sequence: record.sequence,
+                dispute_rounds: record.dispute_rounds,
+            })
+            .collect();
+        history.sort_by_key(|entry| entry.sequence);
+        history
+    }
+
+    /// The amount `transaction_id` had before it was amended, `None` if it wasn't amended or isn't in the history.
+    pub fn original_amount(&self, transaction_id: T) -> Option<Amount> {
+        self.transaction_history
+            .get(&transaction_id)
+            .and_then(|r| r.original_amount)
+    }
+
+    /// See [`PaymentEngine::close_account`].
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn credit_limit(&self) -> Amount {
+        self.credit_limit
+    }
+
+    /// Lets withdrawals take the available funds down to `-credit_limit`, a limit of zero allows no overdraft.
+    pub fn set_credit_limit(&mut self, credit_limit: Amount) {
+        self.credit_limit = credit_limit;
+    }
+
+    /// How far the available funds have gone below zero, e.g. because a deposit was charged back after it was withdrawn.
+    pub fn debt(&self) -> Amount {
+        (-self.available).max(Amount::ZERO)
+    }
+
+    pub fn in_deficit(&self) -> bool {
+        self.available.is_negative()
+    }
+
+    /// Brings the account back to the state of a new one, keeping the memory allocated for its history. The id and
+    /// metadata of the client stay, as does the [`Self::last_activity`], which is up to the engine.
+    pub fn reset(&mut self) {
+        self.transaction_history.clear();
+        self.dispute_history.clear();
+        self.transaction_count = 0;
+        self.open_disputes = 0;
+        self.available = Amount::ZERO;
+        self.held = Amount::ZERO;
+        self.provisional = Amount::ZERO;
+        self.clamped_held = Amount::ZERO;
+        self.credit_limit = Amount::ZERO;
+        self.locked = false;
+        self.closed = false;
+        #[cfg(feature = "audit")]
+        {
+            self.audit_head = audit::GENESIS;
+        }
+    }
+
+    /// See [`PaymentEngine::set_client_metadata`].
+    pub fn metadata(&self) -> Option<&ClientMetadata> {
+        self.metadata.as_deref()
+    }
+
+    /// The engine sequence number at which the account was created, or a record was last applied to it.
+    pub fn last_activity(&self) -> u64 {
+        self.last_activity
+    }
+
+    /// The hash of every record the engine applied to the account, chained in order, see [`audit`].
+    #[cfg(feature = "audit")]
+    pub fn audit_head(&self) -> audit::AuditHash {
+        self.audit_head
+    }
+
+    #[cfg(feature = "audit")]
+    fn audit(&mut self, record: &impl audit::Audited) {
+        self.audit_head = audit::chain(&self.audit_head, record);
+    }
+
+    pub fn set_metadata(&mut self, metadata: Option<ClientMetadata>) {
+        self.metadata = metadata.map(Arc::new);
+    }
+
+    /// Posts a deposit against the debt of an account in deficit. Unlike a regular deposit it's also accepted when the
+    /// account is locked, since that's typically how the debt came to be. Any surplus over the debt becomes available.
+    pub fn add_recovery(&mut self, transaction_id: T, amount: Amount) -> Outcome {
+        if !self.in_deficit() {
+            return Outcome::Rejected(RejectionReason::NoOutstandingDebt);
+        }
+
+        let available = match checked_funds(self.available.checked_add(amount), Some(self.held)) {
+            Ok((available, _)) => available,
+            Err(reason) => return Outcome::Rejected(reason),
+        };
+        self.available = available;
+        self.record_transaction(
+            Transaction::Deposit {
+                client: self.id,
+                transaction_id,
+                amount,
+            },
+            true,
+        );
+        Outcome::Applied
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+// The bounds on the ids already require them to be deserializable.
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "")))]
+pub struct PaymentEngine<C: ClientId = u16, T: TransactionId = u32> {
+    /// Accounts are shared with snapshots and forks until they are modified, making those cheap to take.
+    state: HashMap<C, Arc<ClientAccount<C, T>>>,
+    stats: EngineStats,
+    indexes: Indexes<C, T>,
+    /// The number of records that have been added to the engine, used to tell how long ago something happened.
+    sequence: u64,
+    config: EngineConfig,
+    /// Events that haven't been taken by [`PaymentEngine::take_events`] yet.
+    events: EventQueue<C, T>,
+    /// Attached to the account of the client once it's created, see [`PaymentEngine::set_client_metadata`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    client_metadata: HashMap<C, Arc<ClientMetadata>>,
+    /// See [`PaymentEngine::add_standing_order`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    schedule: Schedule<C, T>,
+    /// See [`PaymentEngine::loss_ledger`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    loss_ledger: Vec<LossEntry<C, T>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    store: StoreHandle<C, T>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    screening: ScreeningHandle<C>,
+}
+
+/// A transaction in the history of an account, see [`ClientAccount::history`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryEntry<'a, C = u16, T = u32> {
+    pub transaction: &'a Transaction<C, T>,
+    pub state: TransactionState,
+    /// The position of the transaction within all transactions of the account.
+    pub sequence: u64,
+    /// How many times the transaction was disputed, more than once if it was re-disputed after being resolved.
+    pub dispute_rounds: u32,
+}
+
+impl<C, T> HistoryEntry<'_, C, T> {
+    /// How the transaction changed the total of the account when it was applied: positive for deposits, negative
+    /// for withdrawals and zero when it was rejected, cancelled or is still awaiting its settlement. Disputes of
+    /// the transaction aren't taken into account.
+    pub fn balance_change(&self) -> Amount {
+        match (self.transaction, self.state) {
+            (
+                _,
+                TransactionState::Rejected
+                | TransactionState::Cancelled
+                | TransactionState::Requested,
+            ) => Amount::ZERO,
+            (Transaction::Deposit { amount, .. }, _) => *amount,
+            (
+                Transaction::Withdrawal { amount, .. }
+                | Transaction::WithdrawalRequest { amount, .. },
+                _,
+            ) => -*amount,
+        }
+    }
+}
+
+/// A dispute that has neither been resolved nor charged back, see [`PaymentEngine::open_disputes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenDispute<C = u16, T = u32> {
+    pub client: C,
+    pub transaction_id: T,
+    pub amount: Amount,
+    /// How many records the engine has processed since the dispute was opened.
+    pub age: u64,
+}
+
+/// The account of a client in two engines, see [`PaymentEngine::compare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientComparison<'a, C: ClientId = u16, T: TransactionId = u32> {
+    pub client: C,
+    /// `None` when the engine has no account for the client.
+    pub this: Option<&'a ClientAccount<C, T>>,
+    pub other: Option<&'a ClientAccount<C, T>>,
+}
+
+/// Which accounts were dormant at the moment it was taken, see [`PaymentEngine::dormancy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dormancy {
+    /// The engine sequence number at that moment.
+    sequence: u64,
+    /// See [`EngineConfig::dormant_after`].
+    after: u64,
+}
+
+impl Dormancy {
+    pub fn is_dormant<C: ClientId, T: TransactionId>(&self, account: &ClientAccount<C, T>) -> bool {
+        self.sequence.saturating_sub(account.last_activity) >= self.after
+    }
+}
+
+/// A consistent, read-only view of all accounts at the moment it was taken, see [`PaymentEngine::snapshot`].
+#[derive(Debug, Clone)]
+pub struct EngineSnapshot<C: ClientId = u16, T: TransactionId = u32> {
+    state: HashMap<C, Arc<ClientAccount<C, T>>>,
+    stats: EngineStats,
+}
+
+impl<C: ClientId, T: TransactionId> EngineSnapshot<C, T> {
+    pub fn stats(&self) -> &EngineStats {
+        &self.stats
+    }
+
+    pub fn get_all_client_states(&self) -> impl Iterator<Item = &ClientAccount<C, T>> {
+        self.state.values().map(Arc::as_ref)
+    }
+
+    pub fn get_client_state(&self, client_id: C) -> Option<&ClientAccount<C, T>> {
+        self.state.get(&client_id).map(Arc::as_ref)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Default for PaymentEngine<C, T> {
+    fn default() -> Self {
+        Self {
+            state: HashMap::new(),
+            stats: EngineStats::default(),
+            indexes: Indexes::default(),
+            sequence: 0,
+            config: EngineConfig::default(),
+            events: EventQueue::default(),
+            client_metadata: HashMap::new(),
+            schedule: Schedule::default(),
+            loss_ledger: Vec::new(),
+            store: StoreHandle::default(),
+            screening: ScreeningHandle::default(),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> PaymentEngine<C, T> {
+    /// Configures a new engine, see [`PaymentEngineBuilder`].
+    pub fn builder() -> PaymentEngineBuilder<C, T> {
+        PaymentEngineBuilder::default()
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Notifies `observer` of every event from now on, like [`PaymentEngineBuilder::with_observer`],
+    /// e.g. for an engine that was restored from a checkpoint.
+    pub fn observe(&mut self, observer: impl EngineObserver<C, T> + 'static) {
+        self.events.observe(Arc::new(observer));
+    }
+
+    /// Screens every transaction from now on, like [`PaymentEngineBuilder::with_screening`],
+    /// e.g. for an engine that was restored from a checkpoint.
+    pub fn set_screening(&mut self, screening: impl Screening<C> + 'static) {
+        self.screening = ScreeningHandle(Some(Arc::new(screening)));
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction<C, T>) -> Outcome {
+        self.advance_sequence();
+        if self.disabled(transaction.record_type()) {
+            return Outcome::Rejected(RejectionReason::Disabled);
+        }
+        self.apply_transaction(transaction)
+    }
+
+    /// Whether records of `record_type` are ignored, which counts them if they are.
+    fn disabled(&mut self, record_type: WireRecordType) -> bool {
+        let disabled = self.config.disabled.contains(&record_type);
+        if disabled {
+            self.stats.disabled += 1;
+        }
+        disabled
+    }
+
+    /// Applies all legs of `composite` as a single record, or rejects it with the reason of the first leg that
+    /// would have been rejected, in which case none of them leave a trace.
+    ///
+    /// The legs are tried against copies of the accounts they touch first, so those are best kept to a few.
+    pub fn add_composite_transaction(&mut self, composite: CompositeTransaction<C, T>) -> Outcome {
+        self.advance_sequence();
+        // The legs only make sense together, so a single disabled leg disables all of them.
+        if composite
+            .legs
+            .iter()
+            .any(|leg| self.config.disabled.contains(&leg.record_type()))
+        {
+            self.stats.disabled += 1;
+            return Outcome::Rejected(RejectionReason::Disabled);
+        }
+        let outcome = self.try_composite_transaction(&composite);
+        self.stats.composites.count(outcome);
+        if outcome == Outcome::Applied {
+            for leg in composite.legs {
+                let outcome = self.apply_transaction(leg);
+                debug_assert_eq!(outcome, Outcome::Applied, "The leg was tried already.");
+            }
+        }
+        outcome
+    }
+
+    fn try_composite_transaction(&self, composite: &CompositeTransaction<C, T>) -> Outcome {
+        let mut trial: HashMap<C, ClientAccount<C, T>> = HashMap::new();
+        for leg in &composite.legs {
+            let client = *leg.get_client_id();
+            if self.screening.blocks(client) {
+                return Outcome::Rejected(RejectionReason::Screened);
+            }
+            if !self.state.contains_key(&client)
+                && !trial.contains_key(&client)
+                && !self.config.account_creation.creates_account(leg)
+            {
+                return Outcome::Rejected(RejectionReason::UnregisteredClient);
+            }
+            let account = trial
+                .entry(client)
+                .or_insert_with(|| match self.state.get(&client) {
+                    Some(account) => account.as_ref().clone(),
+                    None => new_account(client, &self.client_metadata, self.sequence),
+                });
+            let outcome = account
+                .apply_transaction(leg.clone(), &self.config)
+                .expect("Retrieved the correct client.");
+            if outcome != Outcome::Applied {
+                return outcome;
+            }
+        }
+        Outcome::Applied
+    }
+
+    fn apply_transaction(&mut self, transaction: Transaction<C, T>) -> Outcome {
+        let stats = &mut self.stats;
+        let client_id = *transaction.get_client_id();
+        let rejection = if self.screening.blocks(client_id) {
+            Some(RejectionReason::Screened)
+        } else if !self.state.contains_key(&client_id)
+            && !self.config.account_creation.creates_account(&transaction)
+        {
+            Some(RejectionReason::UnregisteredClient)
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            let outcome = Outcome::Rejected(reason);
+            match transaction {
+                Transaction::Deposit { .. } => stats.deposits.count(outcome),
+                Transaction::Withdrawal { .. } | Transaction::WithdrawalRequest { .. } => {
+                    stats.withdrawals.count(outcome)
+                }
+            }
+            return outcome;
+        }
+        let metadata = &self.client_metadata;
+        let client = Arc::make_mut(self.state.entry(client_id).or_insert_with(|| {
+            stats.clients += 1;
+            Arc::new(new_account(client_id, metadata, self.sequence))
+        }));
+        let before = AccountTotals::of(client);
+        let counts = match transaction {
+            Transaction::Deposit { .. } => &mut stats.deposits,
+            Transaction::Withdrawal { .. } | Transaction::WithdrawalRequest { .. } => {
+                &mut stats.withdrawals
+            }
+        };
+        // SAFETY:
+        // `add_transaction` only returns an Err if we give it a transaction that does not belong to the client,
+        // while we just ensured that we got the correct client.
+        let transaction_id = *transaction.get_transaction_id();
+        let requested = matches!(transaction, Transaction::WithdrawalRequest { .. });
+        #[cfg(feature = "audit")]
+        let audited = transaction.clone();
+        let outcome = client
+            .apply_transaction(transaction, &self.config)
+            .expect("Retrieved the correct client.");
+        if outcome == Outcome::Applied && requested {
+            self.indexes
+                .request_placed(client.id(), transaction_id, self.sequence);
+        }
+
+        counts.count(outcome);
+        if outcome == Outcome::Applied {
+            client.last_activity = self.sequence;
+            #[cfg(feature = "audit")]
+            client.audit(&audited);
+        }
+        let after = AccountTotals::of(client);
+        stats.account_changed(before, after);
+        let client_id = client.id();
+        self.indexes.account_changed(client_id, before, after);
+        self.save(client_id, &[transaction_id]);
+        outcome
+    }
+
+    /// Posts a recovery deposit against the debt of `client`, see [`ClientAccount::add_recovery`].
+    pub fn add_recovery(&mut self, client: C, transaction_id: T, amount: Amount) -> Outcome {
+        self.advance_sequence();
+        let Some(account) = self.state.get_mut(&client) else {
+            return Outcome::Rejected(RejectionReason::NoOutstandingDebt);
+        };
+        let account = Arc::make_mut(account);
+        let before = AccountTotals::of(account);
+        let outcome = account.add_recovery(transaction_id, amount);
+        if outcome == Outcome::Applied {
+            account.last_activity = self.sequence;
+            // A recovery is recorded as a deposit.
+            #[cfg(feature = "audit")]
+            account.audit(&Transaction::Deposit {
+                client,
+                transaction_id,
+                amount,
+            });
+        }
+
+        self.stats.deposits.count(outcome);
+        let after = AccountTotals::of(account);
+        self.stats.account_changed(before, after);
+        self.indexes.account_changed(client, before, after);
+        self.save(client, &[transaction_id]);
+        outcome
+    }
+
+    pub fn add_dispute_action(&mut self, dispute_action: DisputeAction<C, T>) -> Outcome {
+        self.advance_sequence();
+        if self.disabled(dispute_action.record_type()) {
+            return Outcome::Rejected(RejectionReason::Disabled);
+        }
+        self.apply_dispute_action(dispute_action)
+    }
+
+    /// Applies an administrative correction to the account of the client, which has to exist already.
+    pub fn add_adjustment(&mut self, adjustment: Adjustment<C, T>) -> Outcome {
+        self.advance_sequence();
+        if self.disabled(adjustment.record_type()) {
+            return Outcome::Rejected(RejectionReason::Disabled);
+        }
+        let client = *adjustment.get_client_id();
+        let Some(account) = self.state.get_mut(&client) else {
+            let outcome = Outcome::Rejected(RejectionReason::UnknownTransaction);
+            self.stats.adjustments.count(outcome);
+            return outcome;
+        };
+        let account = Arc::make_mut(account);
+        let before = AccountTotals::of(account);
+        let debt = account.debt();
+        let outcome = account.apply_adjustment(&adjustment, &self.config);
+        if outcome == Outcome::Applied {
+            account.last_activity = self.sequence;
+            #[cfg(feature = "audit")]
+            account.audit(&adjustment);
+            if let Adjustment::WriteOff { transaction_id, .. } = adjustment {
+                self.loss_ledger.push(LossEntry {
+                    client,
+                    transaction_id,
+                    amount: debt,
+                });
+                self.stats.total_written_off =
+                    self.stats.total_written_off.saturating_add(debt.into());
+            }
+        }
+
+        self.stats.adjustments.count(outcome);
+        let after = AccountTotals::of(account);
+        self.stats.account_changed(before, after);
+        self.indexes.account_changed(client, before, after);
+        self.save(client, &[*adjustment.get_transaction_id()]);
+        outcome
+    }
+
+    /// Opens or closes an account. An opening with an initial deposit has the outcome of that deposit, the account
+    /// stays open when it's rejected.
+    pub fn add_account_action(&mut self, action: AccountAction<C, T>) -> Outcome {
+        self.advance_sequence();
+        if self.disabled(action.record_type()) {
+            return Outcome::Rejected(RejectionReason::Disabled);
+        }
+        let client = *action.get_client_id();
+        match action {
+            AccountAction::Open {
+                transaction_id,
+                initial_deposit,
+                ..
+            } => {
+                let outcome = self.open_account(client);
+                self.stats.account_actions.count(outcome);
+                #[cfg(feature = "audit")]
+                if outcome == Outcome::Applied {
+                    if let Some(account) = self.state.get_mut(&client) {
+                        Arc::make_mut(account).audit(&AccountAction::Open {
+                            client,
+                            transaction_id,
+                            initial_deposit,
+                        });
+                    }
+                }
+                match initial_deposit {
+                    Some(amount) if outcome == Outcome::Applied => {
+                        self.apply_transaction(Transaction::Deposit {
+                            client,
+                            transaction_id,
+                            amount,
+                        })
+                    }
+                    _ => outcome,
+                }
+            }
+            AccountAction::Close { transaction_id, .. } => {
+                let outcome = self
+                    .close_account(client, None, transaction_id)
+                    .unwrap_or(Outcome::Rejected(RejectionReason::UnregisteredClient));
+                self.stats.account_actions.count(outcome);
+                outcome
+            }
+        }
+    }
+
+    /// Confirms or cancels a [`Transaction::WithdrawalRequest`] of the client.
+    pub fn add_withdrawal_action(&mut self, action: WithdrawalAction<C, T>) -> Outcome {
+        self.advance_sequence();
+        if self.disabled(action.record_type()) {
+            return Outcome::Rejected(RejectionReason::Disabled);
+        }
+        self.apply_withdrawal_action(action)
+    }
+
+    fn apply_withdrawal_action(&mut self, action: WithdrawalAction<C, T>) -> Outcome {
+        let client = *action.get_client_id();
+        let Some(account) = self.state.get_mut(&client) else {
+            self.stats
+                .settlements
+                .count(Outcome::Rejected(RejectionReason::UnknownTransaction));
+            return Outcome::Rejected(RejectionReason::UnknownTransaction);
+        };
+        let account = Arc::make_mut(account);
+        let before = AccountTotals::of(account);
+        let outcome = account.apply_withdrawal_action(&action);
+        let transaction_id = *action.get_referenced_transaction_id();
+        if outcome == Outcome::Applied {
+            account.last_activity = self.sequence;
+            #[cfg(feature = "audit")]
+            account.audit(&action);
+            self.indexes.request_settled(client, transaction_id);
+        }
+
+        self.stats.settlements.count(outcome);
+        let after = AccountTotals::of(account);
+        self.stats.account_changed(before, after);
+        self.indexes.account_changed(client, before, after);
+        self.save(client, &[transaction_id]);
+        outcome
+    }
+
+    /// Registers a standing order, its first run is `order.every` records from now.
+    /// Returns `None` for an order from an account to itself.
+    pub fn add_standing_order(&mut self, order: StandingOrder<C, T>) -> Option<StandingOrderId> {
+        (order.from != order.to).then(|| self.schedule.add(order, self.sequence))
+    }
+
+    /// Returns the order with the transaction ids it didn't use yet, or `None` if it already ended.
+    pub fn cancel_standing_order(&mut self, id: StandingOrderId) -> Option<StandingOrder<C, T>> {
+        self.schedule.cancel(id)
+    }
+
+    /// The standing orders that haven't ended yet, in the order they were registered.
+    pub fn standing_orders(&self) -> impl Iterator<Item = (StandingOrderId, &StandingOrder<C, T>)> {
+        self.schedule.orders()
+    }
+
+    /// Runs a standing order: like a [`CompositeTransaction`], the withdrawal and the deposit are tried against
+    /// copies of both accounts first, so a transfer never takes funds from one account without crediting them to the
+    /// other.
+    fn transfer(&mut self, transfer: Transfer<C, T>) -> Outcome {
+        let Transfer {
+            from,
+            to,
+            amount,
+            transaction_id,
+            ..
+        } = transfer;
+        let composite: CompositeTransaction<C, T> = [
+            Transaction::Withdrawal {
+                client: from,
+                transaction_id,
+                amount,
+            },
+            Transaction::Deposit {
+                client: to,
+                transaction_id,
+                amount,
+            },
+        ]
+        .into_iter()
+        .collect();
+        let outcome = self.try_composite_transaction(&composite);
+        if outcome == Outcome::Applied {
+            for leg in composite.legs {
+                let outcome = self.apply_transaction(leg);
+                debug_assert_eq!(outcome, Outcome::Applied, "The leg was tried already.");
+            }
+        }
+        outcome
+    }
+
+    /// Adds a transaction or dispute action, whichever `record` holds.
+    pub fn apply(&mut self, record: impl Into<Record<C, T>>) -> Outcome {
+        match record.into() {
+            Record::Transaction(transaction) => self.add_transaction(transaction),
+            Record::Dispute(dispute_action) => self.add_dispute_action(dispute_action),
+            Record::Withdrawal(action) => self.add_withdrawal_action(action),
+            Record::Adjustment(adjustment) => self.add_adjustment(adjustment),
+            Record::Account(action) => self.add_account_action(action),
+        }
+    }
+
+    fn advance_sequence(&mut self) {
+        self.sequence += 1;
+
+        while let Some(transfer) = self.schedule.take_due(self.sequence) {
+            let outcome = self.transfer(transfer);
+            if let Outcome::Rejected(reason) = outcome {
+                self.events.push(EngineEvent::StandingOrderFailed {
+                    order: transfer.order,
+                    from: transfer.from,
+                    to: transfer.to,
+                    transaction_id: transfer.transaction_id,
+                    reason,
+                });
+            }
+        }
+
+        if let Some(placed_at_or_before) = self
+            .config
+            .withdrawal_request_ttl
+            .and_then(|ttl| self.sequence.checked_sub(ttl))
+        {
+            while let Some((client, transaction_id)) = self
+                .indexes
+                .oldest_request_placed_at_or_before(placed_at_or_before)
+            {
+                let amount = *self.state[&client].transaction_history[&transaction_id]
+                    .transaction
+                    .get_amount();
+                let outcome = self.apply_withdrawal_action(WithdrawalAction::WithdrawalCancel {
+                    client,
+                    referenced_transaction_id: transaction_id,
+                });
+                if outcome == Outcome::Applied {
+                    self.events.push(EngineEvent::HoldExpired {
+                        client,
+                        transaction_id,
+                        amount,
+                    });
+                } else {
+                    // e.g. the account got locked in the meantime, leave the request for manual handling.
+                    self.indexes.stop_expiry(client, transaction_id);
+                }
+            }
+        }
+
+        if let Some(limit) = self.config.disputes.auto_resolve_after {
+            let Some(opened_at_or_before) = self.sequence.checked_sub(limit) else {
+                return;
+            };
+            while let Some((client, transaction_id)) = self
+                .indexes
+                .oldest_dispute_opened_at_or_before(opened_at_or_before)
+            {
+                let amount = *self.state[&client].transaction_history[&transaction_id]
+                    .transaction
+                    .get_amount();
+                let outcome = self.apply_dispute_action(DisputeAction::Resolve {
+                    client,
+                    referenced_transaction_id: transaction_id,
+                });
+                if outcome == Outcome::Applied {
+                    self.events.push(EngineEvent::AutoResolved {
+                        client,
+                        transaction_id,
+                        amount,
+                    });
+                } else {
+                    // e.g. the account got locked in the meantime, leave the dispute for manual handling.
+                    self.indexes.stop_aging(client, transaction_id);
+                }
+            }
+        }
+    }
+
+    fn apply_dispute_action(&mut self, dispute_action: DisputeAction<C, T>) -> Outcome {
+        if let DisputeAction::Chargeback {
+            client,
+            referenced_transaction_id,
+        } = dispute_action
+        {
+            let undisputed = self
+                .state
+                .get(&client)
+                .and_then(|account| account.transaction_history.get(&referenced_transaction_id))
+                .filter(|record| record.state == TransactionState::Accepted)
+                .map(|record| *record.transaction.get_amount());
+            if let (true, Some(amount)) = (self.config.disputes.direct_chargeback, undisputed) {
+                let dispute = DisputeAction::Dispute {
+                    client,
+                    referenced_transaction_id,
+                };
+                // Both steps are tried on a copy first, so a rejected chargeback doesn't leave the dispute open. The
+                // chargeback is rejected for whatever the dispute would have been rejected for.
+                let mut trial = ClientAccount::clone(&self.state[&client]);
+                let mut outcome = trial
+                    .apply_dispute_action(dispute.clone(), &self.config)
+                    .expect("Retrieved the correct client.");
+                if outcome == Outcome::Applied {
+                    outcome = trial
+                        .apply_dispute_action(dispute_action.clone(), &self.config)
+                        .expect("Retrieved the correct client.");
+                }
+                if outcome != Outcome::Applied {
+                    self.stats.chargebacks.count(outcome);
+                    return outcome;
+                }
+                // The implicit dispute isn't a record of its own, only the chargeback is counted.
+                let disputes = self.stats.disputes;
+                let outcome = self.apply_dispute_action(dispute);
+                self.stats.disputes = disputes;
+                debug_assert_eq!(outcome, Outcome::Applied, "The dispute was tried already.");
+                self.events.push(EngineEvent::ImplicitDispute {
+                    client,
+                    transaction_id: referenced_transaction_id,
+                    amount,
+                });
+            }
+        }
+
+        if let Some(owner) = self.cross_client_owner(&dispute_action) {
+            let outcome = Outcome::Rejected(RejectionReason::UnknownTransaction);
+            dispute_counts(&mut self.stats, &dispute_action).count(outcome);
+            if self.config.disputes.cross_client == CrossClientDisputes::Report {
+                self.events.push(EngineEvent::CrossClientDispute {
+                    client: *dispute_action.get_client_id(),
+                    transaction_id: *dispute_action.get_referenced_transaction_id(),
+                    owner,
+                });
+            }
+            return outcome;
+        }
+        if self.config.account_creation != AccountCreation::AnyRecord
+            && !self.state.contains_key(dispute_action.get_client_id())
+        {
+            let outcome = Outcome::Rejected(RejectionReason::UnknownTransaction);
+            dispute_counts(&mut self.stats, &dispute_action).count(outcome);
+            return outcome;
+        }
+        self.settle_dispute_action(dispute_action, false)
+    }
+
+    /// Applies `dispute_action` to the account of its client and does the bookkeeping around it. A `forced` action
+    /// also applies when the account is locked, see [`ClientAccount::force_dispute_action`].
+    fn settle_dispute_action(
+        &mut self,
+        dispute_action: DisputeAction<C, T>,
+        forced: bool,
+    ) -> Outcome {
+        let stats = &mut self.stats;
+        let metadata = &self.client_metadata;
+        let client = Arc::make_mut(
+            self.state
+                .entry(*dispute_action.get_client_id())
+                .or_insert_with(|| {
+                    stats.clients += 1;
+                    Arc::new(new_account(
+                        *dispute_action.get_client_id(),
+                        metadata,
+                        self.sequence,
+                    ))
+                }),
+        );
+        let before = AccountTotals::of(client);
+        let clamped_before = client.clamped_held;
+        let referenced_transaction_id = *dispute_action.get_referenced_transaction_id();
+        let charges_back = matches!(
+            dispute_action,
+            DisputeAction::Chargeback { .. } | DisputeAction::ArbitrationWon { .. }
+        );
+        let open_disputes_change: i64 = match dispute_action {
+            DisputeAction::Dispute { .. } => 1,
+            DisputeAction::Escalate { .. } => 0,
+            DisputeAction::Resolve { .. }
+            | DisputeAction::Chargeback { .. }
+            | DisputeAction::ArbitrationWon { .. }
+            | DisputeAction::ArbitrationLost { .. } => -1,
+        };
+        let counts = dispute_counts(stats, &dispute_action);
+        // SAFETY:
+        // `add_dispute_action` only returns an Err if we give it an action that does not belong to the client,
+        // while we just ensured that we got the correct client.
+        #[cfg(feature = "audit")]
+        let audited = dispute_action.clone();
+        let outcome = if forced {
+            client.force_dispute_action(dispute_action, &self.config)
+        } else {
+            client.apply_dispute_action(dispute_action, &self.config)
+        }
+        .expect("Retrieved the correct client.");
+
+        counts.count(outcome);
+        if outcome == Outcome::Rejected(RejectionReason::NegativeHeld) {
+            stats.negative_held_prevented += 1;
+            self.events.push(EngineEvent::NegativeHeldRejected {
+                client: client.id(),
+                transaction_id: referenced_transaction_id,
+            });
+        } else if client.clamped_held != clamped_before {
+            stats.negative_held_prevented += 1;
+            self.events.push(EngineEvent::NegativeHeldClamped {
+                client: client.id(),
+                transaction_id: referenced_transaction_id,
+                shortfall: client
+                    .clamped_held
+                    .checked_sub(clamped_before)
+                    .expect("Clamping only ever adds to a non-negative amount."),
+            });
+        }
+        if outcome == Outcome::Applied {
+            client.last_activity = self.sequence;
+            #[cfg(feature = "audit")]
+            client.audit(&audited);
+            stats.open_disputes = stats
+                .open_disputes
+                .wrapping_add_signed(open_disputes_change);
+            if open_disputes_change > 0 {
+                self.indexes
+                    .dispute_opened(client.id(), referenced_transaction_id, self.sequence);
+            } else if open_disputes_change == 0 {
+                // Arbitration takes as long as it takes, it shouldn't be auto-resolved.
+                self.indexes
+                    .stop_aging(client.id(), referenced_transaction_id);
+            } else {
+                self.indexes
+                    .dispute_closed(client.id(), referenced_transaction_id);
+            }
+        }
+        if outcome == Outcome::Applied && charges_back {
+            self.events.push(EngineEvent::ChargedBack {
+                client: client.id(),
+                transaction_id: referenced_transaction_id,
+                amount: *client.transaction_history[&referenced_transaction_id]
+                    .transaction
+                    .get_amount(),
+                metadata: client.metadata.clone(),
+            });
+        }
+        let after = AccountTotals::of(client);
+        if after.locked && !before.locked {
+            self.events.push(EngineEvent::AccountLocked {
+                client: client.id(),
+                metadata: client.metadata.clone(),
+            });
+        }
+        stats.account_changed(before, after);
+        let client_id = client.id();
+        self.indexes.account_changed(client_id, before, after);
+        self.save(client_id, &[referenced_transaction_id]);
+        outcome
+    }
+
+    /// The client whose transaction `dispute_action` references, when that's another client than the one of the
+    /// action and [`config::DisputePolicy::cross_client`] doesn't ignore it.
+    fn cross_client_owner(&self, dispute_action: &DisputeAction<C, T>) -> Option<C> {
+        if self.config.disputes.cross_client == CrossClientDisputes::Ignore {
+            return None;
+        }
+        let client = *dispute_action.get_client_id();
+        let transaction_id = *dispute_action.get_referenced_transaction_id();
+        if self
+            .state
+            .get(&client)
+            .is_some_and(|account| account.transaction_history.contains_key(&transaction_id))
+        {
+            return None;
+        }
+        self.indexes.other_owner(transaction_id, client)
+    }
+
+    /// Writes the account of `client` through to the store, if there is one, and indexes who owns `transactions`,
+    /// the ones whose history changed, see [`store::AccountStore::save`].
+    fn save(&mut self, client: C, transactions: &[T]) {
+        let account = &self.state[&client];
+        for transaction_id in transactions {
+            let recorded = account.transaction_history.contains_key(transaction_id);
+            self.indexes
+                .history_changed(client, *transaction_id, recorded);
+        }
+        let Some(store) = &self.store.0 else {
+            return;
+        };
+        if let Err(error) = store.save(account, transactions) {
+            self.events.push(EngineEvent::StoreFailed {
+                client,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    fn remove_from_store(store: &StoreHandle<C, T>, events: &mut EventQueue<C, T>, client: C) {
+        let Some(store) = &store.0 else {
+            return;
+        };
+        if let Err(error) = store.remove(client) {
+            events.push(EngineEvent::StoreFailed {
+                client,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    /// Returns the events that happened since the last call.
+    pub fn take_events(&mut self) -> Vec<EngineEvent<C, T>> {
+        self.events.take()
+    }
+
+    /// The debts that were written off, in the order they were.
+    pub fn loss_ledger(&self) -> &[LossEntry<C, T>] {
+        &self.loss_ledger
+    }
+
+    /// Statistics over everything the engine has processed, this doesn't need to visit every account.
+    pub fn stats(&self) -> EngineStats {
+        self.stats.clone()
+    }
+
+    pub fn get_all_client_states(&self) -> impl Iterator<Item = &ClientAccount<C, T>> {
+        self.state.values().map(Arc::as_ref)
+    }
+
+    pub fn get_client_state(&self, client_id: C) -> Option<&ClientAccount<C, T>> {
+        self.state.get(&client_id).map(Arc::as_ref)
+    }
+
+    /// The root of a Merkle tree over every account, ordered by client, see [`audit::leaf_hash`].
+    #[cfg(feature = "audit")]
+    pub fn merkle_root(&self) -> audit::AuditHash {
+        audit::merkle_root(
+            self.merkle_leaves()
+                .into_iter()
+                .map(|(_, leaf)| leaf)
+                .collect(),
+        )
+    }
+
+    /// Proves the current state of the account of `client` against [`PaymentEngine::merkle_root`], `None` when it
+    /// has no account.
+    #[cfg(feature = "audit")]
+    pub fn inclusion_proof(&self, client: C) -> Option<audit::InclusionProof> {
+        let leaves = self.merkle_leaves();
+        let index = leaves.binary_search_by_key(&client, |(id, _)| *id).ok()?;
+        Some(audit::inclusion_proof(
+            leaves.into_iter().map(|(_, leaf)| leaf).collect(),
+            index,
+        ))
+    }
+
+    /// The leaf of every account, ordered by client.
+    #[cfg(feature = "audit")]
+    fn merkle_leaves(&self) -> Vec<(C, audit::AuditHash)> {
+        let mut accounts: Vec<_> = self.get_all_client_states().collect();
+        accounts.sort_unstable_by_key(|account| account.id());
+        accounts
+            .into_iter()
+            .map(|account| {
+                (
+                    account.id(),
+                    audit::leaf_hash(&pipeline::AccountRow::from(account)),
+                )
+            })
+            .collect()
+    }
+
+    /// Which accounts are dormant as of now, `None` unless [`EngineConfig::dormant_after`] is set.
+    pub fn dormancy(&self) -> Option<Dormancy> {
+        self.config.dormant_after.map(|after| Dormancy {
+            sequence: self.sequence,
+            after,
+        })
+    }
+
+    /// Accounts without any activity for [`EngineConfig::dormant_after`] records, in no particular order.
+    /// None are dormant when that isn't set.
+    pub fn dormant_accounts(&self) -> impl Iterator<Item = &ClientAccount<C, T>> {
+        let dormancy = self.dormancy();
+        self.get_all_client_states()
+            .filter(move |account| dormancy.is_some_and(|d| d.is_dormant(account)))
+    }
+
+    /// Queries served from indexes the engine maintains while processing, instead of scanning every account.
+    pub fn query(&self) -> Query<'_, C, T> {
+        Query {
+            state: &self.state,
+            indexes: &self.indexes,
+        }
+    }
+
+    /// All locked accounts, ordered by client id.
+    pub fn locked_accounts(&self) -> impl Iterator<Item = &ClientAccount<C, T>> {
+        self.query().locked_accounts()
+    }
+
+    /// Lists the accounts that are in a state that should be looked into. This visits every account.
+    pub fn invariant_report(&self) -> InvariantReport<C> {
+        let mut report = InvariantReport {
+            negative_held: vec![],
+            negative_total: vec![],
+            negative_held_prevented: self.stats.negative_held_prevented,
+        };
+        for account in self.state.values() {
+            if account.held().is_negative() {
+                report.negative_held.push(account.id());
+            }
+            if account.total().is_negative() {
+                report.negative_total.push(account.id());
+            }
+        }
+        report.negative_held.sort_unstable();
+        report.negative_total.sort_unstable();
+        report
+    }
+
+    /// Accounts whose available funds went below zero, ordered by client id.
+    pub fn accounts_in_deficit(&self) -> impl Iterator<Item = &ClientAccount<C, T>> {
+        self.query().accounts_in_deficit()
+    }
+
+    /// All disputes that still await a resolve or chargeback, ordered by client id.
+    pub fn open_disputes(&self) -> impl Iterator<Item = OpenDispute<C, T>> + '_ {
+        self.query().disputed_transactions_since().map(
+            move |((client, transaction_id), opened_at)| OpenDispute {
+                client,
+                transaction_id,
+                amount: *self.state[&client].transaction_history[&transaction_id]
+                    .transaction
+                    .get_amount(),
+                age: self.sequence - opened_at,
+            },
+        )
+    }
+
+    /// Takes a snapshot that can be read (e.g. exported on another thread) while the engine keeps processing.
+    /// This only copies a pointer per account, the accounts themselves are copied once they get modified.
+    pub fn snapshot(&self) -> EngineSnapshot<C, T> {
+        EngineSnapshot {
+            state: self.state.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// The number of client accounts.
+    pub fn len(&self) -> usize {
+        self.state.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+
+    pub fn contains_client(&self, client_id: C) -> bool {
+        self.state.contains_key(&client_id)
+    }
+
+    pub fn client_ids(&self) -> impl Iterator<Item = C> + '_ {
+        self.state.keys().copied()
+    }
+
+    /// Opens an empty account for `client`, which is the only way to get one with [`AccountCreation::Registered`].
+    pub fn open_account(&mut self, client: C) -> Outcome {
+        if self.screening.blocks(client) {
+            return Outcome::Rejected(RejectionReason::Screened);
+        }
+        if self.state.contains_key(&client) {
+            return Outcome::Rejected(RejectionReason::AccountExists);
+        }
+        self.stats.clients += 1;
+        self.indexes
+            .account_changed(client, AccountTotals::ZERO, AccountTotals::ZERO);
+        self.state.insert(
+            client,
+            Arc::new(new_account(client, &self.client_metadata, self.sequence)),
+        );
+        self.save(client, &[]);
+        Outcome::Applied
+    }
+
+    /// Adds an existing account, e.g. one that was loaded from a store, replacing the account of that client if any.
+    /// Open disputes and pending withdrawal requests of the account count as opened now, and the account counts as
+    /// active now.
+    /// Unlike records this isn't written through to the store.
+    pub fn insert_account(
+        &mut self,
+        mut account: ClientAccount<C, T>,
+    ) -> Option<ClientAccount<C, T>> {
+        account.last_activity = self.sequence;
+        let client_id = account.id();
+        let previous = self.state.remove(&client_id).map(|previous| {
+            let totals = AccountTotals::of(&previous);
+            self.stats
+                .account_removed(totals, previous.open_dispute_count());
+            self.indexes.account_removed(
+                client_id,
+                totals,
+                previous.disputed_transaction_ids(),
+                previous.requested_transaction_ids(),
+                previous.transaction_history.keys().copied(),
+            );
+            Arc::unwrap_or_clone(previous)
+        });
+
+        let totals = AccountTotals::of(&account);
+        self.stats.clients += 1;
+        self.stats.open_disputes += account.open_dispute_count();
+        self.stats.account_changed(AccountTotals::ZERO, totals);
+        self.indexes.account_changed(client_id, totals, totals);
+        for (transaction_id, record) in &account.transaction_history {
+            self.indexes
+                .history_changed(client_id, *transaction_id, true);
+            match record.state {
+                TransactionState::Disputed => {
+                    self.indexes
+                        .dispute_opened(client_id, *transaction_id, self.sequence)
+                }
+                TransactionState::Arbitration => {
+                    self.indexes
+                        .dispute_opened(client_id, *transaction_id, self.sequence);
+                    self.indexes.stop_aging(client_id, *transaction_id);
+                }
+                TransactionState::Requested => {
+                    self.indexes
+                        .request_placed(client_id, *transaction_id, self.sequence)
+                }
+                _ => {}
+            }
+        }
+        self.state.insert(client_id, Arc::new(account));
+        previous
+    }
+
+    /// Evicts a client account, e.g. one that is closed and has no balance left.
+    /// Records arriving for it afterwards will open a fresh account.
+    pub fn remove_client(&mut self, client_id: C) -> Option<ClientAccount<C, T>> {
+        let client = self.state.remove(&client_id)?;
+        let totals = AccountTotals::of(&client);
+        self.stats
+            .account_removed(totals, client.open_dispute_count());
+        self.indexes.account_removed(
+            client_id,
+            totals,
+            client.disputed_transaction_ids(),
+            client.requested_transaction_ids(),
+            client.transaction_history.keys().copied(),
+        );
+        Self::remove_from_store(&self.store, &mut self.events, client_id);
+        Some(Arc::unwrap_or_clone(client))
+    }
+
+    /// Lifts the lock a chargeback put on the account of `client`, e.g. once the case has been investigated.
+    /// Returns whether the account was locked, or `None` if there is no such client.
+    pub fn unlock(&mut self, client: C) -> Option<bool> {
+        self.set_locked(client, false)
+    }
+
+    fn set_locked(&mut self, client: C, locked: bool) -> Option<bool> {
+        let account = Arc::make_mut(self.state.get_mut(&client)?);
+        let before = AccountTotals::of(account);
+        let was_locked = core::mem::replace(&mut account.locked, locked);
+
+        let after = AccountTotals::of(account);
+        self.stats.account_changed(before, after);
+        self.indexes.account_changed(client, before, after);
+        self.save(client, &[]);
+        Some(was_locked)
+    }
+
+    /// Settles a dispute in favour of the merchant, as for a resolve, also when the account is locked or the dispute
+    /// has been taken to arbitration. The account stays locked if it was.
+    pub fn force_resolve(&mut self, client: C, transaction_id: T) -> Outcome {
+        self.advance_sequence();
+        let Some(state) = self
+            .state
+            .get(&client)
+            .and_then(|account| account.transaction_history.get(&transaction_id))
+            .map(|record| record.state)
+        else {
+            return Outcome::Rejected(RejectionReason::UnknownTransaction);
+        };
+        let referenced_transaction_id = transaction_id;
+        let dispute_action = match state {
+            TransactionState::Arbitration => DisputeAction::ArbitrationLost {
+                client,
+                referenced_transaction_id,
+            },
+            _ => DisputeAction::Resolve {
+                client,
+                referenced_transaction_id,
+            },
+        };
+        self.settle_dispute_action(dispute_action, true)
+    }
+
+    /// Closes the account of `client`, after which every record for it is rejected as
+    /// [`RejectionReason::AccountClosed`]. Accounts with open disputes can't be closed until those are settled.
+    ///
+    /// With `sweep_to`, any available funds are first moved to the account of that client, recorded as a deposit
+    /// there and a withdrawal here that both have `transaction_id`. The account isn't closed when that deposit is
+    /// rejected, e.g. because the receiving account is closed itself, or when either account already has a
+    /// transaction with that id, which the sweep would replace.
+    /// Returns `None` if there is no such client.
+    pub fn close_account(
+        &mut self,
+        client: C,
+        sweep_to: Option<C>,
+        transaction_id: T,
+    ) -> Option<Outcome> {
+        let account = self.state.get(&client)?;
+        if account.closed {
+            return Some(Outcome::Rejected(RejectionReason::AccountClosed));
+        }
+        if account.open_disputes > 0 {
+            return Some(Outcome::Rejected(RejectionReason::OpenDisputes));
+        }
+        if account.held > Amount::ZERO {
+            return Some(Outcome::Rejected(RejectionReason::FundsHeld));
+        }
+        let amount = account.available;
+        let sweep_to = sweep_to.filter(|_| amount > Amount::ZERO);
+        if let Some(sweep_to) = sweep_to {
+            if account.locked {
+                return Some(Outcome::Rejected(RejectionReason::AccountLocked));
+            }
+            if sweep_to == client {
+                return Some(Outcome::Rejected(RejectionReason::AccountClosed));
+            }
+            let known = |client: C| {
+                self.state.get(&client).is_some_and(|account| {
+                    account.transaction_history.contains_key(&transaction_id)
+                })
+            };
+            if known(client) || known(sweep_to) {
+                return Some(Outcome::Rejected(RejectionReason::DuplicateTransaction));
+            }
+            // Part of closing rather than a record of its own, so the sweep neither advances the sequence nor is
+            // subject to `EngineConfig::disabled`.
+            let outcome = self.apply_transaction(Transaction::Deposit {
+                client: sweep_to,
+                transaction_id,
+                amount,
+            });
+            if outcome != Outcome::Applied {
+                return Some(outcome);
+            }
+        }
+
+        let account = Arc::make_mut(self.state.get_mut(&client)?);
+        let before = AccountTotals::of(account);
+        if sweep_to.is_some() {
+            account.available = Amount::ZERO;
+            account.record_transaction(
+                Transaction::Withdrawal {
+                    client,
+                    transaction_id,
+                    amount,
+                },
+                true,
+            );
+            self.stats.withdrawals.count(Outcome::Applied);
+            #[cfg(feature = "audit")]
+            account.audit(&Transaction::Withdrawal {
+                client,
+                transaction_id,
+                amount,
+            });
+        }
+        account.closed = true;
+        #[cfg(feature = "audit")]
+        account.audit(&AccountAction::Close {
+            client,
+            transaction_id,
+        });
+        account.last_activity = self.sequence;
+
+        let after = AccountTotals::of(account);
+        self.stats.account_changed(before, after);
+        self.indexes.account_changed(client, before, after);
+        match sweep_to {
+            Some(_) => self.save(client, &[transaction_id]),
+            None => self.save(client, &[]),
+        }
+        Some(Outcome::Applied)
+    }
+
+    /// See [`ClientAccount::set_credit_limit`]. Returns `false` if there is no such client.
+    pub fn set_credit_limit(&mut self, client: C, credit_limit: Amount) -> bool {
+        let Some(account) = self.state.get_mut(&client) else {
+            return false;
+        };
+        Arc::make_mut(account).set_credit_limit(credit_limit);
+        self.save(client, &[]);
+        true
+    }
+
+    /// Brings the account of `client_id` back to the state of a new one, see [`ClientAccount::reset`].
+    /// Returns `false` if there is no such client.
+    pub fn reset_client(&mut self, client_id: C) -> bool {
+        let Some(account) = self.state.get_mut(&client_id) else {
+            return false;
+        };
+        let account = Arc::make_mut(account);
+        let before = AccountTotals::of(account);
+        self.stats.open_disputes -= account.open_dispute_count();
+        self.indexes.account_removed(
+            client_id,
+            before,
+            account.disputed_transaction_ids(),
+            account.requested_transaction_ids(),
+            account.transaction_history.keys().copied(),
+        );
+        let dropped: Vec<T> = account.transaction_history.keys().copied().collect();
+        account.reset();
+        account.last_activity = self.sequence;
+
+        let after = AccountTotals::of(account);
+        self.stats.account_changed(before, after);
+        self.indexes.account_changed(client_id, after, after);
+        self.save(client_id, &dropped);
+        true
+    }
+
+    /// Empties the engine, as if it was just built, e.g. between benchmark iterations. The accounts and the metadata
+    /// of clients are dropped, the maps holding them keep their capacity.
+    ///
+    /// The configuration, screening, observers and store are kept. Nothing is written to the store, so it still
+    /// holds the accounts as they were before.
+    pub fn reset(&mut self) {
+        self.state.clear();
+        self.client_metadata.clear();
+        self.stats = EngineStats::default();
+        self.indexes = Indexes::default();
+        self.schedule = Schedule::default();
+        self.loss_ledger.clear();
+        self.sequence = 0;
+        self.events.take();
+    }
+
+    /// An independent copy of the engine, e.g. to apply a hypothetical sequence of records to
+    /// and compare the outcome with the original through [`PaymentEngine::differing_clients`].
+    /// Like [`PaymentEngine::snapshot`], accounts are only copied once they're modified.
+    /// The fork has neither observers nor a store, so it doesn't affect anything outside of it.
+    pub fn fork(&self) -> Self {
+        let mut fork = self.clone();
+        fork.events.clear_observers();
+        fork.store = StoreHandle::default();
+        fork
+    }
+
+    /// The ids of the clients whose account differs between both engines, including clients only known to one of them.
+    /// Sorted ascending.
+    pub fn differing_clients(&self, other: &Self) -> Vec<C> {
+        let mut differing: Vec<C> = self
+            .state
+            .iter()
+            .filter(|(id, account)| match other.state.get(*id) {
+                // Accounts that are still shared between forks are equal without comparing them.
+                Some(other_account) => {
+                    !Arc::ptr_eq(account, other_account) && account != &other_account
+                }
+                None => true,
+            })
+            .map(|(id, _)| *id)
+            .chain(
+                other
+                    .state
+                    .keys()
+                    .filter(|id| !self.state.contains_key(*id))
+                    .copied(),
+            )
+            .collect();
+        differing.sort_unstable();
+        differing
+    }
+
+    /// The accounts of every client in [`PaymentEngine::differing_clients`], side by side, e.g. to see what a
+    /// different configuration would have done to the same records.
+    pub fn compare<'a>(
+        &'a self,
+        other: &'a Self,
+    ) -> impl Iterator<Item = ClientComparison<'a, C, T>> + 'a {
+        self.differing_clients(other)
+            .into_iter()
+            .map(move |client| ClientComparison {
+                client,
+                this: self.get_client_state(client),
+                other: other.get_client_state(client),
+            })
+    }
+
+    /// Consumes the engine, yielding every client account.
+    pub fn into_accounts(self) -> IntoAccounts<C, T> {
+        self.state.into_values().map(Arc::unwrap_or_clone)
+    }
+
+    /// An estimate of the memory held by the engine, for capacity planning. This visits every account.
+    /// Accounts shared with a snapshot or fork are counted in full, as are the accounts of each of those.
+    pub fn approx_memory_bytes(&self) -> usize {
+        size_of::<Self>()
+            + hash_map_bytes(&self.state)
+            + self
+                .state
+                .values()
+                // The reference counts of the `Arc`.
+                .map(|account| 2 * size_of::<usize>() + account.approx_memory_bytes())
+                .sum::<usize>()
+            + self.indexes.approx_memory_bytes()
+            + self.events.approx_memory_bytes()
+    }
+
+    /// Attaches `metadata` to the account of `client`, now or when it gets created.
+    /// The engine only carries it along, e.g. into events, it doesn't affect how records are applied.
+    pub fn set_client_metadata(&mut self, client: C, metadata: ClientMetadata) {
+        let metadata = Arc::new(metadata);
+        if let Some(account) = self.state.get_mut(&client) {
+            Arc::make_mut(account).metadata = Some(metadata.clone());
+        }
+        self.client_metadata.insert(client, metadata);
+    }
+
+    /// Prunes the history of every account, see [`ClientAccount::prune_history`].
+    pub fn prune_history(&mut self, retention: Retention) -> usize {
+        let disputes = &self.config.disputes;
+        let pruned: Vec<(C, Vec<T>)> = self
+            .state
+            .iter_mut()
+            .map(|(id, c)| (*id, Arc::make_mut(c).prune(retention, disputes)))
+            .filter(|(_, dropped)| !dropped.is_empty())
+            .collect();
+        for (client, dropped) in &pruned {
+            self.save(*client, dropped);
+        }
+        pruned.iter().map(|(_, dropped)| dropped.len()).sum()
+    }
+
+    /// Only keeps the client accounts for which `predicate` returns `true`.
+    pub fn retain(&mut self, mut predicate: impl FnMut(&ClientAccount<C, T>) -> bool) {
+        let stats = &mut self.stats;
+        let indexes = &mut self.indexes;
+        let store = &self.store;
+        let events = &mut self.events;
+        self.state.retain(|id, client| {
+            let keep = predicate(client);
+            if !keep {
+                let totals = AccountTotals::of(client);
+                stats.account_removed(totals, client.open_dispute_count());
+                indexes.account_removed(
+                    *id,
+                    totals,
+                    client.disputed_transaction_ids(),
+                    client.requested_transaction_ids(),
+                    client.transaction_history.keys().copied(),
+                );
+                Self::remove_from_store(store, events, *id);
+            }
+            keep
+        });
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Extend<Transaction<C, T>> for PaymentEngine<C, T> {
+    fn extend<I: IntoIterator<Item = Transaction<C, T>>>(&mut self, iter: I) {
+        for transaction in iter {
+            self.add_transaction(transaction);
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Extend<DisputeAction<C, T>> for PaymentEngine<C, T> {
+    fn extend<I: IntoIterator<Item = DisputeAction<C, T>>>(&mut self, iter: I) {
+        for dispute_action in iter {
+            self.add_dispute_action(dispute_action);
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Extend<Record<C, T>> for PaymentEngine<C, T> {
+    fn extend<I: IntoIterator<Item = Record<C, T>>>(&mut self, iter: I) {
+        for record in iter {
+            self.apply(record);
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> FromIterator<Transaction<C, T>> for PaymentEngine<C, T> {
+    fn from_iter<I: IntoIterator<Item = Transaction<C, T>>>(iter: I) -> Self {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.extend(iter);
+        payment_engine
+    }
+}
+
+pub type IntoAccounts<C = u16, T = u32> = core::iter::Map<
+    hash_map::IntoValues<C, Arc<ClientAccount<C, T>>>,
+    fn(Arc<ClientAccount<C, T>>) -> ClientAccount<C, T>,
+>;
+
+impl<C: ClientId, T: TransactionId> IntoIterator for PaymentEngine<C, T> {
+    type Item = ClientAccount<C, T>;
+    type IntoIter = IntoAccounts<C, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_accounts()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::config::{AccountCreation, CrossClientDisputes, DisputePolicy, JurisdictionRules};
+    use crate::policy::FundsMovement;
+    #[cfg(feature = "std")]
+    use crate::store::MemoryStore;
+
+    fn amount(value: Decimal) -> Amount {
+        Amount::non_negative(value).unwrap()
+    }
+
+    fn amount_of_one() -> Amount {
+        amount(Decimal::ONE)
+    }
+
+    #[test]
+    fn no_transactions_no_problem() {
+        let payment_engine: PaymentEngine = PaymentEngine::default();
+        assert_eq!(payment_engine.get_all_client_states().count(), 0);
+        assert!(payment_engine.is_empty());
+    }
+
+    #[test]
+    fn client_queries() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 3,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 2,
+            amount: amount(dec!(2.0)),
+        });
+
+        assert_eq!(payment_engine.len(), 2);
+        assert!(!payment_engine.is_empty());
+        assert!(payment_engine.contains_client(3));
+        assert!(!payment_engine.contains_client(2));
+        let mut client_ids: Vec<_> = payment_engine.client_ids().collect();
+        client_ids.sort_unstable();
+        assert_eq!(client_ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn simple_deposit() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available,
+            amount
+        );
+    }
+
+    #[test]
+    fn withdrawal_with_no_funds_available() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available,
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn rejections_carry_a_reason() {
+        let mut payment_engine = PaymentEngine::default();
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            }),
+            Outcome::Rejected(RejectionReason::InsufficientFunds)
+        );
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 2,
+            }),
+            Outcome::Rejected(RejectionReason::UnknownTransaction)
+        );
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 2,
+                amount: amount(dec!(2.0)),
+            }),
+            Outcome::Applied
+        );
+    }
+
+    #[test]
+    fn stats_are_maintained() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 2,
+            transaction_id: 2,
+            amount: amount(dec!(3.0)),
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 2,
+            transaction_id: 3,
+            amount: amount(dec!(5.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+
+        let stats = payment_engine.stats();
+        assert_eq!(stats.clients, 2);
+        assert_eq!(stats.deposits.accepted, 2);
+        assert_eq!(stats.withdrawals.rejected, 1);
+        assert_eq!(stats.open_disputes, 1);
+        assert_eq!(stats.total_available, dec!(3.0));
+        assert_eq!(stats.total_held, dec!(2.0));
+
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+
+        let stats = payment_engine.stats();
+        assert_eq!(stats.open_disputes, 0);
+        assert_eq!(stats.chargebacks.accepted, 1);
+        assert_eq!(stats.locked_clients, 1);
+        assert_eq!(stats.total_held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn removing_clients_updates_stats() {
+        let mut payment_engine = PaymentEngine::default();
+        for client in 1..=3 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client,
+                transaction_id: client as u32,
+                amount: amount(dec!(2.0)),
+            });
+        }
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 2,
+            referenced_transaction_id: 2,
+        });
+
+        let removed = payment_engine.remove_client(2).unwrap();
+        assert_eq!(removed.held(), dec!(2.0));
+        assert!(payment_engine.remove_client(2).is_none());
+
+        payment_engine.retain(|c| c.id() != 3);
+
+        assert_eq!(payment_engine.len(), 1);
+        let stats = payment_engine.stats();
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.open_disputes, 0);
+        assert_eq!(stats.total_available, dec!(2.0));
+        assert_eq!(stats.total_held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn pruning_keeps_disputable_transactions() {
+        let mut client = ClientAccount::new(1);
+        for transaction_id in 1..=4 {
+            client
+                .add_transaction(Transaction::Deposit {
+                    client: 1,
+                    transaction_id,
+                    amount: amount(dec!(1.0)),
+                })
+                .unwrap();
+        }
+        for action in [
+            DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 1,
+            },
+            DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 1,
+            },
+            DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 2,
+            },
+            DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 3,
+            },
+            DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 3,
+            },
+        ] {
+            client.add_dispute_action(action).unwrap();
+        }
+
+        assert_eq!(client.prune_history(Retention::KeepLast(1)), 1);
+        assert!(!client.transaction_history.contains_key(&1));
+        assert!(client.transaction_history.contains_key(&3));
+        assert_eq!(client.dispute_history.len(), 3);
+
+        // Transaction 2 is still disputed and 4 can still be disputed.
+        assert_eq!(client.prune_history(Retention::OlderThan(0)), 1);
+        assert_eq!(client.transaction_history.len(), 2);
+        assert_eq!(client.dispute_history.len(), 1);
+        // Pruning never changes the balances.
+        assert_eq!(client.available(), dec!(3.0));
+        assert_eq!(client.held(), dec!(1.0));
+    }
+
+    #[test]
+    fn engine_composes_with_iterators() {
+        let mut payment_engine: PaymentEngine = (1..=3)
+            .map(|transaction_id| Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(1.0)),
+            })
+            .collect();
+        payment_engine.extend([DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 2,
+        }]);
+
+        let accounts: Vec<ClientAccount> = payment_engine.into_iter().collect();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available(), dec!(2.0));
+        assert_eq!(accounts[0].held(), dec!(1.0));
+    }
+
+    #[test]
+    fn cloned_engine_diverges_independently() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+
+        let mut branch = payment_engine.clone();
+        assert_eq!(branch, payment_engine);
+
+        branch.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        assert_ne!(branch, payment_engine);
+        assert_ne!(
+            branch.get_client_state(1),
+            payment_engine.get_client_state(1)
+        );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().held(),
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn forked_scenario_can_be_compared() {
+        let mut mainline = PaymentEngine::default();
+        for client in 1..=3 {
+            mainline.add_transaction(Transaction::Deposit {
+                client,
+                transaction_id: client as u32,
+                amount: amount(dec!(2.0)),
+            });
+        }
+
+        let mut scenario = mainline.fork();
+        assert!(scenario.differing_clients(&mainline).is_empty());
+
+        scenario.add_dispute_action(DisputeAction::Dispute {
+            client: 2,
+            referenced_transaction_id: 2,
+        });
+        scenario.add_transaction(Transaction::Deposit {
+            client: 4,
+            transaction_id: 4,
+            amount: amount(dec!(2.0)),
+        });
+
+        assert_eq!(scenario.differing_clients(&mainline), vec![2, 4]);
+        assert_eq!(mainline.differing_clients(&scenario), vec![2, 4]);
+        let comparison: Vec<_> = mainline.compare(&scenario).collect();
+        assert_eq!(comparison[0].this.unwrap().held(), dec!(0.0));
+        assert_eq!(comparison[0].other.unwrap().held(), dec!(2.0));
+        assert!(comparison[1].this.is_none());
+    }
+
+    #[test]
+    fn snapshot_is_unaffected_by_further_processing() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 2,
+            transaction_id: 2,
+            amount: amount(dec!(2.0)),
+        });
+
+        let snapshot = payment_engine.snapshot();
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 3,
+            amount: amount(dec!(1.0)),
+        });
+
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get_client_state(1).unwrap().available(), dec!(2.0));
+        assert_eq!(snapshot.stats().total_available, dec!(4.0));
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().available(),
+            dec!(1.0)
+        );
+        // The untouched account is still shared.
+        assert!(Arc::ptr_eq(&snapshot.state[&2], &payment_engine.state[&2]));
+    }
+
+    #[test]
+    fn indexed_queries() {
+        let mut payment_engine = PaymentEngine::default();
+        for client in 1..=4 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client,
+                transaction_id: client as u32,
+                amount: amount(Decimal::from(client)),
+            });
+        }
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 2,
+            referenced_transaction_id: 2,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 3,
+            referenced_transaction_id: 3,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 3,
+            referenced_transaction_id: 3,
+        });
+
+        let query = payment_engine.query();
+        assert_eq!(
+            query.disputed_transactions().collect::<Vec<_>>(),
+            vec![(2, 2)]
+        );
+        assert_eq!(
+            query.locked_accounts().map(|c| c.id()).collect::<Vec<_>>(),
+            vec![3]
+        );
+        assert_eq!(
+            query
+                .accounts_with_total(dec!(1.0)..dec!(4.0))
+                .map(|c| c.id())
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            query
+                .accounts_by_descending_total()
+                .map(|c| c.id())
+                .collect::<Vec<_>>(),
+            vec![4, 2, 1, 3]
+        );
+
+        payment_engine.remove_client(2);
+        let query = payment_engine.query();
+        assert_eq!(query.disputed_transactions().count(), 0);
+        assert_eq!(query.accounts_with_total(..).count(), 3);
+    }
+
+    #[test]
+    fn open_disputes_work_queue() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 2,
+            transaction_id: 2,
+            amount: amount(dec!(3.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 2,
+            referenced_transaction_id: 2,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(
+            payment_engine.open_disputes().collect::<Vec<_>>(),
+            vec![OpenDispute {
+                client: 2,
+                transaction_id: 2,
+                amount: amount(dec!(3.0)),
+                age: 2,
+            }]
+        );
+        assert_eq!(
+            payment_engine
+                .locked_accounts()
+                .map(|c| c.id())
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn open_disputes_are_limited_per_client() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                max_open_per_client: Some(1),
+                ..Default::default()
+            })
+            .build();
+        for transaction_id in 1..=3 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(1.0)),
+            });
+        }
+
+        let dispute = |transaction_id| DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: transaction_id,
+        };
+        assert_eq!(
+            payment_engine.add_dispute_action(dispute(1)),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.add_dispute_action(dispute(2)),
+            Outcome::Rejected(RejectionReason::TooManyOpenDisputes)
+        );
+        payment_engine.add_dispute_action(DisputeAction::Resolve {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        assert_eq!(
+            payment_engine.add_dispute_action(dispute(3)),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().held(),
+            dec!(1.0)
+        );
+    }
+
+    #[test]
+    fn aged_disputes_are_auto_resolved() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                auto_resolve_after: Some(2),
+                ..Default::default()
+            })
+            .build();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 2,
+            transaction_id: 2,
+            amount: amount(dec!(1.0)),
+        });
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().held(),
+            dec!(2.0)
+        );
+        assert!(payment_engine.take_events().is_empty());
+
+        // Too late, the dispute was resolved before the chargeback got applied.
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Chargeback {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+            Outcome::Rejected(RejectionReason::InvalidState)
+        );
+
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.held(), Decimal::ZERO);
+        assert_eq!(client.available(), dec!(2.0));
+        assert!(!client.locked());
+        assert_eq!(
+            payment_engine.take_events(),
+            vec![EngineEvent::AutoResolved {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            }]
+        );
+        assert_eq!(payment_engine.open_disputes().count(), 0);
+    }
+
+    #[test]
+    fn disputes_on_locked_accounts_are_not_auto_resolved() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                auto_resolve_after: Some(4),
+                ..Default::default()
+            })
+            .build();
+        let metadata = ClientMetadata {
+            name: Some("Ada".to_string()),
+            ..Default::default()
+        };
+        payment_engine.set_client_metadata(1, metadata.clone());
+        for transaction_id in 1..=2 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(1.0)),
+            });
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: transaction_id,
+            });
+        }
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 2,
+        });
+        for transaction_id in 3..=5 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 2,
+                transaction_id,
+                amount: amount(dec!(1.0)),
+            });
+        }
+
+        // Only the chargeback itself, the other dispute is left open.
+        assert_eq!(
+            payment_engine.take_events(),
+            vec![
+                EngineEvent::ChargedBack {
+                    client: 1,
+                    transaction_id: 2,
+                    amount: amount(dec!(1.0)),
+                    metadata: Some(Arc::new(metadata.clone())),
+                },
+                EngineEvent::AccountLocked {
+                    client: 1,
+                    metadata: Some(Arc::new(metadata.clone())),
+                },
+            ]
+        );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().metadata(),
+            Some(&metadata)
+        );
+        assert_eq!(payment_engine.open_disputes().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().held(),
+            dec!(1.0)
+        );
+    }
+
+    #[test]
+    fn provisional_credit_on_disputed_withdrawal() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                provisional_credit: true,
+                ..Default::default()
+            })
+            .build();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(5.0)),
+        });
+        for transaction_id in 2..=3 {
+            payment_engine.add_transaction(Transaction::Withdrawal {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(2.0)),
+            });
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: transaction_id,
+            });
+        }
+
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), dec!(5.0));
+        assert_eq!(client.provisional(), dec!(4.0));
+
+        payment_engine.add_dispute_action(DisputeAction::Resolve {
+            client: 1,
+            referenced_transaction_id: 2,
+        });
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), dec!(5.0));
+        assert_eq!(client.provisional(), dec!(2.0));
+
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 3,
+        });
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), dec!(3.0));
+        assert_eq!(client.provisional(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn resolved_transactions_are_redisputed_up_to_the_maximum() {
+        let run = |max_redisputes| {
+            let mut payment_engine = PaymentEngine::builder()
+                .dispute_policy(DisputePolicy {
+                    max_redisputes,
+                    ..Default::default()
+                })
+                .build();
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(5.0)),
+            });
+            let mut outcomes = vec![];
+            for _ in 0..3 {
+                outcomes.push(payment_engine.add_dispute_action(DisputeAction::Dispute {
+                    client: 1,
+                    referenced_transaction_id: 1,
+                }));
+                payment_engine.add_dispute_action(DisputeAction::Resolve {
+                    client: 1,
+                    referenced_transaction_id: 1,
+                });
+            }
+            let account = payment_engine.get_client_state(1).unwrap();
+            assert_eq!(account.available(), dec!(5.0));
+            (outcomes, account.history()[0].dispute_rounds)
+        };
+
+        let rejected = Outcome::Rejected(RejectionReason::InvalidState);
+        assert_eq!(run(0), (vec![Outcome::Applied, rejected, rejected], 1));
+        assert_eq!(
+            run(1),
+            (vec![Outcome::Applied, Outcome::Applied, rejected], 2)
+        );
+    }
+
+    #[test]
+    fn withdrawals_are_only_redisputed_when_a_round_restores_the_funds() {
+        let run = |funds_movement| {
+            let mut payment_engine = PaymentEngine::builder()
+                .dispute_policy(DisputePolicy {
+                    max_redisputes: 4,
+                    funds_movement,
+                    ..Default::default()
+                })
+                .build();
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            });
+            payment_engine.add_transaction(Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 2,
+                amount: amount(dec!(1.0)),
+            });
+            let mut outcomes = vec![];
+            for _ in 0..4 {
+                outcomes.push(payment_engine.add_dispute_action(DisputeAction::Dispute {
+                    client: 1,
+                    referenced_transaction_id: 2,
+                }));
+                payment_engine.add_dispute_action(DisputeAction::Resolve {
+                    client: 1,
+                    referenced_transaction_id: 2,
+                });
+            }
+            let account = payment_engine.get_client_state(1).unwrap();
+            (outcomes, account.available(), account.held())
+        };
+
+        let rejected = Outcome::Rejected(RejectionReason::InvalidState);
+        // The first resolve credits the withdrawal back, crediting it on every round would create money.
+        assert_eq!(
+            run(FundsMovement::SpecDefault),
+            (
+                vec![Outcome::Applied, rejected, rejected, rejected],
+                amount(dec!(2.0)),
+                Amount::ZERO
+            )
+        );
+        assert_eq!(
+            run(FundsMovement::SymmetricHold),
+            (vec![Outcome::Applied; 4], amount(dec!(1.0)), Amount::ZERO)
+        );
+    }
+
+    #[test]
+    fn redisputable_transactions_are_not_pruned() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                max_redisputes: 1,
+                ..Default::default()
+            })
+            .build();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(5.0)),
+        });
+        let round = |payment_engine: &mut PaymentEngine| {
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 1,
+            });
+            payment_engine.add_dispute_action(DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 1,
+            })
+        };
+
+        round(&mut payment_engine);
+        assert_eq!(payment_engine.prune_history(Retention::OlderThan(0)), 0);
+        assert_eq!(round(&mut payment_engine), Outcome::Applied);
+        // Disputed as often as it can be, it's settled now.
+        assert_eq!(payment_engine.prune_history(Retention::OlderThan(0)), 1);
+    }
+
+    #[test]
+    fn disputed_withdrawals_move_funds_per_policy() {
+        let run = |funds_movement| {
+            let mut payment_engine = PaymentEngine::builder()
+                .dispute_policy(DisputePolicy {
+                    funds_movement,
+                    ..Default::default()
+                })
+                .build();
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(5.0)),
+            });
+            for transaction_id in 2..=3 {
+                payment_engine.add_transaction(Transaction::Withdrawal {
+                    client: 1,
+                    transaction_id,
+                    amount: amount(dec!(2.0)),
+                });
+                payment_engine.add_dispute_action(DisputeAction::Dispute {
+                    client: 1,
+                    referenced_transaction_id: transaction_id,
+                });
+            }
+            let mut funds = vec![];
+            let mut record = |payment_engine: &PaymentEngine| {
+                let client = payment_engine.get_client_state(1).unwrap();
+                funds.push((client.available().value(), client.held().value()));
+            };
+            record(&payment_engine);
+            payment_engine.add_dispute_action(DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 2,
+            });
+            record(&payment_engine);
+            payment_engine.add_dispute_action(DisputeAction::Chargeback {
+                client: 1,
+                referenced_transaction_id: 3,
+            });
+            record(&payment_engine);
+            funds
+        };
+
+        assert_eq!(
+            run(FundsMovement::SpecDefault),
+            [
+                (dec!(1.0), dec!(0)),
+                (dec!(3.0), dec!(0)),
+                (dec!(3.0), dec!(0))
+            ]
+        );
+        assert_eq!(
+            run(FundsMovement::SymmetricHold),
+            [
+                (dec!(1.0), dec!(4.0)),
+                (dec!(1.0), dec!(2.0)),
+                (dec!(3.0), dec!(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn arbitration_decides_escalated_disputes() {
+        let mut payment_engine = PaymentEngine::default();
+        for transaction_id in 1..=2 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(3.0)),
+            });
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: transaction_id,
+            });
+            assert_eq!(
+                payment_engine.add_dispute_action(DisputeAction::Escalate {
+                    client: 1,
+                    referenced_transaction_id: transaction_id,
+                }),
+                Outcome::Applied
+            );
+        }
+
+        // Once escalated, only the arbitration can decide.
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+            Outcome::Rejected(RejectionReason::InvalidState)
+        );
+
+        payment_engine.add_dispute_action(DisputeAction::ArbitrationLost {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), dec!(3.0));
+        assert_eq!(client.held(), dec!(3.0));
+        assert!(!client.locked());
+
+        payment_engine.add_dispute_action(DisputeAction::ArbitrationWon {
+            client: 1,
+            referenced_transaction_id: 2,
+        });
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), dec!(3.0));
+        assert_eq!(client.held(), Decimal::ZERO);
+        assert!(client.locked());
+
+        let stats = payment_engine.stats();
+        assert_eq!(stats.escalations.accepted, 2);
+        assert_eq!(stats.arbitrations.accepted, 2);
+        assert_eq!(stats.open_disputes, 0);
+    }
+
+    #[test]
+    fn recovery_deposits_pay_off_debt() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(5.0)),
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 2,
+            amount: amount(dec!(4.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.debt(), dec!(4.0));
+        assert_eq!(
+            payment_engine
+                .accounts_in_deficit()
+                .map(ClientAccount::id)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert_eq!(payment_engine.stats().total_debt, dec!(4.0));
+
+        assert_eq!(
+            payment_engine.add_recovery(1, 3, amount(dec!(4.0))),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.add_recovery(1, 4, amount(dec!(1.0))),
+            Outcome::Rejected(RejectionReason::NoOutstandingDebt)
+        );
+
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), Decimal::ZERO);
+        assert!(client.locked());
+        assert_eq!(payment_engine.accounts_in_deficit().count(), 0);
+        assert_eq!(payment_engine.stats().total_debt, Decimal::ZERO);
+    }
+
+    #[test]
+    fn held_funds_never_go_negative() {
+        // Amounts of records can't be negative, so neither can held funds through the public API.
+        let held = Amount::new(dec!(-2.0)).ok();
+        let mut clamped = Amount::ZERO;
+
+        assert_eq!(
+            settle_funds(
+                Some(Amount::ZERO),
+                held,
+                &EngineConfig::default(),
+                &mut clamped
+            ),
+            Err(RejectionReason::NegativeHeld)
+        );
+        assert_eq!(clamped, Amount::ZERO);
+
+        let config = EngineConfig {
+            disputes: DisputePolicy {
+                negative_held: NegativeHeldPolicy::Clamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            settle_funds(Some(Amount::ZERO), held, &config, &mut clamped),
+            Ok((Amount::ZERO, Amount::ZERO))
+        );
+        assert_eq!(clamped, dec!(2.0));
+    }
+
+    #[test]
+    fn overflowing_balances_are_rejected() {
+        let mut payment_engine = PaymentEngine::default();
+        for transaction_id in 1..=2 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: Amount::MAX,
+            });
+        }
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.available(), Amount::MAX);
+        assert_eq!(payment_engine.stats().deposits.rejected, 1);
+
+        // Moving the funds to held is fine, but the total wouldn't fit if any more became available.
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 3,
+                amount: amount(dec!(1)),
+            }),
+            Outcome::Rejected(RejectionReason::BalanceOverflow)
+        );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().held(),
+            Amount::MAX
+        );
+    }
+
+    #[test]
+    fn wide_ids() {
+        let client = u64::MAX;
+        let transaction_id = u128::MAX;
+        let mut payment_engine: PaymentEngine<u64, u128> = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id,
+            amount: amount(dec!(1.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: transaction_id,
+        });
+
+        assert_eq!(
+            payment_engine
+                .query()
+                .disputed_transactions()
+                .collect::<Vec<_>>(),
+            vec![(client, transaction_id)]
+        );
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().held(),
+            dec!(1.0)
+        );
+    }
+
+    #[test]
+    fn withdrawal_after_deposit_for_same_amount() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client,
+            transaction_id: 2,
+            amount,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available,
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn withdrawal_after_deposit_for_less_amount() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client,
+            transaction_id: 2,
+            amount: amount.checked_sub(amount_of_one()).unwrap(),
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available,
+            Decimal::ONE
+        );
+    }
+
+    #[test]
+    fn dispute_after_deposit_total_remains_same() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().total(),
+            amount
+        );
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available(),
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn chargeback_causes_locked_account() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client,
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        let client_state = payment_engine.get_client_state(client).unwrap();
+
+        assert!(client_state.locked());
+
+        assert_eq!(client_state.total(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn direct_chargebacks_open_the_dispute_themselves() {
+        let run = |direct_chargeback| {
+            let mut payment_engine = PaymentEngine::builder()
+                .dispute_policy(DisputePolicy {
+                    direct_chargeback,
+                    ..Default::default()
+                })
+                .build();
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            });
+            let outcome = payment_engine.add_dispute_action(DisputeAction::Chargeback {
+                client: 1,
+                referenced_transaction_id: 1,
+            });
+            (outcome, payment_engine)
+        };
+
+        let (outcome, mut payment_engine) = run(false);
+        assert_eq!(outcome, Outcome::Rejected(RejectionReason::InvalidState));
+        assert_eq!(payment_engine.take_events(), vec![]);
+
+        let (outcome, mut payment_engine) = run(true);
+        assert_eq!(outcome, Outcome::Applied);
+        assert_eq!(
+            payment_engine.take_events(),
+            vec![
+                EngineEvent::ImplicitDispute {
+                    client: 1,
+                    transaction_id: 1,
+                    amount: amount(dec!(2.0)),
+                },
+                EngineEvent::ChargedBack {
+                    client: 1,
+                    transaction_id: 1,
+                    amount: amount(dec!(2.0)),
+                    metadata: None,
+                },
+                EngineEvent::AccountLocked {
+                    client: 1,
+                    metadata: None,
+                },
+            ]
+        );
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(client.total(), Decimal::ZERO);
+        assert!(client.locked());
+        let stats = payment_engine.stats();
+        assert_eq!(
+            (
+                stats.open_disputes,
+                stats.disputes.accepted,
+                stats.chargebacks.accepted
+            ),
+            (0, 0, 1)
+        );
+    }
+
+    #[test]
+    fn rejected_direct_chargebacks_leave_the_transaction_undisputed() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                direct_chargeback: true,
+                ..Default::default()
+            })
+            .build();
+        for (transaction_id, amount) in [(1, Amount::new(dec!(-1)).unwrap()), (2, Amount::MAX)] {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount,
+            });
+        }
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 3,
+            amount: amount(dec!(1)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 3,
+        });
+        let before = payment_engine.get_client_state(1).unwrap().clone();
+
+        // Disputing the negative deposit fits, charging it back would take the total past the maximum.
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Chargeback {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+            Outcome::Rejected(RejectionReason::BalanceOverflow)
+        );
+        let client = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(
+            (client.available(), client.held(), client.locked()),
+            (before.available(), before.held(), false)
+        );
+        assert_eq!(client.open_dispute_count(), 1);
+        assert_eq!(payment_engine.take_events(), vec![]);
+        let stats = payment_engine.stats();
+        assert_eq!(
+            (
+                stats.open_disputes,
+                stats.disputes.accepted,
+                stats.disputes.rejected,
+                stats.chargebacks.rejected
+            ),
+            (1, 1, 0, 1)
+        );
+
+        // A dispute that would be rejected rejects the chargeback, which is only counted as such.
+        payment_engine.set_locked(1, true);
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Chargeback {
+                client: 1,
+                referenced_transaction_id: 2,
+            }),
+            Outcome::Rejected(RejectionReason::AccountLocked)
+        );
+        let stats = payment_engine.stats();
+        assert_eq!(
+            (stats.disputes.rejected, stats.chargebacks.rejected),
+            (0, 2)
+        );
+    }
+
+    #[test]
+    fn dispute_resolve() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Resolve {
+            client,
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        let client_state = payment_engine.get_client_state(client).unwrap();
+
+        assert!(!client_state.locked());
+        assert_eq!(client_state.total(), amount);
+        assert_eq!(client_state.available(), amount);
+    }
+
+    #[test]
+    fn double_dispute_doesnt_hold_twice() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: 1,
+        });
+
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().total(),
+            amount
+        );
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available(),
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn disputing_rejected_withdrawal_does_nothing() {
+        let client = 1;
+        let amount = amount(dec!(2.0));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client,
+            transaction_id: 1,
+            amount,
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client,
+            transaction_id: 2,
+            amount: amount.checked_add(amount_of_one()).unwrap(),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id: 2,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().total(),
+            amount
+        );
+        assert_eq!(
+            payment_engine.get_client_state(client).unwrap().available(),
+            amount
+        );
+    }
+
+    #[test]
+    fn multiple_clients() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 2,
+            transaction_id: 2,
+            amount: amount(dec!(4.0)),
+        });
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 3,
+            amount: amount(dec!(9.0)),
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 4,
+            amount: amount(dec!(1.0)),
+        });
         payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 2,
+            transaction_id: 5,
+            amount: amount(dec!(1.0)),
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 2);
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().available,
+            dec!(10.0)
+        );
+        assert_eq!(
+            payment_engine.get_client_state(2).unwrap().available,
+            dec!(3.0)
+        );
+    }
+
+    #[test]
+    fn disputing_another_client_than_the_transaction_does_nothing() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 2, // Another client than made the transaction!
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(payment_engine.get_all_client_states().count(), 2);
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().available(),
+            dec!(2.0)
+        );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().held(),
+            Decimal::ZERO
+        );
+        assert_eq!(payment_engine.get_client_state(1).unwrap().locked(), false);
+
+        //The other client has been inserted as well!
+        assert_eq!(
+            payment_engine.get_client_state(2).unwrap().available(),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            payment_engine.get_client_state(2).unwrap().held(),
+            Decimal::ZERO
+        );
+        assert_eq!(payment_engine.get_client_state(2).unwrap().locked(), false);
+    }
+
+    #[test]
+    fn dispute_actions_of_unknown_clients_can_leave_no_account_behind() {
+        let mut payment_engine = PaymentEngine::builder()
+            .account_creation(AccountCreation::Transactions)
+            .build();
+        payment_engine.apply(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+
+        for client in [2, 3] {
+            assert_eq!(
+                payment_engine.apply(DisputeAction::Chargeback {
+                    client,
+                    referenced_transaction_id: 1,
+                }),
+                Outcome::Rejected(RejectionReason::UnknownTransaction)
+            );
+        }
+        assert_eq!(payment_engine.client_ids().collect::<Vec<_>>(), [1]);
+        assert_eq!(payment_engine.stats().clients, 1);
+        assert_eq!(payment_engine.stats().chargebacks.rejected, 2);
+        assert_eq!(
+            payment_engine.apply(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+            Outcome::Applied
+        );
+    }
+
+    #[test]
+    fn accounts_are_only_created_as_configured() {
+        let deposit = |client: u16| Transaction::Deposit {
+            client,
+            transaction_id: u32::from(client),
+            amount: amount(dec!(2.0)),
+        };
+        let withdrawal = |client: u16| Transaction::Withdrawal {
             client,
+            transaction_id: 10 + u32::from(client),
+            amount: amount(dec!(1.0)),
+        };
+
+        let mut payment_engine = PaymentEngine::builder()
+            .account_creation(AccountCreation::Deposits)
+            .build();
+        assert_eq!(
+            payment_engine.apply(withdrawal(1)),
+            Outcome::Rejected(RejectionReason::UnregisteredClient)
+        );
+        assert!(payment_engine.is_empty());
+        assert_eq!(payment_engine.apply(deposit(1)), Outcome::Applied);
+        assert_eq!(payment_engine.apply(withdrawal(1)), Outcome::Applied);
+
+        let mut payment_engine = PaymentEngine::builder()
+            .account_creation(AccountCreation::Registered)
+            .build();
+        assert_eq!(
+            payment_engine.apply(deposit(1)),
+            Outcome::Rejected(RejectionReason::UnregisteredClient)
+        );
+        assert_eq!(
+            payment_engine.add_composite_transaction(CompositeTransaction {
+                legs: vec![deposit(1), withdrawal(1)],
+            }),
+            Outcome::Rejected(RejectionReason::UnregisteredClient)
+        );
+        assert_eq!(payment_engine.stats().deposits.rejected, 1);
+        assert!(payment_engine.is_empty());
+
+        assert_eq!(payment_engine.open_account(1), Outcome::Applied);
+        assert_eq!(
+            payment_engine.open_account(1),
+            Outcome::Rejected(RejectionReason::AccountExists)
+        );
+        assert_eq!(payment_engine.stats().clients, 1);
+        assert_eq!(payment_engine.get_client_state(1).unwrap().total(), dec!(0));
+        assert_eq!(payment_engine.apply(deposit(1)), Outcome::Applied);
+        assert_eq!(
+            payment_engine.apply(deposit(2)),
+            Outcome::Rejected(RejectionReason::UnregisteredClient)
+        );
+    }
+
+    #[test]
+    fn records_describe_the_life_of_an_account() {
+        let mut payment_engine = PaymentEngine::builder()
+            .account_creation(AccountCreation::Registered)
+            .build();
+        let withdrawal = Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 2,
+            amount: amount(dec!(1.0)),
+        };
+        assert_eq!(
+            payment_engine.apply(withdrawal.clone()),
+            Outcome::Rejected(RejectionReason::UnregisteredClient)
+        );
+        assert_eq!(
+            payment_engine.apply(AccountAction::Close {
+                client: 1,
+                transaction_id: 3,
+            }),
+            Outcome::Rejected(RejectionReason::UnregisteredClient)
+        );
+
+        assert_eq!(
+            payment_engine.apply(AccountAction::Open {
+                client: 1,
+                transaction_id: 1,
+                initial_deposit: Some(amount(dec!(5.0))),
+            }),
+            Outcome::Applied
+        );
+        assert_eq!(payment_engine.apply(withdrawal), Outcome::Applied);
+        assert_eq!(
+            payment_engine.apply(AccountAction::Close {
+                client: 1,
+                transaction_id: 3,
+            }),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.apply(Transaction::Deposit {
+                client: 1,
+                transaction_id: 4,
+                amount: amount(dec!(1.0)),
+            }),
+            Outcome::Rejected(RejectionReason::AccountClosed)
+        );
+        assert_eq!(
+            payment_engine.apply(AccountAction::Open {
+                client: 1,
+                transaction_id: 5,
+                initial_deposit: None,
+            }),
+            Outcome::Rejected(RejectionReason::AccountExists)
+        );
+
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account.available(), dec!(4.0));
+        assert_eq!(payment_engine.stats().deposits.accepted, 1);
+        assert_eq!(payment_engine.stats().account_actions.accepted, 2);
+        assert_eq!(payment_engine.stats().account_actions.rejected, 2);
+    }
+
+    #[test]
+    fn cross_client_disputes_are_rejected_and_reported() {
+        for cross_client in [CrossClientDisputes::Reject, CrossClientDisputes::Report] {
+            let mut payment_engine = PaymentEngine::builder()
+                .dispute_policy(DisputePolicy {
+                    cross_client,
+                    ..Default::default()
+                })
+                .build();
+            payment_engine.apply(Transaction::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            });
+            payment_engine.take_events();
+
+            assert_eq!(
+                payment_engine.apply(DisputeAction::Dispute {
+                    client: 2,
+                    referenced_transaction_id: 1,
+                }),
+                Outcome::Rejected(RejectionReason::UnknownTransaction)
+            );
+            // A dispute of a transaction nobody has isn't a cross-client dispute.
+            payment_engine.apply(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 7,
+            });
+
+            assert_eq!(payment_engine.len(), 1);
+            assert_eq!(payment_engine.stats().disputes.rejected, 2);
+            let expected = match cross_client {
+                CrossClientDisputes::Report => vec![EngineEvent::CrossClientDispute {
+                    client: 2,
+                    transaction_id: 1,
+                    owner: 1,
+                }],
+                _ => vec![],
+            };
+            assert_eq!(payment_engine.take_events(), expected);
+        }
+    }
+
+    #[test]
+    fn cross_client_disputes_follow_the_owners_of_a_transaction() {
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                cross_client: CrossClientDisputes::Report,
+                ..Default::default()
+            })
+            .build();
+        for client in [1, 3] {
+            payment_engine.apply(Transaction::Deposit {
+                client,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            });
+        }
+        let dispute = DisputeAction::Dispute {
+            client: 2,
+            referenced_transaction_id: 1,
+        };
+        let owner = |payment_engine: &mut PaymentEngine| {
+            payment_engine.apply(dispute.clone());
+            payment_engine
+                .take_events()
+                .into_iter()
+                .find_map(|event| match event {
+                    EngineEvent::CrossClientDispute { owner, .. } => Some(owner),
+                    _ => None,
+                })
+        };
+
+        assert_eq!(owner(&mut payment_engine), Some(1));
+        payment_engine.remove_client(1);
+        assert_eq!(owner(&mut payment_engine), Some(3));
+        payment_engine.reset_client(3);
+        assert_eq!(owner(&mut payment_engine), None);
+    }
+
+    #[test]
+    fn dispute_withdrawal_and_resolve() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
             transaction_id: 1,
-            amount,
+            amount: amount(dec!(2.0)),
+        });
+
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 2,
+            amount: amount(dec!(1.0)),
+        });
+
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 2,
+        });
+
+        payment_engine.add_dispute_action(DisputeAction::Resolve {
+            client: 1,
+            referenced_transaction_id: 2,
         });
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available,
+            payment_engine.get_client_state(1).unwrap().held(),
             Decimal::ZERO
         );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().available(),
+            dec!(2.0)
+        );
+        assert_eq!(payment_engine.get_client_state(1).unwrap().locked(), false);
     }
 
     #[test]
-    fn withdrawal_after_deposit_for_same_amount() {
-        let client = 1;
-        let amount = dec!(2.0);
+    fn dispute_withdrawal_and_charge_back() {
         let mut payment_engine = PaymentEngine::default();
         payment_engine.add_transaction(Transaction::Deposit {
-            client,
+            client: 1,
             transaction_id: 1,
-            amount,
+            amount: amount(dec!(2.0)),
         });
+
         payment_engine.add_transaction(Transaction::Withdrawal {
-            client,
+            client: 1,
             transaction_id: 2,
-            amount,
+            amount: amount(dec!(1.0)),
+        });
+
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 2,
+        });
+
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 2,
         });
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available,
+            payment_engine.get_client_state(1).unwrap().held(),
             Decimal::ZERO
         );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().available(),
+            dec!(1.0)
+        );
+        assert_eq!(payment_engine.get_client_state(1).unwrap().locked(), true);
     }
 
     #[test]
-    fn withdrawal_after_deposit_for_less_amount() {
-        let client = 1;
-        let amount = dec!(2.0);
+    #[cfg(feature = "std")]
+    fn builder_configures_the_engine() {
+        let store = Arc::new(MemoryStore::default());
+        let observed = Arc::new(std::sync::Mutex::new(vec![]));
+        let observed_by_engine = observed.clone();
+        let mut payment_engine = PaymentEngine::builder()
+            .dispute_policy(DisputePolicy {
+                auto_resolve_after: Some(2),
+                ..Default::default()
+            })
+            .locked_policy(LockedPolicy::AllowDeposits)
+            .with_observer(move |event: &EngineEvent| {
+                observed_by_engine.lock().unwrap().push(event.clone())
+            })
+            .with_store(store.clone())
+            .build();
+
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id: 2,
+                amount: amount(dec!(3.0)),
+            }),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 3,
+                amount: amount_of_one(),
+            }),
+            Outcome::Rejected(RejectionReason::AccountLocked)
+        );
+        assert_eq!(store.get(1).unwrap().available(), dec!(3.0));
+        assert!(store.get(1).unwrap().locked());
+
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 2,
+            transaction_id: 4,
+            amount: amount(dec!(2.0)),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 2,
+            referenced_transaction_id: 4,
+        });
+        for transaction_id in 5..=6 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 2,
+                transaction_id,
+                amount: amount_of_one(),
+            });
+        }
+        let auto_resolved = EngineEvent::AutoResolved {
+            client: 2,
+            transaction_id: 4,
+            amount: amount(dec!(2.0)),
+        };
+        let events = vec![
+            EngineEvent::ChargedBack {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+                metadata: None,
+            },
+            EngineEvent::AccountLocked {
+                client: 1,
+                metadata: None,
+            },
+            auto_resolved,
+        ];
+        assert_eq!(*observed.lock().unwrap(), events);
+        assert_eq!(payment_engine.take_events(), events);
+        assert_eq!(store.get(2).unwrap().held(), Decimal::ZERO);
+
+        payment_engine.remove_client(2);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn foreign_records_are_returned() {
+        let mut account: ClientAccount = ClientAccount::new(1);
+        let transaction = Transaction::Deposit {
+            client: 2,
+            transaction_id: 7,
+            amount: amount_of_one(),
+        };
+
+        let err = account.add_transaction(transaction.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "transaction 7 of client 2 can't be added to the account of client 1"
+        );
+        assert_eq!(
+            err,
+            EngineError::ForeignTransaction {
+                account: 1,
+                transaction
+            }
+        );
+        assert!(matches!(
+            account.add_dispute_action(DisputeAction::Dispute {
+                client: 2,
+                referenced_transaction_id: 7,
+            }),
+            Err(EngineError::ForeignDisputeAction { account: 1, .. })
+        ));
+        assert_eq!(account.available(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn records_are_applied() {
         let mut payment_engine = PaymentEngine::default();
+        payment_engine.extend([
+            Record::from(Transaction::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: amount(dec!(2.0)),
+            }),
+            Record::from(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+        ]);
+        assert_eq!(
+            payment_engine.apply(DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+            Outcome::Applied
+        );
+
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account.available(), dec!(2.0));
+        assert_eq!(account.held(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn jurisdictions_have_their_own_rules() {
+        let mut config = EngineConfig::default();
+        config.jurisdictions.insert(
+            "US".to_string(),
+            JurisdictionRules {
+                dispute_window: Some(2),
+                provisional_credit: Some(true),
+                max_withdrawal: Some(amount(dec!(5.0))),
+            },
+        );
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder().config(config).build();
+        payment_engine.set_client_metadata(
+            1,
+            ClientMetadata {
+                country: Some("US".to_string()),
+                ..Default::default()
+            },
+        );
+        for client in 1..=2 {
+            let deposit = |transaction_id, value| Transaction::Deposit {
+                client,
+                transaction_id,
+                amount: amount(value),
+            };
+            let withdrawal = |transaction_id, value| Transaction::Withdrawal {
+                client,
+                transaction_id,
+                amount: amount(value),
+            };
+            let dispute = |transaction_id| DisputeAction::Dispute {
+                client,
+                referenced_transaction_id: transaction_id,
+            };
+            let tx = |n: u32| u32::from(client) * 10 + n;
+            let us = client == 1;
+
+            payment_engine.add_transaction(deposit(tx(1), dec!(20.0)));
+            let expected = if us {
+                Outcome::Rejected(RejectionReason::WithdrawalLimitExceeded)
+            } else {
+                Outcome::Applied
+            };
+            assert_eq!(
+                payment_engine.add_transaction(withdrawal(tx(2), dec!(6.0))),
+                expected
+            );
+            payment_engine.add_transaction(withdrawal(tx(3), dec!(4.0)));
+            let expected = if us {
+                Outcome::Rejected(RejectionReason::DisputeWindowExpired)
+            } else {
+                Outcome::Applied
+            };
+            assert_eq!(payment_engine.add_dispute_action(dispute(tx(1))), expected);
+            assert_eq!(
+                payment_engine.add_dispute_action(dispute(tx(3))),
+                Outcome::Applied
+            );
+        }
+
+        // Only the disputed withdrawal of the US client was credited provisionally.
+        let available = |client| payment_engine.get_client_state(client).unwrap().available();
+        assert_eq!(available(1), dec!(20.0));
+        assert_eq!(available(2), dec!(-10.0));
+    }
+
+    #[test]
+    fn memory_grows_with_the_history() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
+        let empty = payment_engine.approx_memory_bytes();
         payment_engine.add_transaction(Transaction::Deposit {
-            client,
+            client: 1,
             transaction_id: 1,
-            amount,
+            amount: amount(dec!(1.0)),
         });
-        payment_engine.add_transaction(Transaction::Withdrawal {
+        let one_account = payment_engine.approx_memory_bytes();
+        assert!(one_account > empty);
+
+        let account_bytes = payment_engine
+            .get_client_state(1)
+            .unwrap()
+            .approx_memory_bytes();
+        for transaction_id in 2..100 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(1.0)),
+            });
+        }
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert!(account.approx_memory_bytes() > account_bytes);
+        assert!(payment_engine.approx_memory_bytes() > one_account);
+    }
+
+    #[test]
+    fn engines_and_accounts_can_be_reset() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
+        for client in 1..=2 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client,
+                transaction_id: u32::from(client),
+                amount: amount(dec!(2.0)),
+            });
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client,
+                referenced_transaction_id: u32::from(client),
+            });
+        }
+
+        assert!(payment_engine.reset_client(1));
+        assert!(!payment_engine.reset_client(3));
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account, &ClientAccount::new(1));
+        #[cfg(feature = "audit")]
+        assert_eq!(account.audit_head(), audit::GENESIS);
+        assert_eq!(payment_engine.stats().total_held, dec!(2.0));
+        assert_eq!(payment_engine.stats().open_disputes, 1);
+        assert_eq!(payment_engine.stats().clients, 2);
+        assert_eq!(
+            payment_engine
+                .query()
+                .disputed_transactions()
+                .collect::<Vec<_>>(),
+            vec![(2, 2)]
+        );
+
+        payment_engine.set_client_metadata(3, ClientMetadata::default());
+        payment_engine.reset();
+        assert_eq!(payment_engine, PaymentEngine::default());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn resetting_the_engine_leaves_the_store_alone() {
+        let store = Arc::new(MemoryStore::default());
+        let mut payment_engine: PaymentEngine =
+            PaymentEngine::builder().with_store(store.clone()).build();
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: amount(dec!(2.0)),
+        });
+
+        payment_engine.reset();
+        assert!(payment_engine.is_empty());
+        assert_eq!(store.get(1).unwrap().available(), dec!(2.0));
+    }
+
+    #[test]
+    fn operational_actions() {
+        let mut payment_engine = PaymentEngine::default();
+        for transaction_id in 1..=2 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(2.0)),
+            });
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: transaction_id,
+            });
+        }
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+
+        payment_engine.take_events();
+        assert_eq!(payment_engine.force_resolve(1, 2), Outcome::Applied);
+        // The account stays locked throughout, it isn't unlocked and locked again.
+        assert_eq!(payment_engine.take_events(), vec![]);
+        assert_eq!(payment_engine.stats().locked_clients, 1);
+        assert_eq!(
+            payment_engine.force_resolve(1, 3),
+            Outcome::Rejected(RejectionReason::UnknownTransaction)
+        );
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account.available(), dec!(2.0));
+        assert_eq!(account.held(), Decimal::ZERO);
+        assert!(account.locked());
+
+        assert_eq!(payment_engine.unlock(1), Some(true));
+        assert_eq!(payment_engine.unlock(2), None);
+        assert_eq!(payment_engine.stats().locked_clients, 0);
+        assert!(payment_engine.set_credit_limit(1, amount_of_one()));
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 3,
+                amount: amount(dec!(3.0)),
+            }),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.add_transaction(Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 4,
+                amount: amount(dec!(0.5)),
+            }),
+            Outcome::Rejected(RejectionReason::InsufficientFunds)
+        );
+        assert_eq!(
+            payment_engine.get_client_state(1).unwrap().debt(),
+            dec!(1.0)
+        );
+    }
+
+    #[test]
+    fn screened_clients_are_rejected() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+            .with_screening([2].into_iter().collect::<screening::Blocklist>())
+            .build();
+        let deposit = |client| Transaction::Deposit {
             client,
-            transaction_id: 2,
-            amount: amount - Decimal::ONE,
+            transaction_id: u32::from(client),
+            amount: amount(dec!(1.0)),
+        };
+
+        assert_eq!(payment_engine.add_transaction(deposit(1)), Outcome::Applied);
+        assert_eq!(
+            payment_engine.add_transaction(deposit(2)),
+            Outcome::Rejected(RejectionReason::Screened)
+        );
+        // The blocked client doesn't get an account.
+        assert!(payment_engine.get_client_state(2).is_none());
+        assert_eq!(payment_engine.stats().deposits.rejected, 1);
+    }
+
+    #[test]
+    fn inactive_accounts_become_dormant() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+            .config(EngineConfig {
+                dormant_after: Some(2),
+                ..Default::default()
+            })
+            .build();
+        let deposit = |client, transaction_id| Transaction::Deposit {
+            client,
+            transaction_id,
+            amount: amount(dec!(1.0)),
+        };
+        let dormant = |payment_engine: &PaymentEngine| {
+            let mut dormant: Vec<_> = payment_engine.dormant_accounts().map(|a| a.id()).collect();
+            dormant.sort();
+            dormant
+        };
+
+        payment_engine.add_transaction(deposit(1, 1));
+        payment_engine.add_transaction(deposit(2, 2));
+        assert_eq!(dormant(&payment_engine), Vec::<u16>::new());
+        // Rejected records don't count as activity.
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 2,
+            transaction_id: 3,
+            amount: amount(dec!(5.0)),
         });
+        assert_eq!(dormant(&payment_engine), vec![1]);
+        payment_engine.add_transaction(deposit(1, 4));
+        assert_eq!(dormant(&payment_engine), vec![2]);
+    }
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+    #[test]
+    fn sweeps_are_part_of_closing_an_account() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+            .config(EngineConfig {
+                disabled: [WireRecordType::Deposit, WireRecordType::Withdrawal]
+                    .into_iter()
+                    .collect(),
+                withdrawal_request_ttl: Some(1),
+                ..Default::default()
+            })
+            .build();
+        for client in 1..=3 {
+            payment_engine.apply(AccountAction::Open {
+                client,
+                transaction_id: u32::from(client),
+                initial_deposit: Some(amount(dec!(3.0))),
+            });
+        }
+        payment_engine.apply(Transaction::WithdrawalRequest {
+            client: 3,
+            transaction_id: 4,
+            amount: amount(dec!(1.0)),
+        });
+
+        // The id of the sweep is taken by a transaction of either account.
+        for transaction_id in 1..=2 {
+            assert_eq!(
+                payment_engine.close_account(1, Some(2), transaction_id),
+                Some(Outcome::Rejected(RejectionReason::DuplicateTransaction))
+            );
+        }
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available,
-            Decimal::ONE
+            payment_engine.close_account(1, Some(2), 5),
+            Some(Outcome::Applied)
+        );
+        assert_eq!(
+            payment_engine.get_client_state(2).unwrap().available(),
+            dec!(6.0)
+        );
+        // The sweep didn't advance the sequence, which would have expired the request.
+        assert_eq!(
+            payment_engine.get_client_state(3).unwrap().held(),
+            dec!(1.0)
         );
+        assert_eq!(payment_engine.stats().disabled, 0);
     }
 
     #[test]
-    fn dispute_after_deposit_total_remains_same() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
-        payment_engine.add_transaction(Transaction::Deposit {
+    fn closed_accounts_are_swept_and_reject_records() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
+        let deposit = |client, transaction_id| Transaction::Deposit {
             client,
-            transaction_id: 1,
-            amount,
-        });
+            transaction_id,
+            amount: amount(dec!(3.0)),
+        };
+        payment_engine.add_transaction(deposit(1, 1));
+        payment_engine.add_transaction(deposit(2, 2));
         payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client,
-            referenced_transaction_id: 1,
+            client: 2,
+            referenced_transaction_id: 2,
         });
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        assert_eq!(payment_engine.close_account(3, None, 3), None);
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().total(),
-            amount
+            payment_engine.close_account(2, None, 3),
+            Some(Outcome::Rejected(RejectionReason::OpenDisputes))
         );
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available(),
-            Decimal::ZERO
+            payment_engine.close_account(1, Some(9), 3),
+            Some(Outcome::Applied)
+        );
+        assert_eq!(
+            payment_engine.close_account(1, None, 4),
+            Some(Outcome::Rejected(RejectionReason::AccountClosed))
+        );
+        assert_eq!(
+            payment_engine.add_transaction(deposit(1, 5)),
+            Outcome::Rejected(RejectionReason::AccountClosed)
+        );
+        // Sweeping into a closed account is rejected.
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Resolve {
+                client: 2,
+                referenced_transaction_id: 2,
+            }),
+            Outcome::Applied
+        );
+        assert_eq!(
+            payment_engine.close_account(2, Some(1), 6),
+            Some(Outcome::Rejected(RejectionReason::AccountClosed))
+        );
+        assert!(!payment_engine.get_client_state(2).unwrap().closed());
+
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert!(account.closed());
+        assert_eq!(account.total(), dec!(0.0));
+        assert_eq!(
+            payment_engine.get_client_state(9).unwrap().total(),
+            dec!(3.0)
         );
+        assert_eq!(payment_engine.stats().total_available, dec!(6.0));
     }
 
     #[test]
-    fn chargeback_causes_locked_account() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
-        payment_engine.add_transaction(Transaction::Deposit {
-            client,
-            transaction_id: 1,
-            amount,
-        });
-        payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client,
-            referenced_transaction_id: 1,
-        });
-        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+    fn standing_orders_transfer_as_the_clock_advances() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
+        let deposit = |client, transaction_id| Transaction::Deposit {
             client,
-            referenced_transaction_id: 1,
-        });
+            transaction_id,
+            amount: amount(dec!(5.0)),
+        };
+        payment_engine.add_transaction(deposit(1, 1));
+        let order = payment_engine
+            .add_standing_order(StandingOrder {
+                from: 1,
+                to: 2,
+                amount: amount(dec!(2.0)),
+                every: core::num::NonZeroU64::new(2).unwrap(),
+                transaction_ids: [10, 11, 12].into(),
+            })
+            .unwrap();
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
-        let client_state = payment_engine.get_client_state(client).unwrap();
+        for transaction_id in 2..=7 {
+            payment_engine.add_transaction(deposit(3, transaction_id));
+        }
 
-        assert!(client_state.locked());
+        // The third run found too little funds, which ended the order as it had no ids left.
+        let available = |payment_engine: &PaymentEngine, client| {
+            payment_engine.get_client_state(client).unwrap().available()
+        };
+        assert_eq!(available(&payment_engine, 1), dec!(1.0));
+        assert_eq!(available(&payment_engine, 2), dec!(4.0));
+        assert_eq!(
+            payment_engine.take_events(),
+            vec![EngineEvent::StandingOrderFailed {
+                order,
+                from: 1,
+                to: 2,
+                transaction_id: 12,
+                reason: RejectionReason::InsufficientFunds,
+            }]
+        );
+        assert_eq!(payment_engine.standing_orders().count(), 0);
+        assert_eq!(payment_engine.cancel_standing_order(order), None);
 
-        assert_eq!(client_state.total(), Decimal::ZERO);
+        // The transfers show up in the histories, so they can be disputed like any other transaction.
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 2,
+                referenced_transaction_id: 11,
+            }),
+            Outcome::Applied
+        );
     }
 
     #[test]
-    fn dispute_resolve() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
-        payment_engine.add_transaction(Transaction::Deposit {
-            client,
-            transaction_id: 1,
-            amount,
-        });
-        payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client,
-            referenced_transaction_id: 1,
-        });
-        payment_engine.add_dispute_action(DisputeAction::Resolve {
-            client,
-            referenced_transaction_id: 1,
-        });
+    fn rejected_transfers_leave_both_accounts_untouched() {
+        let run = |config: EngineConfig, prepare: &dyn Fn(&mut PaymentEngine)| {
+            let mut payment_engine: PaymentEngine = PaymentEngine::builder().config(config).build();
+            payment_engine.add_account_action(AccountAction::Open {
+                client: 1,
+                transaction_id: 1,
+                initial_deposit: Some(amount(dec!(5.0))),
+            });
+            prepare(&mut payment_engine);
+            payment_engine.add_standing_order(StandingOrder {
+                from: 1,
+                to: 2,
+                amount: amount(dec!(2.0)),
+                every: core::num::NonZeroU64::new(1).unwrap(),
+                transaction_ids: [10].into(),
+            });
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 3,
+                transaction_id: 3,
+                amount: amount(dec!(1.0)),
+            });
+            let reasons: Vec<_> = payment_engine
+                .take_events()
+                .into_iter()
+                .filter_map(|event| match event {
+                    EngineEvent::StandingOrderFailed { reason, .. } => Some(reason),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                payment_engine.get_client_state(1).unwrap().available(),
+                dec!(5.0)
+            );
+            (reasons, payment_engine)
+        };
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
-        let client_state = payment_engine.get_client_state(client).unwrap();
+        // The receiving account doesn't exist and can't be created by a deposit.
+        let registered = EngineConfig {
+            account_creation: AccountCreation::Registered,
+            ..Default::default()
+        };
+        let (reasons, payment_engine) = run(registered, &|_| {});
+        assert_eq!(reasons, vec![RejectionReason::UnregisteredClient]);
+        assert!(!payment_engine.contains_client(2));
 
-        assert!(!client_state.locked());
-        assert_eq!(client_state.total(), amount);
-        assert_eq!(client_state.available(), amount);
+        // The receiving account holds funds for a disputed transaction with the same id.
+        let (reasons, payment_engine) = run(EngineConfig::default(), &|payment_engine| {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 2,
+                transaction_id: 10,
+                amount: amount(dec!(3.0)),
+            });
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 2,
+                referenced_transaction_id: 10,
+            });
+        });
+        assert_eq!(reasons, vec![RejectionReason::DuplicateTransaction]);
+        let receiving = payment_engine.get_client_state(2).unwrap();
+        assert_eq!(
+            (receiving.available(), receiving.held()),
+            (Amount::ZERO, amount(dec!(3.0)))
+        );
+
+        // The rules of the jurisdiction of the sending account cap the withdrawal.
+        let mut config = EngineConfig::default();
+        config.jurisdictions.insert(
+            "US".to_string(),
+            JurisdictionRules {
+                max_withdrawal: Some(amount(dec!(1.0))),
+                ..Default::default()
+            },
+        );
+        let (reasons, payment_engine) = run(config, &|payment_engine| {
+            payment_engine.set_client_metadata(
+                1,
+                ClientMetadata {
+                    country: Some("US".to_string()),
+                    ..Default::default()
+                },
+            );
+        });
+        assert_eq!(reasons, vec![RejectionReason::WithdrawalLimitExceeded]);
+        assert!(!payment_engine.contains_client(2));
     }
 
     #[test]
-    fn double_dispute_doesnt_hold_twice() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
+    fn withdrawal_requests_hold_funds_until_settled() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
         payment_engine.add_transaction(Transaction::Deposit {
-            client,
+            client: 1,
             transaction_id: 1,
-            amount,
-        });
-        payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client,
-            referenced_transaction_id: 1,
-        });
-
-        payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client,
-            referenced_transaction_id: 1,
+            amount: amount(dec!(10.0)),
         });
-
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        for transaction_id in 2..=3 {
+            assert_eq!(
+                payment_engine.add_transaction(Transaction::WithdrawalRequest {
+                    client: 1,
+                    transaction_id,
+                    amount: amount(dec!(4.0)),
+                }),
+                Outcome::Applied
+            );
+        }
+        let funds = |payment_engine: &PaymentEngine| {
+            let account = payment_engine.get_client_state(1).unwrap();
+            (account.available(), account.held())
+        };
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().total(),
-            amount
+            funds(&payment_engine),
+            (amount(dec!(2.0)), amount(dec!(8.0)))
         );
+        // A pending request can't be disputed, its funds haven't left the account yet.
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available(),
-            Decimal::ZERO
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 2,
+            }),
+            Outcome::Rejected(RejectionReason::InvalidState)
         );
-    }
 
-    #[test]
-    fn disputing_rejected_withdrawal_does_nothing() {
-        let client = 1;
-        let amount = dec!(2.0);
-        let mut payment_engine = PaymentEngine::default();
-        payment_engine.add_transaction(Transaction::Deposit {
-            client,
-            transaction_id: 1,
-            amount,
-        });
-        payment_engine.add_transaction(Transaction::Withdrawal {
-            client,
-            transaction_id: 2,
-            amount: amount + Decimal::ONE,
-        });
-        payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client,
+        let confirm = WithdrawalAction::WithdrawalConfirm {
+            client: 1,
             referenced_transaction_id: 2,
-        });
-
-        assert_eq!(payment_engine.get_all_client_states().count(), 1);
+        };
+        assert_eq!(payment_engine.apply(confirm.clone()), Outcome::Applied);
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().total(),
-            amount
+            payment_engine.apply(confirm),
+            Outcome::Rejected(RejectionReason::InvalidState)
         );
         assert_eq!(
-            payment_engine.get_client_state(client).unwrap().available(),
-            amount
+            payment_engine.apply(WithdrawalAction::WithdrawalCancel {
+                client: 1,
+                referenced_transaction_id: 3,
+            }),
+            Outcome::Applied
+        );
+        assert_eq!(funds(&payment_engine), (amount(dec!(6.0)), Amount::ZERO));
+        assert_eq!(payment_engine.stats().settlements.accepted, 2);
+
+        // Once confirmed, it's disputed like a withdrawal.
+        assert_eq!(
+            payment_engine.add_dispute_action(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 2,
+            }),
+            Outcome::Applied
         );
     }
 
     #[test]
-    fn multiple_clients() {
-        let mut payment_engine = PaymentEngine::default();
+    fn unsettled_withdrawal_requests_expire() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+            .config(EngineConfig {
+                withdrawal_request_ttl: Some(3),
+                ..Default::default()
+            })
+            .build();
         payment_engine.add_transaction(Transaction::Deposit {
             client: 1,
             transaction_id: 1,
-            amount: dec!(2.0),
-        });
-        payment_engine.add_transaction(Transaction::Deposit {
-            client: 2,
-            transaction_id: 2,
-            amount: dec!(4.0),
-        });
-        payment_engine.add_transaction(Transaction::Deposit {
-            client: 1,
-            transaction_id: 3,
-            amount: dec!(9.0),
+            amount: amount(dec!(10.0)),
         });
-        payment_engine.add_transaction(Transaction::Withdrawal {
+        for transaction_id in 2..=3 {
+            payment_engine.add_transaction(Transaction::WithdrawalRequest {
+                client: 1,
+                transaction_id,
+                amount: amount(dec!(4.0)),
+            });
+        }
+        payment_engine.apply(WithdrawalAction::WithdrawalConfirm {
             client: 1,
-            transaction_id: 4,
-            amount: dec!(1.0),
-        });
-        payment_engine.add_transaction(Transaction::Withdrawal {
-            client: 2,
-            transaction_id: 5,
-            amount: dec!(1.0),
+            referenced_transaction_id: 3,
         });
+        assert_eq!(payment_engine.take_events(), vec![]);
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 2);
-        assert_eq!(
-            payment_engine.get_client_state(1).unwrap().available,
-            dec!(10.0)
-        );
+        // The request placed at the second record expires at the fifth, the confirmed one never does.
+        for transaction_id in 4..=6 {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client: 2,
+                transaction_id,
+                amount: amount(dec!(1.0)),
+            });
+        }
         assert_eq!(
-            payment_engine.get_client_state(2).unwrap().available,
-            dec!(3.0)
+            payment_engine.take_events(),
+            vec![EngineEvent::HoldExpired {
+                client: 1,
+                transaction_id: 2,
+                amount: amount(dec!(4.0)),
+            }]
         );
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account.available(), dec!(6.0));
+        assert_eq!(account.held(), dec!(0.0));
     }
 
     #[test]
-    fn disputing_another_client_than_the_transaction_does_nothing() {
-        let mut payment_engine = PaymentEngine::default();
+    fn amendments_adjust_the_funds_by_the_difference() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
         payment_engine.add_transaction(Transaction::Deposit {
             client: 1,
             transaction_id: 1,
-            amount: dec!(2.0),
+            amount: amount(dec!(10.0)),
         });
-
-        payment_engine.add_dispute_action(DisputeAction::Dispute {
-            client: 2, // Another client than made the transaction!
-            referenced_transaction_id: 1,
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 1,
+            transaction_id: 2,
+            amount: amount(dec!(4.0)),
         });
+        let amend = |referenced_transaction_id, value| Adjustment::Amend {
+            client: 1,
+            referenced_transaction_id,
+            amount: amount(value),
+        };
 
-        assert_eq!(payment_engine.get_all_client_states().count(), 2);
-        assert_eq!(
-            payment_engine.get_client_state(1).unwrap().available(),
-            dec!(2.0)
-        );
+        assert_eq!(payment_engine.apply(amend(1, dec!(12.0))), Outcome::Applied);
+        assert_eq!(payment_engine.apply(amend(1, dec!(11.0))), Outcome::Applied);
+        assert_eq!(payment_engine.apply(amend(2, dec!(3.0))), Outcome::Applied);
+        // Taking more than is available is rejected like a withdrawal.
         assert_eq!(
-            payment_engine.get_client_state(1).unwrap().held(),
-            Decimal::ZERO
+            payment_engine.apply(amend(2, dec!(20.0))),
+            Outcome::Rejected(RejectionReason::InsufficientFunds)
         );
-        assert_eq!(payment_engine.get_client_state(1).unwrap().locked(), false);
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account.available(), dec!(8.0));
+        // The history keeps the amount the transaction was first recorded with.
+        assert_eq!(account.original_amount(1), Some(amount(dec!(10.0))));
+        assert_eq!(account.original_amount(2), Some(amount(dec!(4.0))));
+        assert_eq!(account.original_amount(3), None);
 
-        //The other client has been inserted as well!
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
         assert_eq!(
-            payment_engine.get_client_state(2).unwrap().available(),
-            Decimal::ZERO
+            payment_engine.apply(amend(1, dec!(1.0))),
+            Outcome::Rejected(RejectionReason::InvalidState)
         );
         assert_eq!(
-            payment_engine.get_client_state(2).unwrap().held(),
-            Decimal::ZERO
+            payment_engine.apply(Adjustment::Amend {
+                client: 2,
+                referenced_transaction_id: 1,
+                amount: amount(dec!(1.0)),
+            }),
+            Outcome::Rejected(RejectionReason::UnknownTransaction)
         );
-        assert_eq!(payment_engine.get_client_state(2).unwrap().locked(), false);
     }
 
     #[test]
-    fn dispute_withdrawal_and_resolve() {
-        let mut payment_engine = PaymentEngine::default();
+    fn debts_are_written_off_to_the_loss_ledger() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
         payment_engine.add_transaction(Transaction::Deposit {
             client: 1,
             transaction_id: 1,
-            amount: dec!(2.0),
+            amount: amount(dec!(5.0)),
         });
-
         payment_engine.add_transaction(Transaction::Withdrawal {
             client: 1,
             transaction_id: 2,
-            amount: dec!(1.0),
+            amount: amount(dec!(3.0)),
         });
+        let write_off = |transaction_id| Adjustment::WriteOff {
+            client: 1,
+            transaction_id,
+        };
+        assert_eq!(
+            payment_engine.apply(write_off(3)),
+            Outcome::Rejected(RejectionReason::NoOutstandingDebt)
+        );
 
         payment_engine.add_dispute_action(DisputeAction::Dispute {
             client: 1,
-            referenced_transaction_id: 2,
+            referenced_transaction_id: 1,
         });
-
-        payment_engine.add_dispute_action(DisputeAction::Resolve {
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
             client: 1,
-            referenced_transaction_id: 2,
+            referenced_transaction_id: 1,
         });
+        // The chargeback locked the account, which doesn't keep its debt from being written off.
+        assert_eq!(payment_engine.apply(write_off(4)), Outcome::Applied);
 
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert_eq!(account.available(), dec!(0.0));
+        assert!(account.locked());
         assert_eq!(
-            payment_engine.get_client_state(1).unwrap().held(),
-            Decimal::ZERO
+            payment_engine.loss_ledger(),
+            &[LossEntry {
+                client: 1,
+                transaction_id: 4,
+                amount: amount(dec!(3.0)),
+            }]
+        );
+        let stats = payment_engine.stats();
+        assert_eq!(stats.total_written_off, dec!(3.0));
+        assert_eq!(stats.total_debt, dec!(0.0));
+    }
+
+    #[test]
+    fn composite_transactions_are_applied_atomically() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::default();
+        for (client, value) in [(1, dec!(10.0)), (2, dec!(3.0))] {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client,
+                transaction_id: u32::from(client),
+                amount: amount(value),
+            });
+        }
+        let payment = |from_second| {
+            [
+                Transaction::Withdrawal {
+                    client: 1,
+                    transaction_id: 10,
+                    amount: amount(dec!(6.0)),
+                },
+                Transaction::Withdrawal {
+                    client: 2,
+                    transaction_id: 10,
+                    amount: amount(from_second),
+                },
+                Transaction::Deposit {
+                    client: 3,
+                    transaction_id: 10,
+                    amount: amount(dec!(5.0) + from_second),
+                },
+                // The fee.
+                Transaction::Deposit {
+                    client: 9,
+                    transaction_id: 10,
+                    amount: amount(dec!(1.0)),
+                },
+            ]
+            .into_iter()
+            .collect::<CompositeTransaction>()
+        };
+
+        assert_eq!(
+            payment_engine.add_composite_transaction(payment(dec!(4.0))),
+            Outcome::Rejected(RejectionReason::InsufficientFunds)
         );
         assert_eq!(
             payment_engine.get_client_state(1).unwrap().available(),
-            dec!(2.0)
+            dec!(10.0)
         );
-        assert_eq!(payment_engine.get_client_state(1).unwrap().locked(), false);
+        assert!(payment_engine.get_client_state(3).is_none());
+
+        assert_eq!(
+            payment_engine.add_composite_transaction(payment(dec!(2.0))),
+            Outcome::Applied
+        );
+        let available = |client| payment_engine.get_client_state(client).unwrap().available();
+        assert_eq!(available(1), dec!(4.0));
+        assert_eq!(available(2), dec!(1.0));
+        assert_eq!(available(3), dec!(7.0));
+        assert_eq!(available(9), dec!(1.0));
+        let stats = payment_engine.stats();
+        assert_eq!(
+            stats.composites,
+            stats::RecordCounts {
+                accepted: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(stats.withdrawals.accepted, 2);
     }
 
     #[test]
-    fn dispute_withdrawal_and_charge_back() {
-        let mut payment_engine = PaymentEngine::default();
+    fn disabled_record_types_are_ignored() {
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+            .config(EngineConfig {
+                disabled: [WireRecordType::Chargeback].into_iter().collect(),
+                ..Default::default()
+            })
+            .build();
         payment_engine.add_transaction(Transaction::Deposit {
             client: 1,
             transaction_id: 1,
-            amount: dec!(2.0),
-        });
-
-        payment_engine.add_transaction(Transaction::Withdrawal {
-            client: 1,
-            transaction_id: 2,
-            amount: dec!(1.0),
+            amount: amount(dec!(5.0)),
         });
-
         payment_engine.add_dispute_action(DisputeAction::Dispute {
             client: 1,
-            referenced_transaction_id: 2,
-        });
-
-        payment_engine.add_dispute_action(DisputeAction::Chargeback {
-            client: 1,
-            referenced_transaction_id: 2,
+            referenced_transaction_id: 1,
         });
-
         assert_eq!(
-            payment_engine.get_client_state(1).unwrap().held(),
-            Decimal::ZERO
+            payment_engine.add_dispute_action(DisputeAction::Chargeback {
+                client: 1,
+                referenced_transaction_id: 1,
+            }),
+            Outcome::Rejected(RejectionReason::Disabled)
         );
+
+        let account = payment_engine.get_client_state(1).unwrap();
+        assert!(!account.locked());
+        assert_eq!(account.held(), dec!(5.0));
+        let stats = payment_engine.stats();
+        assert_eq!(stats.disabled, 1);
+        assert_eq!(stats.chargebacks.rejected, 0);
+    }
+
+    #[test]
+    fn history_is_ordered_with_the_balance_changes() {
+        let mut payment_engine = PaymentEngine::default();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                transaction_id: 9,
+                amount: amount(dec!(5.0)),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 3,
+                amount: amount(dec!(2.0)),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                transaction_id: 4,
+                amount: amount(dec!(10.0)),
+            },
+        ] {
+            payment_engine.add_transaction(transaction);
+        }
+
+        let account = payment_engine.get_client_state(1).unwrap();
+        let history: Vec<_> = account
+            .history()
+            .iter()
+            .map(|entry| {
+                (
+                    *entry.transaction.get_transaction_id(),
+                    entry.state,
+                    entry.balance_change(),
+                )
+            })
+            .collect();
         assert_eq!(
-            payment_engine.get_client_state(1).unwrap().available(),
-            dec!(1.0)
+            history,
+            [
+                (9, TransactionState::Accepted, amount(dec!(5.0))),
+                (3, TransactionState::Accepted, -amount(dec!(2.0))),
+                (4, TransactionState::Rejected, Amount::ZERO),
+            ]
         );
-        assert_eq!(payment_engine.get_client_state(1).unwrap().locked(), true);
     }
 }
@@ -1,224 +1,5132 @@
-use banking::{ClientAccount, DisputeAction, PaymentEngine, Transaction};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use banking::amount::{Amount, AmountError, PrecisionPolicy};
+use banking::audit::AuditHash;
+#[cfg(feature = "tcp")]
+use banking::auth::ApiKeys;
+#[cfg(all(unix, feature = "tcp"))]
+use banking::auth::Role;
+use banking::config::EngineConfig;
+#[cfg(feature = "encryption")]
+use banking::encryption::{EncryptionError, Encryptor, EnvKey, FileKey};
+use banking::event::EngineEvent;
+use banking::metadata::ClientMetadata;
+#[cfg(feature = "mt940")]
+use banking::pipeline::PipelineError;
+use banking::pipeline::{self, AccountSink, DeadLetterStage};
+use banking::rate_limit::TokenBucket;
+use banking::screening::Blocklist;
+#[cfg(feature = "signing")]
+use banking::signing::{SignedOutput, SigningKey};
+#[cfg(feature = "tcp")]
+use banking::tcp::TcpIngest;
+use banking::tenant::MultiTenantEngine;
+#[cfg(feature = "webhooks")]
+use banking::webhook::{WebhookConfig, WebhookDispatcher};
+#[cfg(all(feature = "tcp", feature = "websocket"))]
+use banking::websocket::AccountFeed;
+use banking::{
+    AccountAction, Adjustment, ClientAccount, ClientComparison, DisputeAction, Dormancy, Outcome,
+    PaymentEngine, Record, RejectionReason, Transaction, TransactionState, WithdrawalAction,
+};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut args = std::env::args();
+    args.next(); // Skip the bin name
+    let options = Options::parse(args)?;
+    let shutdown = Arc::clone(&options.pipeline.shutdown);
+    on_shutdown(move || shutdown.store(true, Ordering::SeqCst))?;
+
+    write_output(&options)
+}
+
+/// Runs and writes the output to `--output` or stdout. A run that was cut short by a signal is an error, and leaves
+/// `--output` and its signature as they were, so they only ever hold a complete result.
+fn write_output(options: &Options) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let interrupted = || options.pipeline.shutdown.load(Ordering::SeqCst);
+    match &options.output {
+        Some(path) => {
+            let state_digest =
+                write_atomically::<_, Box<dyn std::error::Error + Send + Sync>>(path, |writer| {
+                    let state_digest = run(options, writer)?;
+                    if interrupted() {
+                        return Err("Interrupted, `--output` has been left as it was.".into());
+                    }
+                    Ok(state_digest)
+                })?;
+            #[cfg(feature = "signing")]
+            if let Some(key) = &options.signing_key {
+                write_signature(path, key, state_digest)?;
+            }
+            #[cfg(not(feature = "signing"))]
+            let _ = state_digest;
+            Ok(())
+        }
+        None => {
+            run(options, csv::Writer::from_writer(std::io::stdout()))?;
+            if interrupted() {
+                return Err("Interrupted, the output only covers the records read before.".into());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Returns the state digest, the [`PaymentEngine::merkle_root`] of the accounts once all records have been applied.
+fn run<W: std::io::Write>(
+    options: &Options,
+    csv_writer: csv::Writer<W>,
+) -> Result<AuditHash, Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(feature = "tcp")]
+    if let Some(address) = &options.listen {
+        return Ok(listen(address, csv_writer, options)?);
+    }
+
+    let state_digest = match options.format {
+        InputFormat::Csv => {
+            let checkpoint = match &options.pipeline.checkpoints {
+                Some(checkpoint_options) => {
+                    Checkpoint::load(checkpoint_options, &options.pipeline.at_rest)?
+                }
+                None => None,
+            };
+            if options.pipeline.backfill.is_some() {
+                let csv_reader = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_path(&options.file_path)?;
+                return Ok(process_backfill(csv_reader, csv_writer, &options.pipeline)?);
+            }
+
+            if options.file_path == "-" {
+                if checkpoint.is_some() {
+                    return Err("Cannot resume from a checkpoint when reading from stdin.".into());
+                }
+                let csv_reader = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_reader(std::io::stdin());
+                process(csv_reader, csv_writer, &options.pipeline)?
+            } else {
+                let mut csv_reader = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_path(&options.file_path)?;
+                match checkpoint {
+                    Some(mut checkpoint) => {
+                        // Read the headers before seeking, they are needed to deserialize the remaining records.
+                        csv_reader.headers()?;
+                        csv_reader.seek(checkpoint.position())?;
+                        process_from(
+                            csv_reader,
+                            csv_writer,
+                            &options.pipeline,
+                            &mut checkpoint.state,
+                        )?;
+                        checkpoint.state.payment_engine.merkle_root()
+                    }
+                    None => process(csv_reader, csv_writer, &options.pipeline)?,
+                }
+            }
+        }
+        #[cfg(feature = "mt940")]
+        InputFormat::Mt940 => {
+            if options.pipeline.command != Command::Accounts {
+                return Err("Commands require CSV input.".into());
+            }
+            let input = std::fs::read_to_string(&options.file_path)?;
+            process_mt940(&input, csv_writer, &options.pipeline)?
+        }
+    };
+
+    Ok(state_digest)
+}
+
+/// Calls `shutdown` on the first SIGINT, SIGTERM or SIGHUP. Another one exits right away, for when finishing up
+/// takes too long.
+fn on_shutdown(shutdown: impl Fn() + Send + 'static) -> Result<(), ctrlc::Error> {
+    let requested = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if requested.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        shutdown();
+    })
+}
+
+/// Applies the records sent to `address` with a [`banking::tcp::TcpClient`] until it's drained, through `--control`
+/// or on a signal (see [`PipelineOptions::shutdown`]), or until the listener fails. Then writes the accounts.
+/// Only the engine configuration, the blocklist and the rate limit apply to these records, the other options are
+/// about CSV input.
+#[cfg(feature = "tcp")]
+fn listen<W: std::io::Write>(
+    address: &str,
+    writer: csv::Writer<W>,
+    options: &Options,
+) -> Result<AuditHash, IoPipelineError> {
+    let builder = PaymentEngine::builder().config(options.pipeline.engine_config.clone());
+    #[cfg(feature = "websocket")]
+    let builder = match &options.ws_listen {
+        Some(address) => {
+            let mut feed = AccountFeed::default();
+            if let Some(api_keys) = &options.api_keys {
+                feed = feed.api_keys(api_keys.clone());
+            }
+            let listener = std::net::TcpListener::bind(address)?;
+            let builder = builder.with_store(feed.clone());
+            std::thread::spawn(move || feed.serve(listener));
+            builder
+        }
+        None => builder,
+    };
+    let mut payment_engine = builder.build();
+    if let Some(blocklist) = &options.pipeline.blocklist {
+        payment_engine.set_screening(blocklist.clone());
+    }
+    let payment_engine = std::sync::Arc::new(std::sync::RwLock::new(payment_engine));
+    let mut ingest = TcpIngest::new(&payment_engine);
+    if let Some(api_keys) = &options.api_keys {
+        ingest = ingest.api_keys(api_keys.clone());
+    }
+    if let Some(rate_limit) = &options.pipeline.rate_limit {
+        ingest = ingest.rate_limit(TokenBucket::new(
+            rate_limit.records_per_second,
+            rate_limit.burst,
+        ));
+    }
+
+    let listener = std::net::TcpListener::bind(address)?;
+    // Removed again however `listen` returns.
+    #[cfg(unix)]
+    let mut control_socket = None;
+    #[cfg(unix)]
+    if let Some(path) = &options.control {
+        let (socket, control) = ControlSocket::bind(path)?;
+        control_socket = Some(socket);
+        let ingest = ingest.clone();
+        let payment_engine = std::sync::Arc::clone(&payment_engine);
+        let snapshots = options.pipeline.snapshots.clone();
+        let at_rest = options.pipeline.at_rest.clone();
+        let api_keys = options.api_keys.clone();
+        std::thread::spawn(move || {
+            serve_control(
+                control,
+                &ingest,
+                &payment_engine,
+                snapshots.as_ref(),
+                &at_rest,
+                api_keys.as_ref(),
+            )
+        });
+    }
+    {
+        let ingest = ingest.clone();
+        let shutdown = Arc::clone(&options.pipeline.shutdown);
+        std::thread::spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+            ingest.stop();
+        });
+    }
+    let diagnostics = options.pipeline.diagnostics;
+    ingest.serve(listener, move |peer, error| {
+        if let Some(format) = diagnostics {
+            let mut diagnostics = Diagnostics {
+                format,
+                out: std::io::stderr(),
+            };
+            // There's nobody to report a failure to report to.
+            let _ = diagnostics.report(Diagnostic::ConnectionClosed {
+                peer: peer.to_string(),
+                error: error.to_string(),
+            });
+        }
+    })?;
+    #[cfg(unix)]
+    drop(control_socket);
+
+    let payment_engine = payment_engine
+        .read()
+        .expect("No panics while holding the lock.");
+    write_client_states(
+        payment_engine.get_all_client_states(),
+        writer,
+        false,
+        payment_engine.dormancy(),
+    )?;
+    Ok(payment_engine.merkle_root())
+}
+
+/// The socket file of `--control`, removed when it's dropped.
+#[cfg(all(unix, feature = "tcp"))]
+struct ControlSocket {
+    path: PathBuf,
+}
+
+#[cfg(all(unix, feature = "tcp"))]
+impl ControlSocket {
+    /// Binds `path`, only the user running the process can connect to it. A socket left behind by a process that
+    /// is gone is replaced, one that is still listened on is an error.
+    fn bind(path: &std::path::Path) -> std::io::Result<(Self, std::os::unix::net::UnixListener)> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket()
+                || std::os::unix::net::UnixStream::connect(path).is_ok()
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("`{}` is in use", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        let socket = Self {
+            path: path.to_path_buf(),
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok((socket, listener))
+    }
+}
+
+#[cfg(all(unix, feature = "tcp"))]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        // Nothing to do when it's gone already.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Answers the commands sent to `--control`, one per line, each with a line starting with `ok` or `error`:
+///
+/// - `auth <key>` gives the connection the role of one of the `--api-keys`, which the other commands require when
+///   there are any.
+/// - `snapshot` writes a snapshot of the accounts and answers with its path, see [`Snapshotter`]. Requires the admin
+///   role.
+/// - `stats` answers with the [`ControlStats`] as JSON. Any role will do.
+/// - `drain` stops accepting connections, once the open ones are closed the accounts are written and the process
+///   exits. Requires the admin role.
+#[cfg(all(unix, feature = "tcp"))]
+fn serve_control(
+    listener: std::os::unix::net::UnixListener,
+    ingest: &TcpIngest,
+    payment_engine: &std::sync::RwLock<PaymentEngine>,
+    snapshots: Option<&SnapshotOptions>,
+    at_rest: &AtRest,
+    api_keys: Option<&ApiKeys>,
+) {
+    use std::io::{BufRead, Write};
+
+    let mut snapshotter = snapshots.map(|options| Snapshotter::new(options, false, at_rest));
+    // Operators connect one at a time, a failed connection only ends that connection.
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        // Only the user running the process can connect, without keys that's all the authentication there is.
+        let mut role = match api_keys {
+            Some(_) => None,
+            None => Some(Role::Admin),
+        };
+        for line in std::io::BufReader::new(&stream).lines() {
+            let Ok(line) = line else { break };
+            let answer = match line.trim().strip_prefix("auth ") {
+                Some(key) => {
+                    role = api_keys.and_then(|api_keys| api_keys.role(key.trim().as_bytes()));
+                    match role {
+                        Some(role) => Ok(role.to_string()),
+                        None => Err("unknown key".to_string()),
+                    }
+                }
+                None => {
+                    control_command(line.trim(), role, ingest, payment_engine, &mut snapshotter)
+                }
+            };
+            let answer = match answer {
+                Ok(answer) => format!("ok {}\n", answer),
+                Err(error) => format!("error {}\n", error),
+            };
+            if (&stream).write_all(answer.as_bytes()).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// What the `stats` control command answers with.
+#[cfg(all(unix, feature = "tcp"))]
+#[derive(Serialize)]
+struct ControlStats {
+    /// Received over all connections, see [`TcpIngest::records`].
+    records: u64,
+    accounts: usize,
+    engine: banking::stats::EngineStats,
+}
+
+/// Fails unless `role`, the role of the connection, allows `required`.
+#[cfg(all(unix, feature = "tcp"))]
+fn authorize(role: Option<Role>, required: Role) -> Result<(), String> {
+    match role {
+        Some(role) if role.allows(required) => Ok(()),
+        Some(role) => Err(format!("the {} role can't run this command", role)),
+        None => Err("authenticate with `auth <key>` first".to_string()),
+    }
+}
+
+#[cfg(all(unix, feature = "tcp"))]
+fn control_command(
+    command: &str,
+    role: Option<Role>,
+    ingest: &TcpIngest,
+    payment_engine: &std::sync::RwLock<PaymentEngine>,
+    snapshotter: &mut Option<Snapshotter>,
+) -> Result<String, String> {
+    match command {
+        "snapshot" => {
+            authorize(role, Role::Admin)?;
+            let snapshotter = snapshotter.as_mut().ok_or("snapshots are disabled")?;
+            let path = snapshotter
+                .write(
+                    &payment_engine
+                        .read()
+                        .expect("No panics while holding the lock."),
+                    ingest.records(),
+                )
+                .and_then(|path| snapshotter.finish().map(|()| path))
+                .map_err(|e| e.to_string())?;
+            Ok(path.display().to_string())
+        }
+        "stats" => {
+            authorize(role, Role::ReadOnly)?;
+            let payment_engine = payment_engine
+                .read()
+                .expect("No panics while holding the lock.");
+            serde_json::to_string(&ControlStats {
+                records: ingest.records(),
+                accounts: payment_engine.len(),
+                engine: payment_engine.stats(),
+            })
+            .map_err(|e| e.to_string())
+        }
+        "drain" => {
+            authorize(role, Role::Admin)?;
+            ingest.stop();
+            Ok("draining".to_string())
+        }
+        other => Err(format!("unknown command '{}'", other)),
+    }
+}
+
+/// Writes the [`SignedOutput`] of the file at `path` next to it, as `<path>.sig`.
+#[cfg(feature = "signing")]
+fn write_signature(
+    path: &std::path::Path,
+    key: &SigningKey,
+    state_digest: AuditHash,
+) -> Result<(), std::io::Error> {
+    let output = std::fs::read(path)?;
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(".sig");
+    std::fs::write(
+        signature_path,
+        SignedOutput::sign(key, &output, state_digest).to_text(),
+    )
+}
+
+/// Has `write` write to a temporary file next to `path`, which only replaces `path` once `write` succeeded.
+/// Downstream jobs never see partial output, a failed run leaves `path` as it was.
+fn write_atomically<T, E: From<std::io::Error> + From<csv::Error>>(
+    path: &std::path::Path,
+    write: impl FnOnce(csv::Writer<std::fs::File>) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    let result = csv::Writer::from_path(&temporary_path)
+        .map_err(E::from)
+        .and_then(write);
+    match result {
+        Ok(value) => {
+            std::fs::File::open(&temporary_path)?.sync_all()?;
+            std::fs::rename(&temporary_path, path)?;
+            Ok(value)
+        }
+        Err(error) => {
+            // The original error is what matters, the temporary file might not even exist.
+            let _ = std::fs::remove_file(&temporary_path);
+            Err(error)
+        }
+    }
+}
+
+enum InputFormat {
+    Csv,
+    #[cfg(feature = "mt940")]
+    Mt940,
+}
+
+struct Options {
+    /// `-` reads from stdin.
+    file_path: String,
+    /// Where to write the account states, stdout when `None`.
+    output: Option<PathBuf>,
+    format: InputFormat,
+    pipeline: PipelineOptions,
+    /// Signs the output, see [`write_signature`].
+    #[cfg(feature = "signing")]
+    signing_key: Option<SigningKey>,
+    /// Records come from the connections to this address instead of a file, see [`listen`].
+    #[cfg(feature = "tcp")]
+    listen: Option<String>,
+    /// The Unix socket `--listen` is managed through, see [`serve_control`].
+    #[cfg(all(unix, feature = "tcp"))]
+    control: Option<PathBuf>,
+    /// Required from the connections to `--listen` and `--control`, see [`ApiKeys::parse`] for the file.
+    #[cfg(feature = "tcp")]
+    api_keys: Option<ApiKeys>,
+    /// Dashboards connect to this address for the accounts `--listen` changes, see [`AccountFeed`].
+    #[cfg(all(feature = "tcp", feature = "websocket"))]
+    ws_listen: Option<String>,
+}
+
+#[derive(Default)]
+struct PipelineOptions {
+    engine_config: EngineConfig,
+    snapshots: Option<SnapshotOptions>,
+    checkpoints: Option<CheckpointOptions>,
+    rate_limit: Option<RateLimitOptions>,
+    /// Where to write a report of every record that was rejected by the engine.
+    rejections: Option<PathBuf>,
+    /// Where to write the records that were screened out, in the input format so they can be re-submitted once
+    /// they're cleared, see [`RawDeadLetterRecord`].
+    dead_letters: Option<PathBuf>,
+    deduplication: Option<DeduplicationOptions>,
+    /// The `tx` column holds arbitrary references (e.g. UUIDs) instead of numeric ids.
+    string_transaction_ids: bool,
+    /// How to report rejections, duplicates, engine events and progress on stderr, nothing is reported when `None`.
+    diagnostics: Option<DiagnosticsFormat>,
+    /// How many of the accounts that took the most time to apply records to are reported at the end.
+    hot_accounts: Option<usize>,
+    /// How many records are parsed ahead at once, `None` uses [`RecordReader::DEFAULT_BATCH_SIZE`].
+    /// A batch is only applied once it's full, a small batch keeps a trickling input (e.g. a named pipe) current.
+    batch_size: Option<usize>,
+    backfill: Option<BackfillOptions>,
+    /// Where the accounts of every tenant are written to, as `<tenant>.csv`.
+    /// Records with a tenant are an error without it, as their accounts would not be written anywhere.
+    tenant_output: Option<PathBuf>,
+    /// Tenants whose engine is configured differently from `engine_config`.
+    tenant_configs: BTreeMap<String, EngineConfig>,
+    /// Attached to the accounts of these clients, and written to the output as additional columns.
+    client_metadata: HashMap<u16, ClientMetadata>,
+    /// The transactions of these clients are rejected as screened.
+    blocklist: Option<std::sync::Arc<Blocklist>>,
+    /// Replaces the client of every record, see [`Pseudonyms`]. The replay filter still matches the original
+    /// clients.
+    pseudonyms: Option<Pseudonyms>,
+    /// Only the records it matches are applied, e.g. to investigate a few clients.
+    filter: ReplayFilter,
+    shadow: Option<ShadowOptions>,
+    aml: Option<AmlOptions>,
+    /// Where to post chargebacks and locked accounts.
+    #[cfg(feature = "webhooks")]
+    webhook: Option<WebhookConfig>,
+    /// How snapshots, checkpoints and the backfill state are written to disk.
+    at_rest: AtRest,
+    command: Command,
+    /// Set on a signal, see [`on_shutdown`]. No more records are read, the output is written for the records that
+    /// have been applied and a checkpoint is kept to resume from. The run then fails, see [`write_output`].
+    shutdown: Arc<AtomicBool>,
+}
+
+/// How often blocked waits for input check [`PipelineOptions::shutdown`].
+const SHUTDOWN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// What is written to the output once all records have been applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum Command {
+    /// The state of every account.
+    #[default]
+    Accounts,
+    /// `history --client <id>`, the transactions of a single client, see [`write_history`]. With `--anonymize`, the
+    /// id given is the original one and `client` its pseudonym.
+    History { client: u16 },
+    /// `disputes`, the open disputes of all clients, see [`write_disputes`].
+    Disputes,
+    /// `report --top <n>`, the accounts that stand out, see [`write_top_accounts`].
+    Top { top: usize },
+    /// `report --group-by day`, the totals of every day, see [`DailyTotals`].
+    Daily,
+    /// `audit [--verify <file>]`, the audit head of every account, see [`write_audit_heads`].
+    Audit { verify: Option<PathBuf> },
+}
+
+/// What happened on a single day, counting the applied records without a tenant.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct DailyTotals {
+    deposits: Decimal,
+    /// Withdrawal requests aren't included, their settlement doesn't have a date of its own.
+    withdrawals: Decimal,
+    disputes_opened: u64,
+    chargebacks: u64,
+}
+
+impl DailyTotals {
+    fn record_applied(&mut self, record: &RawInputRecord) {
+        match record.record_type {
+            RawRecordType::Deposit | RawRecordType::Open => {
+                self.deposits += record.amount.unwrap_or_default()
+            }
+            RawRecordType::Withdrawal => self.withdrawals += record.amount.unwrap_or_default(),
+            RawRecordType::Dispute => self.disputes_opened += 1,
+            RawRecordType::Chargeback => self.chargebacks += 1,
+            _ => {}
+        }
+    }
+}
+
+/// The day of the `date` column, which might be a timestamp like `2024-01-31T12:00:00Z`.
+fn day_of(date: &str) -> &str {
+    date.split(['T', ' ']).next().unwrap_or(date)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiagnosticsFormat {
+    Text,
+    /// One JSON object per line, with a `kind` field telling what it reports.
+    Json,
+}
+
+/// The records applied to a single account, and the time it took to apply them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ClientLoad {
+    records: u64,
+    time: std::time::Duration,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AmlFlag {
+    /// A single deposit or withdrawal above [`AmlOptions::threshold`].
+    LargeTransaction,
+    /// The deposits of a client on a single day exceed [`AmlOptions::daily_deposit_limit`].
+    Structuring,
+}
+
+#[derive(Serialize, Debug)]
+struct RawAmlRecord<'a> {
+    flag: AmlFlag,
+    /// The flagged record, not set for structuring as that spans several records.
+    record: Option<u64>,
+    tenant: Option<&'a str>,
+    client: u16,
+    tx: Option<Cow<'a, str>>,
+    date: Option<&'a str>,
+    /// The amount of the record, or the total of the day's deposits for structuring.
+    amount: Decimal,
+}
+
+/// Writes the AML report. Large transactions are written as they're applied, the daily deposits are only
+/// known to exceed the limit once every record has been seen.
+struct AmlMonitor<'a> {
+    options: &'a AmlOptions,
+    writer: csv::Writer<std::fs::File>,
+    /// The sum of the applied deposits, by tenant, day and client. Records without a date all count as one day.
+    daily_deposits: BTreeMap<(Option<String>, Option<String>, u16), Decimal>,
+}
+
+impl<'a> AmlMonitor<'a> {
+    fn new(options: &'a AmlOptions) -> Result<Self, IoPipelineError> {
+        Ok(Self {
+            options,
+            writer: csv::Writer::from_path(&options.report)?,
+            daily_deposits: BTreeMap::new(),
+        })
+    }
+
+    /// Only applied records are monitored, rejected ones didn't move any funds.
+    fn record_applied(
+        &mut self,
+        record: &RawInputRecord,
+        record_number: u64,
+    ) -> Result<(), IoPipelineError> {
+        let Some(amount) = record.amount else {
+            return Ok(());
+        };
+        let is_deposit = match record.record_type {
+            RawRecordType::Deposit | RawRecordType::Open => true,
+            // Funds of a request are reserved for the payout, it's monitored like the withdrawal it becomes.
+            RawRecordType::Withdrawal | RawRecordType::WithdrawalRequest => false,
+            _ => return Ok(()),
+        };
+        if self.options.threshold.is_some_and(|t| amount > t) {
+            self.writer.serialize(RawAmlRecord {
+                flag: AmlFlag::LargeTransaction,
+                record: Some(record_number),
+                tenant: record.tenant.as_deref(),
+                client: record.client,
+                tx: Some(record.tx_label()),
+                date: record.date.as_deref(),
+                amount,
+            })?;
+        }
+        if is_deposit && self.options.daily_deposit_limit.is_some() {
+            *self
+                .daily_deposits
+                .entry((record.tenant.clone(), record.date.clone(), record.client))
+                .or_default() += amount;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), IoPipelineError> {
+        if let Some(limit) = self.options.daily_deposit_limit {
+            for ((tenant, date, client), total) in &self.daily_deposits {
+                if *total > limit {
+                    self.writer.serialize(RawAmlRecord {
+                        flag: AmlFlag::Structuring,
+                        record: None,
+                        tenant: tenant.as_deref(),
+                        client: *client,
+                        tx: None,
+                        date: date.as_deref(),
+                        amount: *total,
+                    })?;
+                }
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Tracks the load per account, to find accounts that receive a disproportionate share of the records.
+#[derive(Default)]
+struct HotAccounts {
+    per_client: HashMap<u16, ClientLoad>,
+}
+
+impl HotAccounts {
+    fn record_applied(&mut self, client: u16, time: std::time::Duration) {
+        let load = self.per_client.entry(client).or_default();
+        load.records += 1;
+        load.time += time;
+    }
+
+    /// The `n` accounts that took the most time, hottest first.
+    fn top(&self, n: usize) -> Vec<(u16, ClientLoad)> {
+        let mut loads: Vec<_> = self.per_client.iter().map(|(c, l)| (*c, *l)).collect();
+        loads.sort_unstable_by(|(a_client, a), (b_client, b)| {
+            (b.time, b.records, a_client).cmp(&(a.time, a.records, b_client))
+        });
+        loads.truncate(n);
+        loads
+    }
+}
+
+/// Drops records that are identical (same type, client, tx and amount) to one seen before.
+struct DeduplicationOptions {
+    mode: DeduplicationMode,
+    /// Where to write a report of every dropped duplicate.
+    report: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy)]
+enum DeduplicationMode {
+    /// Only compare against the directly preceding record.
+    Consecutive,
+    /// Compare against every record seen so far.
+    Global,
+}
+
+/// The engine settings of `--config`, a TOML file with the fields of [`EngineConfig`] and a `tenants` table holding,
+/// per tenant, only the settings that differ for that tenant, e.g.
+///
+/// ```toml
+/// [disputes]
+/// max_open_per_client = 3
+///
+/// [tenants.acme.disputes]
+/// provisional_credit = true
+/// ```
+///
+/// Files ending in `.json` are read as JSON with the same layout.
+struct ConfigFile {
+    engine: serde_json::Value,
+    tenants: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ConfigFile {
+    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut engine: serde_json::Value = if path.ends_with(".json") {
+            serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?
+        } else {
+            toml::from_str(&std::fs::read_to_string(path)?)?
+        };
+        let tenants = match engine.as_object_mut().and_then(|e| e.remove("tenants")) {
+            Some(serde_json::Value::Object(tenants)) => tenants,
+            Some(_) => return Err("`tenants` in the configuration file must be a table.".into()),
+            None => serde_json::Map::new(),
+        };
+        Ok(Self { engine, tenants })
+    }
+
+    fn engine_config(&self) -> Result<EngineConfig, serde_json::Error> {
+        serde_json::from_value(self.engine.clone())
+    }
+
+    /// The settings of every tenant, on top of `engine_config`, the configuration after applying the flags.
+    fn tenant_configs(
+        &self,
+        engine_config: &EngineConfig,
+    ) -> Result<BTreeMap<String, EngineConfig>, serde_json::Error> {
+        self.tenants
+            .iter()
+            .map(|(tenant, overrides)| {
+                let mut config = serde_json::to_value(engine_config)?;
+                merge_json(&mut config, overrides.clone());
+                Ok((tenant.clone(), serde_json::from_value(config)?))
+            })
+            .collect()
+    }
+}
+
+/// Replaces the values in `base` by those in `overrides`, merging objects field by field.
+fn merge_json(base: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (base, overrides) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// Flags records for anti-money laundering review, see [`AmlMonitor`].
+struct AmlOptions {
+    report: PathBuf,
+    /// Deposits and withdrawals of a larger amount are flagged.
+    threshold: Option<Decimal>,
+    /// Clients whose deposits of a single day add up to more than this are flagged, as they might be structuring
+    /// large amounts into deposits below `threshold`.
+    daily_deposit_limit: Option<Decimal>,
+}
+
+/// Runs the records without a tenant through a second, differently configured engine as well, e.g. with a proposed
+/// dispute policy, and reports the clients whose account ends up different.
+struct ShadowOptions {
+    config: EngineConfig,
+    report: PathBuf,
+}
+
+/// Keeps the engine state between runs over different input files, skipping the records of a file that have already
+/// been applied, so a partially processed file can safely be processed again.
+struct BackfillOptions {
+    /// Where the state is kept, it's created by the first run.
+    state: PathBuf,
+    /// Identifies the input file within the state, by its file name.
+    input: String,
+}
+
+/// Caps the number of records applied per second, slowing down reading from the input.
+struct RateLimitOptions {
+    records_per_second: u32,
+    burst: u32,
+}
+
+/// Periodically persisted engine state, so an interrupted run can resume where it left off.
+struct CheckpointOptions {
+    directory: PathBuf,
+    every_records: u64,
+}
+
+/// Intermediate snapshots of all account states, for inputs that might never reach EOF (e.g. a named pipe).
+#[derive(Clone)]
+struct SnapshotOptions {
+    /// `None` with `--listen`, which only writes snapshots on the `snapshot` control command.
+    every_records: Option<u64>,
+    directory: PathBuf,
+    /// How many snapshot files are kept around before the oldest one is removed.
+    keep: usize,
+}
+
+impl Options {
+    fn parse(
+        args: impl Iterator<Item = String>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let args: Vec<String> = args.collect();
+        // The configuration file provides the defaults, regardless of where it appears the flags override it.
+        let config_file = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => Some(ConfigFile::load(
+                args.get(i + 1).ok_or("`--config` requires a value.")?,
+            )?),
+            None => None,
+        };
+        let mut args = args.into_iter().peekable();
+        // A command comes first, without one the accounts are written.
+        let command_name =
+            args.next_if(|arg| ["history", "disputes", "report", "audit"].contains(&arg.as_str()));
+        let mut client = None;
+        let mut verify = None;
+        let mut top = None;
+        let mut group_by_day = false;
+        let mut file_path = None;
+        let mut format = InputFormat::Csv;
+        let mut snapshot_every = None;
+        let mut snapshot_directory = PathBuf::from(".");
+        let mut snapshot_keep = 3;
+        let mut checkpoint_directory = None;
+        let mut checkpoint_every = 100_000;
+        let mut max_rate = None;
+        let mut burst = None;
+        let mut rejections = None;
+        let mut dead_letters = None;
+        let mut deduplication_mode = None;
+        let mut engine_config = match &config_file {
+            Some(config_file) => config_file.engine_config()?,
+            None => EngineConfig::default(),
+        };
+        let mut duplicates = None;
+        let mut string_transaction_ids = false;
+        let mut output = None;
+        let mut diagnostics = None;
+        let mut hot_accounts = None;
+        let mut batch_size = None;
+        let mut backfill_state = None;
+        let mut tenant_output = None;
+        let mut client_metadata = HashMap::new();
+        let mut blocklist = None;
+        let mut anonymize = false;
+        let mut filter = ReplayFilter::default();
+        let mut shadow_config = None;
+        let mut shadow_report = None;
+        let mut aml_report = None;
+        let mut aml_threshold = None;
+        let mut aml_daily_limit = None;
+        #[cfg(feature = "webhooks")]
+        let mut webhook_url: Option<String> = None;
+        #[cfg(feature = "signing")]
+        let mut signing_key = None;
+        #[cfg(feature = "encryption")]
+        let mut encrypt = false;
+        #[cfg(feature = "encryption")]
+        let mut encryption_key_file: Option<PathBuf> = None;
+        #[cfg(feature = "tcp")]
+        let mut listen: Option<String> = None;
+        #[cfg(all(unix, feature = "tcp"))]
+        let mut control: Option<PathBuf> = None;
+        #[cfg(feature = "tcp")]
+        let mut api_keys_file: Option<String> = None;
+        #[cfg(all(feature = "tcp", feature = "websocket"))]
+        let mut ws_listen: Option<String> = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => {
+                    format = match args.next().as_deref() {
+                        Some("csv") => InputFormat::Csv,
+                        #[cfg(feature = "mt940")]
+                        Some("mt940") => InputFormat::Mt940,
+                        Some(other) => {
+                            return Err(format!("Unknown input format '{}'.", other).into())
+                        }
+                        None => return Err("`--format` requires a value.".into()),
+                    }
+                }
+                "--snapshot-every" => snapshot_every = Some(parse_value(&arg, args.next())?),
+                "--snapshot-dir" => snapshot_directory = parse_value(&arg, args.next())?,
+                "--snapshot-keep" => snapshot_keep = parse_value(&arg, args.next())?,
+                "--checkpoint-dir" => checkpoint_directory = Some(parse_value(&arg, args.next())?),
+                "--checkpoint-every" => checkpoint_every = parse_value(&arg, args.next())?,
+                "--max-rate" => max_rate = Some(parse_value(&arg, args.next())?),
+                "--burst" => burst = Some(parse_value(&arg, args.next())?),
+                "--rejections" => rejections = Some(parse_value(&arg, args.next())?),
+                "--dead-letters" => dead_letters = Some(parse_value(&arg, args.next())?),
+                "--dedup" => {
+                    deduplication_mode = match args.next().as_deref() {
+                        Some("consecutive") => Some(DeduplicationMode::Consecutive),
+                        Some("global") => Some(DeduplicationMode::Global),
+                        Some(other) => {
+                            return Err(format!("Unknown deduplication mode '{}'.", other).into())
+                        }
+                        None => return Err("`--dedup` requires a value.".into()),
+                    }
+                }
+                "--max-open-disputes" => {
+                    engine_config.disputes.max_open_per_client =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--provisional-credit" => engine_config.disputes.provisional_credit = true,
+                "--precision" => {
+                    engine_config.precision = match args.next().as_deref() {
+                        Some("reject") => PrecisionPolicy::Reject,
+                        Some("round") => PrecisionPolicy::Round,
+                        Some(other) => {
+                            return Err(format!("Unknown precision policy '{}'.", other).into())
+                        }
+                        None => return Err("`--precision` requires a value.".into()),
+                    }
+                }
+                "--duplicates" => duplicates = Some(parse_value(&arg, args.next())?),
+                "--string-tx-ids" => string_transaction_ids = true,
+                "--output" => output = Some(parse_value(&arg, args.next())?),
+                "--diagnostics" => {
+                    diagnostics = match args.next().as_deref() {
+                        Some("text") => Some(DiagnosticsFormat::Text),
+                        Some("json") => Some(DiagnosticsFormat::Json),
+                        Some(other) => {
+                            return Err(format!("Unknown diagnostics format '{}'.", other).into())
+                        }
+                        None => return Err("`--diagnostics` requires a value.".into()),
+                    }
+                }
+                "--config" => {
+                    // Already loaded.
+                    args.next();
+                }
+                "--dormant-after" => {
+                    engine_config.dormant_after = Some(parse_value(&arg, args.next())?)
+                }
+                "--clients" => {
+                    let clients: String = parse_value(&arg, args.next())?;
+                    filter.clients = Some(
+                        clients
+                            .split(',')
+                            .map(|client| parse_value(&arg, Some(client.trim().to_string())))
+                            .collect::<Result<_, _>>()?,
+                    );
+                }
+                "--since" => filter.since = Some(parse_value(&arg, args.next())?),
+                "--until" => filter.until = Some(parse_value(&arg, args.next())?),
+                "--disable" => {
+                    let record_type: String = parse_value(&arg, args.next())?;
+                    engine_config.disabled.insert(
+                        serde_json::from_value(serde_json::Value::String(record_type.clone()))
+                            .map_err(|_| format!("Unknown record type '{}'.", record_type))?,
+                    );
+                }
+                "--shadow-config" => {
+                    shadow_config = Some(
+                        ConfigFile::load(&parse_value::<String>(&arg, args.next())?)?
+                            .engine_config()?,
+                    )
+                }
+                "--shadow-report" => shadow_report = Some(parse_value(&arg, args.next())?),
+                "--aml-report" => aml_report = Some(parse_value(&arg, args.next())?),
+                "--aml-threshold" => aml_threshold = Some(parse_value(&arg, args.next())?),
+                "--aml-daily-limit" => aml_daily_limit = Some(parse_value(&arg, args.next())?),
+                "--client-metadata" => {
+                    client_metadata =
+                        load_client_metadata(&parse_value::<String>(&arg, args.next())?)?
+                }
+                "--blocklist" => blocklist = Some(parse_value::<String>(&arg, args.next())?),
+                "--anonymize" => anonymize = true,
+                "--tenant-output-dir" => tenant_output = Some(parse_value(&arg, args.next())?),
+                "--backfill" => backfill_state = Some(parse_value(&arg, args.next())?),
+                "--batch-size" => batch_size = Some(parse_value(&arg, args.next())?),
+                "--hot-accounts" => hot_accounts = Some(parse_value(&arg, args.next())?),
+                "--client" => client = Some(parse_value(&arg, args.next())?),
+                "--verify" => verify = Some(parse_value(&arg, args.next())?),
+                "--top" => top = Some(parse_value(&arg, args.next())?),
+                "--group-by" => match args.next().as_deref() {
+                    Some("day") => group_by_day = true,
+                    Some(other) => return Err(format!("Unknown grouping '{}'.", other).into()),
+                    None => return Err("`--group-by` requires a value.".into()),
+                },
+                #[cfg(feature = "webhooks")]
+                "--webhook-url" => webhook_url = Some(parse_value(&arg, args.next())?),
+                #[cfg(feature = "signing")]
+                "--sign-key" => {
+                    signing_key = Some(banking::signing::load_signing_key(
+                        &parse_value::<PathBuf>(&arg, args.next())?,
+                    )?)
+                }
+                #[cfg(feature = "encryption")]
+                "--encrypt" => encrypt = true,
+                #[cfg(feature = "encryption")]
+                "--encryption-key-file" => {
+                    encryption_key_file = Some(parse_value(&arg, args.next())?)
+                }
+                #[cfg(feature = "tcp")]
+                "--listen" => listen = Some(parse_value(&arg, args.next())?),
+                #[cfg(all(unix, feature = "tcp"))]
+                "--control" => control = Some(parse_value(&arg, args.next())?),
+                #[cfg(feature = "tcp")]
+                "--api-keys" => api_keys_file = Some(parse_value(&arg, args.next())?),
+                #[cfg(all(feature = "tcp", feature = "websocket"))]
+                "--ws-listen" => ws_listen = Some(parse_value(&arg, args.next())?),
+                // Including the flags of features that aren't compiled in.
+                _ if arg.starts_with("--") => return Err(format!("Unknown flag '{}'.", arg).into()),
+                _ if file_path.is_some() => {
+                    return Err(format!(
+                        "Unexpected argument '{}', only one input file is read.",
+                        arg
+                    )
+                    .into())
+                }
+                _ => file_path = Some(arg),
+            }
+        }
+
+        #[cfg(feature = "tcp")]
+        if listen.is_some() {
+            if file_path.is_some() {
+                return Err("`--listen` replaces the input file.".into());
+            }
+            if command_name.is_some() || checkpoint_directory.is_some() || backfill_state.is_some()
+            {
+                return Err(
+                    "`--listen` can't be combined with a command, `--checkpoint-dir` or `--backfill`."
+                        .into(),
+                );
+            }
+            if snapshot_every.is_some() {
+                return Err(
+                    "`--listen` writes snapshots on the `snapshot` control command, not `--snapshot-every`."
+                        .into(),
+                );
+            }
+            if anonymize {
+                // The records of the connections keep their clients, a blocklist of pseudonyms would let them through.
+                return Err("`--listen` can't be combined with `--anonymize`.".into());
+            }
+            // Unused, the records come from the connections.
+            file_path = Some(String::new());
+        }
+        // `--listen` writes snapshots on the `snapshot` control command, into the snapshot directory.
+        #[cfg(feature = "tcp")]
+        let snapshots_on_request = listen.is_some();
+        #[cfg(not(feature = "tcp"))]
+        let snapshots_on_request = false;
+        #[cfg(all(unix, feature = "tcp"))]
+        if control.is_some() && listen.is_none() {
+            return Err("`--control` requires `--listen`.".into());
+        }
+        #[cfg(all(feature = "tcp", feature = "websocket"))]
+        if ws_listen.is_some() && listen.is_none() {
+            return Err("`--ws-listen` requires `--listen`.".into());
+        }
+        #[cfg(feature = "tcp")]
+        let api_keys = match api_keys_file {
+            Some(_) if listen.is_none() => return Err("`--api-keys` requires `--listen`.".into()),
+            Some(path) => Some(
+                ApiKeys::parse(&std::fs::read_to_string(&path)?)
+                    .map_err(|e| format!("Invalid API keys in `{}`: {}.", path, e))?,
+            ),
+            None => None,
+        };
+
+        let file_path = match file_path {
+            Some(path) => path,
+            None => {
+                return Err(
+                    "No path to a file has been found, please provide it as the first argument of this executable.".into(),
+                );
+            }
+        };
+
+        if snapshot_every == Some(0)
+            || snapshot_keep == 0
+            || checkpoint_every == 0
+            || max_rate == Some(0)
+            || burst == Some(0)
+            || batch_size == Some(0)
+        {
+            return Err(
+                "`--snapshot-every`, `--snapshot-keep`, `--checkpoint-every`, `--max-rate`, `--burst` and `--batch-size` must be at least 1."
+                    .into(),
+            );
+        }
+
+        #[cfg(feature = "signing")]
+        if signing_key.is_some() && output.is_none() {
+            return Err(
+                "`--sign-key` requires `--output`, output to stdout can't be signed.".into(),
+            );
+        }
+
+        let backfill = match backfill_state {
+            Some(state) => {
+                if file_path == "-" || checkpoint_directory.is_some() {
+                    return Err(
+                        "`--backfill` can't be combined with reading from stdin or `--checkpoint-dir`."
+                            .into(),
+                    );
+                }
+                let input = std::path::Path::new(&file_path)
+                    .file_name()
+                    .ok_or("`--backfill` requires the path of an input file.")?
+                    .to_string_lossy()
+                    .into_owned();
+                Some(BackfillOptions { state, input })
+            }
+            None => None,
+        };
+
+        let aml = match aml_report {
+            Some(report) => Some(AmlOptions {
+                report,
+                threshold: aml_threshold,
+                daily_deposit_limit: aml_daily_limit,
+            }),
+            None if aml_threshold.is_some() || aml_daily_limit.is_some() => {
+                return Err(
+                    "`--aml-threshold` and `--aml-daily-limit` require `--aml-report`.".into(),
+                )
+            }
+            None => None,
+        };
+
+        let mut command = match command_name.as_deref() {
+            None => Command::Accounts,
+            Some("history") => Command::History {
+                client: client.take().ok_or("`history` requires `--client`.")?,
+            },
+            Some("disputes") => Command::Disputes,
+            Some("audit") => Command::Audit {
+                verify: verify.take(),
+            },
+            Some(_) => match (top.take(), std::mem::take(&mut group_by_day)) {
+                (Some(top), false) => Command::Top { top },
+                (None, true) => Command::Daily,
+                _ => return Err("`report` requires either `--top` or `--group-by`.".into()),
+            },
+        };
+        if client.is_some() {
+            return Err("`--client` requires the `history` command.".into());
+        }
+        if verify.is_some() {
+            return Err("`--verify` requires the `audit` command.".into());
+        }
+        if top.is_some() || group_by_day {
+            return Err("`--top` and `--group-by` require the `report` command.".into());
+        }
+
+        let shadow = match (shadow_config, shadow_report) {
+            (Some(config), Some(report)) => Some(ShadowOptions { config, report }),
+            (None, None) => None,
+            _ => return Err("`--shadow-config` and `--shadow-report` require each other.".into()),
+        };
+
+        let tenant_configs = match &config_file {
+            Some(config_file) => config_file.tenant_configs(&engine_config)?,
+            None => BTreeMap::new(),
+        };
+
+        // Like the webhook secret, the key comes from the environment.
+        let pseudonyms = if anonymize {
+            Some(Pseudonyms {
+                key: std::env::var("BANKING_ANONYMIZE_KEY")
+                    .map_err(|_| "`--anonymize` requires BANKING_ANONYMIZE_KEY to be set.")?
+                    .into_bytes(),
+            })
+        } else {
+            None
+        };
+        if let Some(pseudonyms) = &pseudonyms {
+            // Metadata identifies clients just as well, so it's dropped rather than pseudonymized.
+            client_metadata.clear();
+            // `--client` takes the original id, the accounts are kept under the pseudonyms.
+            if let Command::History { client } = &mut command {
+                *client = pseudonyms.client(*client);
+            }
+        }
+        // Loaded once the pseudonyms are known, it has to block the pseudonyms of the clients.
+        let blocklist = match blocklist {
+            Some(path) => Some(std::sync::Arc::new(load_blocklist(
+                &path,
+                pseudonyms.as_ref(),
+            )?)),
+            None => None,
+        };
+
+        // The secret comes from the environment, so it doesn't show up in the process list.
+        #[cfg(feature = "webhooks")]
+        let webhook = match webhook_url {
+            Some(url) => {
+                let secret = std::env::var("BANKING_WEBHOOK_SECRET")
+                    .map_err(|_| "`--webhook-url` requires BANKING_WEBHOOK_SECRET to be set.")?;
+                Some(WebhookConfig::new(url, secret))
+            }
+            None => None,
+        };
+
+        // Without a key file, the key comes from the environment like the other secrets.
+        #[cfg(feature = "encryption")]
+        let at_rest = match (encrypt, encryption_key_file) {
+            (_, Some(path)) => {
+                AtRest::Encrypted(Box::new(Encryptor::from_provider(&FileKey(path))?))
+            }
+            (true, None) => AtRest::Encrypted(Box::new(Encryptor::from_provider(&EnvKey(
+                "BANKING_ENCRYPTION_KEY".to_string(),
+            ))?)),
+            (false, None) => AtRest::Plain,
+        };
+        #[cfg(not(feature = "encryption"))]
+        let at_rest = AtRest::Plain;
+
+        Ok(Self {
+            file_path,
+            output,
+            format,
+            #[cfg(feature = "signing")]
+            signing_key,
+            #[cfg(feature = "tcp")]
+            listen,
+            #[cfg(all(unix, feature = "tcp"))]
+            control,
+            #[cfg(feature = "tcp")]
+            api_keys,
+            #[cfg(all(feature = "tcp", feature = "websocket"))]
+            ws_listen,
+            pipeline: PipelineOptions {
+                engine_config,
+                snapshots: (snapshot_every.is_some() || snapshots_on_request).then_some(
+                    SnapshotOptions {
+                        every_records: snapshot_every,
+                        directory: snapshot_directory,
+                        keep: snapshot_keep,
+                    },
+                ),
+                checkpoints: checkpoint_directory.map(|directory| CheckpointOptions {
+                    directory,
+                    every_records: checkpoint_every,
+                }),
+                rate_limit: max_rate.map(|records_per_second| RateLimitOptions {
+                    records_per_second,
+                    burst: burst.unwrap_or(records_per_second),
+                }),
+                rejections,
+                dead_letters,
+                deduplication: deduplication_mode.map(|mode| DeduplicationOptions {
+                    mode,
+                    report: duplicates,
+                }),
+                string_transaction_ids,
+                // The report is part of the diagnostics, so asking for it implies them.
+                diagnostics: diagnostics.or(hot_accounts.map(|_| DiagnosticsFormat::Text)),
+                hot_accounts,
+                batch_size,
+                backfill,
+                tenant_output,
+                tenant_configs,
+                client_metadata,
+                blocklist,
+                pseudonyms,
+                filter,
+                shadow,
+                aml,
+                #[cfg(feature = "webhooks")]
+                webhook,
+                at_rest,
+                command,
+                shutdown: Arc::default(),
+            },
+        })
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(
+    flag: &str,
+    value: Option<String>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let value = value.ok_or_else(|| format!("`{}` requires a value.", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}' for `{}`.", value, flag).into())
+}
+
+/// Everything that can stop the pipeline, records the engine rejects don't.
+#[derive(Debug, thiserror::Error)]
+enum IoPipelineError {
+    #[error("Could not read {}: {source}", describe_record(*.record, None))]
+    InvalidRecord { record: u64, source: csv::Error },
+    #[error("Missing amount for {}.", describe_record(*.record, .correlation_id.as_deref()))]
+    MissingAmount {
+        record: u64,
+        correlation_id: Option<String>,
+    },
+    #[error("Too many distinct transaction references at {}.", describe_record(*.record, None))]
+    TooManyTransactionReferences { record: u64 },
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid checkpoint: {0}")]
+    Checkpoint(#[from] serde_json::Error),
+    #[cfg(feature = "encryption")]
+    #[error("Invalid encrypted file: {0}")]
+    Encryption(#[from] EncryptionError),
+    #[error("Writing a snapshot panicked.")]
+    SnapshotPanicked,
+    #[error("Parsing the input panicked.")]
+    ParserPanicked,
+    #[error("Record {record} has a tenant, which requires `--tenant-output-dir`.")]
+    TenantWithoutOutput { record: u64 },
+    #[error("Record {record} is for client {client}, whose account has already been finalized.")]
+    RecordAfterFinalize { record: u64, client: u16 },
+    #[error("Record {record} finalizes a client, which can't be combined with checkpoints or backfills.")]
+    FinalizeNotResumable { record: u64 },
+    #[error("The audit heads of clients {clients:?} don't match the verified ones.")]
+    AuditMismatch { clients: Vec<u16> },
+    #[error(
+        "Tenant '{tenant}' of record {record} can only contain ASCII letters, digits, '-' and '_'."
+    )]
+    InvalidTenant { record: u64, tenant: String },
+    #[cfg(feature = "mt940")]
+    #[error(transparent)]
+    Mt940(#[from] banking::mt940::Mt940Error),
+    #[cfg(feature = "mt940")]
+    #[error("MT940 account identification '{0}' is not a valid client id.")]
+    Mt940Account(String),
+}
+
+/// Describes a record for error messages and reports.
+fn describe_record(record_number: u64, correlation_id: Option<&str>) -> String {
+    match correlation_id {
+        Some(correlation_id) => format!(
+            "record {} (correlation id {})",
+            record_number, correlation_id
+        ),
+        None => format!("record {}", record_number),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum RawRecordType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Escalate,
+    #[serde(rename = "arbitration_won")]
+    ArbitrationWon,
+    #[serde(rename = "arbitration_lost")]
+    ArbitrationLost,
+    #[serde(rename = "withdrawal_request")]
+    WithdrawalRequest,
+    #[serde(rename = "withdrawal_confirm")]
+    WithdrawalConfirm,
+    #[serde(rename = "withdrawal_cancel")]
+    WithdrawalCancel,
+    /// Corrects the amount of the transaction `tx`.
+    Amend,
+    /// Writes off the debt of the client.
+    #[serde(rename = "write_off")]
+    WriteOff,
+    /// No more records of the client follow, so its account can be written right away, see
+    /// [`PipelineState::finalized`]. The `tx` column is ignored.
+    Finalize,
+    /// Opens the account of the client, the optional amount is deposited as `tx`.
+    Open,
+    /// Closes the account of the client, `tx` only identifies the record.
+    Close,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawInputRecord<Tx = u32> {
+    #[serde(rename = "type")]
+    record_type: RawRecordType,
+    client: u16,
+    tx: Tx,
+    amount: Option<Decimal>,
+    /// Identifies the upstream message this record came from, so it can be traced through the reports.
+    #[serde(default)]
+    correlation_id: Option<String>,
+    /// Records with an idempotency key that has been seen before are not applied again,
+    /// even when they carry a different transaction id.
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    /// The tenant (e.g. partner) this record belongs to, each tenant has its own accounts.
+    /// Records without one are applied to the accounts written to the regular output.
+    #[serde(default)]
+    tenant: Option<String>,
+    /// The day of the record, e.g. `2024-05-01`, only used to sum deposits per day for the AML report.
+    #[serde(default)]
+    date: Option<String>,
+    /// The original value of `tx`, when it was interned from a string reference.
+    #[serde(skip)]
+    tx_reference: Option<String>,
+}
+
+/// The fields that make two records semantically identical.
+type RecordKey = (Option<String>, RawRecordType, u16, u32, Option<Decimal>);
+
+impl RawInputRecord<String> {
+    /// Replaces the string reference by a dense id, remembering the reference for the reports.
+    fn intern(
+        self,
+        transaction_ids: &mut TransactionIds,
+        record_number: u64,
+    ) -> Result<RawInputRecord, IoPipelineError> {
+        Ok(RawInputRecord {
+            record_type: self.record_type,
+            client: self.client,
+            tx: transaction_ids.intern(&self.tx).ok_or(
+                IoPipelineError::TooManyTransactionReferences {
+                    record: record_number,
+                },
+            )?,
+            amount: self.amount,
+            correlation_id: self.correlation_id,
+            idempotency_key: self.idempotency_key,
+            tenant: self.tenant,
+            date: self.date,
+            tx_reference: Some(self.tx),
+        })
+    }
+}
+
+impl RawInputRecord {
+    /// The transaction as it appeared in the input.
+    fn tx_label(&self) -> Cow<'_, str> {
+        match &self.tx_reference {
+            Some(reference) => Cow::Borrowed(reference),
+            None => Cow::Owned(self.tx.to_string()),
+        }
+    }
+
+    fn key(&self) -> RecordKey {
+        (
+            self.tenant.clone(),
+            self.record_type,
+            self.client,
+            self.tx,
+            self.amount,
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum InvalidRecord {
+    #[error("missing amount")]
+    MissingAmount,
+    #[error(transparent)]
+    Amount(#[from] AmountError),
+}
+
+impl TryFrom<&RawInputRecord> for Record {
+    type Error = InvalidRecord;
+
+    /// The amount has to be exact, see [`PrecisionPolicy::apply`] for rounding it beforehand.
+    fn try_from(record: &RawInputRecord) -> Result<Self, Self::Error> {
+        let client = record.client;
+        let amount = || {
+            let amount = record.amount.ok_or(InvalidRecord::MissingAmount)?;
+            Ok::<_, InvalidRecord>(Amount::non_negative(amount)?)
+        };
+        let referenced_transaction_id = record.tx;
+
+        Ok(match record.record_type {
+            RawRecordType::Deposit => Record::Transaction(Transaction::Deposit {
+                client,
+                transaction_id: record.tx,
+                amount: amount()?,
+            }),
+            RawRecordType::Withdrawal => Record::Transaction(Transaction::Withdrawal {
+                client,
+                transaction_id: record.tx,
+                amount: amount()?,
+            }),
+            RawRecordType::Dispute => Record::Dispute(DisputeAction::Dispute {
+                client,
+                referenced_transaction_id,
+            }),
+            RawRecordType::Resolve => Record::Dispute(DisputeAction::Resolve {
+                client,
+                referenced_transaction_id,
+            }),
+            RawRecordType::Chargeback => Record::Dispute(DisputeAction::Chargeback {
+                client,
+                referenced_transaction_id,
+            }),
+            RawRecordType::Escalate => Record::Dispute(DisputeAction::Escalate {
+                client,
+                referenced_transaction_id,
+            }),
+            RawRecordType::ArbitrationWon => Record::Dispute(DisputeAction::ArbitrationWon {
+                client,
+                referenced_transaction_id,
+            }),
+            RawRecordType::ArbitrationLost => Record::Dispute(DisputeAction::ArbitrationLost {
+                client,
+                referenced_transaction_id,
+            }),
+            RawRecordType::WithdrawalRequest => {
+                Record::Transaction(Transaction::WithdrawalRequest {
+                    client,
+                    transaction_id: record.tx,
+                    amount: amount()?,
+                })
+            }
+            RawRecordType::WithdrawalConfirm => {
+                Record::Withdrawal(WithdrawalAction::WithdrawalConfirm {
+                    client,
+                    referenced_transaction_id,
+                })
+            }
+            RawRecordType::WithdrawalCancel => {
+                Record::Withdrawal(WithdrawalAction::WithdrawalCancel {
+                    client,
+                    referenced_transaction_id,
+                })
+            }
+            RawRecordType::Amend => Record::Adjustment(Adjustment::Amend {
+                client,
+                referenced_transaction_id,
+                amount: amount()?,
+            }),
+            RawRecordType::WriteOff => Record::Adjustment(Adjustment::WriteOff {
+                client,
+                transaction_id: record.tx,
+            }),
+            RawRecordType::Open => Record::Account(AccountAction::Open {
+                client,
+                transaction_id: record.tx,
+                initial_deposit: record.amount.map(Amount::non_negative).transpose()?,
+            }),
+            RawRecordType::Close => Record::Account(AccountAction::Close {
+                client,
+                transaction_id: record.tx,
+            }),
+            RawRecordType::Finalize => {
+                unreachable!("Finalize hints are handled by the pipeline, see `finalize_client`.")
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RawDuplicateRecord<'a> {
+    record: u64,
+    #[serde(rename = "type")]
+    record_type: RawRecordType,
+    client: u16,
+    tx: Cow<'a, str>,
+    amount: Option<Decimal>,
+    correlation_id: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+struct RawRejectionRecord<'a> {
+    record: u64,
+    #[serde(rename = "type")]
+    record_type: RawRecordType,
+    client: u16,
+    tx: Cow<'a, str>,
+    reason: RejectionReason,
+    correlation_id: Option<&'a str>,
+}
+
+/// A record that didn't reach an account, with the columns of the input first. Like
+/// [`banking::pipeline::DeadLetterRow`], but with the optional columns of the CLI input.
+#[derive(Serialize, Debug)]
+struct RawDeadLetterRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: RawRecordType,
+    client: u16,
+    tx: Cow<'a, str>,
+    amount: Option<Decimal>,
+    correlation_id: Option<&'a str>,
+    idempotency_key: Option<&'a str>,
+    tenant: Option<&'a str>,
+    date: Option<&'a str>,
+    record: u64,
+    stage: DeadLetterStage,
+    reason: RejectionReason,
+}
+
+/// A transaction of the `history` command, see [`write_history`].
+#[derive(Serialize, Debug)]
+struct RawHistoryRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: RawRecordType,
+    tx: Cow<'a, str>,
+    amount: Amount,
+    state: TransactionState,
+    /// The total of the account after the transaction, see [`banking::HistoryEntry::balance_change`].
+    balance: Decimal,
+}
+
+/// An open dispute of the `disputes` command, see [`write_disputes`].
+#[derive(Serialize, Debug)]
+struct RawDisputeRecord<'a> {
+    client: u16,
+    tx: Cow<'a, str>,
+    amount: Amount,
+    /// The number of records applied since the dispute was opened.
+    age: u64,
+}
+
+/// An account of the `report --top` command, see [`write_top_accounts`].
+#[derive(Serialize, Debug)]
+struct RawRankingRecord {
+    /// What the accounts are ranked by: `total`, `held` or `rejections`.
+    ranking: &'static str,
+    /// Starting at 1.
+    rank: usize,
+    client: u16,
+    value: Decimal,
+}
+
+/// An account of the `audit` command, see [`write_audit_heads`].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct RawAuditRecord {
+    client: u16,
+    /// The hex encoded [`ClientAccount::audit_head`].
+    audit_head: String,
+}
+
+/// A day of the `report --group-by day` command, see [`write_daily_totals`].
+#[derive(Serialize, Debug)]
+struct RawDailyRecord<'a> {
+    day: Option<&'a str>,
+    deposits: Decimal,
+    withdrawals: Decimal,
+    disputes_opened: u64,
+    chargebacks: u64,
+}
+
+/// A client whose account differs between the regular and the shadow engine, see [`ShadowOptions`].
+/// The columns of an engine without an account for the client are empty.
+#[derive(Serialize, Debug)]
+struct RawComparisonRecord {
+    client: u16,
+    available: Option<Amount>,
+    held: Option<Amount>,
+    total: Option<Amount>,
+    locked: Option<bool>,
+    shadow_available: Option<Amount>,
+    shadow_held: Option<Amount>,
+    shadow_total: Option<Amount>,
+    shadow_locked: Option<bool>,
+}
+
+impl<'a> From<ClientComparison<'a>> for RawComparisonRecord {
+    fn from(comparison: ClientComparison<'a>) -> Self {
+        let (this, shadow) = (comparison.this, comparison.other);
+        RawComparisonRecord {
+            client: comparison.client,
+            available: this.map(ClientAccount::available),
+            held: this.map(ClientAccount::held),
+            total: this.map(ClientAccount::total),
+            locked: this.map(ClientAccount::locked),
+            shadow_available: shadow.map(ClientAccount::available),
+            shadow_held: shadow.map(ClientAccount::held),
+            shadow_total: shadow.map(ClientAccount::total),
+            shadow_locked: shadow.map(ClientAccount::locked),
+        }
+    }
+}
+
+/// Only written when dormancy is configured, see [`EngineConfig::dormant_after`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AccountStatus {
+    Active,
+    Dormant,
+}
+
+impl AccountStatus {
+    fn of(account: &ClientAccount, dormancy: Option<Dormancy>) -> Option<Self> {
+        dormancy.map(|dormancy| {
+            if dormancy.is_dormant(account) {
+                AccountStatus::Dormant
+            } else {
+                AccountStatus::Active
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RawOutputRecord {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<AccountStatus>,
+}
+
+impl<'a> From<&'a ClientAccount> for RawOutputRecord {
+    fn from(c: &'a ClientAccount) -> Self {
+        RawOutputRecord {
+            client: c.id(),
+            available: c.available(),
+            held: c.held(),
+            total: c.total(),
+            locked: c.locked(),
+            status: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RawOutputRecordWithMetadata<'a> {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<AccountStatus>,
+    name: Option<&'a str>,
+    email: Option<&'a str>,
+    country: Option<&'a str>,
+    tier: Option<&'a str>,
+}
+
+impl<'a> From<&'a ClientAccount> for RawOutputRecordWithMetadata<'a> {
+    fn from(c: &'a ClientAccount) -> Self {
+        let metadata = c.metadata();
+        let field = |f: fn(&'a ClientMetadata) -> &'a Option<String>| {
+            metadata.and_then(|m| f(m).as_deref())
+        };
+        RawOutputRecordWithMetadata {
+            client: c.id(),
+            available: c.available(),
+            held: c.held(),
+            total: c.total(),
+            locked: c.locked(),
+            status: None,
+            name: field(|m| &m.name),
+            email: field(|m| &m.email),
+            country: field(|m| &m.country),
+            tier: field(|m| &m.tier),
+        }
+    }
+}
+
+/// The `client,name,email,country,tier` records of `--client-metadata`.
+#[derive(Deserialize)]
+struct RawMetadataRecord {
+    client: u16,
+    name: Option<String>,
+    email: Option<String>,
+    country: Option<String>,
+    tier: Option<String>,
+}
+
+fn load_client_metadata(
+    path: &str,
+) -> Result<HashMap<u16, ClientMetadata>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+    let mut client_metadata = HashMap::new();
+    for record in reader.deserialize() {
+        let record: RawMetadataRecord = record?;
+        client_metadata.insert(
+            record.client,
+            ClientMetadata {
+                name: record.name,
+                email: record.email,
+                country: record.country,
+                tier: record.tier,
+            },
+        );
+    }
+    Ok(client_metadata)
+}
+
+/// One client id per line, empty lines and lines starting with `#` are skipped.
+fn load_blocklist(
+    path: &str,
+    pseudonyms: Option<&Pseudonyms>,
+) -> Result<Blocklist, Box<dyn std::error::Error + Send + Sync>> {
+    let mut clients = Vec::new();
+    for line in std::io::BufRead::lines(std::io::BufReader::new(std::fs::File::open(path)?)) {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        clients.push(
+            line.parse::<u16>()
+                .map_err(|e| format!("Invalid client id `{line}` in `{path}`: {e}"))?,
+        );
+    }
+    Ok(clients
+        .into_iter()
+        .map(|client| pseudonyms.map_or(client, |p| p.client(client)))
+        .collect())
+}
+
+/// The pseudonyms of `--anonymize`, so production replays can be shared without the ids of the clients. The mapping
+/// is a permutation of all client ids keyed by `key`: a four round Feistel network over the two bytes of an id, with
+/// SHA-256 of the key and the round as its round function. Different clients never share a pseudonym, and the same
+/// key always gives the same pseudonyms.
+#[derive(Clone)]
+struct Pseudonyms {
+    key: Vec<u8>,
+}
+
+impl Pseudonyms {
+    fn client(&self, client: u16) -> u16 {
+        let [mut left, mut right] = client.to_be_bytes();
+        for round in 0..4 {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(&self.key);
+            hasher.update([round, right]);
+            (left, right) = (right, left ^ hasher.finalize()[0]);
+        }
+        u16::from_be_bytes([left, right])
+    }
+}
+
+/// A single line of diagnostics, see [`DiagnosticsFormat`].
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Diagnostic<'a> {
+    Rejection(&'a RawRejectionRecord<'a>),
+    Duplicate(&'a RawDuplicateRecord<'a>),
+    /// Something the engine reported on its own, e.g. a dispute that was resolved automatically.
+    Event(&'a EngineEvent),
+    Progress {
+        records: u64,
+        /// See [`PaymentEngine::approx_memory_bytes`].
+        memory_bytes: usize,
+    },
+    /// An account that took a lot of time to apply records to, see [`HotAccounts`].
+    HotAccount {
+        client: u16,
+        records: u64,
+        /// The fraction of the records applied in this run that were applied to this account.
+        share: f64,
+        time_us: u128,
+    },
+    /// A connection to `--listen` that was closed because it sent an invalid frame, or failed.
+    #[cfg(feature = "tcp")]
+    ConnectionClosed {
+        peer: String,
+        error: String,
+    },
+    /// The totals of this run, reported at the end.
+    Summary {
+        records: u64,
+        rejected: u64,
+        /// Records whose type is disabled, they aren't counted as rejected.
+        disabled: u64,
+        duplicates: u64,
+        memory_bytes: usize,
+    },
+}
+
+/// Writes diagnostics to stderr, or wherever `out` points to in tests.
+struct Diagnostics<O: std::io::Write> {
+    format: DiagnosticsFormat,
+    out: O,
+}
+
+impl<O: std::io::Write> Diagnostics<O> {
+    /// Progress is reported every this many records.
+    const PROGRESS_EVERY: u64 = 100_000;
+
+    fn report(&mut self, diagnostic: Diagnostic<'_>) -> Result<(), IoPipelineError> {
+        match self.format {
+            DiagnosticsFormat::Json => {
+                serde_json::to_writer(&mut self.out, &diagnostic)?;
+                writeln!(self.out)?;
+            }
+            DiagnosticsFormat::Text => match diagnostic {
+                Diagnostic::Rejection(rejection) => writeln!(
+                    self.out,
+                    "Rejected {} ({:?} of client {}, tx {}): {:?}",
+                    describe_record(rejection.record, rejection.correlation_id),
+                    rejection.record_type,
+                    rejection.client,
+                    rejection.tx,
+                    rejection.reason
+                )?,
+                Diagnostic::Duplicate(duplicate) => writeln!(
+                    self.out,
+                    "Dropped {} as a duplicate ({:?} of client {}, tx {})",
+                    describe_record(duplicate.record, duplicate.correlation_id),
+                    duplicate.record_type,
+                    duplicate.client,
+                    duplicate.tx
+                )?,
+                Diagnostic::Event(event) => writeln!(self.out, "Engine event: {:?}", event)?,
+                Diagnostic::Progress {
+                    records,
+                    memory_bytes,
+                } => writeln!(
+                    self.out,
+                    "Processed {} records, using about {} bytes",
+                    records, memory_bytes
+                )?,
+                Diagnostic::HotAccount {
+                    client,
+                    records,
+                    share,
+                    time_us,
+                } => writeln!(
+                    self.out,
+                    "Client {} received {} records ({:.1}%), taking {}µs",
+                    client,
+                    records,
+                    share * 100.0,
+                    time_us
+                )?,
+                #[cfg(feature = "tcp")]
+                Diagnostic::ConnectionClosed { peer, error } => {
+                    writeln!(self.out, "Closed the connection of {}: {}", peer, error)?
+                }
+                Diagnostic::Summary {
+                    records,
+                    rejected,
+                    disabled,
+                    duplicates,
+                    memory_bytes,
+                } => writeln!(
+                    self.out,
+                    "Processed {} records, {} rejected, {} disabled, {} duplicates, using about {} bytes",
+                    records, rejected, disabled, duplicates, memory_bytes
+                )?,
+            },
+        }
+        Ok(())
+    }
+}
+
+fn process<R: std::io::Read + Send + 'static, W: std::io::Write>(
+    reader: csv::Reader<R>,
+    writer: csv::Writer<W>,
+    options: &PipelineOptions,
+) -> Result<AuditHash, IoPipelineError> {
+    let mut state = PipelineState::new(options);
+    process_from(reader, writer, options, &mut state)?;
+    Ok(state.payment_engine.merkle_root())
+}
+
+/// Tenants end up in file names, so they're restricted to characters that are safe in those.
+fn check_tenant(
+    tenant: &str,
+    options: &PipelineOptions,
+    record_number: u64,
+) -> Result<(), IoPipelineError> {
+    if options.tenant_output.is_none() {
+        return Err(IoPipelineError::TenantWithoutOutput {
+            record: record_number,
+        });
+    }
+    let valid = !tenant.is_empty()
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(IoPipelineError::InvalidTenant {
+            record: record_number,
+            tenant: tenant.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Processes the records that haven't been handled by a previous run, see [`BackfillOptions`].
+fn process_backfill<R: std::io::Read + Send + 'static, W: std::io::Write>(
+    reader: csv::Reader<R>,
+    writer: csv::Writer<W>,
+    options: &PipelineOptions,
+) -> Result<AuditHash, IoPipelineError> {
+    let Some(backfill) = &options.backfill else {
+        return process(reader, writer, options);
+    };
+    let mut state = match load_json(&backfill.state, &options.at_rest)? {
+        Some(state) => state,
+        None => PipelineState::new(options),
+    };
+    // Record numbers are counted per input file, the watermark is compared against them.
+    state.records_processed = 0;
+    let result = process_from(reader, writer, options, &mut state);
+    // Whatever was applied before a failure has to be skipped by the next run as well.
+    let watermark = state.watermarks.entry(backfill.input.clone()).or_default();
+    *watermark = (*watermark).max(state.records_processed);
+    store_json(&backfill.state, &options.at_rest, &state)?;
+    result?;
+    Ok(state.payment_engine.merkle_root())
+}
+
+/// Everything needed to pick up processing where it was left off.
+#[derive(Default, Serialize, Deserialize)]
+struct PipelineState {
+    payment_engine: PaymentEngine,
+    records_processed: u64,
+    /// The idempotency keys of all records that have been handled so far.
+    idempotency_keys: HashSet<String>,
+    /// Used to detect duplicates, `seen_records` is only filled in for global deduplication.
+    last_record: Option<RecordKey>,
+    seen_records: HashSet<RecordKey>,
+    #[serde(default)]
+    transaction_ids: TransactionIds,
+    /// The accounts of records with a tenant, see [`PipelineOptions::tenant_output`].
+    #[serde(default)]
+    tenants: MultiTenantEngine,
+    /// The number of records of each input file that have been handled, by file name, see [`BackfillOptions`].
+    #[serde(default)]
+    watermarks: HashMap<String, u64>,
+    /// See [`PipelineOptions::shadow`].
+    #[serde(default)]
+    shadow: Option<PaymentEngine>,
+    /// The number of rejected records of every client, for the records without a tenant.
+    #[serde(default)]
+    rejections: HashMap<u16, u64>,
+    /// By day, only kept for [`Command::Daily`]. Records without a date all count as one day.
+    #[serde(default)]
+    daily: BTreeMap<Option<String>, DailyTotals>,
+    /// Clients whose account has been written and removed on a [`RawRecordType::Finalize`] hint, before the end of
+    /// the input. Hints are only followed for the accounts written to the regular output, and records for a client
+    /// after its hint stop the pipeline.
+    #[serde(default)]
+    finalized: HashSet<u16>,
+}
+
+impl PipelineState {
+    fn new(options: &PipelineOptions) -> Self {
+        let mut tenants = MultiTenantEngine::new(options.engine_config.clone());
+        for (tenant, config) in &options.tenant_configs {
+            tenants.set_tenant_config(tenant.clone(), config.clone());
+        }
+        Self {
+            payment_engine: PaymentEngine::builder()
+                .config(options.engine_config.clone())
+                .build(),
+            tenants,
+            ..Default::default()
+        }
+    }
+
+    /// The engine of `tenant`, or the regular one for records without a tenant.
+    fn payment_engine_mut(&mut self, tenant: Option<&str>) -> &mut PaymentEngine {
+        match tenant {
+            Some(tenant) => self.tenants.tenant_mut(tenant),
+            None => &mut self.payment_engine,
+        }
+    }
+}
+
+/// Maps string transaction references to dense ids, in the order they are first seen.
+#[derive(Default, Serialize, Deserialize)]
+struct TransactionIds {
+    ids: HashMap<String, u32>,
+}
+
+impl TransactionIds {
+    /// The reference of every interned id.
+    fn references(&self) -> HashMap<u32, &str> {
+        self.ids
+            .iter()
+            .map(|(reference, id)| (*id, reference.as_str()))
+            .collect()
+    }
+
+    /// `None` when there are more distinct references than ids.
+    fn intern(&mut self, reference: &str) -> Option<u32> {
+        if let Some(id) = self.ids.get(reference) {
+            return Some(*id);
+        }
+        let id = u32::try_from(self.ids.len()).ok()?;
+        self.ids.insert(reference.to_string(), id);
+        Some(id)
+    }
+}
+
+/// Selects the records of a replay, records it doesn't match are skipped before they're deserialized.
+#[derive(Debug, Clone, Default)]
+struct ReplayFilter {
+    clients: Option<HashSet<u16>>,
+    /// The first day to include, compared to the `date` column as text, so ISO 8601 dates (or timestamps) compare
+    /// chronologically. MT940 lines compare their value date. Records without a date are skipped once either bound
+    /// is set.
+    since: Option<String>,
+    /// The last day to include.
+    until: Option<String>,
+}
+
+impl ReplayFilter {
+    /// The positions of the `client` and `date` columns of records without headers, see [`RawInputRecord`].
+    const POSITIONS: (usize, usize) = (1, 7);
+
+    fn is_empty(&self) -> bool {
+        self.clients.is_none() && self.since.is_none() && self.until.is_none()
+    }
+
+    /// The positions of the `client` and `date` columns.
+    fn columns(headers: Option<&csv::StringRecord>) -> (Option<usize>, Option<usize>) {
+        match headers {
+            Some(headers) => (
+                headers.iter().position(|h| h == "client"),
+                headers.iter().position(|h| h == "date"),
+            ),
+            None => (Some(Self::POSITIONS.0), Some(Self::POSITIONS.1)),
+        }
+    }
+
+    fn matches(&self, record: &csv::StringRecord, columns: (Option<usize>, Option<usize>)) -> bool {
+        // Records with an invalid client are let through, so deserializing them reports the error.
+        let client = columns
+            .0
+            .and_then(|i| record.get(i))
+            .and_then(|c| c.parse().ok());
+        let date = columns.1.and_then(|i| record.get(i));
+        self.admits(client, date)
+    }
+
+    /// Whether a record of `client` on `date` is included, records without a client aren't filtered by it.
+    fn admits(&self, client: Option<u16>, date: Option<&str>) -> bool {
+        if let Some(clients) = &self.clients {
+            if client.is_some_and(|c| !clients.contains(&c)) {
+                return false;
+            }
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let Some(date) = date.filter(|d| !d.is_empty()) else {
+                return false;
+            };
+            if self.since.as_deref().is_some_and(|since| date < since)
+                || self.until.as_deref().is_some_and(|until| date > until)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A record as it comes out of the CSV reader, before transaction references are interned.
+enum ParsedRecord {
+    Numeric(RawInputRecord),
+    References(RawInputRecord<String>),
+    /// Not matched by the [`ReplayFilter`].
+    Skipped,
+}
+
+/// Parsed records, each with the position right after it in the input, for checkpoints.
+type RecordBatch = Vec<(Result<ParsedRecord, csv::Error>, csv::Position)>;
+
+/// Deserializes input records on a background thread, so reading and parsing overlap with applying them.
+/// Interning string transaction references happens on the applying side, as the ids are part of a checkpoint.
+struct RecordReader {
+    batches: std::sync::mpsc::Receiver<RecordBatch>,
+    current: std::vec::IntoIter<(Result<ParsedRecord, csv::Error>, csv::Position)>,
+    position: csv::Position,
+    /// Joined once all batches have been received, `None` afterwards.
+    parser: Option<std::thread::JoinHandle<()>>,
+    /// Ends the records early, see [`PipelineOptions::shutdown`].
+    shutdown: Arc<AtomicBool>,
+}
+
+impl RecordReader {
+    const DEFAULT_BATCH_SIZE: usize = 1024;
+    /// How many batches the parsing thread may run ahead of the records being applied.
+    const QUEUED_BATCHES: usize = 16;
+
+    fn spawn<R: std::io::Read + Send + 'static>(
+        reader: csv::Reader<R>,
+        string_transaction_ids: bool,
+        batch_size: usize,
+        filter: ReplayFilter,
+        shutdown: Arc<AtomicBool>,
+    ) -> Self {
+        let position = reader.position().clone();
+        let (sender, batches) = std::sync::mpsc::sync_channel(Self::QUEUED_BATCHES);
+        let parser = std::thread::spawn(move || {
+            if string_transaction_ids {
+                parse_batches(
+                    reader,
+                    ParsedRecord::References,
+                    &filter,
+                    batch_size,
+                    sender,
+                )
+            } else {
+                parse_batches(reader, ParsedRecord::Numeric, &filter, batch_size, sender)
+            }
+        });
+        Self {
+            batches,
+            current: Vec::new().into_iter(),
+            position,
+            parser: Some(parser),
+            shutdown,
+        }
+    }
+
+    /// `record_number` is the number of the record that will be read, for error messages.
+    /// Records skipped by the [`ReplayFilter`] are `None`, they still count as records. After a shutdown there are no
+    /// more records, even if some were parsed already.
+    fn next(
+        &mut self,
+        transaction_ids: &mut TransactionIds,
+        record_number: u64,
+    ) -> Option<Result<Option<RawInputRecord>, IoPipelineError>> {
+        let (record, position) = loop {
+            if self.shutdown.load(Ordering::SeqCst) {
+                // The parser stops once it can't send its next batch.
+                return None;
+            }
+            if let Some(next) = self.current.next() {
+                break next;
+            }
+            match self.batches.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(batch) => self.current = batch.into_iter(),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                // All batches have been received, unless the parser panicked.
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    return match self.parser.take()?.join() {
+                        Ok(()) => None,
+                        Err(_) => Some(Err(IoPipelineError::ParserPanicked)),
+                    }
+                }
+            }
+        };
+        self.position = position;
+
+        let record = record.map_err(|source| IoPipelineError::InvalidRecord {
+            record: record_number,
+            source,
+        });
+        Some(record.and_then(|record| match record {
+            ParsedRecord::Numeric(record) => Ok(Some(record)),
+            ParsedRecord::References(record) => {
+                record.intern(transaction_ids, record_number).map(Some)
+            }
+            ParsedRecord::Skipped => Ok(None),
+        }))
+    }
+
+    /// The position right after the last record that was read.
+    fn position(&self) -> &csv::Position {
+        &self.position
+    }
+}
+
+/// Sends the records of `reader` in batches of `batch_size`, until the input ends, a record can't be
+/// deserialized or the receiving side has gone away.
+fn parse_batches<R: std::io::Read, D: serde::de::DeserializeOwned>(
+    mut reader: csv::Reader<R>,
+    parsed: fn(D) -> ParsedRecord,
+    filter: &ReplayFilter,
+    batch_size: usize,
+    sender: std::sync::mpsc::SyncSender<RecordBatch>,
+) {
+    // Like `csv::Reader::deserialize`, but the filter only needs the raw fields.
+    let headers = if reader.has_headers() {
+        reader.headers().ok().cloned()
+    } else {
+        None
+    };
+    let columns = ReplayFilter::columns(headers.as_ref());
+    let mut raw = csv::StringRecord::new();
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        let record = match reader.read_record(&mut raw) {
+            Ok(false) => break,
+            Ok(true) if !filter.is_empty() && !filter.matches(&raw, columns) => {
+                Ok(ParsedRecord::Skipped)
+            }
+            Ok(true) => raw.deserialize::<D>(headers.as_ref()).map(parsed),
+            Err(error) => Err(error),
+        };
+        let failed = record.is_err();
+        batch.push((record, reader.position().clone()));
+        if failed || batch.len() == batch_size {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            if sender.send(full).is_err() || failed {
+                return;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        // Nothing to do when the receiving side has gone away.
+        let _ = sender.send(batch);
+    }
+}
+
+/// Continues processing from a state that has already seen `state.records_processed` records.
+///
+/// Unlike MT940 input, CSV input doesn't go through [`pipeline::run_on`]: checkpoints, deduplication, tenants,
+/// finalize hints and the reports need the raw record and its number around every record that is applied, which
+/// the pipeline doesn't give access to. Only the accounts are written through the pipeline's [`AccountSink`]s. Both
+/// hand every record to [`PaymentEngine::apply`], see [`apply_record`], so the engine's rules are the same either way.
+fn process_from<R: std::io::Read + Send + 'static, W: std::io::Write>(
+    reader: csv::Reader<R>,
+    mut writer: csv::Writer<W>,
+    options: &PipelineOptions,
+    state: &mut PipelineState,
+) -> Result<(), IoPipelineError> {
+    let mut records = RecordReader::spawn(
+        reader,
+        options.string_transaction_ids,
+        options
+            .batch_size
+            .unwrap_or(RecordReader::DEFAULT_BATCH_SIZE),
+        options.filter.clone(),
+        Arc::clone(&options.shutdown),
+    );
+
+    // Observers aren't part of a checkpoint, so they're attached here rather than when the engine is built.
+    #[cfg(feature = "webhooks")]
+    let webhooks = options.webhook.clone().map(|config| {
+        let dispatcher = WebhookDispatcher::new(config);
+        state.payment_engine.observe(dispatcher.clone());
+        dispatcher
+    });
+
+    for (client, metadata) in &options.client_metadata {
+        state
+            .payment_engine
+            .set_client_metadata(*client, metadata.clone());
+    }
+    let with_metadata = !options.client_metadata.is_empty();
+    // Like the observers, the screening isn't part of a checkpoint.
+    if let Some(blocklist) = &options.blocklist {
+        state.payment_engine.set_screening(blocklist.clone());
+    }
+    if let Some(shadow_options) = &options.shadow {
+        let shadow = state.shadow.get_or_insert_with(|| {
+            PaymentEngine::builder()
+                .config(shadow_options.config.clone())
+                .build()
+        });
+        for (client, metadata) in &options.client_metadata {
+            shadow.set_client_metadata(*client, metadata.clone());
+        }
+        if let Some(blocklist) = &options.blocklist {
+            shadow.set_screening(blocklist.clone());
+        }
+    }
+
+    let mut snapshotter = options.snapshots.as_ref().map(|snapshot_options| {
+        Snapshotter::new(snapshot_options, with_metadata, &options.at_rest)
+    });
+    let mut rate_limiter = options
+        .rate_limit
+        .as_ref()
+        .map(|o| TokenBucket::new(o.records_per_second, o.burst));
+
+    let mut rejection_writer = match &options.rejections {
+        Some(path) => Some(csv::Writer::from_path(path)?),
+        None => None,
+    };
+    let mut dead_letter_writer = match &options.dead_letters {
+        Some(path) => Some(csv::Writer::from_path(path)?),
+        None => None,
+    };
+
+    let mut duplicate_writer = match options
+        .deduplication
+        .as_ref()
+        .and_then(|d| d.report.as_ref())
+    {
+        Some(path) => Some(csv::Writer::from_path(path)?),
+        None => None,
+    };
+
+    let mut diagnostics = options.diagnostics.map(|format| Diagnostics {
+        format,
+        out: std::io::stderr().lock(),
+    });
+    let mut rejected = 0;
+    let mut disabled = 0;
+    let mut duplicates = 0;
+    let mut aml_monitor = options.aml.as_ref().map(AmlMonitor::new).transpose()?;
+    // Only measured when asked for, it adds two clock reads per record.
+    let mut hot_accounts = options.hot_accounts.map(|_| HotAccounts::default());
+
+    while let Some(r) = records.next(&mut state.transaction_ids, state.records_processed + 1) {
+        if let Some(backfill) = &options.backfill {
+            let watermark = state.watermarks.get(&backfill.input).copied();
+            if state.records_processed < watermark.unwrap_or(0) {
+                // Handled by a previous run.
+                state.records_processed += 1;
+                continue;
+            }
+        }
+        // Due to internally tagged enums not being supported (https://github.com/BurntSushi/rust-csv/issues/211),
+        // deserialize into an intermediate state before passing it along to the lib.
+        let Some(mut record) = r? else {
+            // Skipped by the replay filter.
+            state.records_processed += 1;
+            continue;
+        };
+        if let Some(pseudonyms) = &options.pseudonyms {
+            record.client = pseudonyms.client(record.client);
+        }
+        if let Some(rate_limiter) = &mut rate_limiter {
+            rate_limiter.acquire();
+        }
+        if let Some(tenant) = &record.tenant {
+            check_tenant(tenant, options, state.records_processed + 1)?;
+        }
+        let tenant = record.tenant.as_deref();
+        // Rounding up front makes records that only differ in excess precision duplicates of each other as well.
+        let precision = state.payment_engine_mut(tenant).config().precision;
+        record.amount = record.amount.map(|amount| precision.apply(amount));
+        // A record with a key we've seen before has already been handled, acknowledge it without applying it again.
+        let already_handled = match &record.idempotency_key {
+            Some(key) => !state.idempotency_keys.insert(key.clone()),
+            None => false,
+        };
+        let duplicate = match &options.deduplication {
+            Some(deduplication) => {
+                let key = record.key();
+                let duplicate = match deduplication.mode {
+                    DeduplicationMode::Consecutive => state.last_record.as_ref() == Some(&key),
+                    DeduplicationMode::Global => !state.seen_records.insert(key.clone()),
+                };
+                state.last_record = Some(key);
+                duplicate
+            }
+            None => false,
+        };
+        if record.record_type == RawRecordType::Finalize {
+            if options.command == Command::Accounts && record.tenant.is_none() {
+                finalize_client(
+                    state,
+                    record.client,
+                    &mut writer,
+                    options,
+                    state.records_processed + 1,
+                )?;
+            }
+        } else if record.tenant.is_none() && state.finalized.contains(&record.client) {
+            return Err(IoPipelineError::RecordAfterFinalize {
+                record: state.records_processed + 1,
+                client: record.client,
+            });
+        } else if duplicate {
+            duplicates += 1;
+            let report = RawDuplicateRecord {
+                record: state.records_processed + 1,
+                record_type: record.record_type,
+                client: record.client,
+                tx: record.tx_label(),
+                amount: record.amount,
+                correlation_id: record.correlation_id.as_deref(),
+            };
+            if let Some(duplicate_writer) = &mut duplicate_writer {
+                duplicate_writer.serialize(&report)?;
+            }
+            if let Some(diagnostics) = &mut diagnostics {
+                diagnostics.report(Diagnostic::Duplicate(&report))?;
+            }
+        } else if !already_handled {
+            let started = hot_accounts.as_ref().map(|_| std::time::Instant::now());
+            let record_number = state.records_processed + 1;
+            let outcome = apply_record(
+                state.payment_engine_mut(record.tenant.as_deref()),
+                &record,
+                record_number,
+            )?;
+            if let (Some(shadow), None) = (&mut state.shadow, &record.tenant) {
+                apply_record(shadow, &record, record_number)?;
+                // Its events would only repeat those of the regular engine.
+                shadow.take_events();
+            }
+            if let (Some(hot_accounts), Some(started)) = (&mut hot_accounts, started) {
+                hot_accounts.record_applied(record.client, started.elapsed());
+            }
+            if options.command == Command::Daily
+                && outcome == Outcome::Applied
+                && record.tenant.is_none()
+            {
+                let day = record.date.as_deref().map(|date| day_of(date).to_string());
+                state.daily.entry(day).or_default().record_applied(&record);
+            }
+            if let (Some(aml_monitor), Outcome::Applied) = (&mut aml_monitor, outcome) {
+                aml_monitor.record_applied(&record, record_number)?;
+            }
+            if outcome == Outcome::Rejected(RejectionReason::Disabled) {
+                disabled += 1;
+            } else if let Outcome::Rejected(reason) = outcome {
+                rejected += 1;
+                if record.tenant.is_none() {
+                    *state.rejections.entry(record.client).or_default() += 1;
+                }
+                let report = RawRejectionRecord {
+                    record: state.records_processed + 1,
+                    record_type: record.record_type,
+                    client: record.client,
+                    tx: record.tx_label(),
+                    reason,
+                    correlation_id: record.correlation_id.as_deref(),
+                };
+                if let Some(rejection_writer) = &mut rejection_writer {
+                    rejection_writer.serialize(&report)?;
+                }
+                if let Some(diagnostics) = &mut diagnostics {
+                    diagnostics.report(Diagnostic::Rejection(&report))?;
+                }
+                if let (Some(dead_letter_writer), RejectionReason::Screened) =
+                    (&mut dead_letter_writer, reason)
+                {
+                    dead_letter_writer.serialize(RawDeadLetterRecord {
+                        record_type: record.record_type,
+                        client: record.client,
+                        tx: record.tx_label(),
+                        amount: record.amount,
+                        correlation_id: record.correlation_id.as_deref(),
+                        idempotency_key: record.idempotency_key.as_deref(),
+                        tenant: record.tenant.as_deref(),
+                        date: record.date.as_deref(),
+                        record: record_number,
+                        stage: DeadLetterStage::Screening,
+                        reason,
+                    })?;
+                }
+            }
+        }
+        // The CLI has no other use for the events, taking them keeps them from piling up in the engine.
+        for event in state
+            .payment_engine_mut(record.tenant.as_deref())
+            .take_events()
+        {
+            if let Some(diagnostics) = &mut diagnostics {
+                diagnostics.report(Diagnostic::Event(&event))?;
+            }
+        }
+
+        state.records_processed += 1;
+        if let Some(diagnostics) = &mut diagnostics {
+            if state
+                .records_processed
+                .is_multiple_of(Diagnostics::<std::io::StderrLock>::PROGRESS_EVERY)
+            {
+                diagnostics.report(Diagnostic::Progress {
+                    records: state.records_processed,
+                    memory_bytes: state.payment_engine.approx_memory_bytes(),
+                })?;
+            }
+        }
+        if let Some(snapshotter) = &mut snapshotter {
+            snapshotter.record_processed(&state.payment_engine, state.records_processed)?;
+        }
+        if let Some(checkpoint_options) = &options.checkpoints {
+            if state
+                .records_processed
+                .is_multiple_of(checkpoint_options.every_records)
+            {
+                Checkpoint::store(
+                    checkpoint_options,
+                    &options.at_rest,
+                    state,
+                    records.position(),
+                )?;
+            }
+        }
+    }
+
+    match options.command {
+        Command::Accounts => write_client_states(
+            state.payment_engine.get_all_client_states(),
+            writer,
+            with_metadata,
+            state.payment_engine.dormancy(),
+        )?,
+        Command::History { client } => write_history(state, client, writer)?,
+        Command::Disputes => write_disputes(state, writer)?,
+        Command::Top { top } => write_top_accounts(state, top, writer)?,
+        Command::Daily => write_daily_totals(state, writer)?,
+        Command::Audit { ref verify } => write_audit_heads(state, verify.as_deref(), writer)?,
+    }
+    if let Some(directory) = &options.tenant_output {
+        if !state.tenants.is_empty() {
+            std::fs::create_dir_all(directory)?;
+        }
+        for (tenant, payment_engine) in state.tenants.tenants() {
+            write_atomically(&directory.join(format!("{}.csv", tenant)), |writer| {
+                write_client_states(
+                    payment_engine.get_all_client_states(),
+                    writer,
+                    false,
+                    payment_engine.dormancy(),
+                )
+            })?;
+        }
+    }
+    if let (Some(diagnostics), Some(hot_accounts), Some(n)) =
+        (&mut diagnostics, &hot_accounts, options.hot_accounts)
+    {
+        let applied: u64 = hot_accounts.per_client.values().map(|l| l.records).sum();
+        for (client, load) in hot_accounts.top(n) {
+            diagnostics.report(Diagnostic::HotAccount {
+                client,
+                records: load.records,
+                share: load.records as f64 / applied as f64,
+                time_us: load.time.as_micros(),
+            })?;
+        }
+    }
+    if let Some(diagnostics) = &mut diagnostics {
+        diagnostics.report(Diagnostic::Summary {
+            records: state.records_processed,
+            rejected,
+            disabled,
+            duplicates,
+            memory_bytes: state.payment_engine.approx_memory_bytes(),
+        })?;
+    }
+    if let Some(snapshotter) = &mut snapshotter {
+        snapshotter.finish()?;
+    }
+    if let Some(rejection_writer) = &mut rejection_writer {
+        rejection_writer.flush()?;
+    }
+    if let Some(dead_letter_writer) = &mut dead_letter_writer {
+        dead_letter_writer.flush()?;
+    }
+    if let Some(aml_monitor) = aml_monitor {
+        aml_monitor.finish()?;
+    }
+    if let (Some(shadow_options), Some(shadow)) = (&options.shadow, &state.shadow) {
+        let mut shadow_writer = csv::Writer::from_path(&shadow_options.report)?;
+        for comparison in state.payment_engine.compare(shadow) {
+            shadow_writer.serialize(RawComparisonRecord::from(comparison))?;
+        }
+        shadow_writer.flush()?;
+    }
+    if let Some(duplicate_writer) = &mut duplicate_writer {
+        duplicate_writer.flush()?;
+    }
+
+    #[cfg(feature = "webhooks")]
+    if let Some(webhooks) = &webhooks {
+        webhooks.close();
+    }
+
+    if let Some(checkpoint_options) = &options.checkpoints {
+        if options.shutdown.load(Ordering::SeqCst) {
+            // The run was cut short, a next run with the same flag picks up after the last record applied.
+            Checkpoint::store(
+                checkpoint_options,
+                &options.at_rest,
+                state,
+                records.position(),
+            )?;
+        } else {
+            // The run is complete, a next run with the same flag should start from scratch.
+            Checkpoint::remove(checkpoint_options)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_record(
+    payment_engine: &mut PaymentEngine,
+    record: &RawInputRecord,
+    record_number: u64,
+) -> Result<Outcome, IoPipelineError> {
+    // An invalid amount only rejects the record, a missing one means the input itself is broken.
+    let outcome = match Record::try_from(record) {
+        Ok(record) => payment_engine.apply(record),
+        Err(InvalidRecord::Amount(error)) => Outcome::Rejected(match error {
+            AmountError::ExcessPrecision(_) => RejectionReason::ExcessPrecision,
+            AmountError::Negative(_) => RejectionReason::NegativeAmount,
+            AmountError::OutOfRange(_) => RejectionReason::BalanceOverflow,
+        }),
+        Err(InvalidRecord::MissingAmount) => {
+            return Err(IoPipelineError::MissingAmount {
+                record: record_number,
+                correlation_id: record.correlation_id.clone(),
+            })
+        }
+    };
+
+    Ok(outcome)
+}
+
+/// How state that outlives a run is written to disk, see `--encrypt`.
+#[derive(Debug, Clone, Default)]
+enum AtRest {
+    #[default]
+    Plain,
+    /// Files are sealed with AES-GCM, see [`banking::encryption`].
+    #[cfg(feature = "encryption")]
+    Encrypted(Box<Encryptor>),
+}
+
+impl AtRest {
+    /// Has `write` write to a temporary file next to `path`, which only replaces `path` once it's complete.
+    /// A crash while writing must not corrupt the previous version, and a reader never picks up a half-written file.
+    fn write_file(
+        &self,
+        path: &std::path::Path,
+        write: impl FnOnce(&mut dyn std::io::Write) -> Result<(), IoPipelineError>,
+    ) -> Result<(), IoPipelineError> {
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+
+        match self {
+            AtRest::Plain => {
+                let mut file = std::io::BufWriter::new(std::fs::File::create(&temporary_path)?);
+                write(&mut file)?;
+                std::io::Write::flush(&mut file)?;
+                file.get_ref().sync_all()?;
+            }
+            // Only complete plaintexts can be sealed, the file is buffered in memory first.
+            #[cfg(feature = "encryption")]
+            AtRest::Encrypted(encryptor) => {
+                let mut plaintext = vec![];
+                write(&mut plaintext)?;
+                let mut file = std::fs::File::create(&temporary_path)?;
+                std::io::Write::write_all(&mut file, &encryptor.encrypt(&plaintext))?;
+                file.sync_all()?;
+            }
+        }
+        std::fs::rename(&temporary_path, path)?;
+
+        Ok(())
+    }
+
+    /// `None` when there's no file at `path`.
+    fn read_file(&self, path: &std::path::Path) -> Result<Option<Vec<u8>>, IoPipelineError> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match self {
+            AtRest::Plain => Ok(Some(contents)),
+            #[cfg(feature = "encryption")]
+            AtRest::Encrypted(encryptor) => Ok(Some(encryptor.decrypt(&contents)?)),
+        }
+    }
+
+    /// Of a file with `extension`, encrypted files are marked as such.
+    fn extension(&self, extension: &str) -> String {
+        match self {
+            AtRest::Plain => extension.to_string(),
+            #[cfg(feature = "encryption")]
+            AtRest::Encrypted(_) => format!("{}.enc", extension),
+        }
+    }
+}
+
+/// `None` when there's no file at `path`.
+fn load_json<V: serde::de::DeserializeOwned>(
+    path: &std::path::Path,
+    at_rest: &AtRest,
+) -> Result<Option<V>, IoPipelineError> {
+    match at_rest.read_file(path)? {
+        Some(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        None => Ok(None),
+    }
+}
+
+fn store_json(
+    path: &std::path::Path,
+    at_rest: &AtRest,
+    value: &impl Serialize,
+) -> Result<(), IoPipelineError> {
+    at_rest.write_file(path, |file| Ok(serde_json::to_writer(file, value)?))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    state: PipelineState,
+    byte: u64,
+    line: u64,
+    record: u64,
+}
+
+impl Checkpoint {
+    const FILE_NAME: &'static str = "checkpoint.json";
+
+    fn load(
+        options: &CheckpointOptions,
+        at_rest: &AtRest,
+    ) -> Result<Option<Self>, IoPipelineError> {
+        load_json(&options.directory.join(Self::FILE_NAME), at_rest)
+    }
+
+    fn store(
+        options: &CheckpointOptions,
+        at_rest: &AtRest,
+        state: &PipelineState,
+        position: &csv::Position,
+    ) -> Result<(), IoPipelineError> {
+        #[derive(Serialize)]
+        struct CheckpointRef<'a> {
+            state: &'a PipelineState,
+            byte: u64,
+            line: u64,
+            record: u64,
+        }
+
+        std::fs::create_dir_all(&options.directory)?;
+        store_json(
+            &options.directory.join(Self::FILE_NAME),
+            at_rest,
+            &CheckpointRef {
+                state,
+                byte: position.byte(),
+                line: position.line(),
+                record: position.record(),
+            },
+        )
+    }
+
+    fn remove(options: &CheckpointOptions) -> Result<(), IoPipelineError> {
+        match std::fs::remove_file(options.directory.join(Self::FILE_NAME)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn position(&self) -> csv::Position {
+        let mut position = csv::Position::new();
+        position
+            .set_byte(self.byte)
+            .set_line(self.line)
+            .set_record(self.record);
+        position
+    }
+}
+
+type SnapshotWrite = std::thread::JoinHandle<Result<(), IoPipelineError>>;
+
+struct Snapshotter<'a> {
+    options: &'a SnapshotOptions,
+    /// See [`write_client_states`].
+    with_metadata: bool,
+    at_rest: &'a AtRest,
+    written: VecDeque<PathBuf>,
+    /// The snapshot currently being written on a background thread, if any.
+    pending: Option<(PathBuf, SnapshotWrite)>,
+}
+
+impl<'a> Snapshotter<'a> {
+    fn new(options: &'a SnapshotOptions, with_metadata: bool, at_rest: &'a AtRest) -> Self {
+        Self {
+            options,
+            with_metadata,
+            at_rest,
+            written: VecDeque::new(),
+            pending: None,
+        }
+    }
+
+    fn record_processed(
+        &mut self,
+        payment_engine: &PaymentEngine,
+        records_processed: u64,
+    ) -> Result<(), IoPipelineError> {
+        let Some(every_records) = self.options.every_records else {
+            return Ok(());
+        };
+        if !records_processed.is_multiple_of(every_records) {
+            return Ok(());
+        }
+        self.write(payment_engine, records_processed)?;
+        Ok(())
+    }
+
+    /// Starts writing a snapshot of the accounts after `records_processed` records, returns its path.
+    fn write(
+        &mut self,
+        payment_engine: &PaymentEngine,
+        records_processed: u64,
+    ) -> Result<PathBuf, IoPipelineError> {
+        self.finish()?;
+
+        let path = self.options.directory.join(format!(
+            "snapshot-{:020}.{}",
+            records_processed,
+            self.at_rest.extension("csv")
+        ));
+        // The snapshot is written on another thread, so processing doesn't have to wait for it.
+        let snapshot = payment_engine.snapshot();
+        let snapshot_path = path.clone();
+        let with_metadata = self.with_metadata;
+        let dormancy = payment_engine.dormancy();
+        let at_rest = self.at_rest.clone();
+        let handle = std::thread::spawn(move || {
+            at_rest.write_file(&snapshot_path, |file| {
+                write_client_states(
+                    snapshot.get_all_client_states(),
+                    csv::Writer::from_writer(file),
+                    with_metadata,
+                    dormancy,
+                )
+            })
+        });
+        self.pending = Some((path.clone(), handle));
+
+        Ok(path)
+    }
+
+    /// Waits for the snapshot that is being written, and rotates out the oldest ones.
+    fn finish(&mut self) -> Result<(), IoPipelineError> {
+        let (path, handle) = match self.pending.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        handle
+            .join()
+            .map_err(|_| IoPipelineError::SnapshotPanicked)??;
+
+        // Without records in between, the snapshot replaced the previous one.
+        if self.written.back() != Some(&path) {
+            self.written.push_back(path);
+        }
+        while self.written.len() > self.options.keep {
+            let oldest = self.written.pop_front().expect("More snapshots than kept.");
+            std::fs::remove_file(oldest)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Feeds every statement line to the engine, the account identification of a statement is used as the client id.
+/// Since MT940 has no numeric transaction ids, they are assigned in order of appearance, including to the lines the
+/// [`ReplayFilter`] skips by their value date.
+#[cfg(feature = "mt940")]
+fn process_mt940<W: std::io::Write>(
+    input: &str,
+    writer: csv::Writer<W>,
+    options: &PipelineOptions,
+) -> Result<AuditHash, IoPipelineError> {
+    let statements = banking::mt940::parse(input)?;
+    let mut transaction_id: u32 = 0;
+    let records = statements.iter().flat_map(|statement| {
+        let records: Vec<_> = match statement.account.parse::<u16>() {
+            Ok(account) => {
+                let client = options
+                    .pseudonyms
+                    .as_ref()
+                    .map_or(account, |p| p.client(account));
+                statement
+                    .lines
+                    .iter()
+                    .filter_map(|line| {
+                        transaction_id += 1;
+                        let date = line.value_date.to_string();
+                        options.filter.admits(Some(account), Some(&date)).then(|| {
+                            Ok(Record::Transaction(
+                                line.to_transaction(client, transaction_id),
+                            ))
+                        })
+                    })
+                    .collect()
+            }
+            Err(_) => vec![Err(IoPipelineError::Mt940Account(
+                statement.account.clone(),
+            ))],
+        };
+        records
+    });
+
+    // Configured like the engine of CSV input, see `process_from`.
+    let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+        .config(options.engine_config.clone())
+        .build();
+    for (client, metadata) in &options.client_metadata {
+        payment_engine.set_client_metadata(*client, metadata.clone());
+    }
+    if let Some(blocklist) = &options.blocklist {
+        payment_engine.set_screening(blocklist.clone());
+    }
+    let dormancy = payment_engine.dormancy();
+    pipeline::run_on(
+        &mut payment_engine,
+        records,
+        CsvAccountSink::new(writer, !options.client_metadata.is_empty(), dormancy),
+    )
+    .map_err(|error| match error {
+        PipelineError::Input { source, .. } => source,
+        PipelineError::Sink(error) => error,
+    })?;
+
+    Ok(payment_engine.merkle_root())
+}
+
+/// Writes the account of `client` and removes it from the engines, see [`PipelineState::finalized`].
+fn finalize_client<W: std::io::Write>(
+    state: &mut PipelineState,
+    client: u16,
+    writer: &mut csv::Writer<W>,
+    options: &PipelineOptions,
+    record_number: u64,
+) -> Result<(), IoPipelineError> {
+    // A resumed run writes its output from scratch, it wouldn't include the accounts written before.
+    if options.checkpoints.is_some() || options.backfill.is_some() {
+        return Err(IoPipelineError::FinalizeNotResumable {
+            record: record_number,
+        });
+    }
+    let dormancy = state.payment_engine.dormancy();
+    if let Some(account) = state.payment_engine.remove_client(client) {
+        write_account(
+            writer,
+            &account,
+            !options.client_metadata.is_empty(),
+            dormancy,
+        )?;
+        writer.flush()?;
+    }
+    if let Some(shadow) = &mut state.shadow {
+        shadow.remove_client(client);
+    }
+    state.finalized.insert(client);
+    Ok(())
+}
+
+/// Writes the transactions of `client` in the order they were applied, nothing when it has no account.
+fn write_history<W: std::io::Write>(
+    state: &PipelineState,
+    client: u16,
+    mut writer: csv::Writer<W>,
+) -> Result<(), IoPipelineError> {
+    let references = state.transaction_ids.references();
+    let mut balance = Decimal::ZERO;
+    let history = state
+        .payment_engine
+        .get_client_state(client)
+        .map(ClientAccount::history)
+        .unwrap_or_default();
+    for entry in history {
+        balance += Decimal::from(entry.balance_change());
+        let (record_type, transaction_id, amount) = match *entry.transaction {
+            Transaction::Deposit {
+                transaction_id,
+                amount,
+                ..
+            } => (RawRecordType::Deposit, transaction_id, amount),
+            Transaction::Withdrawal {
+                transaction_id,
+                amount,
+                ..
+            } => (RawRecordType::Withdrawal, transaction_id, amount),
+            Transaction::WithdrawalRequest {
+                transaction_id,
+                amount,
+                ..
+            } => (RawRecordType::WithdrawalRequest, transaction_id, amount),
+        };
+        writer.serialize(RawHistoryRecord {
+            record_type,
+            tx: match references.get(&transaction_id) {
+                Some(reference) => Cow::Borrowed(reference),
+                None => Cow::Owned(transaction_id.to_string()),
+            },
+            amount,
+            state: entry.state,
+            balance,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the open disputes, those holding the most funds first, so they can be worked through in that order.
+fn write_disputes<W: std::io::Write>(
+    state: &PipelineState,
+    mut writer: csv::Writer<W>,
+) -> Result<(), IoPipelineError> {
+    let references = state.transaction_ids.references();
+    let mut disputes: Vec<_> = state.payment_engine.open_disputes().collect();
+    disputes.sort_by(|a, b| {
+        b.amount
+            .cmp(&a.amount)
+            .then(a.client.cmp(&b.client))
+            .then(a.transaction_id.cmp(&b.transaction_id))
+    });
+    for dispute in disputes {
+        writer.serialize(RawDisputeRecord {
+            client: dispute.client,
+            tx: match references.get(&dispute.transaction_id) {
+                Some(reference) => Cow::Borrowed(reference),
+                None => Cow::Owned(dispute.transaction_id.to_string()),
+            },
+            amount: dispute.amount,
+            age: dispute.age,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the `top` accounts with the largest total, those with the most held funds and those with the most
+/// rejected records, largest first.
+fn write_top_accounts<W: std::io::Write>(
+    state: &PipelineState,
+    top: usize,
+    mut writer: csv::Writer<W>,
+) -> Result<(), IoPipelineError> {
+    let payment_engine = &state.payment_engine;
+    let totals: Vec<_> = payment_engine
+        .query()
+        .accounts_by_descending_total()
+        .take(top)
+        .map(|account| (account.id(), Decimal::from(account.total())))
+        .collect();
+    let mut held: Vec<_> = payment_engine
+        .get_all_client_states()
+        .map(|account| (account.id(), Decimal::from(account.held())))
+        .collect();
+    let mut rejections: Vec<_> = state
+        .rejections
+        .iter()
+        .map(|(client, rejections)| (*client, Decimal::from(*rejections)))
+        .collect();
+    for ranking in [&mut held, &mut rejections] {
+        ranking.sort_by(|(a, a_value), (b, b_value)| b_value.cmp(a_value).then(a.cmp(b)));
+        ranking.truncate(top);
+    }
+
+    for (ranking, accounts) in [
+        ("total", totals),
+        ("held", held),
+        ("rejections", rejections),
+    ] {
+        for (rank, (client, value)) in accounts.into_iter().enumerate() {
+            writer.serialize(RawRankingRecord {
+                ranking,
+                rank: rank + 1,
+                client,
+                value,
+            })?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the totals of every day in chronological order, followed by those of the records without a date.
+fn write_daily_totals<W: std::io::Write>(
+    state: &PipelineState,
+    mut writer: csv::Writer<W>,
+) -> Result<(), IoPipelineError> {
+    let (undated, dated): (Vec<_>, Vec<_>) = state.daily.iter().partition(|(day, _)| day.is_none());
+    for (day, totals) in dated.into_iter().chain(undated) {
+        writer.serialize(RawDailyRecord {
+            day: day.as_deref(),
+            deposits: totals.deposits,
+            withdrawals: totals.withdrawals,
+            disputes_opened: totals.disputes_opened,
+            chargebacks: totals.chargebacks,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the audit head of every account by client. With `verify`, a file written by an earlier run, the heads
+/// are checked against it first and nothing is written unless every client has the same head in both.
+fn write_audit_heads<W: std::io::Write>(
+    state: &PipelineState,
+    verify: Option<&std::path::Path>,
+    mut writer: csv::Writer<W>,
+) -> Result<(), IoPipelineError> {
+    let mut heads: Vec<_> = state
+        .payment_engine
+        .get_all_client_states()
+        .map(|account| RawAuditRecord {
+            client: account.id(),
+            audit_head: account
+                .audit_head()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        })
+        .collect();
+    heads.sort_by_key(|head| head.client);
+
+    if let Some(path) = verify {
+        let mut expected = HashMap::new();
+        for record in csv::Reader::from_path(path)?.deserialize() {
+            let record: RawAuditRecord = record?;
+            expected.insert(record.client, record.audit_head);
+        }
+        let mut clients: Vec<u16> = heads
+            .iter()
+            .filter(|head| expected.remove(&head.client).as_ref() != Some(&head.audit_head))
+            .map(|head| head.client)
+            .collect();
+        // Clients that are verified but have no account anymore.
+        clients.extend(expected.into_keys());
+        if !clients.is_empty() {
+            clients.sort_unstable();
+            return Err(IoPipelineError::AuditMismatch { clients });
+        }
+    }
+
+    for head in heads {
+        writer.serialize(head)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// `with_metadata` adds the columns of [`ClientMetadata`], when they have been loaded with `--client-metadata`.
+fn write_client_states<'a, W: std::io::Write>(
+    client_states: impl Iterator<Item = &'a ClientAccount>,
+    writer: csv::Writer<W>,
+    with_metadata: bool,
+    dormancy: Option<Dormancy>,
+) -> Result<(), IoPipelineError> {
+    pipeline::emit(
+        client_states,
+        CsvAccountSink::new(writer, with_metadata, dormancy),
+    )
+}
+
+/// See [`write_client_states`].
+fn write_account<W: std::io::Write>(
+    writer: &mut csv::Writer<W>,
+    account: &ClientAccount,
+    with_metadata: bool,
+    dormancy: Option<Dormancy>,
+) -> Result<(), IoPipelineError> {
+    let status = AccountStatus::of(account, dormancy);
+    if with_metadata {
+        writer.serialize(RawOutputRecordWithMetadata {
+            status,
+            ..RawOutputRecordWithMetadata::from(account)
+        })?;
+    } else {
+        writer.serialize(RawOutputRecord {
+            status,
+            ..RawOutputRecord::from(account)
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes every account as a row of the CSV output.
+struct CsvAccountSink<W: std::io::Write> {
+    writer: csv::Writer<W>,
+    /// See [`write_client_states`].
+    with_metadata: bool,
+    dormancy: Option<Dormancy>,
+}
+
+impl<W: std::io::Write> CsvAccountSink<W> {
+    fn new(writer: csv::Writer<W>, with_metadata: bool, dormancy: Option<Dormancy>) -> Self {
+        Self {
+            writer,
+            with_metadata,
+            dormancy,
+        }
+    }
+}
+
+impl<W: std::io::Write> AccountSink for CsvAccountSink<W> {
+    type Error = IoPipelineError;
+
+    fn emit(&mut self, account: &ClientAccount) -> Result<(), Self::Error> {
+        write_account(&mut self.writer, account, self.with_metadata, self.dormancy)
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn single_row_with_header_and_leading_spaces() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 1.0"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        dbg!(std::str::from_utf8(&output[..]).unwrap());
+
+        assert_eq!(
+            output,
+            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        )
+    }
+
+    #[test]
+    fn single_row_with_header_and_no_leading_spaces() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type,client,tx,amount
+deposit,1,1,1.0"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        assert_eq!(
+            output,
+            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        )
+    }
+    #[test]
+    fn single_row_no_header_and_no_leading_spaces() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(&br#"deposit,1,1,1.0"#[..]);
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        assert_eq!(
+            output,
+            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        )
+    }
+
+    #[test]
+    fn check_example_csv() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        let output_str = std::str::from_utf8(&output[..]).unwrap();
+
+        assert!(output_str.contains("1,1.5,0,1.5,false"));
+        assert!(output_str.contains("2,2.0,0,2.0,false"));
+    }
+
+    /// Runs every `tests/fixtures/<case>.csv` through `process` and compares the accounts, ordered by client, with
+    /// `<case>.expected.csv`. Flags for a case go in `<case>.args`.
+    /// With `BLESS=1` the expected outputs are written instead, to review as part of an intended change of behavior.
+    #[test]
+    fn golden_files() {
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let bless = std::env::var_os("BLESS").is_some();
+        let mut inputs: Vec<PathBuf> = std::fs::read_dir(&fixtures)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension() == Some("csv".as_ref())
+                    && !path.to_string_lossy().ends_with(".expected.csv")
+            })
+            .collect();
+        inputs.sort();
+        assert!(!inputs.is_empty());
+
+        for input in inputs {
+            let args = std::fs::read_to_string(input.with_extension("args")).unwrap_or_default();
+            let options = Options::parse(
+                std::iter::once(input.to_string_lossy().into_owned())
+                    .chain(args.split_whitespace().map(String::from)),
+            )
+            .unwrap();
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_path(&input)
+                .unwrap();
+            let mut output: Vec<u8> = vec![];
+            process(
+                reader,
+                csv::Writer::from_writer(&mut output),
+                &options.pipeline,
+            )
+            .unwrap();
+
+            let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+            lines[1..].sort_by_key(|line| line.split(',').next().unwrap().parse::<u16>().unwrap());
+            let actual = lines.join("\n") + "\n";
+            let expected_path = input.with_extension("expected.csv");
+            if bless {
+                std::fs::write(&expected_path, actual).unwrap();
+            } else {
+                let expected = std::fs::read_to_string(&expected_path).unwrap_or_default();
+                assert_eq!(
+                    actual,
+                    expected,
+                    "{} doesn't match {}, run with BLESS=1 to update it",
+                    input.display(),
+                    expected_path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backfills_skip_records_that_were_already_applied() {
+        let state =
+            std::env::temp_dir().join(format!("banking-backfill-{}.json", std::process::id()));
+        let options = PipelineOptions {
+            backfill: Some(BackfillOptions {
+                state: state.clone(),
+                input: "day-1.csv".to_string(),
+            }),
+            ..Default::default()
+        };
+        let run = |input: &'static [u8]| {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(input);
+            let mut output: Vec<u8> = vec![];
+            let writer = csv::Writer::from_writer(&mut output);
+            process_backfill(reader, writer, &options).map(|_| output)
+        };
+
+        // The third record stops the first run, after the first two have been applied.
+        let result = run(
+            b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 1, 3,",
+        );
+        assert!(matches!(
+            result,
+            Err(IoPipelineError::MissingAmount { record: 3, .. })
+        ));
+
+        let output = run(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 1, 3, 3.0\ndeposit, 1, 4, 4.0").unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,10.0,0,10.0,false\n"
+        );
+        let stored: PipelineState = load_json(&state, &AtRest::Plain).unwrap().unwrap();
+        assert_eq!(stored.watermarks["day-1.csv"], 4);
+
+        std::fs::remove_file(&state).unwrap();
+    }
+
+    #[test]
+    fn tenants_have_their_own_accounts() {
+        let directory =
+            std::env::temp_dir().join(format!("banking-tenants-{}", std::process::id()));
+        let input = b"type, client, tx, amount, tenant\ndeposit, 1, 1, 1.0,\ndeposit, 1, 1, 2.0, a\ndeposit, 1, 1, 3.0, b";
+        let reader = |input: &'static [u8]| {
+            csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(input)
+        };
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let err = process(reader(input), writer, &PipelineOptions::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            IoPipelineError::TenantWithoutOutput { record: 2 }
+        ));
+
+        let options = PipelineOptions {
+            tenant_output: Some(directory.clone()),
+            ..Default::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader(input), writer, &options).unwrap();
+        let header = "client,available,held,total,locked\n";
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            format!("{}1,1.0,0,1.0,false\n", header)
+        );
+        for (tenant, balance) in [("a", "2.0"), ("b", "3.0")] {
+            assert_eq!(
+                std::fs::read_to_string(directory.join(format!("{}.csv", tenant))).unwrap(),
+                format!("{}1,{},0,{},false\n", header, balance, balance)
+            );
+        }
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let err = process(
+            reader(b"type, client, tx, amount, tenant\ndeposit, 1, 1, 1.0, ../a"),
+            writer,
+            &options,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            IoPipelineError::InvalidTenant { record: 1, .. }
+        ));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn tenant_configs_override_the_engine_config() {
+        let path = std::env::temp_dir().join(format!("banking-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"disputes": {"max_open_per_client": 3}, "tenants": {"acme": {"disputes": {"provisional_credit": true}}}}"#,
+        )
+        .unwrap();
+
+        let args = [
+            "input.csv",
+            "--precision",
+            "round",
+            "--config",
+            path.to_str().unwrap(),
+        ];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        let engine_config = &options.pipeline.engine_config;
+        assert_eq!(engine_config.disputes.max_open_per_client, Some(3));
+        assert_eq!(engine_config.precision, PrecisionPolicy::Round);
+        assert!(!engine_config.disputes.provisional_credit);
+
+        let acme = &options.pipeline.tenant_configs["acme"];
+        assert_eq!(acme.disputes.max_open_per_client, Some(3));
+        assert_eq!(acme.precision, PrecisionPolicy::Round);
+        assert!(acme.disputes.provisional_credit);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn config_files_are_read_as_toml() {
+        let path = std::env::temp_dir().join(format!("banking-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "precision = \"round\"\n\n[disputes]\nmax_open_per_client = 2\n\n[tenants.acme.disputes]\nprovisional_credit = true\n",
+        )
+        .unwrap();
+
+        let args = ["input.csv", "--config", path.to_str().unwrap()];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        let engine_config = &options.pipeline.engine_config;
+        assert_eq!(engine_config.disputes.max_open_per_client, Some(2));
+        assert_eq!(engine_config.precision, PrecisionPolicy::Round);
+        assert!(!engine_config.disputes.provisional_credit);
+        assert!(
+            options.pipeline.tenant_configs["acme"]
+                .disputes
+                .provisional_credit
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn client_metadata_is_added_to_the_output() {
+        let path =
+            std::env::temp_dir().join(format!("banking-metadata-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "client, name, email, country, tier\n1, Ada, ada@example.com, BE, gold\n3, Bob, , NL,\n",
+        )
+        .unwrap();
+        let options = PipelineOptions {
+            client_metadata: load_client_metadata(path.to_str().unwrap()).unwrap(),
+            ..Default::default()
+        };
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(&b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0"[..]);
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options).unwrap();
+
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines[1..].sort_unstable();
+        assert_eq!(
+            lines,
+            vec![
+                "client,available,held,total,locked,name,email,country,tier",
+                "1,1.0,0,1.0,false,Ada,ada@example.com,BE,gold",
+                "2,2.0,0,2.0,false,,,,",
+            ]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn large_transactions_and_structuring_are_reported() {
+        let path = std::env::temp_dir().join(format!("banking-aml-{}.csv", std::process::id()));
+        let options = PipelineOptions {
+            aml: Some(AmlOptions {
+                report: path.clone(),
+                threshold: Some(dec!(100)),
+                daily_deposit_limit: Some(dec!(150)),
+            }),
+            ..Default::default()
+        };
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount, date
+deposit, 1, 1, 200.0, 2024-05-01
+withdrawal, 1, 2, 500.0, 2024-05-01
+deposit, 2, 3, 90.0, 2024-05-01
+deposit, 2, 4, 90.0, 2024-05-01
+deposit, 2, 5, 90.0, 2024-05-02"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options).unwrap();
+
+        // The withdrawal was rejected, so it didn't move any funds.
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "flag,record,tenant,client,tx,date,amount
+large_transaction,1,,1,1,2024-05-01,200.0
+structuring,,,1,,2024-05-01,200.0
+structuring,,,2,,2024-05-01,180.0
+"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn output_is_replaced_atomically() {
+        let path = std::env::temp_dir().join(format!("banking-output-{}.csv", std::process::id()));
+        std::fs::write(&path, "previous").unwrap();
+
+        let input = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+        let result = write_atomically(&path, |writer| {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(&b"type, client, tx, amount\ndeposit, 1, 1,"[..]);
+            process(reader, writer, &PipelineOptions::default())
+        });
+        assert!(matches!(result, Err(IoPipelineError::MissingAmount { .. })));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+
+        write_atomically(&path, |writer| {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(&input[..]);
+            process(reader, writer, &PipelineOptions::default())
+        })
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        );
+        assert!(!path.with_extension("csv.tmp").exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshots_are_written_and_rotated() {
+        let directory =
+            std::env::temp_dir().join(format!("banking-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+deposit, 1, 3, 1.0
+deposit, 1, 4, 1.0
+deposit, 1, 5, 1.0"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+
+        let options = PipelineOptions {
+            snapshots: Some(SnapshotOptions {
+                every_records: Some(2),
+                directory: directory.clone(),
+                keep: 1,
+            }),
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
+
+        let snapshots: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(
+            snapshots,
+            vec![directory.join("snapshot-00000000000000000004.csv")]
+        );
+        assert_eq!(
+            std::fs::read(&snapshots[0]).unwrap(),
+            b"client,available,held,total,locked\n1,4.0,0,4.0,false\n"
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejections_are_reported_with_correlation_id() {
+        let path =
+            std::env::temp_dir().join(format!("banking-rejections-{}.csv", std::process::id()));
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount, correlation_id
+deposit, 1, 1, 1.0, msg-1
+withdrawal, 1, 2, 5.0, msg-2
+resolve, 1, 1, , msg-3"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            rejections: Some(path.clone()),
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "record,type,client,tx,reason,correlation_id
+2,withdrawal,1,2,insufficient_funds,msg-2
+3,resolve,1,1,invalid_state,msg-3
+"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dormant_accounts_have_a_status() {
+        let options = Options::parse(
+            ["input.csv", "--dormant-after", "2"]
+                .into_iter()
+                .map(String::from),
+        )
+        .unwrap();
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 2, 3, 2.0"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options.pipeline).unwrap();
+
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines[1..].sort();
+        assert_eq!(
+            lines,
+            vec![
+                "client,available,held,total,locked,status",
+                "1,1.0,0,1.0,false,dormant",
+                "2,4.0,0,4.0,false,active",
+            ]
+        );
+    }
+
+    #[test]
+    fn withdrawal_requests_are_confirmed_or_cancelled() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal_request, 1, 2, 3.0
+withdrawal_request, 1, 3, 4.0
+withdrawal_request, 1, 4, 1.0
+withdrawal_confirm, 1, 2,
+withdrawal_cancel, 1, 3,"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,6.0,1.0,7.0,false\n"
+        );
+    }
+
+    #[test]
+    fn disabled_record_types_are_ignored() {
+        let options = Options::parse(
+            ["input.csv", "--disable", "chargeback"]
+                .into_iter()
+                .map(String::from),
+        )
+        .unwrap();
+        assert!(Options::parse(
+            ["input.csv", "--disable", "bogus"]
+                .into_iter()
+                .map(String::from)
+        )
+        .is_err());
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+chargeback, 1, 1,"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options.pipeline).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,0.0,5.0,5.0,false\n"
+        );
+    }
+
+    #[test]
+    fn replays_are_filtered_by_client_and_date() {
+        let options = Options::parse(
+            [
+                "input.csv",
+                "--clients",
+                "1, 3",
+                "--since",
+                "2024-05-02",
+                "--until",
+                "2024-05-31",
+            ]
+            .into_iter()
+            .map(String::from),
+        )
+        .unwrap();
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount, date
+deposit, 1, 1, 1.0, 2024-05-01
+deposit, 1, 2, 2.0, 2024-05-02
+deposit, 2, 3, 4.0, 2024-05-03
+deposit, 3, 4, 8.0, 2024-05-31
+deposit, 3, 5, 16.0, 2024-06-01
+deposit, 3, 6, 32.0,"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options.pipeline).unwrap();
+
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines[1..].sort();
+        assert_eq!(
+            lines,
+            vec![
+                "client,available,held,total,locked",
+                "1,2.0,0,2.0,false",
+                "3,8.0,0,8.0,false",
+            ]
+        );
+    }
+
+    #[test]
+    fn debts_are_written_off() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 5.0
+dispute, 1, 1,
+chargeback, 1, 1,
+write_off, 1, 3,"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,0,0.0,0.0,true\n"
+        );
+    }
+
+    #[test]
+    fn accounts_are_opened_and_closed_by_records() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 5.0
+open, 1, 2, 5.0
+open, 2, 3,
+withdrawal, 1, 4, 1.0
+close, 1, 5,
+deposit, 1, 6, 1.0"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            engine_config: EngineConfig {
+                account_creation: banking::config::AccountCreation::Registered,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
+
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines[1..].sort();
+        assert_eq!(
+            lines,
+            vec![
+                "client,available,held,total,locked",
+                "1,4.0,0,4.0,false",
+                "2,0,0,0,false",
+            ]
+        );
+    }
+
+    #[test]
+    fn blocklisted_clients_are_screened() {
+        let directory =
+            std::env::temp_dir().join(format!("banking-blocklist-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let blocklist = directory.join("blocklist.txt");
+        std::fs::write(&blocklist, "# sanctioned\n2\n\n").unwrap();
+        let rejections = directory.join("rejections.csv");
+        let dead_letters = directory.join("dead-letters.csv");
+        let options = PipelineOptions {
+            blocklist: Some(std::sync::Arc::new(
+                load_blocklist(blocklist.to_str().unwrap(), None).unwrap(),
+            )),
+            rejections: Some(rejections.clone()),
+            dead_letters: Some(dead_letters.clone()),
+            ..Default::default()
+        };
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(&b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0"[..]);
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&rejections).unwrap(),
+            "record,type,client,tx,reason,correlation_id\n2,deposit,2,2,screened,\n"
+        );
+
+        // Dead letters can be re-submitted as they are.
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_path(&dead_letters)
+            .unwrap();
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n2,2.0,0,2.0,false\n"
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn clients_are_pseudonymized() {
+        let pseudonyms = Pseudonyms {
+            key: b"secret".to_vec(),
+        };
+        let all: HashSet<u16> = (0..=u16::MAX).map(|c| pseudonyms.client(c)).collect();
+        assert_eq!(all.len(), usize::from(u16::MAX) + 1);
+        let other = Pseudonyms {
+            key: b"other".to_vec(),
+        };
+        assert!((1..=10).any(|c| pseudonyms.client(c) != other.client(c)));
+
+        let directory =
+            std::env::temp_dir().join(format!("banking-anonymize-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let blocklist = directory.join("blocklist.txt");
+        std::fs::write(&blocklist, "2\n").unwrap();
+        let options = PipelineOptions {
+            blocklist: Some(std::sync::Arc::new(
+                load_blocklist(blocklist.to_str().unwrap(), Some(&pseudonyms)).unwrap(),
+            )),
+            pseudonyms: Some(pseudonyms.clone()),
+            ..Default::default()
+        };
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(&b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0"[..]);
+        let mut output: Vec<u8> = vec![];
+        process(reader, csv::Writer::from_writer(&mut output), &options).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            format!(
+                "client,available,held,total,locked\n{},1.0,0,1.0,false\n",
+                pseudonyms.client(1)
+            )
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn shadow_engines_are_compared_per_client() {
+        let directory = std::env::temp_dir().join(format!("banking-shadow-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config = directory.join("shadow.json");
+        std::fs::write(&config, r#"{"disabled": ["chargeback"]}"#).unwrap();
+        let report = directory.join("shadow.csv");
+        let options = Options::parse(
+            [
+                "input.csv",
+                "--shadow-config",
+                config.to_str().unwrap(),
+                "--shadow-report",
+                report.to_str().unwrap(),
+            ]
+            .into_iter()
+            .map(String::from),
+        )
+        .unwrap();
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 2.0
+dispute, 1, 1,
+chargeback, 1, 1,"#[..],
+            );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &options.pipeline).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&report).unwrap(),
+            "client,available,held,total,locked,shadow_available,shadow_held,shadow_total,shadow_locked\n\
+             1,0.0,0.0,0.0,true,0.0,5.0,5.0,false\n"
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn invalid_amounts_are_rejected() {
+        let path = std::env::temp_dir().join(format!(
+            "banking-invalid-amounts-{}.csv",
+            std::process::id()
+        ));
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 1.00001
+deposit, 1, 2, -1.0
+deposit, 1, 3, 1.5000"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            rejections: Some(path.clone()),
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "record,type,client,tx,reason,correlation_id
+1,deposit,1,1,excess_precision,
+2,deposit,1,2,negative_amount,
+"
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0,1.5000,false\n"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn string_transaction_ids_are_interned() {
+        let path =
+            std::env::temp_dir().join(format!("banking-string-tx-ids-{}.csv", std::process::id()));
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 8c3e1a52-5d2f-4c4e-9a57-7f0c8e2b6d01, 3.0
+deposit, 1, 00042, 2.0
+dispute, 1, 8c3e1a52-5d2f-4c4e-9a57-7f0c8e2b6d01,
+resolve, 1, 42,"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            rejections: Some(path.clone()),
+            string_transaction_ids: true,
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,2.0,3.0,5.0,false\n"
+        );
+        // `42` is a different reference than `00042`, so it was never disputed.
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "record,type,client,tx,reason,correlation_id\n4,resolve,1,42,unknown_transaction,\n"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_amount_mentions_correlation_id() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount, correlation_id
+deposit, 1, 1, , msg-1"#[..],
+            );
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let err = process(reader, writer, &PipelineOptions::default()).unwrap_err();
 
-fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut args = std::env::args();
-    args.next(); // Skip the bin name
-    let file_path = match args.next() {
-        Some(path) => path,
-        None => {
-            return Err(
-                "No path to a file has been found, please provide it as the first argument of this executable.".into(),
+        assert!(matches!(
+            err,
+            IoPipelineError::MissingAmount { record: 1, .. }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Missing amount for record 1 (correlation id msg-1)."
+        );
+    }
+
+    #[test]
+    fn diagnostics_are_json_lines() {
+        let mut diagnostics = Diagnostics {
+            format: DiagnosticsFormat::Json,
+            out: Vec::new(),
+        };
+        diagnostics
+            .report(Diagnostic::Rejection(&RawRejectionRecord {
+                record: 2,
+                record_type: RawRecordType::Withdrawal,
+                client: 1,
+                tx: "2".into(),
+                reason: RejectionReason::InsufficientFunds,
+                correlation_id: None,
+            }))
+            .unwrap();
+        diagnostics
+            .report(Diagnostic::Summary {
+                records: 2,
+                rejected: 1,
+                disabled: 0,
+                duplicates: 0,
+                memory_bytes: 1024,
+            })
+            .unwrap();
+
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&diagnostics.out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["kind"], "rejection");
+        assert_eq!(lines[0]["record"], 2);
+        assert_eq!(lines[1]["kind"], "summary");
+        assert_eq!(lines[1]["rejected"], 1);
+    }
+
+    #[test]
+    fn records_are_parsed_in_batches() {
+        let input = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 1, 3, 3.0\nbogus, 1, 4, 4.0";
+        for batch_size in [1, 2, 1024] {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(&input[..]);
+            let mut records = RecordReader::spawn(
+                reader,
+                false,
+                batch_size,
+                ReplayFilter::default(),
+                Arc::default(),
             );
+            let mut transaction_ids = TransactionIds::default();
+            for record_number in 1..=3 {
+                let record = records
+                    .next(&mut transaction_ids, record_number)
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(u64::from(record.tx), record_number);
+            }
+            assert!(matches!(
+                records.next(&mut transaction_ids, 4),
+                Some(Err(IoPipelineError::InvalidRecord { record: 4, .. }))
+            ));
+            assert!(records.next(&mut transaction_ids, 5).is_none());
         }
-    };
+    }
 
-    let csv_reader = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .trim(csv::Trim::All)
-        .from_path(file_path)?;
+    #[test]
+    fn hottest_accounts_come_first() {
+        let mut hot_accounts = HotAccounts::default();
+        let ms = std::time::Duration::from_millis;
+        hot_accounts.record_applied(1, ms(1));
+        hot_accounts.record_applied(2, ms(2));
+        hot_accounts.record_applied(2, ms(2));
+        hot_accounts.record_applied(3, ms(1));
 
-    let csv_writer = csv::WriterBuilder::new()
-        .has_headers(true)
-        .from_writer(std::io::stdout());
+        assert_eq!(
+            hot_accounts.top(2),
+            vec![
+                (
+                    2,
+                    ClientLoad {
+                        records: 2,
+                        time: ms(4)
+                    }
+                ),
+                (
+                    1,
+                    ClientLoad {
+                        records: 1,
+                        time: ms(1)
+                    }
+                ),
+            ]
+        );
+    }
 
-    process(csv_reader, csv_writer)?;
+    #[test]
+    fn records_with_a_known_idempotency_key_are_not_reapplied() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount, correlation_id, idempotency_key
+deposit, 1, 1, 1.0, , payment-1
+deposit, 1, 2, 1.0, , payment-1
+deposit, 1, 3, 2.0, , payment-2
+deposit, 1, 4, 4.0, ,"#[..],
+            );
 
-    Ok(())
-}
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-enum RawRecordType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
+        process(reader, writer, &PipelineOptions::default()).unwrap();
 
-#[derive(Deserialize, Debug)]
-struct RawInputRecord {
-    #[serde(rename = "type")]
-    record_type: RawRecordType,
-    client: u16,
-    tx: u32,
-    amount: Option<Decimal>,
-}
+        assert_eq!(
+            output,
+            b"client,available,held,total,locked\n1,7.0,0,7.0,false\n"
+        )
+    }
 
-#[derive(Serialize, Debug)]
-struct RawOutputRecord {
-    client: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
-}
+    #[test]
+    fn duplicates_are_dropped_and_reported() {
+        let input = br#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 1, 1.00
+deposit, 1, 2, 2.0
+deposit, 1, 1, 1.0"#;
+        let path =
+            std::env::temp_dir().join(format!("banking-duplicates-{}.csv", std::process::id()));
 
-impl<'a> From<&'a ClientAccount> for RawOutputRecord {
-    fn from(c: &'a ClientAccount) -> Self {
-        RawOutputRecord {
-            client: c.id(),
-            available: c.available(),
-            held: c.held(),
-            total: c.total(),
-            locked: c.locked(),
+        for (mode, expected_output, expected_report) in [
+            (
+                DeduplicationMode::Consecutive,
+                &b"client,available,held,total,locked\n1,4.0,0,4.0,false\n"[..],
+                "record,type,client,tx,amount,correlation_id\n2,deposit,1,1,1.00,\n",
+            ),
+            (
+                DeduplicationMode::Global,
+                &b"client,available,held,total,locked\n1,3.0,0,3.0,false\n"[..],
+                "record,type,client,tx,amount,correlation_id\n2,deposit,1,1,1.00,\n4,deposit,1,1,1.0,\n",
+            ),
+        ] {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(&input[..]);
+            let mut output: Vec<u8> = vec![];
+            let writer = csv::Writer::from_writer(&mut output);
+            let options = PipelineOptions {
+                deduplication: Some(DeduplicationOptions {
+                    mode,
+                    report: Some(path.clone()),
+                }),
+                ..Default::default()
+            };
+
+            process(reader, writer, &options).unwrap();
+
+            assert_eq!(output, expected_output);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), expected_report);
         }
+
+        std::fs::remove_file(&path).unwrap();
     }
-}
 
-fn process<R: std::io::Read, W: std::io::Write>(
-    mut reader: csv::Reader<R>,
-    mut writer: csv::Writer<W>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let iter = reader.deserialize();
+    #[test]
+    fn resume_from_checkpoint() {
+        let directory =
+            std::env::temp_dir().join(format!("banking-checkpoints-{}", std::process::id()));
+        let input = b"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 2, 3, 4.0
+withdrawal, 1, 4, 0.5";
+        let options = PipelineOptions {
+            checkpoints: Some(CheckpointOptions {
+                directory: directory.clone(),
+                every_records: 2,
+            }),
+            ..Default::default()
+        };
 
-    let mut payment_engine = PaymentEngine::default();
+        // Simulate a run that got interrupted after checkpointing the second record.
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(&input[..]);
+        let mut iter = reader.into_deserialize::<RawInputRecord>();
+        let mut state = PipelineState::default();
+        for _ in 0..2 {
+            let record = iter.next().unwrap().unwrap();
+            state.payment_engine.add_transaction(Transaction::Deposit {
+                client: record.client,
+                transaction_id: record.tx,
+                amount: Amount::non_negative(record.amount.unwrap()).unwrap(),
+            });
+            state.records_processed += 1;
+        }
+        Checkpoint::store(
+            options.checkpoints.as_ref().unwrap(),
+            &options.at_rest,
+            &state,
+            iter.reader().position(),
+        )
+        .unwrap();
 
-    for r in iter {
-        // Due to internally tagged enums not being supported (https://github.com/BurntSushi/rust-csv/issues/211),
-        // deserialize into an intermediate state before passing it along to the lib.
-        let record: RawInputRecord = r?;
-        match record.record_type {
-            RawRecordType::Deposit => {
-                let t = Transaction::Deposit {
-                    client: record.client,
-                    transaction_id: record.tx,
-                    amount: record.amount.unwrap(),
-                };
-                payment_engine.add_transaction(t);
-            }
-            RawRecordType::Withdrawal => {
-                let t = Transaction::Withdrawal {
-                    client: record.client,
-                    transaction_id: record.tx,
-                    amount: record.amount.unwrap(),
-                };
-                payment_engine.add_transaction(t);
-            }
-            RawRecordType::Dispute => {
-                let d = DisputeAction::Dispute {
-                    client: record.client,
-                    referenced_transaction_id: record.tx,
-                };
-                payment_engine.add_dispute_action(d);
-            }
-            RawRecordType::Resolve => {
-                let d = DisputeAction::Resolve {
-                    client: record.client,
-                    referenced_transaction_id: record.tx,
-                };
-                payment_engine.add_dispute_action(d)
-            }
-            RawRecordType::Chargeback => {
-                let d = DisputeAction::Chargeback {
-                    client: record.client,
-                    referenced_transaction_id: record.tx,
-                };
-                payment_engine.add_dispute_action(d);
-            }
+        let mut checkpoint =
+            Checkpoint::load(options.checkpoints.as_ref().unwrap(), &options.at_rest)
+                .unwrap()
+                .unwrap();
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(std::io::Cursor::new(&input[..]));
+        reader.headers().unwrap();
+        reader.seek(checkpoint.position()).unwrap();
+
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process_from(reader, writer, &options, &mut checkpoint.state).unwrap();
+
+        let output_str = std::str::from_utf8(&output[..]).unwrap();
+        assert!(output_str.contains("1,2.5,0,2.5,false"));
+        assert!(output_str.contains("2,4.0,0,4.0,false"));
+        // A completed run cleans up after itself.
+        assert!(
+            Checkpoint::load(options.checkpoints.as_ref().unwrap(), &options.at_rest)
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn a_shutdown_writes_the_output_and_keeps_a_checkpoint() {
+        use std::io::Write;
+
+        let directory =
+            std::env::temp_dir().join(format!("banking-shutdown-{}", std::process::id()));
+        let checkpoints = CheckpointOptions {
+            directory: directory.clone(),
+            every_records: 2,
+        };
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        // Like a named pipe, the input doesn't end while the writing side is open.
+        let (input, mut feed) = std::io::pipe().unwrap();
+        feed.write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\n")
+            .unwrap();
+        let processing = {
+            let options = PipelineOptions {
+                checkpoints: Some(CheckpointOptions {
+                    directory: directory.clone(),
+                    every_records: 2,
+                }),
+                batch_size: Some(1),
+                shutdown: Arc::clone(&shutdown),
+                ..Default::default()
+            };
+            std::thread::spawn(move || {
+                let reader = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_reader(input);
+                let mut output: Vec<u8> = vec![];
+                process(reader, csv::Writer::from_writer(&mut output), &options).unwrap();
+                output
+            })
+        };
+        while !directory.join(Checkpoint::FILE_NAME).exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        shutdown.store(true, Ordering::SeqCst);
+
+        let output = processing.join().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,3.0,0,3.0,false\n"
+        );
+        let checkpoint = Checkpoint::load(&checkpoints, &AtRest::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.state.records_processed, 2);
+        drop(feed);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn an_interrupted_run_leaves_the_output_file_alone() {
+        let directory =
+            std::env::temp_dir().join(format!("banking-interrupted-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("input.csv");
+        let mut records = String::from("type, client, tx, amount\n");
+        for tx in 1..=1000 {
+            records.push_str(&format!("deposit, 1, {}, 1.0\n", tx));
+        }
+        std::fs::write(&input, records).unwrap();
+        let output = directory.join("output.csv");
+        std::fs::write(
+            &output,
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n",
+        )
+        .unwrap();
+        let checkpoints = directory.join("checkpoints");
+
+        // Rate limited so the signal arrives long before the input ends.
+        let options = Options::parse(
+            [
+                "--output",
+                output.to_str().unwrap(),
+                "--max-rate",
+                "100",
+                "--burst",
+                "1",
+                "--checkpoint-dir",
+                checkpoints.to_str().unwrap(),
+                "--checkpoint-every",
+                "1",
+                input.to_str().unwrap(),
+            ]
+            .map(String::from)
+            .into_iter(),
+        )
+        .unwrap();
+        let shutdown = Arc::clone(&options.pipeline.shutdown);
+        let running = std::thread::spawn(move || write_output(&options).map_err(|e| e.to_string()));
+        while !checkpoints.join(Checkpoint::FILE_NAME).exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
         }
+        shutdown.store(true, Ordering::SeqCst);
+
+        assert!(running.join().unwrap().unwrap_err().contains("Interrupted"));
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        );
+        assert!(!directory.join("output.csv.tmp").exists());
+        assert!(checkpoints.join(Checkpoint::FILE_NAME).exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 
-    payment_engine
-        .get_all_client_states()
-        .map(RawOutputRecord::from)
-        .for_each(|r| {
-            writer.serialize(r).unwrap();
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_checkpoints_and_snapshots() {
+        let directory =
+            std::env::temp_dir().join(format!("banking-encrypted-{}", std::process::id()));
+        let encryptor = Encryptor::new(&[3; 32]);
+        let at_rest = AtRest::Encrypted(Box::new(encryptor.clone()));
+        let checkpoint_options = CheckpointOptions {
+            directory: directory.clone(),
+            every_records: 1,
+        };
+        let mut state = PipelineState::default();
+        state.payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: Amount::non_negative(dec!(1.5)).unwrap(),
         });
+        Checkpoint::store(&checkpoint_options, &at_rest, &state, &csv::Position::new()).unwrap();
 
-    Ok(())
-}
+        let stored = std::fs::read(directory.join(Checkpoint::FILE_NAME)).unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("1.5"));
+        assert!(Checkpoint::load(&checkpoint_options, &AtRest::Plain).is_err());
+        assert!(matches!(
+            Checkpoint::load(
+                &checkpoint_options,
+                &AtRest::Encrypted(Box::new(Encryptor::new(&[4; 32])))
+            ),
+            Err(IoPipelineError::Encryption(_))
+        ));
+        let checkpoint = Checkpoint::load(&checkpoint_options, &at_rest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            checkpoint
+                .state
+                .payment_engine
+                .get_client_state(1)
+                .unwrap()
+                .total(),
+            dec!(1.5)
+        );
 
-#[cfg(test)]
-mod tests {
+        let snapshot_options = SnapshotOptions {
+            every_records: Some(1),
+            directory: directory.clone(),
+            keep: 1,
+        };
+        let mut snapshotter = Snapshotter::new(&snapshot_options, false, &at_rest);
+        snapshotter
+            .record_processed(&state.payment_engine, 1)
+            .unwrap();
+        snapshotter.finish().unwrap();
+        let snapshot =
+            std::fs::read(directory.join(format!("snapshot-{:020}.csv.enc", 1))).unwrap();
+        assert!(std::str::from_utf8(&encryptor.decrypt(&snapshot).unwrap())
+            .unwrap()
+            .contains("1,1.5,0,1.5,false"));
 
-    use super::*;
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 
+    #[cfg(feature = "mt940")]
     #[test]
-    fn single_row_with_header_and_leading_spaces() {
-        let reader = csv::ReaderBuilder::new()
-            .has_headers(true)
-            .trim(csv::Trim::All)
-            .from_reader(
-                &br#"type, client, tx, amount
-deposit, 1, 1, 1.0"#[..],
-            );
+    fn mt940_statement() {
+        let input = ":20:REF
+:25:1
+:61:2101020102C2,00NTRFNONREF
+:61:2101030103D0,50NTRFNONREF
+";
 
         let mut output: Vec<u8> = vec![];
         let writer = csv::Writer::from_writer(&mut output);
 
-        process(reader, writer).unwrap();
+        process_mt940(input, writer, &PipelineOptions::default()).unwrap();
 
-        dbg!(std::str::from_utf8(&output[..]).unwrap());
+        assert_eq!(
+            output,
+            b"client,available,held,total,locked\n1,1.50,0,1.50,false\n"
+        );
+
+        let pseudonyms = Pseudonyms {
+            key: b"secret".to_vec(),
+        };
+        let options = PipelineOptions {
+            pseudonyms: Some(pseudonyms.clone()),
+            ..PipelineOptions::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        process_mt940(input, csv::Writer::from_writer(&mut output), &options).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            format!(
+                "client,available,held,total,locked\n{},1.50,0,1.50,false\n",
+                pseudonyms.client(1)
+            )
+        );
 
+        // The engine is configured by the options, as for CSV input.
+        let options = PipelineOptions {
+            engine_config: EngineConfig {
+                disabled: [banking::wire::WireRecordType::Withdrawal]
+                    .into_iter()
+                    .collect(),
+                ..EngineConfig::default()
+            },
+            ..PipelineOptions::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        process_mt940(input, csv::Writer::from_writer(&mut output), &options).unwrap();
         assert_eq!(
             output,
-            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
-        )
+            b"client,available,held,total,locked\n1,2.00,0,2.00,false\n"
+        );
+
+        // The value date of the debit is after `--until`.
+        let options = PipelineOptions {
+            filter: ReplayFilter {
+                until: Some("2021-01-02".to_string()),
+                ..ReplayFilter::default()
+            },
+            ..PipelineOptions::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        process_mt940(input, csv::Writer::from_writer(&mut output), &options).unwrap();
+        assert_eq!(
+            output,
+            b"client,available,held,total,locked\n1,2.00,0,2.00,false\n"
+        );
     }
 
     #[test]
-    fn single_row_with_header_and_no_leading_spaces() {
+    fn history_lists_the_transactions_of_a_client() {
         let reader = csv::ReaderBuilder::new()
             .has_headers(true)
             .trim(csv::Trim::All)
             .from_reader(
-                &br#"type,client,tx,amount
-deposit,1,1,1.0"#[..],
+                &br#"type, client, tx, amount
+deposit, 1, a, 5.0
+deposit, 2, b, 1.0
+withdrawal, 1, c, 2.0
+withdrawal, 1, d, 9.0
+dispute, 1, a,"#[..],
             );
-
         let mut output: Vec<u8> = vec![];
         let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            string_transaction_ids: true,
+            command: Command::History { client: 1 },
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
 
-        process(reader, writer).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "type,tx,amount,state,balance\n\
+             deposit,a,5.0,Disputed,5.0\n\
+             withdrawal,c,2.0,Accepted,3.0\n\
+             withdrawal,d,9.0,Rejected,3.0\n"
+        );
+
+        let args = ["history", "--client", "7", "input.csv"].map(String::from);
+        let options = Options::parse(args.into_iter()).unwrap();
+        assert_eq!(options.pipeline.command, Command::History { client: 7 });
+        assert_eq!(options.file_path, "input.csv");
+        assert!(Options::parse(["history", "input.csv"].map(String::from).into_iter()).is_err());
+    }
 
+    #[test]
+    fn unknown_flags_and_extra_arguments_are_rejected() {
+        let parse = |args: &[&str]| Options::parse(args.iter().map(|arg| arg.to_string())).err();
         assert_eq!(
-            output,
-            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
-        )
+            parse(&["--snaphsot-every", "10", "input.csv"])
+                .unwrap()
+                .to_string(),
+            "Unknown flag '--snaphsot-every'."
+        );
+        assert_eq!(
+            parse(&["input.csv", "other.csv"]).unwrap().to_string(),
+            "Unexpected argument 'other.csv', only one input file is read."
+        );
+        assert!(parse(&["input.csv"]).is_none());
     }
+
     #[test]
-    fn single_row_no_header_and_no_leading_spaces() {
+    fn open_disputes_are_listed_by_amount() {
         let reader = csv::ReaderBuilder::new()
-            .has_headers(false)
+            .has_headers(true)
             .trim(csv::Trim::All)
-            .from_reader(&br#"deposit,1,1,1.0"#[..]);
-
+            .from_reader(
+                &br#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 3.0
+deposit, 3, 3, 2.0
+dispute, 1, 1,
+dispute, 2, 2,
+dispute, 3, 3,
+resolve, 3, 3,
+deposit, 3, 4, 1.0"#[..],
+            );
         let mut output: Vec<u8> = vec![];
         let writer = csv::Writer::from_writer(&mut output);
-
-        process(reader, writer).unwrap();
+        let options = PipelineOptions {
+            command: Command::Disputes,
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
 
         assert_eq!(
-            output,
-            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
-        )
+            std::str::from_utf8(&output).unwrap(),
+            "client,tx,amount,age\n2,2,3.0,3\n1,1,1.0,4\n"
+        );
     }
 
     #[test]
-    fn check_example_csv() {
+    fn audit_heads_are_written_and_verified() {
+        let run = |input: &'static str, verify: Option<PathBuf>| {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(input.as_bytes());
+            let mut output: Vec<u8> = vec![];
+            let options = PipelineOptions {
+                command: Command::Audit { verify },
+                ..Default::default()
+            };
+            process(reader, csv::Writer::from_writer(&mut output), &options).map(|_| output)
+        };
+        let input = "type, client, tx, amount\ndeposit, 2, 1, 1.0\ndeposit, 1, 2, 3.0\n";
+        let heads = run(input, None).unwrap();
+        let lines: Vec<_> = std::str::from_utf8(&heads).unwrap().lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "client,audit_head");
+        assert!(lines[1].starts_with("1,") && lines[2].starts_with("2,"));
+        assert_eq!(lines[1].len(), "1,".len() + 64);
+
+        let path = std::env::temp_dir().join(format!("banking-audit-{}.csv", std::process::id()));
+        std::fs::write(&path, &heads).unwrap();
+        assert_eq!(run(input, Some(path.clone())).unwrap(), heads);
+        let tampered = "type, client, tx, amount\ndeposit, 2, 1, 1.0\ndeposit, 1, 2, 30.0\n";
+        assert!(matches!(
+            run(tampered, Some(path.clone())),
+            Err(IoPipelineError::AuditMismatch { clients }) if clients == [1]
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn top_accounts_are_reported() {
         let reader = csv::ReaderBuilder::new()
             .has_headers(true)
             .trim(csv::Trim::All)
             .from_reader(
                 &br#"type, client, tx, amount
 deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0
-deposit, 1, 3, 2.0
-withdrawal, 1, 4, 1.5
-withdrawal, 2, 5, 3.0"#[..],
+deposit, 2, 2, 3.0
+deposit, 3, 3, 2.0
+dispute, 3, 3,
+withdrawal, 1, 4, 5.0
+withdrawal, 1, 5, 5.0
+resolve, 2, 2,"#[..],
             );
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            command: Command::Top { top: 2 },
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "ranking,rank,client,value\n\
+             total,1,2,3.0\n\
+             total,2,3,2.0\n\
+             held,1,3,2.0\n\
+             held,2,1,0\n\
+             rejections,1,1,2\n\
+             rejections,2,2,1\n"
+        );
+    }
 
+    #[test]
+    fn totals_are_grouped_by_day() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(
+                &br#"type, client, tx, amount, date
+deposit, 1, 1, 5.0, 2024-01-02T09:00:00Z
+deposit, 2, 2, 1.0,
+withdrawal, 1, 3, 2.0, 2024-01-01
+withdrawal, 1, 4, 9.0, 2024-01-02
+dispute, 1, 1, , 2024-01-02T17:30:00Z
+chargeback, 1, 1, , 2024-01-03"#[..],
+            );
         let mut output: Vec<u8> = vec![];
         let writer = csv::Writer::from_writer(&mut output);
+        let options = PipelineOptions {
+            command: Command::Daily,
+            ..Default::default()
+        };
+        process(reader, writer, &options).unwrap();
 
-        process(reader, writer).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "day,deposits,withdrawals,disputes_opened,chargebacks\n\
+             2024-01-01,0,2.0,0,0\n\
+             2024-01-02,5.0,0,1,0\n\
+             2024-01-03,0,0,0,1\n\
+             ,1.0,0,0,0\n"
+        );
 
-        let output_str = std::str::from_utf8(&output[..]).unwrap();
+        let args = ["report", "--group-by", "day", "input.csv"].map(String::from);
+        let options = Options::parse(args.into_iter()).unwrap();
+        assert_eq!(options.pipeline.command, Command::Daily);
+        let args = ["report", "--group-by", "week", "input.csv"].map(String::from);
+        assert!(Options::parse(args.into_iter()).is_err());
+    }
 
-        assert!(output_str.contains("1,1.5,0,1.5,false"));
-        assert!(output_str.contains("2,2.0,0,2.0,false"));
+    #[test]
+    #[cfg(feature = "tcp")]
+    fn listen_replaces_the_input_file() {
+        let args = ["--listen", "127.0.0.1:7000"].map(String::from);
+        let options = Options::parse(args.into_iter()).unwrap();
+        assert_eq!(options.listen.as_deref(), Some("127.0.0.1:7000"));
+
+        for args in [
+            &["--listen", "127.0.0.1:7000", "input.csv"][..],
+            &["disputes", "--listen", "127.0.0.1:7000"],
+            &[
+                "--listen",
+                "127.0.0.1:7000",
+                "--checkpoint-dir",
+                "checkpoints",
+            ],
+            &["--listen", "127.0.0.1:7000", "--snapshot-every", "10"],
+            &["--listen", "127.0.0.1:7000", "--anonymize"],
+            &["--control", "control.sock", "input.csv"],
+        ] {
+            assert!(Options::parse(args.iter().map(|arg| arg.to_string())).is_err());
+        }
+    }
+
+    #[cfg(all(unix, feature = "tcp"))]
+    #[test]
+    fn the_control_socket_answers_commands() {
+        use std::io::{BufRead, Write};
+
+        let directory =
+            std::env::temp_dir().join(format!("banking-control-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let socket = directory.join("control.sock");
+        // Left behind, like by a process that crashed.
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        let (control_socket, listener) = ControlSocket::bind(&socket).unwrap();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(
+                &std::fs::metadata(&socket).unwrap().permissions()
+            ) & 0o777,
+            0o600
+        );
+        assert!(ControlSocket::bind(&socket).is_err());
+
+        let payment_engine = std::sync::Arc::new(std::sync::RwLock::new(PaymentEngine::default()));
+        let ingest = TcpIngest::new(&payment_engine);
+        let serving = {
+            let ingest = ingest.clone();
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            std::thread::spawn(move || ingest.serve(listener, |_, error| panic!("{}", error)))
+        };
+        {
+            let ingest = ingest.clone();
+            let payment_engine = std::sync::Arc::clone(&payment_engine);
+            let snapshots = SnapshotOptions {
+                every_records: None,
+                directory: directory.clone(),
+                keep: 1,
+            };
+            std::thread::spawn(move || {
+                serve_control(
+                    listener,
+                    &ingest,
+                    &payment_engine,
+                    Some(&snapshots),
+                    &AtRest::default(),
+                    None,
+                )
+            });
+        }
+        payment_engine.write().unwrap().apply(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: Amount::non_negative(dec!(1.0)).unwrap(),
+        });
+
+        let stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+        let mut answers = std::io::BufReader::new(&stream).lines();
+        let mut command = |command: &str| {
+            (&stream)
+                .write_all(format!("{}\n", command).as_bytes())
+                .unwrap();
+            answers.next().unwrap().unwrap()
+        };
+
+        let snapshot = directory.join("snapshot-00000000000000000000.csv");
+        assert_eq!(command("snapshot"), format!("ok {}", snapshot.display()));
+        assert_eq!(
+            std::fs::read(&snapshot).unwrap(),
+            b"client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        );
+        assert_eq!(command("snapshot"), format!("ok {}", snapshot.display()));
+        assert!(command("stats").starts_with(r#"ok {"records":0,"accounts":1,"#));
+        assert_eq!(command("rotate-wal"), "error unknown command 'rotate-wal'");
+        assert_eq!(command("drain"), "ok draining");
+        serving.join().unwrap().unwrap();
+        drop(control_socket);
+        assert!(!socket.exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "tcp"))]
+    #[test]
+    fn control_commands_require_a_role_that_allows_them() {
+        let payment_engine = std::sync::Arc::new(std::sync::RwLock::new(PaymentEngine::default()));
+        let ingest = TcpIngest::new(&payment_engine);
+        let command = |command: &str, role: Option<Role>| {
+            control_command(command, role, &ingest, &payment_engine, &mut None)
+        };
+
+        assert_eq!(
+            command("stats", None),
+            Err("authenticate with `auth <key>` first".to_string())
+        );
+        assert!(command("stats", Some(Role::ReadOnly)).is_ok());
+        assert_eq!(
+            command("drain", Some(Role::Ingest)),
+            Err("the ingest role can't run this command".to_string())
+        );
+        assert_eq!(
+            command("drain", Some(Role::Admin)),
+            Ok("draining".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tcp")]
+    fn api_keys_are_read_for_the_listener() {
+        let path = std::env::temp_dir().join(format!("banking-api-keys-{}", std::process::id()));
+        std::fs::write(&path, "ingest feed-key\nadmin operator-key\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let args = ["--listen", "127.0.0.1:7000", "--api-keys", path];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        let api_keys = options.api_keys.unwrap();
+        assert_eq!(
+            api_keys.role(b"feed-key"),
+            Some(banking::auth::Role::Ingest)
+        );
+        assert_eq!(
+            api_keys.role(b"operator-key"),
+            Some(banking::auth::Role::Admin)
+        );
+        let args = ["input.csv", "--api-keys", path];
+        assert!(Options::parse(args.into_iter().map(String::from)).is_err());
+
+        std::fs::write(path, "ingest\n").unwrap();
+        let args = ["--listen", "127.0.0.1:7000", "--api-keys", path];
+        assert!(Options::parse(args.into_iter().map(String::from)).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "tcp", feature = "websocket"))]
+    fn dashboards_connect_next_to_the_listener() {
+        let args = [
+            "--listen",
+            "127.0.0.1:7000",
+            "--ws-listen",
+            "127.0.0.1:7001",
+        ];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(options.ws_listen.as_deref(), Some("127.0.0.1:7001"));
+
+        let args = ["input.csv", "--ws-listen", "127.0.0.1:7001"];
+        assert!(Options::parse(args.into_iter().map(String::from)).is_err());
+    }
+
+    #[test]
+    fn finalized_clients_are_written_right_away() {
+        let input = br#"type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 1.0
+finalize, 1, 0,
+deposit, 2, 3, 1.0"#;
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(&input[..]);
+        let mut output: Vec<u8> = vec![];
+        let writer = csv::Writer::from_writer(&mut output);
+        process(reader, writer, &PipelineOptions::default()).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked\n1,5.0,0,5.0,false\n2,2.0,0,2.0,false\n"
+        );
+
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(&b"type, client, tx, amount\nfinalize, 1, 0,\ndeposit, 1, 1, 1.0"[..]);
+        let writer = csv::Writer::from_writer(vec![]);
+        assert!(matches!(
+            process(reader, writer, &PipelineOptions::default()),
+            Err(IoPipelineError::RecordAfterFinalize {
+                record: 2,
+                client: 1
+            })
+        ));
     }
 }
@@ -0,0 +1,16 @@
+//! Descriptive data about clients, e.g. from a CRM export, that the engine carries along but never acts on.
+
+use alloc::string::String;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClientMetadata {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    /// An ISO 3166-1 alpha-2 country code, e.g. `BE`.
+    pub country: Option<String>,
+    /// The service tier of the client, e.g. `gold`.
+    pub tier: Option<String>,
+}
@@ -0,0 +1,303 @@
+//! Parser for SWIFT MT940 customer statements.
+//!
+//! Only the fields needed to feed the engine are interpreted: the account identification (`:25:`)
+//! and the statement lines (`:61:`). Every other tag is accepted and skipped.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+
+use crate::amount::Amount;
+use crate::Transaction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ValueDate {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// As an ISO 8601 date, e.g. `2021-01-02`, so it compares to the `date` column of CSV input.
+impl fmt::Display for ValueDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    Credit,
+    Debit,
+    /// Reversal of an earlier credit, which takes funds out of the account.
+    ReversalOfCredit,
+    /// Reversal of an earlier debit, which puts funds back into the account.
+    ReversalOfDebit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementLine {
+    pub value_date: ValueDate,
+    pub mark: Mark,
+    pub amount: Amount,
+    /// The reference for the account owner, e.g. `NONREF`.
+    pub reference: String,
+}
+
+impl StatementLine {
+    /// Whether this line puts funds into the account.
+    pub fn is_credit(&self) -> bool {
+        matches!(self.mark, Mark::Credit | Mark::ReversalOfDebit)
+    }
+
+    pub fn to_transaction(&self, client: u16, transaction_id: u32) -> Transaction {
+        if self.is_credit() {
+            Transaction::Deposit {
+                client,
+                transaction_id,
+                amount: self.amount,
+            }
+        } else {
+            Transaction::Withdrawal {
+                client,
+                transaction_id,
+                amount: self.amount,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    /// The contents of the `:25:` field.
+    pub account: String,
+    pub lines: Vec<StatementLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid MT940 input at line {line}: {message}")]
+pub struct Mt940Error {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Parses all statements contained in `input`.
+///
+/// A statement starts at every `:20:` tag, statement lines before the first `:25:` are an error.
+pub fn parse(input: &str) -> Result<Vec<Statement>, Mt940Error> {
+    let mut statements: Vec<Statement> = vec![];
+    let mut in_statement = false;
+
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: &str| Mt940Error {
+            line: line_number,
+            message: message.to_string(),
+        };
+
+        let line = line.trim();
+        let (tag, value) = match split_tag(line) {
+            Some(t) => t,
+            // Continuation lines (e.g. of `:86:`) and block delimiters carry nothing we need.
+            None => continue,
+        };
+
+        match tag {
+            "20" => in_statement = false,
+            "25" => {
+                statements.push(Statement {
+                    account: value.trim().to_string(),
+                    lines: vec![],
+                });
+                in_statement = true;
+            }
+            "61" => {
+                if !in_statement {
+                    return Err(error("statement line before account identification"));
+                }
+                let statement_line = parse_statement_line(value).map_err(error)?;
+                statements
+                    .last_mut()
+                    .expect("A statement was started by the `:25:` tag.")
+                    .lines
+                    .push(statement_line);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(statements)
+}
+
+fn split_tag(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix(':')?;
+    let end = rest.find(':')?;
+    Some((&rest[..end], &rest[end + 1..]))
+}
+
+/// Parses the value of a `:61:` field, e.g. `2101010102C100,00NTRFNONREF//123`.
+fn parse_statement_line(value: &str) -> Result<StatementLine, &'static str> {
+    let value_date = parse_date(value.get(..6).ok_or("missing value date")?)?;
+    let mut rest = &value[6..];
+
+    // The entry date (MMDD) is optional.
+    if rest.len() >= 4 && rest.as_bytes()[..4].iter().all(u8::is_ascii_digit) {
+        rest = &rest[4..];
+    }
+
+    let (mark, rest) = if let Some(r) = rest.strip_prefix("RC") {
+        (Mark::ReversalOfCredit, r)
+    } else if let Some(r) = rest.strip_prefix("RD") {
+        (Mark::ReversalOfDebit, r)
+    } else if let Some(r) = rest.strip_prefix('C') {
+        (Mark::Credit, r)
+    } else if let Some(r) = rest.strip_prefix('D') {
+        (Mark::Debit, r)
+    } else {
+        return Err("missing debit/credit mark");
+    };
+
+    // The funds code (third character of the currency) is optional.
+    let rest = match rest.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => &rest[1..],
+        _ => rest,
+    };
+
+    let amount_end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == ','))
+        .unwrap_or(rest.len());
+    let amount = Decimal::from_str(&rest[..amount_end].replace(',', "."))
+        .ok()
+        .and_then(|amount| Amount::non_negative(amount).ok())
+        .ok_or("invalid amount")?;
+    let rest = &rest[amount_end..];
+
+    // Skip the transaction type identification code, e.g. `NTRF`.
+    let reference = rest.get(4..).unwrap_or_default();
+    let reference = reference.split("//").next().unwrap_or_default();
+
+    Ok(StatementLine {
+        value_date,
+        mark,
+        amount,
+        reference: reference.to_string(),
+    })
+}
+
+fn parse_date(value: &str) -> Result<ValueDate, &'static str> {
+    let digits = |range: std::ops::Range<usize>| {
+        value
+            .get(range)
+            .and_then(|digits| digits.parse::<u8>().ok())
+            .ok_or("invalid value date")
+    };
+    let year = digits(0..2)?;
+    let month = digits(2..4)?;
+    let day = digits(4..6)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err("invalid value date");
+    }
+
+    Ok(ValueDate {
+        // MT940 only carries two digits for the year, SWIFT uses a sliding window around 2000.
+        year: if year >= 80 {
+            1900 + year as u16
+        } else {
+            2000 + year as u16
+        },
+        month,
+        day,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const EXAMPLE: &str = ":20:STARTUMSE
+:25:7
+:28C:00001/001
+:60F:C210101EUR1000,00
+:61:2101020102C100,50NTRFNONREF//8327000090031789
+:86:Some description
+that spans lines
+:61:210103D20,NCHGREF1
+:61:210104RC5,00NTRFREF2
+:62F:C210104EUR1075,50
+";
+
+    #[test]
+    fn parses_statement_lines() {
+        let statements = parse(EXAMPLE).unwrap();
+
+        assert_eq!(statements.len(), 1);
+        assert_eq!(statements[0].account, "7");
+        let lines = &statements[0].lines;
+        assert_eq!(lines.len(), 3);
+
+        assert_eq!(
+            lines[0],
+            StatementLine {
+                value_date: ValueDate {
+                    year: 2021,
+                    month: 1,
+                    day: 2
+                },
+                mark: Mark::Credit,
+                amount: Amount::new(dec!(100.50)).unwrap(),
+                reference: "NONREF".to_string(),
+            }
+        );
+        assert_eq!(lines[1].mark, Mark::Debit);
+        assert_eq!(lines[1].amount, dec!(20));
+        assert_eq!(lines[1].reference, "REF1");
+        assert_eq!(lines[2].mark, Mark::ReversalOfCredit);
+        assert!(!lines[2].is_credit());
+    }
+
+    #[test]
+    fn statement_line_maps_to_transaction() {
+        let statements = parse(EXAMPLE).unwrap();
+        let lines = &statements[0].lines;
+
+        assert!(matches!(
+            lines[0].to_transaction(7, 1),
+            Transaction::Deposit {
+                client: 7,
+                transaction_id: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            lines[1].to_transaction(7, 2),
+            Transaction::Withdrawal { .. }
+        ));
+    }
+
+    #[test]
+    fn non_ascii_value_dates_are_an_error() {
+        let err = parse(":25:1\n:61:2é0102C1,00NTRFNONREF\n").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (2, "invalid value date"));
+    }
+
+    #[test]
+    fn value_dates_display_as_iso_8601() {
+        assert_eq!(
+            ValueDate {
+                year: 2021,
+                month: 1,
+                day: 2
+            }
+            .to_string(),
+            "2021-01-02"
+        );
+    }
+
+    #[test]
+    fn statement_line_without_account_is_an_error() {
+        let err = parse(":20:REF\n:61:2101020102C100,50NTRFNONREF\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+}
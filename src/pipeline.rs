@@ -0,0 +1,525 @@
+//! Applies a stream of records to an engine and hands the resulting accounts to an [`AccountSink`], independent of
+//! where the records come from, e.g. a CSV file, an HTTP endpoint or a test.
+//!
+//! Records that are dropped on their way to an account, by a [`RecordTransformer`] or by screening, can be kept in a
+//! [`DeadLetterSink`] to be re-submitted later.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::Infallible;
+#[cfg(feature = "serde")]
+use std::io::Write;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::id::{ClientId, TransactionId};
+use crate::transform::{RecordTransformer, TransformerChain};
+#[cfg(feature = "csv")]
+use crate::wire::{WireRecord, WireRecordType};
+use crate::{ClientAccount, Outcome, PaymentEngine, Record, RejectionReason};
+
+/// Consumes the accounts at the end of a [`run`].
+pub trait AccountSink<C: ClientId = u16, T: TransactionId = u32> {
+    type Error;
+
+    fn emit(&mut self, account: &ClientAccount<C, T>) -> Result<(), Self::Error>;
+
+    /// Called after the last account, e.g. to flush a writer.
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<C: ClientId, T: TransactionId, S: AccountSink<C, T> + ?Sized> AccountSink<C, T> for &mut S {
+    type Error = S::Error;
+
+    fn emit(&mut self, account: &ClientAccount<C, T>) -> Result<(), Self::Error> {
+        (**self).emit(account)
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        (**self).finish()
+    }
+}
+
+/// Collects a copy of every account, e.g. for a test or a caller that post-processes them.
+impl<C: ClientId, T: TransactionId> AccountSink<C, T> for Vec<ClientAccount<C, T>> {
+    type Error = Infallible;
+
+    fn emit(&mut self, account: &ClientAccount<C, T>) -> Result<(), Self::Error> {
+        self.push(account.clone());
+        Ok(())
+    }
+}
+
+/// The `client,available,held,total,locked` columns every serializing sink writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AccountRow<C = u16> {
+    pub client: C,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl<C: ClientId, T: TransactionId> From<&ClientAccount<C, T>> for AccountRow<C> {
+    fn from(account: &ClientAccount<C, T>) -> Self {
+        Self {
+            client: account.id(),
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked(),
+        }
+    }
+}
+
+/// Writes every account as an [`AccountRow`] of a CSV file with a header.
+#[cfg(feature = "csv")]
+pub struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+#[cfg(feature = "csv")]
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<C: ClientId, T: TransactionId, W: Write> AccountSink<C, T> for CsvSink<W> {
+    type Error = csv::Error;
+
+    fn emit(&mut self, account: &ClientAccount<C, T>) -> Result<(), Self::Error> {
+        self.writer.serialize(AccountRow::from(account))
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes every account as an [`AccountRow`] object on a line of its own, see <https://jsonlines.org>.
+#[cfg(feature = "serde")]
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+}
+
+#[cfg(feature = "serde")]
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<C: ClientId, T: TransactionId, W: Write> AccountSink<C, T> for JsonLinesSink<W> {
+    type Error = serde_json::Error;
+
+    fn emit(&mut self, account: &ClientAccount<C, T>) -> Result<(), Self::Error> {
+        serde_json::to_writer(&mut self.writer, &AccountRow::from(account))?;
+        self.writer.write_all(b"\n").map_err(serde_json::Error::io)
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        self.writer.flush().map_err(serde_json::Error::io)
+    }
+}
+
+/// Where a [`DeadLetter`] was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DeadLetterStage {
+    /// Dropped by the [`RecordTransformer`], the reason is its [`RecordTransformer::name`].
+    Transform,
+    /// Rejected by the engine as [`RejectionReason::Screened`], the reason is `screened`.
+    Screening,
+}
+
+/// A record that didn't reach an account, as it was read, before any transformer changed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter<C = u16, T = u32> {
+    /// The number of the record in the input, starting at 1.
+    pub record_number: u64,
+    pub stage: DeadLetterStage,
+    pub reason: String,
+    pub record: Record<C, T>,
+}
+
+/// Consumes the [`DeadLetter`]s of a [`run_with_dead_letters`].
+pub trait DeadLetterSink<C: ClientId = u16, T: TransactionId = u32> {
+    type Error;
+
+    fn dead_letter(&mut self, letter: DeadLetter<C, T>) -> Result<(), Self::Error>;
+
+    /// Called after the last record, e.g. to flush a writer.
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<C: ClientId, T: TransactionId, S: DeadLetterSink<C, T> + ?Sized> DeadLetterSink<C, T>
+    for &mut S
+{
+    type Error = S::Error;
+
+    fn dead_letter(&mut self, letter: DeadLetter<C, T>) -> Result<(), Self::Error> {
+        (**self).dead_letter(letter)
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        (**self).finish()
+    }
+}
+
+/// Discards every dead letter.
+impl<C: ClientId, T: TransactionId> DeadLetterSink<C, T> for () {
+    type Error = Infallible;
+
+    fn dead_letter(&mut self, _: DeadLetter<C, T>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<C: ClientId, T: TransactionId> DeadLetterSink<C, T> for Vec<DeadLetter<C, T>> {
+    type Error = Infallible;
+
+    fn dead_letter(&mut self, letter: DeadLetter<C, T>) -> Result<(), Self::Error> {
+        self.push(letter);
+        Ok(())
+    }
+}
+
+/// A [`DeadLetter`] as a row with the columns of the CSV input, so a file of them can be re-submitted as is.
+#[cfg(feature = "csv")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeadLetterRow<'a, C = u16, T = u32> {
+    #[serde(rename = "type")]
+    pub record_type: WireRecordType,
+    pub client: C,
+    pub tx: T,
+    pub amount: Option<Amount>,
+    pub record: u64,
+    pub stage: DeadLetterStage,
+    pub reason: &'a str,
+}
+
+/// Writes every dead letter as a [`DeadLetterRow`] of a CSV file with a header.
+#[cfg(feature = "csv")]
+pub struct CsvDeadLetterSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+#[cfg(feature = "csv")]
+impl<W: Write> CsvDeadLetterSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<C: ClientId, T: TransactionId, W: Write> DeadLetterSink<C, T> for CsvDeadLetterSink<W> {
+    type Error = csv::Error;
+
+    fn dead_letter(&mut self, letter: DeadLetter<C, T>) -> Result<(), Self::Error> {
+        let wire = WireRecord::from(letter.record);
+        self.writer.serialize(DeadLetterRow {
+            record_type: wire.record_type,
+            client: wire.client,
+            tx: wire.tx,
+            amount: wire.amount,
+            record: letter.record_number,
+            stage: letter.stage,
+            reason: &letter.reason,
+        })
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineSummary {
+    pub records: u64,
+    /// Records the engine rejected, they don't stop the pipeline.
+    pub rejected: u64,
+    /// Records a [`RecordTransformer`] dropped before they reached the engine.
+    pub dropped: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError<E, S, D = Infallible> {
+    /// The input failed, the records before it have been applied but no accounts were emitted.
+    #[error("could not read record {record}: {source}")]
+    Input { record: u64, source: E },
+    #[error("could not emit the accounts: {0}")]
+    Sink(S),
+    #[error("could not write a dead letter: {0}")]
+    DeadLetter(D),
+}
+
+/// Applies `records` to a new engine with the default configuration, then emits its accounts to `sink`.
+pub fn run<C: ClientId, T: TransactionId, E, S: AccountSink<C, T>>(
+    records: impl IntoIterator<Item = Result<Record<C, T>, E>>,
+    sink: S,
+) -> Result<PipelineSummary, PipelineError<E, S::Error>> {
+    run_on(&mut PaymentEngine::default(), records, sink)
+}
+
+/// Like [`run`], on an engine that is configured, or already holds accounts.
+pub fn run_on<C: ClientId, T: TransactionId, E, S: AccountSink<C, T>>(
+    payment_engine: &mut PaymentEngine<C, T>,
+    records: impl IntoIterator<Item = Result<Record<C, T>, E>>,
+    sink: S,
+) -> Result<PipelineSummary, PipelineError<E, S::Error>> {
+    run_transformed(payment_engine, records, TransformerChain::new(), sink)
+}
+
+/// Like [`run_on`], with every record passed through `transformer` before it's applied, e.g. a
+/// [`TransformerChain`].
+pub fn run_transformed<C: ClientId, T: TransactionId, E, S: AccountSink<C, T>>(
+    payment_engine: &mut PaymentEngine<C, T>,
+    records: impl IntoIterator<Item = Result<Record<C, T>, E>>,
+    transformer: impl RecordTransformer<C, T>,
+    sink: S,
+) -> Result<PipelineSummary, PipelineError<E, S::Error>> {
+    run_with_dead_letters(payment_engine, records, transformer, (), sink)
+}
+
+/// Like [`run_transformed`], with the records that are dropped by `transformer` or screened out by the engine
+/// handed to `dead_letters`.
+pub fn run_with_dead_letters<
+    C: ClientId,
+    T: TransactionId,
+    E,
+    D: DeadLetterSink<C, T>,
+    S: AccountSink<C, T>,
+>(
+    payment_engine: &mut PaymentEngine<C, T>,
+    records: impl IntoIterator<Item = Result<Record<C, T>, E>>,
+    mut transformer: impl RecordTransformer<C, T>,
+    mut dead_letters: D,
+    sink: S,
+) -> Result<PipelineSummary, PipelineError<E, S::Error, D::Error>> {
+    let mut summary = PipelineSummary::default();
+    for record in records {
+        summary.records += 1;
+        let record = record.map_err(|source| PipelineError::Input {
+            record: summary.records,
+            source,
+        })?;
+        let dead_letter = |stage, reason: &str| DeadLetter {
+            record_number: summary.records,
+            stage,
+            reason: reason.to_string(),
+            record: record.clone(),
+        };
+        let Some(transformed) = transformer.transform(record.clone()) else {
+            summary.dropped += 1;
+            dead_letters
+                .dead_letter(dead_letter(DeadLetterStage::Transform, transformer.name()))
+                .map_err(PipelineError::DeadLetter)?;
+            continue;
+        };
+        match payment_engine.apply(transformed) {
+            Outcome::Applied => {}
+            Outcome::Rejected(reason) => {
+                summary.rejected += 1;
+                if reason == RejectionReason::Screened {
+                    dead_letters
+                        .dead_letter(dead_letter(DeadLetterStage::Screening, "screened"))
+                        .map_err(PipelineError::DeadLetter)?;
+                }
+            }
+        }
+    }
+    dead_letters.finish().map_err(PipelineError::DeadLetter)?;
+    emit(payment_engine.get_all_client_states(), sink).map_err(PipelineError::Sink)?;
+    Ok(summary)
+}
+
+/// Emits every account of `accounts` to `sink`, then finishes it.
+pub fn emit<'a, C: ClientId + 'a, T: TransactionId + 'a, S: AccountSink<C, T>>(
+    accounts: impl IntoIterator<Item = &'a ClientAccount<C, T>>,
+    mut sink: S,
+) -> Result<(), S::Error> {
+    for account in accounts {
+        sink.emit(account)?;
+    }
+    sink.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::Transaction;
+
+    struct Balances(Vec<(u16, Amount)>);
+
+    impl AccountSink for Balances {
+        type Error = Infallible;
+
+        fn emit(&mut self, account: &ClientAccount) -> Result<(), Self::Error> {
+            self.0.push((account.id(), account.available()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_are_applied_and_accounts_emitted() {
+        let deposit = |client, transaction_id| {
+            Ok::<_, &str>(Record::Transaction(Transaction::Deposit {
+                client,
+                transaction_id,
+                amount: Amount::non_negative(dec!(1.0)).unwrap(),
+            }))
+        };
+        let withdrawal = Record::Transaction(Transaction::Withdrawal {
+            client: 2,
+            transaction_id: 3,
+            amount: Amount::non_negative(dec!(5.0)).unwrap(),
+        });
+
+        let mut balances = Balances(vec![]);
+        let summary = run(
+            [deposit(1, 1), deposit(2, 2), Ok(withdrawal)],
+            &mut balances,
+        )
+        .unwrap();
+        balances.0.sort();
+        assert_eq!(
+            summary,
+            PipelineSummary {
+                records: 3,
+                rejected: 1,
+                dropped: 0
+            }
+        );
+        assert_eq!(balances.0.len(), 2);
+        assert_eq!(balances.0[1].1, dec!(1.0));
+
+        assert!(matches!(
+            run([deposit(1, 1), Err("truncated")], Balances(vec![])),
+            Err(PipelineError::Input {
+                record: 2,
+                source: "truncated"
+            })
+        ));
+    }
+
+    #[test]
+    fn records_are_transformed_before_they_are_applied() {
+        let records = [1, 2, 3].map(|client| {
+            Ok::<_, Infallible>(Record::Transaction(Transaction::Deposit {
+                client,
+                transaction_id: u32::from(client),
+                amount: Amount::non_negative(dec!(2.0)).unwrap(),
+            }))
+        });
+        let chain = TransformerChain::new()
+            .then(crate::transform::DropClients::new([3]))
+            .then(crate::transform::ConvertAmounts { rate: dec!(1.5) });
+
+        let mut accounts: Vec<ClientAccount> = vec![];
+        let summary =
+            run_transformed(&mut PaymentEngine::default(), records, chain, &mut accounts).unwrap();
+        accounts.sort_by_key(|account| account.id());
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.dropped, 1);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].total(), dec!(3.0));
+    }
+
+    #[test]
+    #[cfg(feature = "csv")]
+    fn dropped_and_screened_records_are_dead_letters() {
+        let records = [1, 2, 3].map(|client| {
+            Ok::<_, Infallible>(Record::Transaction(Transaction::Deposit {
+                client,
+                transaction_id: u32::from(client),
+                amount: Amount::non_negative(dec!(2.0)).unwrap(),
+            }))
+        });
+        let chain = TransformerChain::new()
+            .then(crate::transform::DropClients::new([3]))
+            .then(crate::transform::RenameClients::new([(1, 4)]));
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.set_screening([4].into_iter().collect::<crate::screening::Blocklist>());
+
+        let mut dead_letters = vec![];
+        let summary = run_with_dead_letters(
+            &mut payment_engine,
+            records,
+            chain,
+            CsvDeadLetterSink::new(&mut dead_letters),
+            Vec::<ClientAccount>::new(),
+        )
+        .unwrap();
+        assert_eq!(summary.dropped, 1);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(payment_engine.len(), 1);
+        assert_eq!(
+            core::str::from_utf8(&dead_letters).unwrap(),
+            "type,client,tx,amount,record,stage,reason\n\
+             deposit,1,1,2.0,1,screening,screened\n\
+             deposit,3,3,2.0,3,transform,drop_clients\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "csv")]
+    fn accounts_are_emitted_as_csv_json_or_values() {
+        let records = || {
+            [1, 2].map(|client| {
+                Ok::<_, Infallible>(Record::Transaction(Transaction::Deposit {
+                    client,
+                    transaction_id: u32::from(client),
+                    amount: Amount::non_negative(dec!(1.5)).unwrap(),
+                }))
+            })
+        };
+
+        let mut accounts: Vec<ClientAccount> = vec![];
+        run(records(), &mut accounts).unwrap();
+        accounts.sort_by_key(|account| account.id());
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].total(), dec!(1.5));
+
+        let mut csv = vec![];
+        run(records(), CsvSink::new(&mut csv)).unwrap();
+        let mut lines: Vec<_> = core::str::from_utf8(&csv).unwrap().lines().collect();
+        lines[1..].sort();
+        assert_eq!(
+            lines,
+            [
+                "client,available,held,total,locked",
+                "1,1.5,0,1.5,false",
+                "2,1.5,0,1.5,false"
+            ]
+        );
+
+        let mut json = vec![];
+        run(records(), JsonLinesSink::new(&mut json)).unwrap();
+        let mut lines: Vec<_> = core::str::from_utf8(&json).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines[0],
+            r#"{"client":1,"available":"1.5","held":"0","total":"1.5","locked":false}"#
+        );
+    }
+}
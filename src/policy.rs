@@ -0,0 +1,217 @@
+//! How disputes move the funds of the transaction they concern, see [`crate::config::DisputePolicy::funds_movement`].
+//!
+//! The specification only describes disputes of deposits, what happens to the funds of a disputed withdrawal is up
+//! to interpretation. Every interpretation is a [`FundsMovementPolicy`], so switching between them doesn't take
+//! changes to the engine.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+
+/// Which way the funds of the disputed transaction went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A deposit.
+    Credit,
+    /// A withdrawal, or a confirmed withdrawal request.
+    Debit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeStage {
+    Opened,
+    /// The dispute was resolved, or the arbitration lost.
+    Resolved,
+    /// The transaction was charged back, or the arbitration won.
+    ChargedBack,
+}
+
+/// What to add to the funds of the account, negative amounts take funds away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FundsChange {
+    pub available: Amount,
+    pub held: Amount,
+}
+
+impl FundsChange {
+    pub const NONE: Self = Self {
+        available: Amount::ZERO,
+        held: Amount::ZERO,
+    };
+}
+
+pub trait FundsMovementPolicy {
+    /// How the funds of the account change when a dispute of a transaction of `amount` reaches `stage`.
+    fn funds_change(
+        &self,
+        direction: Direction,
+        stage: DisputeStage,
+        amount: Amount,
+    ) -> FundsChange;
+
+    /// Whether a dispute of a transaction of `amount` that is opened and then resolved leaves the funds as they were.
+    /// Only then can the transaction be disputed again, as every round would otherwise move the funds once more.
+    fn round_trips(&self, direction: Direction, amount: Amount) -> bool {
+        let opened = self.funds_change(direction, DisputeStage::Opened, amount);
+        let resolved = self.funds_change(direction, DisputeStage::Resolved, amount);
+        opened.available.checked_add(resolved.available) == Some(Amount::ZERO)
+            && opened.held.checked_add(resolved.held) == Some(Amount::ZERO)
+    }
+}
+
+/// Disputed deposits are held until the dispute is settled. A disputed withdrawal isn't held, as its funds already
+/// left the account; resolving the dispute credits them back and a chargeback leaves the account as it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpecDefault;
+
+impl FundsMovementPolicy for SpecDefault {
+    fn funds_change(
+        &self,
+        direction: Direction,
+        stage: DisputeStage,
+        amount: Amount,
+    ) -> FundsChange {
+        match (direction, stage) {
+            (Direction::Credit, DisputeStage::Opened) => FundsChange {
+                available: -amount,
+                held: amount,
+            },
+            (Direction::Credit, DisputeStage::Resolved) => FundsChange {
+                available: amount,
+                held: -amount,
+            },
+            (Direction::Credit, DisputeStage::ChargedBack) => FundsChange {
+                available: Amount::ZERO,
+                held: -amount,
+            },
+            (Direction::Debit, DisputeStage::Resolved) => FundsChange {
+                available: amount,
+                held: Amount::ZERO,
+            },
+            (Direction::Debit, DisputeStage::Opened | DisputeStage::ChargedBack) => {
+                FundsChange::NONE
+            }
+        }
+    }
+}
+
+/// Treats both directions alike: a dispute holds the disputed amount, resolving it undoes the hold and a
+/// chargeback reverses the transaction. For a withdrawal that means the hold adds to the total while the dispute
+/// is open, and the chargeback makes the funds available again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SymmetricHold;
+
+impl FundsMovementPolicy for SymmetricHold {
+    fn funds_change(
+        &self,
+        direction: Direction,
+        stage: DisputeStage,
+        amount: Amount,
+    ) -> FundsChange {
+        match (direction, stage) {
+            (Direction::Credit, _) => SpecDefault.funds_change(direction, stage, amount),
+            (Direction::Debit, DisputeStage::Opened) => FundsChange {
+                available: Amount::ZERO,
+                held: amount,
+            },
+            (Direction::Debit, DisputeStage::Resolved) => FundsChange {
+                available: Amount::ZERO,
+                held: -amount,
+            },
+            (Direction::Debit, DisputeStage::ChargedBack) => FundsChange {
+                available: amount,
+                held: -amount,
+            },
+        }
+    }
+}
+
+/// Selects a [`FundsMovementPolicy`] in the configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FundsMovement {
+    #[default]
+    SpecDefault,
+    SymmetricHold,
+}
+
+impl FundsMovement {
+    pub fn policy(self) -> &'static dyn FundsMovementPolicy {
+        match self {
+            FundsMovement::SpecDefault => &SpecDefault,
+            FundsMovement::SymmetricHold => &SymmetricHold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    /// The total of the account after every stage, starting from zero.
+    fn totals(
+        policy: &dyn FundsMovementPolicy,
+        direction: Direction,
+        last: DisputeStage,
+    ) -> [Amount; 2] {
+        let amount = Amount::new(dec!(1.0)).unwrap();
+        [DisputeStage::Opened, last].map(|stage| {
+            let change = policy.funds_change(direction, stage, amount);
+            change.available.checked_add(change.held).unwrap()
+        })
+    }
+
+    #[test]
+    fn deposits_are_held_by_both_policies() {
+        for policy in [FundsMovement::SpecDefault, FundsMovement::SymmetricHold] {
+            let policy = policy.policy();
+            assert_eq!(
+                totals(policy, Direction::Credit, DisputeStage::Resolved),
+                [dec!(0), dec!(0)]
+            );
+            assert_eq!(
+                totals(policy, Direction::Credit, DisputeStage::ChargedBack),
+                [dec!(0), dec!(-1.0)]
+            );
+        }
+    }
+
+    #[test]
+    fn withdrawals_are_only_held_symmetrically() {
+        assert_eq!(
+            SpecDefault.funds_change(
+                Direction::Debit,
+                DisputeStage::Opened,
+                Amount::new(dec!(1.0)).unwrap()
+            ),
+            FundsChange::NONE
+        );
+        assert_eq!(
+            totals(&SpecDefault, Direction::Debit, DisputeStage::Resolved),
+            [dec!(0), dec!(1.0)]
+        );
+        assert_eq!(
+            totals(&SymmetricHold, Direction::Debit, DisputeStage::Resolved),
+            [dec!(1.0), dec!(-1.0)]
+        );
+        assert_eq!(
+            totals(&SymmetricHold, Direction::Debit, DisputeStage::ChargedBack),
+            [dec!(1.0), dec!(0)]
+        );
+    }
+
+    #[test]
+    fn only_rounds_that_restore_the_funds_can_be_repeated() {
+        let amount = Amount::new(dec!(1.0)).unwrap();
+        for direction in [Direction::Credit, Direction::Debit] {
+            assert!(SymmetricHold.round_trips(direction, amount));
+        }
+        assert!(SpecDefault.round_trips(Direction::Credit, amount));
+        // Every resolved round would credit the withdrawal back again.
+        assert!(!SpecDefault.round_trips(Direction::Debit, amount));
+    }
+}
@@ -0,0 +1,99 @@
+//! Token bucket used to cap the rate at which records are fed to the engine.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full, allowing a burst of `capacity` records before `rate` kicks in.
+    pub fn new(rate: u32, capacity: u32) -> Self {
+        Self::new_at(rate, capacity, Instant::now())
+    }
+
+    pub fn new_at(rate: u32, capacity: u32, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token if one is available. When it returns `false` the caller should push back on its source,
+    /// e.g. by answering with `429 Too Many Requests` or pausing consumption.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the next token becomes available.
+    pub fn wait_time_at(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 || self.rate == 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    /// Blocks the current thread until a token is available and takes it.
+    pub fn acquire(&mut self) {
+        while !self.try_acquire() {
+            std::thread::sleep(self.wait_time_at(Instant::now()));
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(10, 2, start);
+
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+        assert_eq!(bucket.wait_time_at(start), Duration::from_millis(100));
+
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn does_not_exceed_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(10, 2, start);
+        let later = start + Duration::from_secs(60);
+
+        assert!(bucket.try_acquire_at(later));
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(later));
+    }
+}
@@ -0,0 +1,127 @@
+//! Standing orders: transfers between two accounts that recur as the engine clock advances,
+//! see [`crate::PaymentEngine::add_standing_order`].
+
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use core::num::NonZeroU64;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::id::{ClientId, TransactionId};
+
+/// Transfers `amount` from one account to another every `every` records.
+///
+/// Every run is materialized as a withdrawal from `from` and a deposit to `to` that both use the next of
+/// `transaction_ids`, so they show up in the histories like any other transaction. The order ends once its
+/// transaction ids are used up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+// The bounds on the ids already require them to be deserializable.
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "")))]
+pub struct StandingOrder<C: ClientId = u16, T: TransactionId = u32> {
+    pub from: C,
+    pub to: C,
+    pub amount: Amount,
+    /// The number of records from one run to the next, and from registering the order to its first run.
+    pub every: NonZeroU64,
+    pub transaction_ids: VecDeque<T>,
+}
+
+/// Identifies a registered [`StandingOrder`], e.g. to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StandingOrderId(u64);
+
+/// A single run of a standing order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Transfer<C, T> {
+    pub(crate) order: StandingOrderId,
+    pub(crate) from: C,
+    pub(crate) to: C,
+    pub(crate) amount: Amount,
+    pub(crate) transaction_id: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "")))]
+struct ScheduledOrder<C: ClientId, T: TransactionId> {
+    order: StandingOrder<C, T>,
+    /// The engine sequence number of its next run.
+    due: u64,
+}
+
+/// The standing orders of an engine, ordered by when they're due.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "")))]
+pub(crate) struct Schedule<C: ClientId, T: TransactionId> {
+    orders: BTreeMap<u64, ScheduledOrder<C, T>>,
+    /// `(due, order)` of every order in `orders`.
+    by_due: BTreeSet<(u64, u64)>,
+    next_id: u64,
+}
+
+impl<C: ClientId, T: TransactionId> Default for Schedule<C, T> {
+    fn default() -> Self {
+        Self {
+            orders: BTreeMap::new(),
+            by_due: BTreeSet::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Schedule<C, T> {
+    /// Registers `order` at engine sequence number `sequence`.
+    pub(crate) fn add(&mut self, order: StandingOrder<C, T>, sequence: u64) -> StandingOrderId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let due = sequence.saturating_add(order.every.get());
+        self.by_due.insert((due, id));
+        self.orders.insert(id, ScheduledOrder { order, due });
+        StandingOrderId(id)
+    }
+
+    pub(crate) fn cancel(&mut self, id: StandingOrderId) -> Option<StandingOrder<C, T>> {
+        let scheduled = self.orders.remove(&id.0)?;
+        self.by_due.remove(&(scheduled.due, id.0));
+        Some(scheduled.order)
+    }
+
+    pub(crate) fn orders(&self) -> impl Iterator<Item = (StandingOrderId, &StandingOrder<C, T>)> {
+        self.orders
+            .iter()
+            .map(|(id, scheduled)| (StandingOrderId(*id), &scheduled.order))
+    }
+
+    /// Takes the next run that is due at or before `sequence`, and schedules the run after it.
+    pub(crate) fn take_due(&mut self, sequence: u64) -> Option<Transfer<C, T>> {
+        let (due, id) = *self.by_due.first().filter(|(due, _)| *due <= sequence)?;
+        self.by_due.remove(&(due, id));
+        let scheduled = self
+            .orders
+            .get_mut(&id)
+            .expect("Every due order is scheduled.");
+        let Some(transaction_id) = scheduled.order.transaction_ids.pop_front() else {
+            self.orders.remove(&id);
+            return self.take_due(sequence);
+        };
+        let order = &scheduled.order;
+        let transfer = Transfer {
+            order: StandingOrderId(id),
+            from: order.from,
+            to: order.to,
+            amount: order.amount,
+            transaction_id,
+        };
+        if order.transaction_ids.is_empty() {
+            self.orders.remove(&id);
+        } else {
+            scheduled.due = due.saturating_add(order.every.get());
+            self.by_due.insert((scheduled.due, id));
+        }
+        Some(transfer)
+    }
+}
@@ -0,0 +1,96 @@
+//! Screening clients against sanctions lists or other blocklists before their transactions are applied,
+//! see [`crate::PaymentEngineBuilder::with_screening`].
+
+use alloc::sync::Arc;
+use core::fmt;
+
+use crate::id::ClientId;
+use crate::HashSet;
+
+/// Consulted before every transaction, transactions of a blocked client are rejected as
+/// [`crate::RejectionReason::Screened`] without touching their account.
+pub trait Screening<C: ClientId = u16>: Send + Sync {
+    fn is_blocked(&self, client: C) -> bool;
+}
+
+impl<C: ClientId, S: Screening<C> + ?Sized> Screening<C> for Arc<S> {
+    fn is_blocked(&self, client: C) -> bool {
+        self.as_ref().is_blocked(client)
+    }
+}
+
+/// A fixed set of blocked clients, e.g. loaded from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blocklist<C: ClientId = u16> {
+    clients: HashSet<C>,
+}
+
+impl<C: ClientId> Default for Blocklist<C> {
+    fn default() -> Self {
+        Self {
+            clients: HashSet::new(),
+        }
+    }
+}
+
+impl<C: ClientId> Blocklist<C> {
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+impl<C: ClientId> FromIterator<C> for Blocklist<C> {
+    fn from_iter<I: IntoIterator<Item = C>>(clients: I) -> Self {
+        Self {
+            clients: clients.into_iter().collect(),
+        }
+    }
+}
+
+impl<C: ClientId + Send + Sync> Screening<C> for Blocklist<C> {
+    fn is_blocked(&self, client: C) -> bool {
+        self.clients.contains(&client)
+    }
+}
+
+/// The screening of an engine, if it has one. Like the store it isn't part of the engine state,
+/// so it isn't serialized or compared.
+pub(crate) struct ScreeningHandle<C: ClientId>(pub(crate) Option<Arc<dyn Screening<C>>>);
+
+impl<C: ClientId> ScreeningHandle<C> {
+    pub(crate) fn blocks(&self, client: C) -> bool {
+        self.0.as_ref().is_some_and(|s| s.is_blocked(client))
+    }
+}
+
+impl<C: ClientId> Default for ScreeningHandle<C> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<C: ClientId> Clone for ScreeningHandle<C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C: ClientId> fmt::Debug for ScreeningHandle<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ScreeningHandle")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+impl<C: ClientId> PartialEq for ScreeningHandle<C> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl<C: ClientId> Eq for ScreeningHandle<C> {}
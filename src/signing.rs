@@ -0,0 +1,126 @@
+//! Detached Ed25519 signatures of an output file, so a consumer can check the file wasn't modified between the
+//! batch job that wrote it and its ingestion.
+//!
+//! The signature covers `SHA-256(output) || state digest`, where the state digest is the
+//! [`crate::PaymentEngine::merkle_root`] of the engine that produced the output. A [`SignedOutput`] is stored next to
+//! the output as text:
+//!
+//! ```text
+//! state-digest <64 hex digits>
+//! signature <128 hex digits>
+//! ```
+
+use std::path::Path;
+
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use ed25519_dalek::{Signer, Verifier};
+use sha2::{Digest, Sha256};
+
+use crate::audit::AuditHash;
+use crate::hex::parse_hex;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SigningError {
+    #[error("could not read the signing key {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("the signing key {0} is not 64 hex digits")]
+    InvalidKey(String),
+    #[error("invalid signature file")]
+    InvalidSignatureFile,
+}
+
+/// Reads a secret key stored as 64 hex digits, e.g. from `openssl rand -hex 32`.
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, SigningError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| SigningError::Io(path.display().to_string(), e))?;
+    let bytes = parse_hex::<32>(contents.trim())
+        .ok_or_else(|| SigningError::InvalidKey(path.display().to_string()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedOutput {
+    pub state_digest: AuditHash,
+    pub signature: Signature,
+}
+
+impl SignedOutput {
+    pub fn sign(key: &SigningKey, output: &[u8], state_digest: AuditHash) -> Self {
+        Self {
+            state_digest,
+            signature: key.sign(&message(output, &state_digest)),
+        }
+    }
+
+    /// Whether `output` is what was signed by the owner of `key`.
+    pub fn verify(&self, key: &VerifyingKey, output: &[u8]) -> bool {
+        key.verify(&message(output, &self.state_digest), &self.signature)
+            .is_ok()
+    }
+
+    pub fn to_text(&self) -> String {
+        format!(
+            "state-digest {}\nsignature {}\n",
+            to_hex(&self.state_digest),
+            to_hex(&self.signature.to_bytes())
+        )
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SigningError> {
+        let mut lines = text.lines();
+        let mut field = |name: &str| {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|value| value.strip_prefix(' '))
+                .ok_or(SigningError::InvalidSignatureFile)
+        };
+        let state_digest = parse_hex(field("state-digest")?);
+        let signature = parse_hex(field("signature")?);
+        match (state_digest, signature) {
+            (Some(state_digest), Some(signature)) => Ok(Self {
+                state_digest,
+                signature: Signature::from_bytes(&signature),
+            }),
+            _ => Err(SigningError::InvalidSignatureFile),
+        }
+    }
+}
+
+fn message(output: &[u8], state_digest: &AuditHash) -> Vec<u8> {
+    let mut message = Sha256::digest(output).to_vec();
+    message.extend_from_slice(state_digest);
+    message
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_cover_the_output_and_the_state_digest() {
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let output = b"client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+        let signed = SignedOutput::sign(&key, output, [9; 32]);
+
+        let parsed = SignedOutput::parse(&signed.to_text()).unwrap();
+        assert_eq!(parsed, signed);
+        assert!(parsed.verify(&key.verifying_key(), output));
+        assert!(!parsed.verify(
+            &key.verifying_key(),
+            b"client,available,held,total,locked\n1,9.5,0,9.5,false\n"
+        ));
+        let other_digest = SignedOutput {
+            state_digest: [8; 32],
+            ..parsed.clone()
+        };
+        assert!(!other_digest.verify(&key.verifying_key(), output));
+        assert!(!parsed.verify(&SigningKey::from_bytes(&[6; 32]).verifying_key(), output));
+
+        assert!(SignedOutput::parse("signature 00\n").is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Drives the engine with a seeded random workload, injecting faults and checking the invariants of the engine after
+//! every record, so a failing run can be replayed exactly from its seed.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use rust_decimal::Decimal;
+
+use crate::amount::Amount;
+use crate::config::EngineConfig;
+use crate::stats::EngineStats;
+use crate::{DisputeAction, PaymentEngine, Record, Transaction};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    /// Runs with the same seed and configuration generate the same records.
+    pub seed: u64,
+    /// Records are generated for clients `1..=clients`.
+    pub clients: u16,
+    pub batches: u64,
+    /// Every batch holds between one and this many records.
+    pub max_batch_size: usize,
+    pub faults: FaultRates,
+    pub engine: EngineConfig,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clients: 10,
+            batches: 1_000,
+            max_batch_size: 8,
+            faults: FaultRates::default(),
+            engine: EngineConfig::default(),
+        }
+    }
+}
+
+/// The probability, between 0 and 1, of every kind of fault.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultRates {
+    /// A record is delivered again, after the records generated since.
+    pub duplicate: f64,
+    /// A dispute action arrives before the transaction it references, or for the transaction of another client.
+    pub out_of_order: f64,
+    /// A batch loses its tail, as if the upstream connection dropped halfway.
+    pub truncated_batch: f64,
+}
+
+impl Default for FaultRates {
+    fn default() -> Self {
+        Self {
+            duplicate: 0.05,
+            out_of_order: 0.05,
+            truncated_batch: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulationReport {
+    /// The records that were applied, including duplicates.
+    pub records: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub truncated_batches: u64,
+    /// The statistics of the engine at the end of the run.
+    pub stats: EngineStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invariant violated after record {record} of the simulation with seed {seed}: {violation}")]
+pub struct InvariantViolation {
+    pub seed: u64,
+    pub record: u64,
+    pub violation: String,
+}
+
+/// Runs a simulation, stopping at the first record after which an invariant doesn't hold.
+pub fn run(config: &SimulationConfig) -> Result<SimulationReport, InvariantViolation> {
+    let mut simulation = Simulation {
+        rng: SplitMix64(config.seed),
+        config,
+        engine: PaymentEngine::builder()
+            .config(config.engine.clone())
+            .build(),
+        sent: Vec::new(),
+        next_transaction_id: 1,
+        report: SimulationReport {
+            records: 0,
+            duplicates: 0,
+            out_of_order: 0,
+            truncated_batches: 0,
+            stats: EngineStats::default(),
+        },
+    };
+    for _ in 0..config.batches {
+        simulation.batch()?;
+    }
+    simulation.report.stats = simulation.engine.stats();
+    Ok(simulation.report)
+}
+
+struct Simulation<'a> {
+    rng: SplitMix64,
+    config: &'a SimulationConfig,
+    engine: PaymentEngine,
+    /// Every record generated so far, to pick duplicates and dispute targets from.
+    sent: Vec<Record>,
+    next_transaction_id: u32,
+    report: SimulationReport,
+}
+
+impl Simulation<'_> {
+    fn batch(&mut self) -> Result<(), InvariantViolation> {
+        let size = 1 + self.rng.below(self.config.max_batch_size.max(1) as u64) as usize;
+        let mut batch: Vec<Record> = (0..size).map(|_| self.record()).collect();
+        if self.rng.chance(self.config.faults.truncated_batch) {
+            self.report.truncated_batches += 1;
+            batch.truncate(self.rng.below(size as u64) as usize);
+        }
+        for record in batch {
+            self.report.records += 1;
+            self.engine.apply(record);
+            if let Err(violation) = check_invariants(&self.engine) {
+                return Err(InvariantViolation {
+                    seed: self.config.seed,
+                    record: self.report.records,
+                    violation,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self) -> Record {
+        let faults = self.config.faults;
+        if !self.sent.is_empty() && self.rng.chance(faults.duplicate) {
+            self.report.duplicates += 1;
+            let index = self.rng.below(self.sent.len() as u64) as usize;
+            return self.sent[index].clone();
+        }
+        let client = 1 + self.rng.below(u64::from(self.config.clients.max(1))) as u16;
+        let record = if self.rng.chance(faults.out_of_order) {
+            self.report.out_of_order += 1;
+            // Either a transaction that doesn't exist yet, or one of any client.
+            let referenced_transaction_id = match self.rng.below(2) {
+                0 => self.next_transaction_id + 1 + self.rng.below(8) as u32,
+                _ => 1 + self.rng.below(u64::from(self.next_transaction_id)) as u32,
+            };
+            self.dispute_action(client, referenced_transaction_id)
+        } else {
+            match self.rng.below(10) {
+                0..=3 => Record::Transaction(Transaction::Deposit {
+                    client,
+                    transaction_id: self.transaction_id(),
+                    amount: self.amount(),
+                }),
+                4..=6 => Record::Transaction(Transaction::Withdrawal {
+                    client,
+                    transaction_id: self.transaction_id(),
+                    amount: self.amount(),
+                }),
+                _ => {
+                    let transactions: Vec<(u16, u32)> = self
+                        .sent
+                        .iter()
+                        .filter_map(|record| match record {
+                            Record::Transaction(Transaction::Deposit {
+                                client,
+                                transaction_id,
+                                ..
+                            }) => Some((*client, *transaction_id)),
+                            _ => None,
+                        })
+                        .collect();
+                    if transactions.is_empty() {
+                        return self.record();
+                    }
+                    let (client, transaction_id) =
+                        transactions[self.rng.below(transactions.len() as u64) as usize];
+                    self.dispute_action(client, transaction_id)
+                }
+            }
+        };
+        self.sent.push(record.clone());
+        record
+    }
+
+    fn dispute_action(&mut self, client: u16, referenced_transaction_id: u32) -> Record {
+        Record::Dispute(match self.rng.below(4) {
+            0 | 1 => DisputeAction::Dispute {
+                client,
+                referenced_transaction_id,
+            },
+            2 => DisputeAction::Resolve {
+                client,
+                referenced_transaction_id,
+            },
+            _ => DisputeAction::Chargeback {
+                client,
+                referenced_transaction_id,
+            },
+        })
+    }
+
+    fn transaction_id(&mut self) -> u32 {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1;
+        transaction_id
+    }
+
+    /// Up to 100 with four decimal places.
+    fn amount(&mut self) -> Amount {
+        let ten_thousandths = 1 + self.rng.below(1_000_000) as i64;
+        Amount::non_negative(Decimal::new(ten_thousandths, 4))
+            .expect("The amount is positive and exact.")
+    }
+}
+
+/// Checks what has to hold whatever records the engine gets, describing the first thing that doesn't.
+fn check_invariants(engine: &PaymentEngine) -> Result<(), String> {
+    let report = engine.invariant_report();
+    if !report.negative_held.is_empty() {
+        return Err(format!(
+            "negative held funds for {:?}",
+            report.negative_held
+        ));
+    }
+
+    let stats = engine.stats();
+    let (mut available, mut held, mut locked) = (Decimal::ZERO, Decimal::ZERO, 0);
+    for account in engine.get_all_client_states() {
+        available += Decimal::from(account.available());
+        held += Decimal::from(account.held());
+        locked += usize::from(account.locked());
+    }
+    if stats.total_available != available || stats.total_held != held {
+        return Err(format!(
+            "the totals of the statistics ({}, {}) don't match the accounts ({}, {})",
+            stats.total_available, stats.total_held, available, held
+        ));
+    }
+    if stats.clients != engine.len() || stats.locked_clients != locked {
+        return Err(format!(
+            "the statistics count {} clients and {} locked ones, the engine has {} and {}",
+            stats.clients,
+            stats.locked_clients,
+            engine.len(),
+            locked
+        ));
+    }
+    let open_disputes = engine.open_disputes().count() as u64;
+    if stats.open_disputes != open_disputes {
+        return Err(format!(
+            "the statistics count {} open disputes, the engine has {}",
+            stats.open_disputes, open_disputes
+        ));
+    }
+    Ok(())
+}
+
+/// A small, fast generator whose output only depends on its seed, see <https://prng.di.unimi.it/splitmix64.c>.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, `n` has to be positive.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulations_hold_the_invariants_and_are_deterministic() {
+        for seed in 0..8 {
+            let config = SimulationConfig {
+                seed,
+                batches: 200,
+                ..Default::default()
+            };
+            let report = run(&config).unwrap();
+            assert!(report.duplicates > 0);
+            assert!(report.out_of_order > 0);
+            assert!(report.truncated_batches > 0);
+            assert_eq!(run(&config).unwrap(), report);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Engine-wide statistics, maintained incrementally while records are applied.
+
+use alloc::vec::Vec;
+use rust_decimal::Decimal;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::id::{ClientId, TransactionId};
+use crate::{ClientAccount, Outcome};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordCounts {
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+impl RecordCounts {
+    pub(crate) fn count(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Applied => self.accepted += 1,
+            Outcome::Rejected(_) => self.rejected += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EngineStats {
+    pub clients: usize,
+    pub locked_clients: usize,
+    pub deposits: RecordCounts,
+    pub withdrawals: RecordCounts,
+    pub disputes: RecordCounts,
+    pub resolves: RecordCounts,
+    /// Accepted chargebacks are the number of transactions that have been charged back.
+    pub chargebacks: RecordCounts,
+    /// Accepted escalations are the number of disputes that have been taken to arbitration.
+    pub escalations: RecordCounts,
+    /// Decided arbitrations, whether they were won or lost.
+    pub arbitrations: RecordCounts,
+    /// Confirmed or cancelled withdrawal requests.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub settlements: RecordCounts,
+    /// Composite transactions as a whole, their legs are counted as deposits and withdrawals as well.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub composites: RecordCounts,
+    /// Amendments and other administrative corrections.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub adjustments: RecordCounts,
+    /// Accounts opened or closed by records, see [`crate::AccountAction`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub account_actions: RecordCounts,
+    /// Records whose type is disabled, see [`crate::config::EngineConfig::disabled`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub disabled: u64,
+    /// Disputes that have neither been resolved nor charged back yet.
+    pub open_disputes: u64,
+    pub total_available: Decimal,
+    pub total_held: Decimal,
+    /// The sum of the debt of all accounts in deficit, see [`ClientAccount::debt`].
+    pub total_debt: Decimal,
+    /// The sum of all debts that were written off, see [`crate::PaymentEngine::loss_ledger`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub total_written_off: Decimal,
+    /// Dispute actions that would have made the held funds of an account negative, whether they were rejected or clamped.
+    pub negative_held_prevented: u64,
+}
+
+/// Accounts in a state that shouldn't be possible, see [`crate::PaymentEngine::invariant_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InvariantReport<C = u16> {
+    /// Accounts with negative held funds, ordered by client id.
+    pub negative_held: Vec<C>,
+    /// Accounts with a negative total, ordered by client id. Unlike negative held funds this can happen
+    /// through a chargeback of funds that were already withdrawn, see [`ClientAccount::debt`].
+    pub negative_total: Vec<C>,
+    /// See [`EngineStats::negative_held_prevented`].
+    pub negative_held_prevented: u64,
+}
+
+impl<C> InvariantReport<C> {
+    /// Whether there's no account to look into.
+    pub fn is_clean(&self) -> bool {
+        self.negative_held.is_empty() && self.negative_total.is_empty()
+    }
+}
+
+/// The parts of an account that contribute to the engine-wide totals.
+#[derive(Clone, Copy)]
+pub(crate) struct AccountTotals {
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) locked: bool,
+}
+
+impl AccountTotals {
+    /// The totals of a new account.
+    pub(crate) const ZERO: Self = Self {
+        available: Decimal::ZERO,
+        held: Decimal::ZERO,
+        locked: false,
+    };
+
+    fn debt(&self) -> Decimal {
+        (-self.available).max(Decimal::ZERO)
+    }
+
+    pub(crate) fn of<C: ClientId, T: TransactionId>(client: &ClientAccount<C, T>) -> Self {
+        Self {
+            available: client.available().value(),
+            held: client.held().value(),
+            locked: client.locked(),
+        }
+    }
+}
+
+impl EngineStats {
+    /// Folds the change of a single account into the totals.
+    pub(crate) fn account_changed(&mut self, before: AccountTotals, after: AccountTotals) {
+        // Each account's balances fit, but their sum over all accounts might not, so these saturate rather than panic.
+        self.total_available = self
+            .total_available
+            .saturating_add(after.available.saturating_sub(before.available));
+        self.total_held = self
+            .total_held
+            .saturating_add(after.held.saturating_sub(before.held));
+        self.total_debt = self
+            .total_debt
+            .saturating_add(after.debt().saturating_sub(before.debt()));
+        match (before.locked, after.locked) {
+            (false, true) => self.locked_clients += 1,
+            (true, false) => self.locked_clients -= 1,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn account_removed(&mut self, totals: AccountTotals, open_disputes: u64) {
+        self.clients -= 1;
+        self.open_disputes -= open_disputes;
+        self.account_changed(totals, AccountTotals::ZERO);
+    }
+}
@@ -0,0 +1,133 @@
+//! Keeping accounts somewhere besides the engine's memory, see [`crate::PaymentEngineBuilder::with_store`].
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::error::Error;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+
+use crate::id::{ClientId, TransactionId};
+use crate::ClientAccount;
+#[cfg(feature = "std")]
+use crate::HashMap;
+
+#[cfg(feature = "sled")]
+pub mod sled;
+
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+/// Receives every account the engine changes, after the change, so the store always has the latest state.
+///
+/// The engine keeps working from memory when the store fails, reporting the failure as
+/// [`crate::event::EngineEvent::StoreFailed`]. Whether to retry is up to the store.
+pub trait AccountStore<C: ClientId = u16, T: TransactionId = u32>: Send + Sync {
+    /// `transactions` are the ones whose entry in the history of the account was added, changed or removed,
+    /// so a store that keeps the history separately only has to write those.
+    /// An entry was removed (e.g. pruned) when the account doesn't have it anymore.
+    fn save(&self, account: &ClientAccount<C, T>, transactions: &[T]) -> Result<(), StoreError>;
+
+    /// The account was removed from the engine, e.g. through [`crate::PaymentEngine::remove_client`].
+    fn remove(&self, client: C) -> Result<(), StoreError>;
+}
+
+impl<C: ClientId, T: TransactionId, S: AccountStore<C, T> + ?Sized> AccountStore<C, T> for Arc<S> {
+    fn save(&self, account: &ClientAccount<C, T>, transactions: &[T]) -> Result<(), StoreError> {
+        self.as_ref().save(account, transactions)
+    }
+
+    fn remove(&self, client: C) -> Result<(), StoreError> {
+        self.as_ref().remove(client)
+    }
+}
+
+/// Keeps the stored accounts in a map, mostly useful for tests.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct MemoryStore<C: ClientId = u16, T: TransactionId = u32> {
+    accounts: Mutex<HashMap<C, ClientAccount<C, T>>>,
+}
+
+#[cfg(feature = "std")]
+impl<C: ClientId, T: TransactionId> Default for MemoryStore<C, T> {
+    fn default() -> Self {
+        Self {
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: ClientId, T: TransactionId> MemoryStore<C, T> {
+    pub fn get(&self, client: C) -> Option<ClientAccount<C, T>> {
+        self.accounts
+            .lock()
+            .expect("No panics while holding the lock.")
+            .get(&client)
+            .cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts
+            .lock()
+            .expect("No panics while holding the lock.")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: ClientId + Send, T: TransactionId + Send> AccountStore<C, T> for MemoryStore<C, T> {
+    fn save(&self, account: &ClientAccount<C, T>, _: &[T]) -> Result<(), StoreError> {
+        self.accounts
+            .lock()
+            .expect("No panics while holding the lock.")
+            .insert(account.id(), account.clone());
+        Ok(())
+    }
+
+    fn remove(&self, client: C) -> Result<(), StoreError> {
+        self.accounts
+            .lock()
+            .expect("No panics while holding the lock.")
+            .remove(&client);
+        Ok(())
+    }
+}
+
+/// The store of an engine, if it has one. Like observers it isn't part of the engine state,
+/// so it isn't serialized or compared.
+pub(crate) struct StoreHandle<C: ClientId, T: TransactionId>(
+    pub(crate) Option<Arc<dyn AccountStore<C, T>>>,
+);
+
+impl<C: ClientId, T: TransactionId> Default for StoreHandle<C, T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Clone for StoreHandle<C, T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C: ClientId, T: TransactionId> fmt::Debug for StoreHandle<C, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StoreHandle")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+impl<C: ClientId, T: TransactionId> PartialEq for StoreHandle<C, T> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl<C: ClientId, T: TransactionId> Eq for StoreHandle<C, T> {}
@@ -0,0 +1,226 @@
+//! An [`AccountStore`] in an embedded [sled](https://docs.rs/sled) database.
+//!
+//! The balances of every account are kept in the `accounts` tree, keyed by client id. The transaction history is kept
+//! in the `history` tree, keyed by client and transaction id, so a change only writes the history entries it touched.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use ::sled::transaction::{ConflictableTransactionError, TransactionError};
+use ::sled::{Db, Transactional, Tree};
+use serde::{Deserialize, Serialize};
+
+use super::{AccountStore, StoreError};
+use crate::amount::Amount;
+use crate::id::{ClientId, TransactionId};
+use crate::metadata::ClientMetadata;
+use crate::{ClientAccount, DisputeAction};
+
+/// Everything of an account but its transaction history.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "C: ClientId, T: TransactionId",
+    deserialize = "C: ClientId, T: TransactionId"
+))]
+struct StoredAccount<'a, C: Clone, T: Clone> {
+    id: C,
+    dispute_history: Cow<'a, [DisputeAction<C, T>]>,
+    transaction_count: u64,
+    open_disputes: usize,
+    available: Amount,
+    held: Amount,
+    provisional: Amount,
+    clamped_held: Amount,
+    credit_limit: Amount,
+    locked: bool,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    metadata: Option<Arc<ClientMetadata>>,
+    #[cfg(feature = "audit")]
+    #[serde(default)]
+    audit_head: crate::audit::AuditHash,
+}
+
+pub struct SledStore<C: ClientId = u16, T: TransactionId = u32> {
+    accounts: Tree,
+    history: Tree,
+    ids: PhantomData<fn() -> (C, T)>,
+}
+
+impl<C: ClientId, T: TransactionId> SledStore<C, T> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::in_db(&::sled::open(path)?)
+    }
+
+    /// Uses the trees of an already opened database, e.g. one that is shared with other data.
+    pub fn in_db(db: &Db) -> Result<Self, StoreError> {
+        Ok(Self {
+            accounts: db.open_tree("accounts")?,
+            history: db.open_tree("history")?,
+            ids: PhantomData,
+        })
+    }
+
+    /// Every stored account, to restore an engine from with [`crate::PaymentEngine::insert_account`].
+    pub fn load(&self) -> Result<Vec<ClientAccount<C, T>>, StoreError> {
+        let mut accounts = vec![];
+        for entry in self.accounts.iter() {
+            let (key, value) = entry?;
+            let stored: StoredAccount<C, T> = serde_json::from_slice(&value)?;
+            let mut transaction_history = HashMap::new();
+            for entry in self.history.scan_prefix(history_prefix(&key)) {
+                let (key, value) = entry?;
+                let transaction_id: T = serde_json::from_slice(&key[history_prefix_len(&key)..])?;
+                transaction_history.insert(transaction_id, serde_json::from_slice(&value)?);
+            }
+            accounts.push(ClientAccount {
+                id: stored.id,
+                transaction_history,
+                dispute_history: stored.dispute_history.into_owned(),
+                transaction_count: stored.transaction_count,
+                open_disputes: stored.open_disputes,
+                available: stored.available,
+                held: stored.held,
+                provisional: stored.provisional,
+                clamped_held: stored.clamped_held,
+                credit_limit: stored.credit_limit,
+                locked: stored.locked,
+                closed: stored.closed,
+                metadata: stored.metadata,
+                // Sequence numbers are local to an engine, the account counts as active once it's inserted.
+                last_activity: 0,
+                #[cfg(feature = "audit")]
+                audit_head: stored.audit_head,
+            });
+        }
+        Ok(accounts)
+    }
+
+    /// Waits until everything that was saved is durable on disk.
+    pub fn flush(&self) -> Result<(), StoreError> {
+        self.accounts.flush()?;
+        self.history.flush()?;
+        Ok(())
+    }
+}
+
+/// Client ids are stored as JSON, which never contains a NUL byte, so it separates them from transaction ids.
+fn history_prefix(client_key: &[u8]) -> Vec<u8> {
+    let mut prefix = client_key.to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn history_prefix_len(history_key: &[u8]) -> usize {
+    history_key
+        .iter()
+        .position(|byte| *byte == 0)
+        .map_or(history_key.len(), |index| index + 1)
+}
+
+impl<C: ClientId, T: TransactionId> AccountStore<C, T> for SledStore<C, T> {
+    fn save(&self, account: &ClientAccount<C, T>, transactions: &[T]) -> Result<(), StoreError> {
+        let client_key = serde_json::to_vec(&account.id)?;
+        let stored = serde_json::to_vec(&StoredAccount {
+            id: account.id,
+            dispute_history: Cow::Borrowed(&account.dispute_history),
+            transaction_count: account.transaction_count,
+            open_disputes: account.open_disputes,
+            available: account.available,
+            held: account.held,
+            provisional: account.provisional,
+            clamped_held: account.clamped_held,
+            credit_limit: account.credit_limit,
+            locked: account.locked,
+            closed: account.closed,
+            metadata: account.metadata.clone(),
+            #[cfg(feature = "audit")]
+            audit_head: account.audit_head,
+        })?;
+        let mut history = vec![];
+        for transaction_id in transactions {
+            let mut key = history_prefix(&client_key);
+            key.extend(serde_json::to_vec(transaction_id)?);
+            let value = match account.transaction_history.get(transaction_id) {
+                Some(record) => Some(serde_json::to_vec(record)?),
+                None => None,
+            };
+            history.push((key, value));
+        }
+
+        // Both trees are updated at once, so the balances never disagree with the history.
+        (&self.accounts, &self.history)
+            .transaction(|(accounts, history_tree)| {
+                accounts.insert(client_key.as_slice(), stored.as_slice())?;
+                for (key, value) in &history {
+                    match value {
+                        Some(value) => history_tree.insert(key.as_slice(), value.as_slice())?,
+                        None => history_tree.remove(key.as_slice())?,
+                    };
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|error: TransactionError<()>| match error {
+                TransactionError::Storage(error) => error.into(),
+                TransactionError::Abort(()) => "the sled transaction was aborted".into(),
+            })
+    }
+
+    fn remove(&self, client: C) -> Result<(), StoreError> {
+        let client_key = serde_json::to_vec(&client)?;
+        self.accounts.remove(&client_key)?;
+        for key in self.history.scan_prefix(history_prefix(&client_key)).keys() {
+            self.history.remove(key?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{PaymentEngine, Retention, Transaction};
+
+    #[test]
+    fn accounts_survive_a_restart() {
+        let db = ::sled::Config::new().temporary(true).open().unwrap();
+        let store = std::sync::Arc::new(SledStore::in_db(&db).unwrap());
+        let mut payment_engine: PaymentEngine =
+            PaymentEngine::builder().with_store(store.clone()).build();
+        for (client, transaction_id) in [(1, 1), (1, 2), (2, 3)] {
+            payment_engine.add_transaction(Transaction::Deposit {
+                client,
+                transaction_id,
+                amount: Amount::new(dec!(1.5)).unwrap(),
+            });
+        }
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 2,
+        });
+        payment_engine.add_transaction(Transaction::Withdrawal {
+            client: 2,
+            transaction_id: 4,
+            amount: Amount::new(dec!(5)).unwrap(),
+        });
+        payment_engine.prune_history(Retention::KeepLast(0));
+        payment_engine.remove_client(2);
+
+        let mut restored: PaymentEngine = PaymentEngine::default();
+        for account in store.load().unwrap() {
+            restored.insert_account(account);
+        }
+        assert_eq!(
+            restored.differing_clients(&payment_engine),
+            Vec::<u16>::new()
+        );
+        assert_eq!(restored.stats().open_disputes, 1);
+        assert_eq!(store.history.len(), 2);
+    }
+}
@@ -0,0 +1,541 @@
+//! A TCP listener for internal systems that send too many records for JSON over HTTP, see [`TcpIngest`] and
+//! [`TcpClient`].
+//!
+//! Every record is a frame: its length as a big-endian `u32`, followed by the [`WireRecord`] encoded with
+//! [bincode](https://docs.rs/bincode/1). Records aren't acknowledged one by one. A client that closes its side of the
+//! connection gets the connection closed by the listener once every record it sent has been applied, see
+//! [`TcpClient::finish`].
+//!
+//! A listener with [`TcpIngest::api_keys`] expects a key with the [`Role::Ingest`] role before the records: its length
+//! as a big-endian `u32`, followed by the key itself, see [`TcpClient::authenticate`].
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::auth::{ApiKeys, Role};
+use crate::id::{ClientId, TransactionId};
+use crate::rate_limit::TokenBucket;
+use crate::wire::{WireError, WireRecord};
+use crate::{PaymentEngine, Record};
+
+/// Frames longer than this are refused, a record is a few dozen bytes.
+pub const MAX_FRAME_LENGTH: u32 = 64 * 1024;
+
+/// Keys longer than this are refused.
+pub const MAX_KEY_LENGTH: u32 = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TcpError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("a frame of {0} bytes is longer than the maximum of {MAX_FRAME_LENGTH}")]
+    FrameTooLong(u32),
+    #[error("invalid frame: {0}")]
+    Encoding(#[from] bincode::Error),
+    #[error(transparent)]
+    Record(#[from] WireError),
+    #[error("the connection didn't start with a key that may send records")]
+    Unauthorized,
+}
+
+/// Writes `record` as a frame.
+pub fn write_frame<C: ClientId, T: TransactionId>(
+    writer: &mut impl Write,
+    record: &WireRecord<C, T>,
+) -> Result<(), TcpError> {
+    let encoded = bincode::serialize(record)?;
+    let length = u32::try_from(encoded.len()).unwrap_or(u32::MAX);
+    if length > MAX_FRAME_LENGTH {
+        return Err(TcpError::FrameTooLong(length));
+    }
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(&encoded)?;
+    Ok(())
+}
+
+/// Reads the next frame, `None` when the input ends before one starts.
+pub fn read_frame<C: ClientId, T: TransactionId>(
+    reader: &mut impl Read,
+) -> Result<Option<WireRecord<C, T>>, TcpError> {
+    let mut length = [0; 4];
+    match reader.read(&mut length[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut length[1..])?,
+    }
+    let length = u32::from_be_bytes(length);
+    if length > MAX_FRAME_LENGTH {
+        return Err(TcpError::FrameTooLong(length));
+    }
+    let mut encoded = vec![0; length as usize];
+    reader.read_exact(&mut encoded)?;
+    Ok(Some(bincode::deserialize(&encoded)?))
+}
+
+/// Applies the records of every connection to an engine that is shared behind a lock, like a
+/// [`crate::PaymentEngineView`] reads it.
+///
+/// Every connection has a thread of its own, records of a connection are applied in the order they were sent. A
+/// connection that sends an invalid frame is closed, the records before it stay applied.
+pub struct TcpIngest<C: ClientId = u16, T: TransactionId = u32> {
+    engine: Arc<RwLock<PaymentEngine<C, T>>>,
+    state: Arc<IngestState>,
+    drain_timeout: Duration,
+    /// Shared by all connections, see [`TcpIngest::rate_limit`].
+    rate_limit: Option<Arc<Mutex<TokenBucket>>>,
+    api_keys: Option<Arc<ApiKeys>>,
+}
+
+#[derive(Default)]
+struct IngestState {
+    records: AtomicU64,
+    stopping: AtomicBool,
+    /// Where [`TcpIngest::serve`] listens, to wake it up when it's stopped.
+    address: Mutex<Option<SocketAddr>>,
+}
+
+impl<C: ClientId, T: TransactionId> Clone for TcpIngest<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: Arc::clone(&self.engine),
+            state: Arc::clone(&self.state),
+            drain_timeout: self.drain_timeout,
+            rate_limit: self.rate_limit.clone(),
+            api_keys: self.api_keys.clone(),
+        }
+    }
+}
+
+impl<C: ClientId + Send + Sync + 'static, T: TransactionId + Send + Sync + 'static>
+    TcpIngest<C, T>
+{
+    /// How long [`TcpIngest::serve`] waits for open connections once it's stopped, by default.
+    pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(engine: &Arc<RwLock<PaymentEngine<C, T>>>) -> Self {
+        Self {
+            engine: Arc::clone(engine),
+            state: Arc::default(),
+            drain_timeout: Self::DRAIN_TIMEOUT,
+            rate_limit: None,
+            api_keys: None,
+        }
+    }
+
+    /// Only applies the records of connections that start with one of `api_keys` that allows [`Role::Ingest`].
+    /// Other connections are closed with [`TcpError::Unauthorized`].
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = Some(Arc::new(api_keys));
+        self
+    }
+
+    /// Caps the number of records applied per second, over all connections. A connection isn't read from while it
+    /// waits for a token, so TCP flow control pushes back on its client.
+    pub fn rate_limit(mut self, bucket: TokenBucket) -> Self {
+        self.rate_limit = Some(Arc::new(Mutex::new(bucket)));
+        self
+    }
+
+    /// How long the open connections get to close once [`TcpIngest::stop`] is called, see [`TcpIngest::serve`].
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// How many records have been received, over all connections.
+    pub fn records(&self) -> u64 {
+        self.state.records.load(Ordering::SeqCst)
+    }
+
+    /// Accepts connections on `listener` until [`TcpIngest::stop`] is called, or accepting one fails. `on_error` gets
+    /// the connections that were closed because of an error.
+    ///
+    /// Once stopped, no more connections are accepted and `serve` returns when the open ones have been closed by
+    /// their clients, with all of their records applied. Connections that are still open after the drain timeout are
+    /// closed, records they hadn't sent completely yet are lost and reported to `on_error`.
+    pub fn serve(
+        &self,
+        listener: TcpListener,
+        on_error: impl Fn(SocketAddr, TcpError) + Send + Sync + 'static,
+    ) -> std::io::Result<()> {
+        *self
+            .state
+            .address
+            .lock()
+            .expect("No panics while holding the lock.") = Some(listener.local_addr()?);
+        let on_error = Arc::new(on_error);
+        // Every connection keeps a handle on its socket, to close it when draining takes too long.
+        let mut connections: Vec<(std::thread::JoinHandle<()>, TcpStream)> = vec![];
+        while !self.state.stopping.load(Ordering::SeqCst) {
+            let (stream, peer) = listener.accept()?;
+            if self.state.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let socket = stream.try_clone()?;
+            let ingest = self.clone();
+            let on_error = Arc::clone(&on_error);
+            connections.retain(|(connection, _)| !connection.is_finished());
+            connections.push((
+                std::thread::spawn(move || {
+                    if let Err(error) = ingest.handle(stream) {
+                        on_error(peer, error);
+                    }
+                }),
+                socket,
+            ));
+        }
+
+        let deadline = Instant::now() + self.drain_timeout;
+        while Instant::now() < deadline
+            && connections
+                .iter()
+                .any(|(connection, _)| !connection.is_finished())
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        for (connection, socket) in connections {
+            if !connection.is_finished() {
+                // Reading reaches the end of the input, the record being applied still is.
+                let _ = socket.shutdown(Shutdown::Read);
+            }
+            // A panic while applying a record poisons the engine lock, which has the other connections panic on their
+            // next record too. Their records are no longer applied, and the caller finds the engine poisoned.
+            let _ = connection.join();
+        }
+        Ok(())
+    }
+
+    /// Has [`TcpIngest::serve`] stop accepting connections, it returns once the open ones are closed.
+    pub fn stop(&self) {
+        self.state.stopping.store(true, Ordering::SeqCst);
+        let address = *self
+            .state
+            .address
+            .lock()
+            .expect("No panics while holding the lock.");
+        if let Some(mut address) = address {
+            // A connection wakes up the accepting thread, which then sees it's stopping.
+            if address.ip().is_unspecified() {
+                address.set_ip(match address {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(address);
+        }
+    }
+
+    /// Applies the records of a single connection until the client closes it, returns how many there were.
+    pub fn handle(&self, stream: TcpStream) -> Result<u64, TcpError> {
+        let mut reader = BufReader::new(stream);
+        let result = self.apply_frames(&mut reader);
+        // Closing the connection tells the client every record has been applied. It's shut down explicitly, as
+        // `serve` keeps another handle on the socket.
+        let _ = reader.get_ref().shutdown(Shutdown::Both);
+        result
+    }
+
+    fn apply_frames(&self, reader: &mut impl Read) -> Result<u64, TcpError> {
+        if let Some(api_keys) = &self.api_keys {
+            let mut length = [0; 4];
+            reader.read_exact(&mut length)?;
+            let length = u32::from_be_bytes(length);
+            if length > MAX_KEY_LENGTH {
+                return Err(TcpError::Unauthorized);
+            }
+            let mut key = vec![0; length as usize];
+            reader.read_exact(&mut key)?;
+            if !api_keys
+                .role(&key)
+                .is_some_and(|role| role.allows(Role::Ingest))
+            {
+                return Err(TcpError::Unauthorized);
+            }
+        }
+        let mut records = 0;
+        while let Some(record) = read_frame::<C, T>(reader)? {
+            let record = Record::try_from(record)?;
+            if let Some(rate_limit) = &self.rate_limit {
+                rate_limit
+                    .lock()
+                    .expect("No panics while holding the lock.")
+                    .acquire();
+            }
+            self.engine
+                .write()
+                .expect("No panics while holding the lock.")
+                .apply(record);
+            self.state.records.fetch_add(1, Ordering::SeqCst);
+            records += 1;
+        }
+        Ok(records)
+    }
+}
+
+/// Sends records to a [`TcpIngest`], buffered.
+#[derive(Debug)]
+pub struct TcpClient {
+    stream: BufWriter<TcpStream>,
+}
+
+impl TcpClient {
+    pub fn connect(address: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends `key` to a listener with [`TcpIngest::api_keys`], before any record.
+    pub fn authenticate(&mut self, key: &str) -> Result<(), TcpError> {
+        let length = u32::try_from(key.len()).unwrap_or(u32::MAX);
+        if length > MAX_KEY_LENGTH {
+            return Err(TcpError::Unauthorized);
+        }
+        self.stream.write_all(&length.to_be_bytes())?;
+        self.stream.write_all(key.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn send<C: ClientId, T: TransactionId>(
+        &mut self,
+        record: impl Into<Record<C, T>>,
+    ) -> Result<(), TcpError> {
+        write_frame(&mut self.stream, &WireRecord::from(record.into()))
+    }
+
+    /// Sends the buffered records.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+
+    /// Sends the buffered records and waits for the listener to close the connection, after which every record
+    /// has been applied.
+    pub fn finish(self) -> std::io::Result<()> {
+        let mut stream = self.stream.into_inner().map_err(|e| e.into_error())?;
+        stream.shutdown(Shutdown::Write)?;
+        // The listener doesn't send anything, the read ends once it closed the connection.
+        stream.read_to_end(&mut vec![])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::{DisputeAction, Transaction};
+
+    #[test]
+    fn records_sent_by_a_client_are_applied() {
+        let engine = Arc::new(RwLock::new(PaymentEngine::<u16, u32>::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let ingest = TcpIngest::new(&engine);
+        std::thread::spawn(move || ingest.serve(listener, |_, error| panic!("{}", error)));
+
+        let mut client = TcpClient::connect(address).unwrap();
+        for transaction_id in 1..=100 {
+            client
+                .send(Transaction::<u16, u32>::Deposit {
+                    client: 1,
+                    transaction_id,
+                    amount: Amount::non_negative(dec!(0.5)).unwrap(),
+                })
+                .unwrap();
+        }
+        client
+            .send(DisputeAction::<u16, u32>::Dispute {
+                client: 1,
+                referenced_transaction_id: 1,
+            })
+            .unwrap();
+        client.finish().unwrap();
+
+        let account = engine.read().unwrap().get_client_state(1).cloned().unwrap();
+        assert_eq!(account.total(), dec!(50.0));
+        assert_eq!(account.held(), dec!(0.5));
+    }
+
+    #[test]
+    fn stopping_waits_for_open_connections() {
+        let engine = Arc::new(RwLock::new(PaymentEngine::<u16, u32>::default()));
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let ingest = TcpIngest::new(&engine);
+        let serving = {
+            let ingest = ingest.clone();
+            std::thread::spawn(move || ingest.serve(listener, |_, error| panic!("{}", error)))
+        };
+
+        let mut client = TcpClient::connect(("127.0.0.1", port)).unwrap();
+        client
+            .send(Transaction::<u16, u32>::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: Amount::non_negative(dec!(1.0)).unwrap(),
+            })
+            .unwrap();
+        client.flush().unwrap();
+        while ingest.records() == 0 {
+            std::thread::yield_now();
+        }
+        ingest.stop();
+        assert!(!serving.is_finished());
+
+        client
+            .send(Transaction::<u16, u32>::Deposit {
+                client: 1,
+                transaction_id: 2,
+                amount: Amount::non_negative(dec!(1.0)).unwrap(),
+            })
+            .unwrap();
+        client.finish().unwrap();
+        serving.join().unwrap().unwrap();
+        assert_eq!(ingest.records(), 2);
+        assert_eq!(
+            engine.read().unwrap().get_client_state(1).unwrap().total(),
+            dec!(2.0)
+        );
+    }
+
+    #[test]
+    fn idle_connections_are_closed_after_the_drain_timeout() {
+        let engine = Arc::new(RwLock::new(PaymentEngine::<u16, u32>::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let ingest = TcpIngest::new(&engine).drain_timeout(Duration::from_millis(50));
+        let serving = {
+            let ingest = ingest.clone();
+            std::thread::spawn(move || ingest.serve(listener, |_, error| panic!("{}", error)))
+        };
+
+        let mut client = TcpClient::connect(address).unwrap();
+        client
+            .send(Transaction::<u16, u32>::Deposit {
+                client: 1,
+                transaction_id: 1,
+                amount: Amount::non_negative(dec!(1.0)).unwrap(),
+            })
+            .unwrap();
+        client.flush().unwrap();
+        while ingest.records() == 0 {
+            std::thread::yield_now();
+        }
+        // The client never closes its side of the connection.
+        ingest.stop();
+        serving.join().unwrap().unwrap();
+
+        assert_eq!(
+            engine.read().unwrap().get_client_state(1).unwrap().total(),
+            dec!(1.0)
+        );
+        let mut stream = client.stream.into_inner().unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn only_keys_that_may_ingest_get_their_records_applied() {
+        let engine = Arc::new(RwLock::new(PaymentEngine::<u16, u32>::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut api_keys = ApiKeys::default();
+        api_keys.insert("feed", Role::Ingest);
+        api_keys.insert("dashboard", Role::ReadOnly);
+        let ingest = TcpIngest::new(&engine).api_keys(api_keys);
+        let (errors, closed) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            ingest.serve(listener, move |_, error| {
+                errors.send(error.to_string()).unwrap();
+            })
+        });
+
+        let deposit = |client: &mut TcpClient, transaction_id| {
+            client
+                .send(Transaction::<u16, u32>::Deposit {
+                    client: 1,
+                    transaction_id,
+                    amount: Amount::non_negative(dec!(1.0)).unwrap(),
+                })
+                .unwrap();
+        };
+        for key in [None, Some("dashboard"), Some("feeds")] {
+            let mut client = TcpClient::connect(address).unwrap();
+            if let Some(key) = key {
+                client.authenticate(key).unwrap();
+            }
+            deposit(&mut client, 1);
+            // The listener might close the connection before everything was sent.
+            let _ = client.finish();
+            assert_eq!(closed.recv().unwrap(), TcpError::Unauthorized.to_string());
+        }
+        let mut client = TcpClient::connect(address).unwrap();
+        client.authenticate("feed").unwrap();
+        deposit(&mut client, 2);
+        client.finish().unwrap();
+
+        let account = engine.read().unwrap().get_client_state(1).cloned().unwrap();
+        assert_eq!(account.total(), dec!(1.0));
+        assert_eq!(account.history().len(), 1);
+    }
+
+    #[test]
+    fn records_are_applied_at_the_rate_limit() {
+        let engine = Arc::new(RwLock::new(PaymentEngine::<u16, u32>::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let ingest = TcpIngest::new(&engine).rate_limit(TokenBucket::new(100, 1));
+        std::thread::spawn(move || ingest.serve(listener, |_, error| panic!("{}", error)));
+
+        let start = Instant::now();
+        let mut client = TcpClient::connect(address).unwrap();
+        for transaction_id in 1..=6 {
+            client
+                .send(Transaction::<u16, u32>::Deposit {
+                    client: 1,
+                    transaction_id,
+                    amount: Amount::non_negative(dec!(1.0)).unwrap(),
+                })
+                .unwrap();
+        }
+        client.finish().unwrap();
+
+        // The first record uses the burst, the other five wait 10ms each.
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            engine.read().unwrap().get_client_state(1).unwrap().total(),
+            dec!(6.0)
+        );
+    }
+
+    #[test]
+    fn frames_round_trip_and_long_ones_are_refused() {
+        let record = WireRecord::<u16, u32>::from(Record::Dispute(DisputeAction::Resolve {
+            client: 3,
+            referenced_transaction_id: 4,
+        }));
+        let mut frames = vec![];
+        write_frame(&mut frames, &record).unwrap();
+        write_frame(&mut frames, &record).unwrap();
+        let mut reader = &frames[..];
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(record.clone()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(record));
+        assert_eq!(read_frame::<u16, u32>(&mut reader).unwrap(), None);
+
+        let mut reader = &u32::MAX.to_be_bytes()[..];
+        assert!(matches!(
+            read_frame::<u16, u32>(&mut reader),
+            Err(TcpError::FrameTooLong(u32::MAX))
+        ));
+        let mut reader = &frames[..6];
+        assert!(matches!(
+            read_frame::<u16, u32>(&mut reader),
+            Err(TcpError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,174 @@
+//! Engines for several tenants (e.g. partners) whose client and transaction ids overlap.
+//!
+//! Every tenant gets its own [`PaymentEngine`], so the accounts and balances of one tenant are never affected by
+//! the records of another.
+
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::borrow::Borrow;
+
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::config::EngineConfig;
+use crate::id::{ClientId, TransactionId};
+use crate::{Outcome, PaymentEngine, Record};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+// The bounds on the ids already require them to be deserializable, only the tenant key needs one.
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "K: DeserializeOwned")))]
+pub struct MultiTenantEngine<K: Ord = String, C: ClientId = u16, T: TransactionId = u32> {
+    tenants: BTreeMap<K, PaymentEngine<C, T>>,
+    /// The configuration of the engine of every new tenant, unless it has one in `tenant_configs`.
+    config: EngineConfig,
+    /// Tenants with their own rules, e.g. partners with different scheme rules.
+    #[cfg_attr(feature = "serde", serde(default))]
+    tenant_configs: BTreeMap<K, EngineConfig>,
+}
+
+impl<K: Ord, C: ClientId, T: TransactionId> Default for MultiTenantEngine<K, C, T> {
+    fn default() -> Self {
+        Self::new(EngineConfig::default())
+    }
+}
+
+impl<K: Ord, C: ClientId, T: TransactionId> MultiTenantEngine<K, C, T> {
+    pub fn new(config: EngineConfig) -> Self {
+        Self {
+            tenants: BTreeMap::new(),
+            config,
+            tenant_configs: BTreeMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Configures the engine of `tenant` differently from the others.
+    /// Only takes effect when the engine of the tenant is created, i.e. before its first record.
+    pub fn set_tenant_config(&mut self, tenant: K, config: EngineConfig) {
+        self.tenant_configs.insert(tenant, config);
+    }
+
+    /// The configuration the engine of `tenant` is created with.
+    pub fn tenant_config<Q>(&self, tenant: &Q) -> &EngineConfig
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tenant_configs.get(tenant).unwrap_or(&self.config)
+    }
+
+    /// Applies `record` to the engine of `tenant`, which is created when this is its first record.
+    pub fn apply<Q>(&mut self, tenant: &Q, record: impl Into<Record<C, T>>) -> Outcome
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + Ord + ?Sized,
+    {
+        self.tenant_mut(tenant).apply(record)
+    }
+
+    pub fn tenant<Q>(&self, tenant: &Q) -> Option<&PaymentEngine<C, T>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tenants.get(tenant)
+    }
+
+    /// The engine of `tenant`, created if the tenant has none yet.
+    pub fn tenant_mut<Q>(&mut self, tenant: &Q) -> &mut PaymentEngine<C, T>
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + Ord + ?Sized,
+    {
+        // Looking up first saves the allocation of an owned key for every record of a known tenant.
+        if !self.tenants.contains_key(tenant) {
+            let config = self.tenant_config(tenant).clone();
+            let engine = PaymentEngine::builder().config(config).build();
+            self.tenants.insert(tenant.to_owned(), engine);
+        }
+        self.tenants
+            .get_mut(tenant)
+            .expect("The engine of the tenant was just created.")
+    }
+
+    /// Ordered by tenant.
+    pub fn tenants(&self) -> impl Iterator<Item = (&K, &PaymentEngine<C, T>)> {
+        self.tenants.iter()
+    }
+
+    /// Ordered by tenant.
+    pub fn tenants_mut(&mut self) -> impl Iterator<Item = (&K, &mut PaymentEngine<C, T>)> {
+        self.tenants.iter_mut()
+    }
+
+    /// The number of tenants.
+    pub fn len(&self) -> usize {
+        self.tenants.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::Transaction;
+
+    #[test]
+    fn tenants_are_isolated() {
+        let mut engine: MultiTenantEngine = MultiTenantEngine::default();
+        let deposit = |amount| Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: Amount::non_negative(amount).unwrap(),
+        };
+
+        assert_eq!(engine.apply("a", deposit(dec!(1.0))), Outcome::Applied);
+        // The same client and transaction id, but of another tenant.
+        assert_eq!(engine.apply("b", deposit(dec!(2.0))), Outcome::Applied);
+
+        let available = |tenant| {
+            engine
+                .tenant(tenant)
+                .unwrap()
+                .get_client_state(1)
+                .unwrap()
+                .available()
+        };
+        assert_eq!(available("a"), dec!(1.0));
+        assert_eq!(available("b"), dec!(2.0));
+        assert_eq!(
+            engine
+                .tenants()
+                .map(|(t, _)| t.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn tenants_can_have_their_own_config() {
+        let mut engine: MultiTenantEngine = MultiTenantEngine::default();
+        let mut config = EngineConfig::default();
+        config.disputes.max_open_per_client = Some(1);
+        engine.set_tenant_config("strict".to_string(), config.clone());
+
+        assert_eq!(engine.tenant_mut("strict").config(), &config);
+        assert_eq!(
+            engine.tenant_mut("other").config(),
+            &EngineConfig::default()
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Helpers for tests of the engine, and of code that embeds it.
+//!
+//! ```
+//! use banking::testing::Scenario;
+//!
+//! let engine = Scenario::new()
+//!     .deposit(1, 1, "10.0")
+//!     .dispute(1, 1)
+//!     .chargeback(1, 1)
+//!     .run();
+//! banking::assert_account!(engine, client: 1, available: "0", held: "0", locked: true);
+//! ```
+
+use alloc::vec::Vec;
+
+use rust_decimal::Decimal;
+
+use crate::amount::Amount;
+use crate::config::EngineConfig;
+use crate::id::{ClientId, TransactionId};
+use crate::{DisputeAction, PaymentEngine, Record, Transaction};
+
+/// A [`ScenarioBuilder`] with the default id types.
+pub type Scenario = ScenarioBuilder<u16, u32>;
+
+/// Collects records and applies them, in order, to a new engine. Amounts are given as strings, like in the CSV
+/// input; an amount that isn't a valid, non-negative decimal panics.
+#[derive(Debug, Clone)]
+pub struct ScenarioBuilder<C: ClientId = u16, T: TransactionId = u32> {
+    config: EngineConfig,
+    records: Vec<Record<C, T>>,
+}
+
+impl<C: ClientId, T: TransactionId> Default for ScenarioBuilder<C, T> {
+    fn default() -> Self {
+        Self {
+            config: EngineConfig::default(),
+            records: Vec::new(),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> ScenarioBuilder<C, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The configuration of the engine the records are applied to.
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn deposit(self, client: C, transaction_id: T, amount: &str) -> Self {
+        self.record(Transaction::Deposit {
+            client,
+            transaction_id,
+            amount: self::amount(amount),
+        })
+    }
+
+    pub fn withdrawal(self, client: C, transaction_id: T, amount: &str) -> Self {
+        self.record(Transaction::Withdrawal {
+            client,
+            transaction_id,
+            amount: self::amount(amount),
+        })
+    }
+
+    pub fn dispute(self, client: C, referenced_transaction_id: T) -> Self {
+        self.record(DisputeAction::Dispute {
+            client,
+            referenced_transaction_id,
+        })
+    }
+
+    pub fn resolve(self, client: C, referenced_transaction_id: T) -> Self {
+        self.record(DisputeAction::Resolve {
+            client,
+            referenced_transaction_id,
+        })
+    }
+
+    pub fn chargeback(self, client: C, referenced_transaction_id: T) -> Self {
+        self.record(DisputeAction::Chargeback {
+            client,
+            referenced_transaction_id,
+        })
+    }
+
+    /// Any other record, e.g. an [`crate::Adjustment`].
+    pub fn record(mut self, record: impl Into<Record<C, T>>) -> Self {
+        self.records.push(record.into());
+        self
+    }
+
+    /// Applies the records, rejected ones are part of the scenario like any other.
+    pub fn run(self) -> PaymentEngine<C, T> {
+        let mut payment_engine = PaymentEngine::with_config(self.config);
+        for record in self.records {
+            payment_engine.apply(record);
+        }
+        payment_engine
+    }
+}
+
+/// Parses an amount like the CSV input does, panics when it isn't a valid, non-negative decimal.
+pub fn amount(amount: &str) -> Amount {
+    amount
+        .parse::<Decimal>()
+        .ok()
+        .and_then(|value| Amount::non_negative(value).ok())
+        .unwrap_or_else(|| panic!("Invalid amount '{}' in a scenario.", amount))
+}
+
+/// Asserts the state of the account of a client, only the given fields are checked:
+///
+/// ```ignore
+/// assert_account!(engine, client: 1, available: "2.0", held: "0", total: "2.0", locked: false);
+/// ```
+///
+/// Amounts are compared by value, `"2"` matches `2.0`. `engine` can be anything with a `get_client_state`, e.g.
+/// a [`PaymentEngine`] or an [`crate::EngineSnapshot`]; a client without an account fails the assertion.
+#[macro_export]
+macro_rules! assert_account {
+    (@field $account:ident, available, $expected:expr) => {
+        assert_eq!(
+            $account.available(),
+            $crate::testing::amount($expected),
+            "available funds of client {:?}",
+            $account.id()
+        )
+    };
+    (@field $account:ident, held, $expected:expr) => {
+        assert_eq!(
+            $account.held(),
+            $crate::testing::amount($expected),
+            "held funds of client {:?}",
+            $account.id()
+        )
+    };
+    (@field $account:ident, total, $expected:expr) => {
+        assert_eq!(
+            $account.total(),
+            $crate::testing::amount($expected),
+            "total funds of client {:?}",
+            $account.id()
+        )
+    };
+    (@field $account:ident, locked, $expected:expr) => {
+        assert_eq!(
+            $account.locked(),
+            $expected,
+            "whether client {:?} is locked",
+            $account.id()
+        )
+    };
+    ($engine:expr, client: $client:expr $(, $field:ident: $expected:expr)* $(,)?) => {{
+        let account = $engine
+            .get_client_state($client)
+            .unwrap_or_else(|| panic!("Client {:?} has no account.", $client));
+        $($crate::assert_account!(@field account, $field, $expected);)*
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios_apply_their_records_in_order() {
+        let engine = Scenario::new()
+            .deposit(1, 1, "10.0")
+            .withdrawal(1, 2, "20.0")
+            .deposit(2, 3, "5.0")
+            .dispute(2, 3)
+            .resolve(2, 3)
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .run();
+
+        assert_account!(engine, client: 1, total: "0", locked: true);
+        assert_account!(engine, client: 2, available: "5.0", held: "0");
+        assert_eq!(engine.stats().withdrawals.rejected, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "held funds of client 1")]
+    fn account_assertions_name_the_field() {
+        let engine = Scenario::new().deposit(1, 1, "2.0").dispute(1, 1).run();
+        assert_account!(engine, client: 1, available: "0", held: "1.0");
+    }
+
+    #[test]
+    #[should_panic(expected = "Client 2 has no account.")]
+    fn account_assertions_require_the_account() {
+        let engine = Scenario::new().deposit(1, 1, "2.0").run();
+        assert_account!(engine, client: 2, locked: false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid amount '-1.0'")]
+    fn invalid_amounts_panic() {
+        Scenario::new().deposit(1, 1, "-1.0");
+    }
+}
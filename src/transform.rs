@@ -0,0 +1,260 @@
+//! Tweaks records between parsing and the engine, e.g. renaming clients, converting amounts or dropping test
+//! transactions, see [`crate::pipeline::run_transformed`].
+//!
+//! Any closure from a [`Record`] to an `Option<Record>` is a [`RecordTransformer`], a [`TransformerChain`] applies
+//! several of them in order:
+//!
+//! ```
+//! use banking::transform::{DropClients, RenameClients, TransformerChain};
+//!
+//! let chain: TransformerChain = TransformerChain::new()
+//!     .then(DropClients::new([999]))
+//!     .then(RenameClients::new([(1, 101)]));
+//! ```
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::amount::Amount;
+use crate::id::{ClientId, TransactionId};
+use crate::wire::WireRecord;
+use crate::{HashMap, HashSet, Record};
+
+/// Turns a record into the one the engine gets to apply.
+pub trait RecordTransformer<C: ClientId = u16, T: TransactionId = u32> {
+    /// The record to apply instead of `record`, `None` drops it.
+    fn transform(&mut self, record: Record<C, T>) -> Option<Record<C, T>>;
+
+    /// Why the last record was dropped, the reason of its [`crate::pipeline::DeadLetter`].
+    fn name(&self) -> &str {
+        "transformer"
+    }
+}
+
+impl<C, T, F> RecordTransformer<C, T> for F
+where
+    C: ClientId,
+    T: TransactionId,
+    F: FnMut(Record<C, T>) -> Option<Record<C, T>>,
+{
+    fn transform(&mut self, record: Record<C, T>) -> Option<Record<C, T>> {
+        self(record)
+    }
+}
+
+/// Applies its transformers in the order they were added, a record that one of them drops doesn't reach the next.
+/// An empty chain passes every record on as is.
+pub struct TransformerChain<C: ClientId = u16, T: TransactionId = u32> {
+    transformers: Vec<Box<dyn RecordTransformer<C, T> + Send>>,
+    /// The transformer that dropped the last record, it names the chain.
+    dropped_by: Option<usize>,
+}
+
+impl<C: ClientId, T: TransactionId> Default for TransformerChain<C, T> {
+    fn default() -> Self {
+        Self {
+            transformers: Vec::new(),
+            dropped_by: None,
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> TransformerChain<C, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `transformer` to the end of the chain.
+    pub fn then(mut self, transformer: impl RecordTransformer<C, T> + Send + 'static) -> Self {
+        self.transformers.push(Box::new(transformer));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.transformers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transformers.is_empty()
+    }
+}
+
+impl<C: ClientId, T: TransactionId> RecordTransformer<C, T> for TransformerChain<C, T> {
+    fn transform(&mut self, mut record: Record<C, T>) -> Option<Record<C, T>> {
+        for (index, transformer) in self.transformers.iter_mut().enumerate() {
+            match transformer.transform(record) {
+                Some(transformed) => record = transformed,
+                None => {
+                    self.dropped_by = Some(index);
+                    return None;
+                }
+            }
+        }
+        Some(record)
+    }
+
+    /// The name of the transformer that dropped the last record.
+    fn name(&self) -> &str {
+        match self.dropped_by {
+            Some(index) => self.transformers[index].name(),
+            None => "transformer_chain",
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> fmt::Debug for TransformerChain<C, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransformerChain")
+            .field("transformers", &self.transformers.len())
+            .finish()
+    }
+}
+
+/// Gives every record of a client in the map the client it maps to, e.g. when ids of another system are merged.
+/// Records of other clients are passed on as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameClients<C: ClientId = u16> {
+    renames: HashMap<C, C>,
+}
+
+impl<C: ClientId> RenameClients<C> {
+    pub fn new(renames: impl IntoIterator<Item = (C, C)>) -> Self {
+        Self {
+            renames: renames.into_iter().collect(),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> RecordTransformer<C, T> for RenameClients<C> {
+    fn transform(&mut self, record: Record<C, T>) -> Option<Record<C, T>> {
+        match self.renames.get(&record.client()) {
+            Some(&client) => modify(record, |wire| {
+                wire.client = client;
+                Some(())
+            }),
+            None => Some(record),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "rename_clients"
+    }
+}
+
+/// Drops every record of these clients, e.g. the test accounts of a production feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DropClients<C: ClientId = u16> {
+    clients: HashSet<C>,
+}
+
+impl<C: ClientId> DropClients<C> {
+    pub fn new(clients: impl IntoIterator<Item = C>) -> Self {
+        Self {
+            clients: clients.into_iter().collect(),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> RecordTransformer<C, T> for DropClients<C> {
+    fn transform(&mut self, record: Record<C, T>) -> Option<Record<C, T>> {
+        (!self.clients.contains(&record.client())).then_some(record)
+    }
+
+    fn name(&self) -> &str {
+        "drop_clients"
+    }
+}
+
+/// Converts the amounts of all records at a fixed rate, e.g. from the currency of a feed to that of the engine.
+/// Converted amounts are rounded to [`Amount::PRECISION`] decimal places with banker's rounding; a record whose
+/// converted amount is out of range is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvertAmounts {
+    pub rate: Decimal,
+}
+
+impl<C: ClientId, T: TransactionId> RecordTransformer<C, T> for ConvertAmounts {
+    fn transform(&mut self, record: Record<C, T>) -> Option<Record<C, T>> {
+        modify(record, |wire| {
+            if let Some(amount) = &mut wire.amount {
+                let converted = amount.value().checked_mul(self.rate)?;
+                *amount = Amount::non_negative(converted.round_dp(Amount::PRECISION)).ok()?;
+            }
+            Some(())
+        })
+    }
+
+    fn name(&self) -> &str {
+        "convert_amounts"
+    }
+}
+
+/// Modifies `record` through its flat [`WireRecord`], which has the same fields for every type of record. `None`
+/// from `modify` drops the record.
+fn modify<C: ClientId, T: TransactionId>(
+    record: Record<C, T>,
+    modify: impl FnOnce(&mut WireRecord<C, T>) -> Option<()>,
+) -> Option<Record<C, T>> {
+    let mut wire = WireRecord::from(record);
+    modify(&mut wire)?;
+    Some(
+        Record::try_from(wire).expect("Converting a record to a wire record and back is lossless."),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{DisputeAction, Transaction};
+
+    fn deposit(client: u16, transaction_id: u32, amount: Decimal) -> Record {
+        Record::Transaction(Transaction::Deposit {
+            client,
+            transaction_id,
+            amount: Amount::non_negative(amount).unwrap(),
+        })
+    }
+
+    #[test]
+    fn chains_apply_their_transformers_in_order() {
+        let mut chain = TransformerChain::new()
+            .then(DropClients::new([9]))
+            .then(RenameClients::new([(1, 2), (9, 3)]))
+            .then(ConvertAmounts { rate: dec!(0.5) })
+            .then(|record: Record| match record {
+                Record::Dispute(_) => None,
+                record => Some(record),
+            });
+        assert_eq!(chain.len(), 4);
+
+        assert_eq!(
+            chain.transform(deposit(1, 1, dec!(3.0))),
+            Some(deposit(2, 1, dec!(1.5)))
+        );
+        assert_eq!(
+            chain.transform(deposit(4, 2, dec!(0.0003))),
+            Some(deposit(4, 2, dec!(0.0002)))
+        );
+        assert_eq!(chain.transform(deposit(9, 3, dec!(1.0))), None);
+        assert_eq!(chain.name(), "drop_clients");
+        assert_eq!(
+            chain.transform(Record::Dispute(DisputeAction::Dispute {
+                client: 1,
+                referenced_transaction_id: 1,
+            })),
+            None
+        );
+        assert_eq!(chain.name(), "transformer");
+
+        let mut empty = TransformerChain::new();
+        assert_eq!(
+            empty.transform(deposit(9, 3, dec!(1.0))),
+            Some(deposit(9, 3, dec!(1.0)))
+        );
+    }
+}
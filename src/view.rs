@@ -0,0 +1,116 @@
+//! Read-only access to an engine that is shared between threads, see [`PaymentEngineView`].
+
+use std::sync::{Arc, RwLock};
+
+use crate::id::{ClientId, TransactionId};
+use crate::stats::{EngineStats, InvariantReport};
+use crate::{ClientAccount, EngineSnapshot, OpenDispute, PaymentEngine};
+
+/// A read-only handle on an engine that is shared behind a lock, e.g. for the reporting components of a server that
+/// must not apply records themselves. The view stays current as records are applied through the lock, and is cheap
+/// to clone.
+///
+/// Every query takes the read lock, records can't be applied while it's held. Results are copied out of the engine,
+/// a [`PaymentEngineView::snapshot`] gives a consistent view of all accounts at once.
+pub struct PaymentEngineView<C: ClientId = u16, T: TransactionId = u32> {
+    engine: Arc<RwLock<PaymentEngine<C, T>>>,
+}
+
+impl<C: ClientId, T: TransactionId> Clone for PaymentEngineView<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: Arc::clone(&self.engine),
+        }
+    }
+}
+
+impl<C: ClientId, T: TransactionId> PaymentEngineView<C, T> {
+    pub fn new(engine: &Arc<RwLock<PaymentEngine<C, T>>>) -> Self {
+        Self {
+            engine: Arc::clone(engine),
+        }
+    }
+
+    /// Runs `query` against the engine, for anything the other methods don't cover.
+    pub fn read<R>(&self, query: impl FnOnce(&PaymentEngine<C, T>) -> R) -> R {
+        query(
+            &self
+                .engine
+                .read()
+                .expect("No panics while holding the lock."),
+        )
+    }
+
+    pub fn get_client_state(&self, client_id: C) -> Option<ClientAccount<C, T>> {
+        self.read(|engine| engine.get_client_state(client_id).cloned())
+    }
+
+    /// See [`PaymentEngine::snapshot`].
+    pub fn snapshot(&self) -> EngineSnapshot<C, T> {
+        self.read(PaymentEngine::snapshot)
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        self.read(PaymentEngine::stats)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read(PaymentEngine::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read(PaymentEngine::is_empty)
+    }
+
+    pub fn contains_client(&self, client_id: C) -> bool {
+        self.read(|engine| engine.contains_client(client_id))
+    }
+
+    pub fn open_disputes(&self) -> Vec<OpenDispute<C, T>> {
+        self.read(|engine| engine.open_disputes().collect())
+    }
+
+    pub fn invariant_report(&self) -> InvariantReport<C> {
+        self.read(PaymentEngine::invariant_report)
+    }
+
+    /// See [`PaymentEngine::merkle_root`].
+    #[cfg(feature = "audit")]
+    pub fn merkle_root(&self) -> crate::audit::AuditHash {
+        self.read(PaymentEngine::merkle_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::{DisputeAction, Transaction};
+
+    #[test]
+    fn views_follow_the_shared_engine() {
+        let engine = Arc::new(RwLock::new(PaymentEngine::default()));
+        let view = PaymentEngineView::new(&engine);
+        let reporting = view.clone();
+        assert!(reporting.is_empty());
+
+        engine.write().unwrap().apply(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: Amount::non_negative(dec!(2.0)).unwrap(),
+        });
+        let snapshot = reporting.snapshot();
+        engine.write().unwrap().apply(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+
+        assert_eq!(reporting.len(), 1);
+        assert_eq!(reporting.get_client_state(1).unwrap().held(), dec!(2.0));
+        assert_eq!(reporting.open_disputes().len(), 1);
+        assert_eq!(snapshot.get_client_state(1).unwrap().held(), dec!(0));
+        assert_eq!(view.get_client_state(2), None);
+    }
+}
@@ -0,0 +1,598 @@
+//! Notifies an HTTP endpoint of chargebacks and locked accounts, e.g. case-management tooling that would otherwise poll.
+//!
+//! Every [`EngineEvent::ChargedBack`] and [`EngineEvent::AccountLocked`] is POSTed as JSON, signed with HMAC-SHA256.
+//! Deliveries happen on a background thread, so a slow endpoint doesn't hold up the engine.
+//!
+//! The signature covers `<timestamp>.<body>`, with the timestamp of the delivery attempt in [`TIMESTAMP_HEADER`].
+//! Endpoints should reject deliveries whose timestamp is further than [`TIMESTAMP_TOLERANCE`] from their own clock,
+//! so a captured request can't be replayed later, see [`verify`].
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::event::{EngineEvent, EngineObserver};
+
+/// The header carrying the hex-encoded HMAC-SHA256 of `<timestamp>.<body>`.
+pub const SIGNATURE_HEADER: &str = "X-Signature-SHA256";
+
+/// The header carrying the time of the delivery attempt, in seconds since the Unix epoch.
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// How far the timestamp of a delivery may be from the clock of the endpoint. Retries are signed again with a new
+/// timestamp, so this only has to cover clock skew and the time a single attempt takes.
+pub const TIMESTAMP_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    /// Only `http://` URLs are supported, put a TLS-terminating proxy in front of `https://` endpoints.
+    pub url: String,
+    /// The key the body is signed with, see [`SIGNATURE_HEADER`].
+    pub secret: Vec<u8>,
+    /// How often a failed delivery is retried before the event is dropped.
+    pub max_retries: u32,
+    /// The delay before the first retry, it doubles on every next one.
+    pub initial_backoff: Duration,
+    /// How many events can wait for delivery. Events that arrive while the queue is full are dropped rather than
+    /// holding up the engine, see [`WebhookDispatcher::dropped`].
+    pub queue_capacity: usize,
+    /// How long [`WebhookDispatcher::close`] waits for the pending events.
+    pub close_timeout: Duration,
+}
+
+impl WebhookConfig {
+    pub fn new(url: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            url: url.into(),
+            secret: secret.into(),
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            queue_capacity: 1024,
+            close_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("unsupported webhook URL '{0}', expected http://host[:port]/path")]
+    InvalidUrl(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the webhook endpoint responded with status {0}")]
+    Status(u16),
+    #[error("the webhook endpoint sent an invalid response")]
+    InvalidResponse,
+}
+
+/// Delivers a signed body to the endpoint.
+pub trait WebhookTransport: Send + 'static {
+    fn post(
+        &mut self,
+        url: &str,
+        timestamp: u64,
+        signature: &str,
+        body: &[u8],
+    ) -> Result<(), WebhookError>;
+}
+
+/// A minimal HTTP/1.1 client, one connection per delivery.
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    /// Applies to connecting, and to every read and write after.
+    pub timeout: Duration,
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl HttpTransport {
+    /// Tries every address `address` resolves to, in order.
+    fn connect(&self, address: &str) -> Result<TcpStream, WebhookError> {
+        let mut last_error = None;
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| std::io::Error::other("the webhook host has no addresses"))
+            .into())
+    }
+}
+
+impl WebhookTransport for HttpTransport {
+    fn post(
+        &mut self,
+        url: &str,
+        timestamp: u64,
+        signature: &str,
+        body: &[u8],
+    ) -> Result<(), WebhookError> {
+        let invalid_url = || WebhookError::InvalidUrl(url.to_string());
+        let rest = url.strip_prefix("http://").ok_or_else(invalid_url)?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid_url());
+        }
+        let address = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+
+        let mut stream = self.connect(&address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}: {}\r\n{}: {}\r\nConnection: close\r\n\r\n",
+            path,
+            authority,
+            body.len(),
+            TIMESTAMP_HEADER,
+            timestamp,
+            SIGNATURE_HEADER,
+            signature
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or(WebhookError::InvalidResponse)?;
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(WebhookError::Status(status))
+        }
+    }
+}
+
+fn mac(secret: &[u8], timestamp: u64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length.");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+/// The hex-encoded HMAC-SHA256 of `<timestamp>.<body>`, as sent in [`SIGNATURE_HEADER`].
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    mac(secret, timestamp, body)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `signature` is the signature of a delivery with `timestamp` and `body`, and `timestamp` is within
+/// [`TIMESTAMP_TOLERANCE`] of `now`, both in seconds since the Unix epoch. For endpoints written in Rust.
+pub fn verify(secret: &[u8], timestamp: u64, body: &[u8], signature: &str, now: u64) -> bool {
+    if now.abs_diff(timestamp) > TIMESTAMP_TOLERANCE.as_secs() {
+        return false;
+    }
+    let signature: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|index| {
+            signature
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect();
+    signature.is_some_and(|signature| {
+        mac(secret, timestamp, body)
+            .verify_slice(&signature)
+            .is_ok()
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// An [`EngineObserver`] posting chargebacks and locked accounts, see the module documentation.
+/// Clones share the same background thread.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sender: Mutex<Option<SyncSender<Vec<u8>>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// Disconnects when the thread is done.
+    finished: Mutex<Option<Receiver<()>>>,
+    close_timeout: Duration,
+    /// Set when `close` gave up waiting, the thread then drops what is left.
+    abandoned: AtomicBool,
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl WebhookDispatcher {
+    pub fn new(config: WebhookConfig) -> Self {
+        Self::with_transport(config, HttpTransport::default())
+    }
+
+    pub fn with_transport(config: WebhookConfig, mut transport: impl WebhookTransport) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(config.queue_capacity);
+        let (finished_sender, finished) = mpsc::channel::<()>();
+        let inner = Arc::new(Inner {
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(None),
+            finished: Mutex::new(Some(finished)),
+            close_timeout: config.close_timeout,
+            abandoned: AtomicBool::new(false),
+            delivered: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+
+        let counters = inner.clone();
+        let thread = std::thread::spawn(move || {
+            let _finished = finished_sender;
+            for body in receiver {
+                let mut backoff = config.initial_backoff;
+                let mut attempt = 0;
+                loop {
+                    if counters.abandoned.load(Ordering::SeqCst) {
+                        counters.failed.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    let timestamp = unix_now();
+                    let signature = sign(&config.secret, timestamp, &body);
+                    match transport.post(&config.url, timestamp, &signature, &body) {
+                        Ok(()) => {
+                            counters.delivered.fetch_add(1, Ordering::Relaxed);
+                            break;
+                        }
+                        Err(_) if attempt < config.max_retries => {
+                            std::thread::sleep(backoff);
+                            backoff = backoff.saturating_mul(2);
+                            attempt += 1;
+                        }
+                        Err(_) => {
+                            counters.failed.fetch_add(1, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        *inner
+            .thread
+            .lock()
+            .expect("No panics while holding the lock.") = Some(thread);
+
+        Self { inner }
+    }
+
+    /// The number of events that were delivered.
+    pub fn delivered(&self) -> u64 {
+        self.inner.delivered.load(Ordering::Relaxed)
+    }
+
+    /// The number of events that were dropped after running out of retries, or because [`Self::close`] stopped
+    /// waiting for them.
+    pub fn failed(&self) -> u64 {
+        self.inner.failed.load(Ordering::Relaxed)
+    }
+
+    /// The number of events that were dropped because the queue was full, see [`WebhookConfig::queue_capacity`].
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting events and waits until the pending ones are delivered, or have run out of retries, for at
+    /// most [`WebhookConfig::close_timeout`]. Returns whether they were, events still pending after that are dropped
+    /// once the delivery in progress is done.
+    pub fn close(&self) -> bool {
+        self.inner
+            .sender
+            .lock()
+            .expect("No panics while holding the lock.")
+            .take();
+        let finished = self
+            .inner
+            .finished
+            .lock()
+            .expect("No panics while holding the lock.")
+            .take();
+        let Some(finished) = finished else {
+            return true;
+        };
+        match finished.recv_timeout(self.inner.close_timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                self.inner.abandoned.store(true, Ordering::SeqCst);
+                false
+            }
+            _ => {
+                let thread = self
+                    .inner
+                    .thread
+                    .lock()
+                    .expect("No panics while holding the lock.")
+                    .take();
+                if let Some(thread) = thread {
+                    // A panicking transport already lost its events, there's nothing left to wait for.
+                    let _ = thread.join();
+                }
+                true
+            }
+        }
+    }
+}
+
+impl<C: Serialize, T: Serialize> EngineObserver<C, T> for WebhookDispatcher {
+    fn on_event(&self, event: &EngineEvent<C, T>) {
+        if !matches!(
+            event,
+            EngineEvent::ChargedBack { .. } | EngineEvent::AccountLocked { .. }
+        ) {
+            return;
+        }
+        let body = serde_json::to_vec(event).expect("Events always serialize.");
+        if let Some(sender) = self
+            .inner
+            .sender
+            .lock()
+            .expect("No panics while holding the lock.")
+            .as_ref()
+        {
+            match sender.try_send(body) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                // Only when the thread is gone, which `close` accounts for.
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{DisputeAction, PaymentEngine, Transaction};
+
+    /// Timestamps, signatures and bodies.
+    type Deliveries = Arc<Mutex<Vec<(u64, String, Vec<u8>)>>>;
+
+    /// Fails the first `failures` deliveries, recording the ones that succeed.
+    struct FlakyTransport {
+        failures: u32,
+        delivered: Deliveries,
+    }
+
+    impl WebhookTransport for FlakyTransport {
+        fn post(
+            &mut self,
+            _: &str,
+            timestamp: u64,
+            signature: &str,
+            body: &[u8],
+        ) -> Result<(), WebhookError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(WebhookError::Status(503));
+            }
+            self.delivered
+                .lock()
+                .unwrap()
+                .push((timestamp, signature.to_string(), body.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Reports every delivery it starts on `started`, and waits for `gate` before it succeeds.
+    struct GatedTransport {
+        started: mpsc::Sender<()>,
+        gate: Receiver<()>,
+    }
+
+    impl WebhookTransport for GatedTransport {
+        fn post(&mut self, _: &str, _: u64, _: &str, _: &[u8]) -> Result<(), WebhookError> {
+            self.started.send(()).unwrap();
+            self.gate.recv().map_err(|_| WebhookError::InvalidResponse)
+        }
+    }
+
+    fn account_locked(client: u16) -> EngineEvent<u16, u32> {
+        EngineEvent::AccountLocked {
+            client,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn chargebacks_are_posted_and_retried() {
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let config = WebhookConfig {
+            initial_backoff: Duration::ZERO,
+            max_retries: 2,
+            ..WebhookConfig::new("http://localhost/cases", "secret")
+        };
+        let dispatcher = WebhookDispatcher::with_transport(
+            config,
+            FlakyTransport {
+                failures: 2,
+                delivered: delivered.clone(),
+            },
+        );
+        let mut payment_engine: PaymentEngine = PaymentEngine::builder()
+            .with_observer(dispatcher.clone())
+            .build();
+
+        payment_engine.add_transaction(Transaction::Deposit {
+            client: 1,
+            transaction_id: 1,
+            amount: crate::amount::Amount::new(dec!(2.5)).unwrap(),
+        });
+        payment_engine.add_dispute_action(DisputeAction::Dispute {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        payment_engine.add_dispute_action(DisputeAction::Chargeback {
+            client: 1,
+            referenced_transaction_id: 1,
+        });
+        dispatcher.close();
+
+        let delivered = delivered.lock().unwrap();
+        let bodies: Vec<_> = delivered
+            .iter()
+            .map(|(_, _, body)| String::from_utf8(body.clone()).unwrap())
+            .collect();
+        assert_eq!(
+            bodies,
+            vec![
+                r#"{"event":"charged_back","client":1,"transaction_id":1,"amount":"2.5"}"#,
+                r#"{"event":"account_locked","client":1}"#,
+            ]
+        );
+        let (timestamp, signature, body) = &delivered[0];
+        assert!(verify(b"secret", *timestamp, body, signature, unix_now()));
+        assert_eq!((dispatcher.delivered(), dispatcher.failed()), (2, 0));
+    }
+
+    #[test]
+    fn signatures_cover_the_timestamp() {
+        // HMAC-SHA256 as in RFC 4231, test case 2, of "1700000000.what do ya want for nothing?".
+        let signature = sign(b"Jefe", 1_700_000_000, b"what do ya want for nothing?");
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"1700000000.what do ya want for nothing?");
+        mac.verify_slice(
+            &(0..64)
+                .step_by(2)
+                .map(|index| u8::from_str_radix(&signature[index..index + 2], 16).unwrap())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let body = b"what do ya want for nothing?";
+        let tolerance = TIMESTAMP_TOLERANCE.as_secs();
+        assert!(verify(
+            b"Jefe",
+            1_700_000_000,
+            body,
+            &signature,
+            1_700_000_000 + tolerance
+        ));
+        // Replayed later, or with the timestamp moved along.
+        assert!(!verify(
+            b"Jefe",
+            1_700_000_000,
+            body,
+            &signature,
+            1_700_000_001 + tolerance
+        ));
+        assert!(!verify(
+            b"Jefe",
+            1_700_000_100,
+            body,
+            &signature,
+            1_700_000_100
+        ));
+        assert!(!verify(
+            b"Jefe",
+            1_700_000_000,
+            body,
+            "not hex",
+            1_700_000_000
+        ));
+    }
+
+    #[test]
+    fn events_are_dropped_while_the_queue_is_full() {
+        let (started, started_receiver) = mpsc::channel();
+        let (gate, gate_receiver) = mpsc::channel();
+        let config = WebhookConfig {
+            queue_capacity: 1,
+            ..WebhookConfig::new("http://localhost/cases", "secret")
+        };
+        let dispatcher = WebhookDispatcher::with_transport(
+            config,
+            GatedTransport {
+                started,
+                gate: gate_receiver,
+            },
+        );
+
+        dispatcher.on_event(&account_locked(1));
+        started_receiver.recv().unwrap();
+        // The first is being delivered, the second waits in the queue and the third doesn't fit.
+        dispatcher.on_event(&account_locked(2));
+        dispatcher.on_event(&account_locked(3));
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+
+        assert!(dispatcher.close());
+        assert_eq!(
+            (
+                dispatcher.delivered(),
+                dispatcher.failed(),
+                dispatcher.dropped()
+            ),
+            (2, 0, 1)
+        );
+    }
+
+    #[test]
+    fn closing_gives_up_on_an_endpoint_that_hangs() {
+        let (started, started_receiver) = mpsc::channel();
+        let (gate, gate_receiver) = mpsc::channel();
+        let config = WebhookConfig {
+            close_timeout: Duration::from_millis(50),
+            ..WebhookConfig::new("http://localhost/cases", "secret")
+        };
+        let dispatcher = WebhookDispatcher::with_transport(
+            config,
+            GatedTransport {
+                started,
+                gate: gate_receiver,
+            },
+        );
+
+        dispatcher.on_event(&account_locked(1));
+        dispatcher.on_event(&account_locked(2));
+        started_receiver.recv().unwrap();
+        assert!(!dispatcher.close());
+        assert_eq!(dispatcher.delivered(), 0);
+
+        // Once the delivery in progress finishes, the rest is dropped.
+        gate.send(()).unwrap();
+        while dispatcher.delivered() + dispatcher.failed() < 2 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!((dispatcher.delivered(), dispatcher.failed()), (1, 1));
+    }
+}
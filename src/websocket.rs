@@ -0,0 +1,351 @@
+//! Pushes account updates to dashboards over WebSocket, see [`AccountFeed`].
+//!
+//! A dashboard connects to [`PATH`], or to `/ws/accounts?clients=1,2` to only follow those clients. Every time the
+//! engine changes a followed account, the dashboard gets a text message with the account as JSON, with the fields of
+//! the CSV output, e.g. `{"client":1,"available":"1.5","held":"0","total":"1.5","locked":false}`.
+//!
+//! Updates are queued per dashboard. One that falls too far behind is disconnected rather than holding up the
+//! engine, it can reconnect and start over from the accounts as they are then.
+
+use std::collections::BTreeSet;
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::Message;
+
+use crate::auth::{ApiKeys, Role};
+use crate::id::{ClientId, TransactionId};
+use crate::store::{AccountStore, StoreError};
+use crate::ClientAccount;
+
+/// Where dashboards connect to.
+pub const PATH: &str = "/ws/accounts";
+
+/// How often an idle dashboard is pinged, which is how one that went away is noticed.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum FeedError {
+    #[error("the WebSocket handshake failed: {0}")]
+    Handshake(String),
+    #[error(transparent)]
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl From<tungstenite::Error> for FeedError {
+    fn from(error: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(error))
+    }
+}
+
+/// The account as a dashboard gets it.
+#[derive(Serialize)]
+struct AccountUpdate<C> {
+    client: C,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+struct Subscriber<C> {
+    id: u64,
+    /// `None` follows every client.
+    clients: Option<BTreeSet<C>>,
+    updates: SyncSender<Message>,
+}
+
+/// An [`AccountStore`], the hook that sees every account the engine changes, that forwards the changes to the
+/// dashboards connected through [`AccountFeed::serve`]. Accounts that are removed from the engine aren't announced.
+#[derive(Clone)]
+pub struct AccountFeed<C: ClientId = u16> {
+    subscribers: Arc<Mutex<Subscribers<C>>>,
+    api_keys: Option<Arc<ApiKeys>>,
+    queue_capacity: usize,
+}
+
+struct Subscribers<C> {
+    next_id: u64,
+    connected: Vec<Subscriber<C>>,
+}
+
+impl<C: ClientId> Default for AccountFeed<C> {
+    fn default() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Subscribers {
+                next_id: 0,
+                connected: Vec::new(),
+            })),
+            api_keys: None,
+            queue_capacity: Self::QUEUE_CAPACITY,
+        }
+    }
+}
+
+impl<C: ClientId> AccountFeed<C> {
+    /// How many updates can wait for a dashboard, by default.
+    pub const QUEUE_CAPACITY: usize = 1024;
+
+    /// Only accepts dashboards that send one of `api_keys` as `Authorization: Bearer <key>`, any role will do.
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = Some(Arc::new(api_keys));
+        self
+    }
+
+    /// How many updates can wait for a dashboard before it's disconnected.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// The number of dashboards that are connected.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers
+            .lock()
+            .expect("No panics while holding the lock.")
+            .connected
+            .len()
+    }
+}
+
+impl<C: ClientId + FromStr + Send + 'static> AccountFeed<C> {
+    /// Accepts dashboards on `listener` until accepting one fails, every dashboard is served on a thread of its own.
+    pub fn serve(&self, listener: TcpListener) -> std::io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let feed = self.clone();
+            // A dashboard that fails only affects itself, it can connect again.
+            std::thread::spawn(move || feed.handle(stream));
+        }
+        Ok(())
+    }
+
+    /// Forwards the updates the dashboard on `stream` follows, until it's gone.
+    pub fn handle(&self, stream: TcpStream) -> Result<(), FeedError> {
+        let mut clients = None;
+        // The error response is what tungstenite asks for, it's only built for dashboards that are turned away.
+        #[allow(clippy::result_large_err)]
+        let accept = |request: &Request, response: Response| match self.admit(request) {
+            Ok(admitted) => {
+                clients = admitted;
+                Ok(response)
+            }
+            Err((status, reason)) => {
+                let mut response = ErrorResponse::new(Some(reason.to_string()));
+                *response.status_mut() = status;
+                Err(response)
+            }
+        };
+        let mut socket = tungstenite::accept_hdr(stream, accept)
+            .map_err(|e| FeedError::Handshake(e.to_string()))?;
+
+        let (updates, received) = mpsc::sync_channel(self.queue_capacity);
+        let id = {
+            let mut subscribers = self
+                .subscribers
+                .lock()
+                .expect("No panics while holding the lock.");
+            let id = subscribers.next_id;
+            subscribers.next_id += 1;
+            subscribers.connected.push(Subscriber {
+                id,
+                clients,
+                updates,
+            });
+            id
+        };
+        loop {
+            let message = match received.recv_timeout(PING_INTERVAL) {
+                Ok(update) => update,
+                Err(RecvTimeoutError::Timeout) => Message::Ping(Default::default()),
+                // The dashboard fell behind and was dropped.
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(error) = socket.send(message) {
+                self.subscribers
+                    .lock()
+                    .expect("No panics while holding the lock.")
+                    .connected
+                    .retain(|subscriber| subscriber.id != id);
+                return Err(error.into());
+            }
+        }
+        socket.close(None)?;
+        Ok(())
+    }
+
+    /// The clients the dashboard follows, `None` for all of them, or the status and reason it's turned away with.
+    fn admit(&self, request: &Request) -> Result<Option<BTreeSet<C>>, (StatusCode, &'static str)> {
+        if request.uri().path() != PATH {
+            return Err((StatusCode::NOT_FOUND, "unknown path"));
+        }
+        if let Some(api_keys) = &self.api_keys {
+            let key = request
+                .headers()
+                .get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            if !key
+                .and_then(|key| api_keys.role(key.as_bytes()))
+                .is_some_and(|role| role.allows(Role::ReadOnly))
+            {
+                return Err((StatusCode::UNAUTHORIZED, "unknown key"));
+            }
+        }
+        let Some(clients) = request
+            .uri()
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|parameter| parameter.strip_prefix("clients="))
+        else {
+            return Ok(None);
+        };
+        clients
+            .split(',')
+            .map(|client| client.parse().ok())
+            .collect::<Option<_>>()
+            .map(Some)
+            .ok_or((StatusCode::BAD_REQUEST, "invalid clients"))
+    }
+}
+
+impl<C: ClientId + Send + Sync, T: TransactionId> AccountStore<C, T> for AccountFeed<C> {
+    fn save(&self, account: &ClientAccount<C, T>, _: &[T]) -> Result<(), StoreError> {
+        let client = account.id();
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .expect("No panics while holding the lock.");
+        if !subscribers
+            .connected
+            .iter()
+            .any(|subscriber| subscriber.follows(client))
+        {
+            return Ok(());
+        }
+        let update = Message::text(serde_json::to_string(&AccountUpdate {
+            client,
+            available: account.available().into(),
+            held: account.held().into(),
+            total: account.total().into(),
+            locked: account.locked(),
+        })?);
+        subscribers.connected.retain(|subscriber| {
+            !subscriber.follows(client) || subscriber.updates.try_send(update.clone()).is_ok()
+        });
+        Ok(())
+    }
+
+    fn remove(&self, _: C) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+impl<C: ClientId> Subscriber<C> {
+    fn follows(&self, client: C) -> bool {
+        self.clients
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use tungstenite::client::IntoClientRequest;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::{PaymentEngine, Transaction};
+
+    fn deposit(client: u16, transaction_id: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            transaction_id,
+            amount: Amount::non_negative(dec!(1.5)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn dashboards_get_the_updates_of_the_clients_they_follow() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let feed = AccountFeed::<u16>::default();
+        {
+            let feed = feed.clone();
+            std::thread::spawn(move || feed.serve(listener));
+        }
+        let mut payment_engine: PaymentEngine =
+            PaymentEngine::builder().with_store(feed.clone()).build();
+
+        let url = format!("ws://{}{}?clients=2,3", address, PATH);
+        let (mut dashboard, _) =
+            tungstenite::client(url, TcpStream::connect(address).unwrap()).unwrap();
+        while feed.subscribers() == 0 {
+            std::thread::yield_now();
+        }
+        payment_engine.apply(deposit(1, 1));
+        payment_engine.apply(deposit(2, 2));
+
+        assert_eq!(
+            dashboard.read().unwrap(),
+            Message::text(
+                r#"{"client":2,"available":"1.5","held":"0","total":"1.5","locked":false}"#
+            )
+        );
+        dashboard.close(None).unwrap();
+    }
+
+    #[test]
+    fn dashboards_need_a_key_and_a_known_path() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut api_keys = ApiKeys::default();
+        api_keys.insert("dashboard", Role::ReadOnly);
+        let feed = AccountFeed::<u16>::default().api_keys(api_keys);
+        {
+            let feed = feed.clone();
+            std::thread::spawn(move || feed.serve(listener));
+        }
+        let connect = |path: &str, key: Option<&str>| {
+            let mut request = format!("ws://{}{}", address, path)
+                .into_client_request()
+                .unwrap();
+            if let Some(key) = key {
+                request
+                    .headers_mut()
+                    .insert("Authorization", format!("Bearer {}", key).parse().unwrap());
+            }
+            match tungstenite::client(request, TcpStream::connect(address).unwrap()) {
+                Ok((_, response)) => response.status(),
+                Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+                    response.status()
+                }
+                Err(error) => panic!("{}", error),
+            }
+        };
+
+        assert_eq!(connect(PATH, None), StatusCode::UNAUTHORIZED);
+        assert_eq!(connect(PATH, Some("admin")), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            connect("/ws/events", Some("dashboard")),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            connect("/ws/accounts?clients=1,x", Some("dashboard")),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            connect(PATH, Some("dashboard")),
+            StatusCode::SWITCHING_PROTOCOLS
+        );
+    }
+}
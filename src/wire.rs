@@ -0,0 +1,498 @@
+//! A flat representation of records for transports, e.g. as JSON over HTTP or on a message queue.
+//!
+//! [`Transaction`] and [`DisputeAction`] serialize as internally tagged objects themselves, but they name their fields
+//! after what they mean (`transaction_id` vs `referenced_transaction_id`). A [`WireRecord`] has the same fields for
+//! every type of record, like the CSV input:
+//!
+//! ```json
+//! { "type": "deposit", "client": 1, "tx": 1, "amount": "1.5" }
+//! { "type": "dispute", "client": 1, "tx": 1, "amount": null }
+//! ```
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::{AccountAction, Adjustment, DisputeAction, Record, Transaction, WithdrawalAction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum WireRecordType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Escalate,
+    ArbitrationWon,
+    ArbitrationLost,
+    WithdrawalRequest,
+    WithdrawalConfirm,
+    WithdrawalCancel,
+    Amend,
+    WriteOff,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WireRecord<C = u16, T = u32> {
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub record_type: WireRecordType,
+    pub client: C,
+    /// The transaction itself, or the one referenced by a dispute action.
+    pub tx: T,
+    /// Only for deposits, withdrawals, withdrawal requests and amendments, optional for openings.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub amount: Option<Amount>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum WireError {
+    #[error("a {0:?} record needs an amount")]
+    MissingAmount(WireRecordType),
+    #[error("a {0:?} record is not a transaction")]
+    NotATransaction(WireRecordType),
+    #[error("a {0:?} record is not a dispute action")]
+    NotADisputeAction(WireRecordType),
+    #[error("a {0:?} record is not a withdrawal action")]
+    NotAWithdrawalAction(WireRecordType),
+    #[error("a {0:?} record is not an adjustment")]
+    NotAnAdjustment(WireRecordType),
+    #[error("a {0:?} record is not an account action")]
+    NotAnAccountAction(WireRecordType),
+}
+
+impl<C, T> Transaction<C, T> {
+    pub(crate) fn record_type(&self) -> WireRecordType {
+        match self {
+            Transaction::Deposit { .. } => WireRecordType::Deposit,
+            Transaction::Withdrawal { .. } => WireRecordType::Withdrawal,
+            Transaction::WithdrawalRequest { .. } => WireRecordType::WithdrawalRequest,
+        }
+    }
+}
+
+impl<C, T> DisputeAction<C, T> {
+    pub(crate) fn record_type(&self) -> WireRecordType {
+        match self {
+            DisputeAction::Dispute { .. } => WireRecordType::Dispute,
+            DisputeAction::Resolve { .. } => WireRecordType::Resolve,
+            DisputeAction::Chargeback { .. } => WireRecordType::Chargeback,
+            DisputeAction::Escalate { .. } => WireRecordType::Escalate,
+            DisputeAction::ArbitrationWon { .. } => WireRecordType::ArbitrationWon,
+            DisputeAction::ArbitrationLost { .. } => WireRecordType::ArbitrationLost,
+        }
+    }
+}
+
+impl<C, T> WithdrawalAction<C, T> {
+    pub(crate) fn record_type(&self) -> WireRecordType {
+        match self {
+            WithdrawalAction::WithdrawalConfirm { .. } => WireRecordType::WithdrawalConfirm,
+            WithdrawalAction::WithdrawalCancel { .. } => WireRecordType::WithdrawalCancel,
+        }
+    }
+}
+
+impl<C, T> Adjustment<C, T> {
+    pub(crate) fn record_type(&self) -> WireRecordType {
+        match self {
+            Adjustment::Amend { .. } => WireRecordType::Amend,
+            Adjustment::WriteOff { .. } => WireRecordType::WriteOff,
+        }
+    }
+}
+
+impl<C, T> AccountAction<C, T> {
+    pub(crate) fn record_type(&self) -> WireRecordType {
+        match self {
+            AccountAction::Open { .. } => WireRecordType::Open,
+            AccountAction::Close { .. } => WireRecordType::Close,
+        }
+    }
+}
+
+impl<C, T> From<Transaction<C, T>> for WireRecord<C, T> {
+    fn from(transaction: Transaction<C, T>) -> Self {
+        let (record_type, client, tx, amount) = match transaction {
+            Transaction::Deposit {
+                client,
+                transaction_id,
+                amount,
+            } => (WireRecordType::Deposit, client, transaction_id, amount),
+            Transaction::Withdrawal {
+                client,
+                transaction_id,
+                amount,
+            } => (WireRecordType::Withdrawal, client, transaction_id, amount),
+            Transaction::WithdrawalRequest {
+                client,
+                transaction_id,
+                amount,
+            } => (
+                WireRecordType::WithdrawalRequest,
+                client,
+                transaction_id,
+                amount,
+            ),
+        };
+        Self {
+            record_type,
+            client,
+            tx,
+            amount: Some(amount),
+        }
+    }
+}
+
+impl<C, T> From<DisputeAction<C, T>> for WireRecord<C, T> {
+    fn from(dispute_action: DisputeAction<C, T>) -> Self {
+        let (record_type, client, tx) = match dispute_action {
+            DisputeAction::Dispute {
+                client,
+                referenced_transaction_id,
+            } => (WireRecordType::Dispute, client, referenced_transaction_id),
+            DisputeAction::Resolve {
+                client,
+                referenced_transaction_id,
+            } => (WireRecordType::Resolve, client, referenced_transaction_id),
+            DisputeAction::Chargeback {
+                client,
+                referenced_transaction_id,
+            } => (
+                WireRecordType::Chargeback,
+                client,
+                referenced_transaction_id,
+            ),
+            DisputeAction::Escalate {
+                client,
+                referenced_transaction_id,
+            } => (WireRecordType::Escalate, client, referenced_transaction_id),
+            DisputeAction::ArbitrationWon {
+                client,
+                referenced_transaction_id,
+            } => (
+                WireRecordType::ArbitrationWon,
+                client,
+                referenced_transaction_id,
+            ),
+            DisputeAction::ArbitrationLost {
+                client,
+                referenced_transaction_id,
+            } => (
+                WireRecordType::ArbitrationLost,
+                client,
+                referenced_transaction_id,
+            ),
+        };
+        Self {
+            record_type,
+            client,
+            tx,
+            amount: None,
+        }
+    }
+}
+
+impl<C, T> From<WithdrawalAction<C, T>> for WireRecord<C, T> {
+    fn from(action: WithdrawalAction<C, T>) -> Self {
+        let (record_type, client, tx) = match action {
+            WithdrawalAction::WithdrawalConfirm {
+                client,
+                referenced_transaction_id,
+            } => (
+                WireRecordType::WithdrawalConfirm,
+                client,
+                referenced_transaction_id,
+            ),
+            WithdrawalAction::WithdrawalCancel {
+                client,
+                referenced_transaction_id,
+            } => (
+                WireRecordType::WithdrawalCancel,
+                client,
+                referenced_transaction_id,
+            ),
+        };
+        Self {
+            record_type,
+            client,
+            tx,
+            amount: None,
+        }
+    }
+}
+
+impl<C, T> From<Adjustment<C, T>> for WireRecord<C, T> {
+    fn from(adjustment: Adjustment<C, T>) -> Self {
+        match adjustment {
+            Adjustment::Amend {
+                client,
+                referenced_transaction_id,
+                amount,
+            } => Self {
+                record_type: WireRecordType::Amend,
+                client,
+                tx: referenced_transaction_id,
+                amount: Some(amount),
+            },
+            Adjustment::WriteOff {
+                client,
+                transaction_id,
+            } => Self {
+                record_type: WireRecordType::WriteOff,
+                client,
+                tx: transaction_id,
+                amount: None,
+            },
+        }
+    }
+}
+
+impl<C, T> From<AccountAction<C, T>> for WireRecord<C, T> {
+    fn from(action: AccountAction<C, T>) -> Self {
+        match action {
+            AccountAction::Open {
+                client,
+                transaction_id,
+                initial_deposit,
+            } => Self {
+                record_type: WireRecordType::Open,
+                client,
+                tx: transaction_id,
+                amount: initial_deposit,
+            },
+            AccountAction::Close {
+                client,
+                transaction_id,
+            } => Self {
+                record_type: WireRecordType::Close,
+                client,
+                tx: transaction_id,
+                amount: None,
+            },
+        }
+    }
+}
+
+impl<C, T> TryFrom<WireRecord<C, T>> for Transaction<C, T> {
+    type Error = WireError;
+
+    fn try_from(record: WireRecord<C, T>) -> Result<Self, Self::Error> {
+        let amount = || {
+            record
+                .amount
+                .ok_or(WireError::MissingAmount(record.record_type))
+        };
+        match record.record_type {
+            WireRecordType::Deposit => Ok(Transaction::Deposit {
+                amount: amount()?,
+                client: record.client,
+                transaction_id: record.tx,
+            }),
+            WireRecordType::Withdrawal => Ok(Transaction::Withdrawal {
+                amount: amount()?,
+                client: record.client,
+                transaction_id: record.tx,
+            }),
+            WireRecordType::WithdrawalRequest => Ok(Transaction::WithdrawalRequest {
+                amount: amount()?,
+                client: record.client,
+                transaction_id: record.tx,
+            }),
+            other => Err(WireError::NotATransaction(other)),
+        }
+    }
+}
+
+impl<C, T> TryFrom<WireRecord<C, T>> for DisputeAction<C, T> {
+    type Error = WireError;
+
+    fn try_from(record: WireRecord<C, T>) -> Result<Self, Self::Error> {
+        let client = record.client;
+        let referenced_transaction_id = record.tx;
+        match record.record_type {
+            WireRecordType::Dispute => Ok(DisputeAction::Dispute {
+                client,
+                referenced_transaction_id,
+            }),
+            WireRecordType::Resolve => Ok(DisputeAction::Resolve {
+                client,
+                referenced_transaction_id,
+            }),
+            WireRecordType::Chargeback => Ok(DisputeAction::Chargeback {
+                client,
+                referenced_transaction_id,
+            }),
+            WireRecordType::Escalate => Ok(DisputeAction::Escalate {
+                client,
+                referenced_transaction_id,
+            }),
+            WireRecordType::ArbitrationWon => Ok(DisputeAction::ArbitrationWon {
+                client,
+                referenced_transaction_id,
+            }),
+            WireRecordType::ArbitrationLost => Ok(DisputeAction::ArbitrationLost {
+                client,
+                referenced_transaction_id,
+            }),
+            other => Err(WireError::NotADisputeAction(other)),
+        }
+    }
+}
+
+impl<C, T> TryFrom<WireRecord<C, T>> for WithdrawalAction<C, T> {
+    type Error = WireError;
+
+    fn try_from(record: WireRecord<C, T>) -> Result<Self, Self::Error> {
+        let client = record.client;
+        let referenced_transaction_id = record.tx;
+        match record.record_type {
+            WireRecordType::WithdrawalConfirm => Ok(WithdrawalAction::WithdrawalConfirm {
+                client,
+                referenced_transaction_id,
+            }),
+            WireRecordType::WithdrawalCancel => Ok(WithdrawalAction::WithdrawalCancel {
+                client,
+                referenced_transaction_id,
+            }),
+            other => Err(WireError::NotAWithdrawalAction(other)),
+        }
+    }
+}
+
+impl<C, T> TryFrom<WireRecord<C, T>> for Adjustment<C, T> {
+    type Error = WireError;
+
+    fn try_from(record: WireRecord<C, T>) -> Result<Self, Self::Error> {
+        match record.record_type {
+            WireRecordType::Amend => Ok(Adjustment::Amend {
+                amount: record
+                    .amount
+                    .ok_or(WireError::MissingAmount(record.record_type))?,
+                client: record.client,
+                referenced_transaction_id: record.tx,
+            }),
+            WireRecordType::WriteOff => Ok(Adjustment::WriteOff {
+                client: record.client,
+                transaction_id: record.tx,
+            }),
+            other => Err(WireError::NotAnAdjustment(other)),
+        }
+    }
+}
+
+impl<C, T> TryFrom<WireRecord<C, T>> for AccountAction<C, T> {
+    type Error = WireError;
+
+    fn try_from(record: WireRecord<C, T>) -> Result<Self, Self::Error> {
+        match record.record_type {
+            WireRecordType::Open => Ok(AccountAction::Open {
+                client: record.client,
+                transaction_id: record.tx,
+                initial_deposit: record.amount,
+            }),
+            WireRecordType::Close => Ok(AccountAction::Close {
+                client: record.client,
+                transaction_id: record.tx,
+            }),
+            other => Err(WireError::NotAnAccountAction(other)),
+        }
+    }
+}
+
+impl<C, T> From<Record<C, T>> for WireRecord<C, T> {
+    fn from(record: Record<C, T>) -> Self {
+        match record {
+            Record::Transaction(transaction) => transaction.into(),
+            Record::Dispute(dispute_action) => dispute_action.into(),
+            Record::Withdrawal(action) => action.into(),
+            Record::Adjustment(adjustment) => adjustment.into(),
+            Record::Account(action) => action.into(),
+        }
+    }
+}
+
+impl<C, T> TryFrom<WireRecord<C, T>> for Record<C, T> {
+    type Error = WireError;
+
+    fn try_from(record: WireRecord<C, T>) -> Result<Self, Self::Error> {
+        match record.record_type {
+            WireRecordType::Deposit
+            | WireRecordType::Withdrawal
+            | WireRecordType::WithdrawalRequest => {
+                Transaction::try_from(record).map(Record::Transaction)
+            }
+            WireRecordType::WithdrawalConfirm | WireRecordType::WithdrawalCancel => {
+                WithdrawalAction::try_from(record).map(Record::Withdrawal)
+            }
+            WireRecordType::Amend | WireRecordType::WriteOff => {
+                Adjustment::try_from(record).map(Record::Adjustment)
+            }
+            WireRecordType::Open | WireRecordType::Close => {
+                AccountAction::try_from(record).map(Record::Account)
+            }
+            _ => DisputeAction::try_from(record).map(Record::Dispute),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let deposit: Transaction = Transaction::Deposit {
+            client: 1,
+            transaction_id: 2,
+            amount: Amount::new(dec!(1.5)).unwrap(),
+        };
+        assert_eq!(
+            serde_json::to_string(&deposit).unwrap(),
+            r#"{"type":"deposit","client":1,"transaction_id":2,"amount":"1.5"}"#
+        );
+        let wire = WireRecord::from(deposit.clone());
+        assert_eq!(
+            serde_json::to_string(&wire).unwrap(),
+            r#"{"type":"deposit","client":1,"tx":2,"amount":"1.5"}"#
+        );
+        assert_eq!(Transaction::try_from(wire), Ok(deposit));
+
+        let wire: WireRecord =
+            serde_json::from_str(r#"{"type":"arbitration_won","client":1,"tx":2}"#).unwrap();
+        assert_eq!(
+            DisputeAction::try_from(wire.clone()),
+            Ok(DisputeAction::ArbitrationWon {
+                client: 1,
+                referenced_transaction_id: 2
+            })
+        );
+        assert_eq!(
+            Transaction::try_from(wire.clone()),
+            Err(WireError::NotATransaction(WireRecordType::ArbitrationWon))
+        );
+        assert_eq!(
+            Record::try_from(wire),
+            Ok(Record::Dispute(DisputeAction::ArbitrationWon {
+                client: 1,
+                referenced_transaction_id: 2
+            }))
+        );
+        let record: Record =
+            serde_json::from_str(r#"{"type":"resolve","client":1,"referenced_transaction_id":2}"#)
+                .unwrap();
+        assert_eq!(
+            record,
+            Record::Dispute(DisputeAction::Resolve {
+                client: 1,
+                referenced_transaction_id: 2
+            })
+        );
+    }
+}